@@ -0,0 +1,27 @@
+use std::process::Command;
+
+/// First line of a command's output, `unknown` if it can't be run
+fn output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|stdout| stdout.lines().next().map(str::to_string))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        output("git", &["rev-parse", "--short", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC_VERSION={}",
+        output(&rustc, &["--version"])
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,245 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde_json::json;
+
+use crate::{own_io::OwnIo, watch::WatchEvent};
+
+/// Appends the events of the watched directories to a file, one JSON object
+/// per line, to find out which file woke a disk up
+pub struct AuditLog {
+    path: PathBuf,
+    /// Opened on the first write and again after rotating
+    file: Option<File>,
+    /// Rotate once the file is this large
+    max_bytes: u64,
+    /// Number of rotated files to keep, like `audit.jsonl.1`
+    keep: usize,
+    /// Entries written per second at most, unlimited if zero
+    rate: u32,
+    /// Start of the current second and how many entries were written in it
+    window: Option<(Instant, u32)>,
+    /// Entries left out since the last one written
+    dropped: u64,
+    own_io: Option<OwnIo>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog {
+            path,
+            file: None,
+            max_bytes: 10 * 1024 * 1024,
+            keep: 3,
+            rate: 100,
+            window: None,
+            dropped: 0,
+            own_io: None,
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: u32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Record the writes, so they aren't mistaken for disk activity
+    pub fn with_own_io(mut self, own_io: OwnIo) -> Self {
+        self.own_io = Some(own_io);
+        self
+    }
+
+    /// Append `event`, seen at `timestamp`. `now` is for the rate limit.
+    pub fn record(
+        &mut self,
+        event: &WatchEvent,
+        timestamp: SystemTime,
+        now: Instant,
+    ) -> Result<()> {
+        let (start, written) = match self.window {
+            Some((start, written)) if now.duration_since(start) < Duration::from_secs(1) => {
+                (start, written)
+            }
+            _ => (now, 0),
+        };
+        if self.rate > 0 && written >= self.rate {
+            self.dropped += 1;
+            return Ok(());
+        }
+        self.window = Some((start, written + 1));
+        let timestamp = json!(humantime::format_rfc3339_millis(timestamp).to_string());
+        let mut lines = String::new();
+        if self.dropped > 0 {
+            lines.push_str(&format!(
+                "{{\"timestamp\":{},\"dropped\":{}}}\n",
+                timestamp, self.dropped
+            ));
+        }
+        lines.push_str(&format!(
+            "{{\"timestamp\":{},\"directory\":{},\"paths\":{},\"kind\":{},\"pid\":{},\"comm\":{}}}\n",
+            timestamp,
+            json!(event.path),
+            json!(event.paths),
+            json!(event.kind_label()),
+            json!(event.pid),
+            json!(event.comm),
+        ));
+        self.write(lines.as_bytes())?;
+        self.dropped = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .with_context(|| format!("Failed to open {}", self.path.to_string_lossy()))?,
+            ),
+        };
+        file.write_all(bytes)
+            .with_context(|| format!("Failed to write {}", self.path.to_string_lossy()))?;
+        if let Some(own_io) = &self.own_io {
+            if let Err(err) = own_io.record_write(&self.path, bytes.len() as u64) {
+                debug!("Failed to record audit log write: {:?}", err);
+            }
+        }
+        if file.metadata()?.len() >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Move the file to `.1`, `.1` to `.2` and so on, dropping the oldest
+    fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        if self.keep == 0 {
+            return remove_if_exists(&self.path);
+        }
+        remove_if_exists(&self.rotated(self.keep))?;
+        for number in (1..self.keep).rev() {
+            rename_if_exists(&self.rotated(number), &self.rotated(number + 1))?;
+        }
+        rename_if_exists(&self.path, &self.rotated(1))
+    }
+
+    fn rotated(&self, number: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {}", path.to_string_lossy()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to rotate {}", from.to_string_lossy()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use notify::EventKind;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut audit_log = AuditLog::new(path.clone()).with_rate(2);
+        let event = WatchEvent {
+            path: String::from("/srv/media"),
+            kind: EventKind::Modify(notify::event::ModifyKind::Any),
+            paths: vec![PathBuf::from("/srv/media/a.mkv")],
+            pid: Some(42),
+            comm: Some(String::from("rsync")),
+        };
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let start = Instant::now();
+        for millis in [0, 100, 200, 300, 1000] {
+            let now = start + Duration::from_millis(millis);
+            audit_log.record(&event, timestamp, now).unwrap();
+        }
+
+        let lines: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4, "{:?}", lines);
+        assert_eq!(
+            lines[0],
+            json!({
+                "timestamp": "2023-11-14T22:13:20.000Z",
+                "directory": "/srv/media",
+                "paths": ["/srv/media/a.mkv"],
+                "kind": "modify",
+                "pid": 42,
+                "comm": "rsync",
+            })
+        );
+        // two over the limit within the first second
+        assert_eq!(lines[2]["dropped"], 2);
+        assert_eq!(lines[3]["kind"], "modify");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut audit_log = AuditLog::new(path.clone())
+            .with_max_bytes(1)
+            .with_keep(2)
+            .with_rate(0);
+        let event = WatchEvent {
+            path: String::from("/srv/media"),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        for _ in 0..4 {
+            audit_log.record(&event, SystemTime::now(), now).unwrap();
+        }
+        let mut files: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["audit.jsonl.1", "audit.jsonl.2"]);
+        let line = fs::read_to_string(dir.path().join("audit.jsonl.1")).unwrap();
+        assert!(line.contains("\"pid\":null,\"comm\":null"));
+    }
+}
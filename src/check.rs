@@ -0,0 +1,266 @@
+use std::{
+    env,
+    ffi::CString,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cli::BackendArgs,
+    config::Config,
+    disk_status::BackendKind,
+    disks::is_same_disk,
+    policy::Rules,
+    watch::{expand_directories, is_pattern},
+};
+
+/// Something `check-config` found
+#[derive(Debug, PartialEq)]
+pub enum Finding {
+    /// The daemon wouldn't start or would fail while running
+    Error(String),
+    /// Works, but probably isn't what was meant
+    Warning(String),
+}
+
+impl Finding {
+    pub fn is_error(&self) -> bool {
+        matches!(self, Finding::Error(_))
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Error(message) => write!(f, "error: {}", message),
+            Finding::Warning(message) => write!(f, "warning: {}", message),
+        }
+    }
+}
+
+/// `path` can be written to by the current user
+fn is_writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: the path is a valid NUL terminated string
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// `path` is a file the current user may execute
+fn is_executable(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: the path is a valid NUL terminated string
+    path.is_file() && unsafe { libc::access(c_path.as_ptr(), libc::X_OK) == 0 }
+}
+
+/// The directory `file` (named by `option`) goes into has to exist and be
+/// writable
+pub fn check_writable_dir(file: &Path, option: &str) -> Option<Finding> {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        return Some(Finding::Error(format!(
+            "{} is in {}, which doesn't exist. Create it or choose another path",
+            option,
+            dir.to_string_lossy()
+        )));
+    }
+    if !is_writable(dir) {
+        return Some(Finding::Error(format!(
+            "{} is in {}, which the current user can't write to. Run as a user who can or \
+             choose another path",
+            option,
+            dir.to_string_lossy()
+        )));
+    }
+    None
+}
+
+/// `program` (set with `option`) is a path or found in `PATH`, and can be
+/// executed
+pub fn check_executable(program: &str, option: &str) -> Option<Finding> {
+    let found = if program.contains('/') {
+        is_executable(Path::new(program))
+    } else {
+        env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+        })
+    };
+    (!found).then(|| {
+        Finding::Error(format!(
+            "{} isn't an executable program. Install it or point {} to it",
+            program, option
+        ))
+    })
+}
+
+/// The programs the backends selected by `args` run. `smart` is set when
+/// SMART attributes are read with smartctl whatever the backend.
+pub fn check_programs(args: &BackendArgs, smart: bool) -> Vec<Finding> {
+    let backends: Vec<&BackendKind> = std::iter::once(&args.backend)
+        .chain(args.disk_backend.iter().map(|o| &o.backend))
+        .collect();
+    let mut programs = Vec::new();
+    if backends.iter().any(|b| matches!(b, BackendKind::Hdparm)) {
+        programs.push((args.hdparm.as_str(), "--hdparm"));
+    }
+    if smart
+        || backends
+            .iter()
+            .any(|b| matches!(b, BackendKind::Smartctl { .. }))
+    {
+        programs.push((args.smartctl.as_str(), "--smartctl"));
+    }
+    if backends.iter().any(|b| matches!(b, BackendKind::Udisks2)) {
+        programs.push((args.busctl.as_str(), "--busctl"));
+    }
+    if let Some(helper) = args.privilege_helper {
+        programs.push((helper.program(), "--privilege-helper"));
+    }
+    programs
+        .into_iter()
+        .filter_map(|(program, option)| check_executable(program, option))
+        .collect()
+}
+
+/// `path` (given with `option`) is an existing directory
+pub fn check_directory(path: &Path, option: &str) -> Option<Finding> {
+    (!path.is_dir()).then(|| {
+        Finding::Error(format!(
+            "{} {} isn't a directory. Create it or remove it from the options",
+            option,
+            path.to_string_lossy()
+        ))
+    })
+}
+
+/// A directory of `--watch-directories` or the config file exists, or a
+/// glob pattern matches some
+pub fn check_watch_directory(directory: &str) -> Option<Finding> {
+    if !is_pattern(directory) {
+        return check_directory(Path::new(directory), "--watch-directories");
+    }
+    match expand_directories(&[directory.to_string()]) {
+        Ok(matches) if matches.is_empty() => Some(Finding::Warning(format!(
+            "--watch-directories {} doesn't match any directories. Watches are added once it does",
+            directory
+        ))),
+        Ok(_) => None,
+        Err(err) => Some(Finding::Error(format!("{:#}", err))),
+    }
+}
+
+/// The `[disks]` sections and rules of `config` apply to disks that are
+/// present and monitored
+pub fn check_disk_settings(config: &Config, monitored: &[String]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for disk in config.disks.keys() {
+        if !PathBuf::from(disk).exists() {
+            findings.push(Finding::Warning(format!(
+                "[disks.\"{}\"] doesn't exist. The settings apply once it's attached, if the \
+                 path is right",
+                disk
+            )));
+        } else if !monitored
+            .iter()
+            .any(|monitored| is_same_disk(disk, monitored))
+        {
+            findings.push(Finding::Warning(format!(
+                "[disks.\"{}\"] isn't monitored, so its settings don't apply. See list-disks \
+                 for why",
+                disk
+            )));
+        }
+    }
+    let rules = Rules::new(config.rules.clone(), config.aliases());
+    for index in 0..config.rules.len() {
+        if !rules.applies_to_any(index, monitored) {
+            findings.push(Finding::Warning(format!(
+                "Rule {} doesn't match any monitored disk. Check its disks and names",
+                rules.name(index)
+            )));
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_paths() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            check_writable_dir(&dir.path().join("disks.prom"), "--textfile"),
+            None
+        );
+        let missing = dir.path().join("missing").join("disks.prom");
+        assert!(check_writable_dir(&missing, "--textfile").is_some_and(|f| f.is_error()));
+
+        let script = dir.path().join("hdparm");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        let script = script.to_string_lossy();
+        assert!(check_executable(&script, "--hdparm").is_some());
+        fs::set_permissions(&*script, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(check_executable(&script, "--hdparm"), None);
+        assert_eq!(check_executable("sh", "--hdparm"), None);
+        assert!(check_executable("no-such-program", "--hdparm").is_some());
+
+        assert_eq!(check_directory(dir.path(), "--watch-directories"), None);
+        assert!(check_directory(&missing, "--watch-directories").is_some());
+        let pattern = format!("{}/*", dir.path().to_string_lossy());
+        assert!(check_watch_directory(&pattern).is_some_and(|f| !f.is_error()));
+        fs::create_dir(dir.path().join("share")).unwrap();
+        assert_eq!(check_watch_directory(&pattern), None);
+        assert!(check_watch_directory("/srv/[media").is_some_and(|f| f.is_error()));
+    }
+
+    #[test]
+    fn test_disk_settings() {
+        let dir = TempDir::new().unwrap();
+        let sda = dir.path().join("sda");
+        let sdb = dir.path().join("sdb");
+        fs::write(&sda, "").unwrap();
+        fs::write(&sdb, "").unwrap();
+        let (sda, sdb) = (sda.to_string_lossy(), sdb.to_string_lossy());
+        let config = Config::parse(&format!(
+            r#"
+[disks."{sda}"]
+name = "media-1"
+[disks."{sdb}"]
+name = "parity"
+[disks."/dev/disk/by-id/no-such-disk"]
+name = "gone"
+
+[[rules]]
+names = ["media-*"]
+action = "keep-awake"
+
+[[rules]]
+name = "parity-at-night"
+names = ["parity"]
+action = "spindown"
+"#
+        ))
+        .unwrap();
+
+        let findings = check_disk_settings(&config, &[sda.to_string()]);
+        let messages: Vec<String> = findings.iter().map(Finding::to_string).collect();
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages[0].contains("no-such-disk\"] doesn't exist"));
+        assert!(messages[1].contains("sdb\"] isn't monitored"));
+        assert!(messages[2].contains("Rule parity-at-night doesn't match"));
+        assert!(!findings.iter().any(Finding::is_error));
+    }
+}
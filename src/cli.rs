@@ -1,32 +1,646 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use clap_complete::Shell;
+use glob::Pattern;
+
+use crate::{
+    command::PrivilegeHelper,
+    disk_status::{BackendKind, DiskBackendOverride},
+    disks::{DiskEnumeration, DiskNaming},
+    duration::{parse_seconds, parse_seconds_f64},
+    exposition::Format,
+    logging::LogFormat,
+    schedule::TimeWindows,
+    status::OutputFormat,
+    watch::{WatchEventKind, WatchMode},
+};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Durations take units like 30s, 5m or 2h, plain numbers are seconds.\n\
+        Every option can also be set with an environment variable, like DSM_TEXTFILE for --textfile",
+    args_conflicts_with_subcommands = true,
+)]
 pub struct Args {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    /// Options of `daemon`, which is what runs without a subcommand
+    #[command(flatten)]
+    pub daemon: DaemonArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Args {
+    /// The subcommand to run, `daemon` if none was given
+    pub fn command(self) -> (GlobalArgs, Command) {
+        let command = self.command.unwrap_or(Command::Daemon(self.daemon));
+        (self.global, command)
+    }
+}
+
+/// Options every subcommand takes. They go after the name of the subcommand,
+/// options in front of it are the daemon's.
+#[derive(clap::Args, Debug)]
+pub struct GlobalArgs {
+    /// Config file with per-disk settings
+    #[arg(long, global = true, env = "DSM_CONFIG")]
+    pub config: Option<String>,
+
+    /// Enable debug mode
+    #[arg(long, global = true, env = "DSM_DEBUG", default_value_t = false)]
+    pub debug: bool,
+
+    /// How to write log lines
+    #[arg(
+        long,
+        global = true,
+        env = "DSM_LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::Text
+    )]
+    pub log_format: LogFormat,
+}
+
+/// How disks are queried and controlled
+#[derive(clap::Args, Debug)]
+pub struct BackendArgs {
+    /// Path to hdparm, defaults to finding it in PATH
+    #[arg(long, env = "DSM_HDPARM", default_value_t = String::from("hdparm"))]
+    pub hdparm: String,
+
+    /// Path to smartctl, defaults to finding it in PATH
+    #[arg(long, env = "DSM_SMARTCTL", default_value_t = String::from("smartctl"))]
+    pub smartctl: String,
+
+    /// Path to busctl, used to talk to udisks2 over D-Bus
+    #[arg(long, env = "DSM_BUSCTL", default_value_t = String::from("busctl"))]
+    pub busctl: String,
+
+    /// Backend used to query disks: `hdparm`, `smartctl[:<device type>]` or `udisks2`. The latter
+    /// doesn't require root
+    #[arg(long, env = "DSM_BACKEND", default_value = "hdparm")]
+    pub backend: BackendKind,
+
+    /// Use a different backend for a single disk, e.g. `/dev/sdc=smartctl:sat`. Repeat argument
+    /// or separate with commas for multiple disks
+    #[arg(long, env = "DSM_DISK_BACKEND", value_delimiter = ',')]
+    pub disk_backend: Vec<DiskBackendOverride>,
+
+    /// Run hdparm, smartctl and dd through this helper so the daemon itself doesn't need root.
+    /// Commands run as `env LC_ALL=C <command>`, so the helper's rules have to allow env
+    #[arg(long, env = "DSM_PRIVILEGE_HELPER", value_enum)]
+    pub privilege_helper: Option<PrivilegeHelper>,
+
+    /// Timeout in seconds after which a hung hdparm/smartctl/busctl invocation gets killed
+    #[arg(long, env = "DSM_COMMAND_TIMEOUT", value_parser = parse_seconds, default_value_t = 30)]
+    pub command_timeout: u64,
+
+    /// Wait at least this many seconds between two disk status queries or spin-ups, so disks
+    /// don't all spin up at the same time
+    #[arg(long, env = "DSM_STAGGER", value_parser = parse_seconds_f64, default_value_t = 0.0)]
+    pub stagger: f64,
+
+    /// Wait up to this many seconds longer, chosen at random, before each query or spin-up
+    #[arg(long, env = "DSM_STAGGER_JITTER", value_parser = parse_seconds_f64, default_value_t = 0.0)]
+    pub stagger_jitter: f64,
+
+    /// Spin up at most this many disks at the same time
+    #[arg(long, env = "DSM_MAX_CONCURRENT_SPINUPS")]
+    pub max_concurrent_spinups: Option<usize>,
+
+    /// Allow spinning down the disks holding / and swap, they are protected by default
+    #[arg(long, env = "DSM_ALLOW_SYSTEM_DISK", default_value_t = false)]
+    pub allow_system_disk: bool,
+
+    /// Only log spin-downs, spin-ups and APM or standby timer changes instead of executing them.
+    /// They still show up in the metrics as if they succeeded
+    #[arg(long, env = "DSM_DRY_RUN", default_value_t = false)]
+    pub dry_run: bool,
+}
+
+/// Which disks are monitored
+#[derive(clap::Args, Debug)]
+pub struct DiskArgs {
+    /// Which block devices to consider disks. `all` also finds virtio, MMC and other non-SCSI
+    /// disks
+    #[arg(long, env = "DSM_ENUMERATE", value_enum, default_value_t = DiskEnumeration::Scsi)]
+    pub enumerate: DiskEnumeration,
+
+    /// Also monitor SSDs and other non-rotational disks. Single disks can be configured with
+    /// `monitor` in the config file
+    #[arg(long, env = "DSM_MONITOR_ALL_DISKS")]
+    pub monitor_all_disks: bool,
+
+    /// Monitor the disks backing this path instead of scanning for disks, resolving its mount
+    /// through device-mapper and md. Repeat argument or separate with commas for multiple paths
+    #[arg(long, env = "DSM_MONITOR_PATH", value_delimiter = ',')]
+    pub monitor_path: Vec<String>,
+
+    /// Scan for disks in addition to the ones found with `--monitor-path`
+    #[arg(long, env = "DSM_SCAN_DISKS")]
+    pub scan_disks: bool,
+
+    /// Only monitor disks matching this glob, e.g. `/dev/sd[c-h]`. Repeat argument or separate
+    /// with commas for multiple patterns
+    #[arg(long, env = "DSM_INCLUDE_DISKS", value_delimiter = ',')]
+    pub include_disks: Vec<Pattern>,
+
+    /// Never monitor disks matching this glob, e.g. `/dev/sda`. Repeat argument or separate with
+    /// commas for multiple patterns
+    #[arg(long, env = "DSM_EXCLUDE_DISKS", value_delimiter = ',')]
+    pub exclude_disks: Vec<Pattern>,
+
+    /// Name used for the `disk` label. Kernel names like `/dev/sda` can change across reboots
+    #[arg(long, env = "DSM_DISK_NAMES", value_enum, default_value_t = DiskNaming::ById)]
+    pub disk_names: DiskNaming,
+
+    /// Also add the kernel name of each disk as a `device` label
+    #[arg(long, env = "DSM_DEVICE_LABEL")]
+    pub device_label: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DaemonArgs {
     /// Textfile path where to write metrics
     #[arg(
         long,
+        env = "DSM_TEXTFILE",
         default_value_t = String::from("/var/lib/node_exporter/textfile_collector/disk_status.prom"),
     )]
     pub textfile: String,
 
     /// Interval at which to save new metrics to textfile
-    #[arg(long, default_value_t = 15)]
+    #[arg(long, env = "DSM_TEXTFILE_INTERVAL", value_parser = parse_seconds, default_value_t = 15)]
     pub textfile_interval: u64,
 
-    /// Path to hdparm, defaults to finding it in PATH
-    #[arg(long, default_value_t = String::from("hdparm"))]
-    pub hdparm: String,
+    /// Mode of the textfile in octal, like 0644. Defaults to what the umask allows
+    #[arg(long, env = "DSM_TEXTFILE_MODE")]
+    pub textfile_mode: Option<String>,
 
-    /// Enable debug mode
-    #[arg(long, default_value_t = false)]
-    pub debug: bool,
+    /// User to own the textfile, by name or uid
+    #[arg(long, env = "DSM_TEXTFILE_OWNER")]
+    pub textfile_owner: Option<String>,
+
+    /// Group to own the textfile, by name or gid
+    #[arg(long, env = "DSM_TEXTFILE_GROUP")]
+    pub textfile_group: Option<String>,
+
+    /// Don't write the textfile, e.g. when metrics are served with --listen
+    #[arg(long, env = "DSM_NO_TEXTFILE", default_value_t = false)]
+    pub no_textfile: bool,
+
+    /// Serve metrics over HTTP on /metrics at this address, like 0.0.0.0:9144
+    #[arg(long, env = "DSM_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Serve metrics over HTTP on /metrics on a unix socket at this path, e.g. for
+    /// `curl --unix-socket`
+    #[arg(long, env = "DSM_LISTEN_UNIX")]
+    pub listen_unix: Option<String>,
+
+    /// Also push metrics to this Prometheus Pushgateway every textfile interval, like
+    /// http://pushgateway:9091. Combine with --no-textfile to only push
+    #[arg(long, env = "DSM_PUSHGATEWAY")]
+    pub pushgateway: Option<String>,
+
+    /// Job to push metrics as
+    #[arg(long, env = "DSM_PUSH_JOB", default_value_t = String::from("disk_spin_manager"))]
+    pub push_job: String,
+
+    /// Instance to push metrics as, defaults to the hostname
+    #[arg(long, env = "DSM_PUSH_INSTANCE")]
+    pub push_instance: Option<String>,
+
+    /// Also write metrics in InfluxDB line protocol to this file every textfile interval, e.g. for
+    /// Telegraf's file input
+    #[arg(long, env = "DSM_INFLUX_FILE")]
+    pub influx_file: Option<String>,
+
+    /// Also post metrics in InfluxDB line protocol to this write endpoint every textfile interval,
+    /// like http://influxdb:8086/api/v2/write?org=home&bucket=disks
+    #[arg(long, env = "DSM_INFLUX_URL", conflicts_with = "influx_file")]
+    pub influx_url: Option<String>,
+
+    /// InfluxDB 2 API token for --influx-url
+    #[arg(long, env = "DSM_INFLUX_TOKEN", hide_env_values = true)]
+    pub influx_token: Option<String>,
+
+    /// Also send metrics to this Graphite Carbon plaintext endpoint, like graphite:2003
+    #[arg(long, env = "DSM_GRAPHITE")]
+    pub graphite: Option<String>,
+
+    /// Prefix of the metric names sent to Graphite, empty for none
+    #[arg(long, env = "DSM_GRAPHITE_PREFIX", default_value_t = String::from("disk_spin_manager"))]
+    pub graphite_prefix: String,
+
+    /// Interval in seconds at which to send metrics to Graphite, rounded up to the textfile
+    /// interval
+    #[arg(long, env = "DSM_GRAPHITE_INTERVAL", value_parser = parse_seconds, default_value_t = 60)]
+    pub graphite_interval: u64,
+
+    /// Also send metrics as values of trapper items to this Zabbix server or proxy every textfile
+    /// interval, like zabbix:10051
+    #[arg(long, env = "DSM_ZABBIX_SERVER")]
+    pub zabbix_server: Option<String>,
+
+    /// Also write metrics as input for `zabbix_sender -T -i` to this file every textfile interval
+    #[arg(long, env = "DSM_ZABBIX_FILE", conflicts_with = "zabbix_server")]
+    pub zabbix_file: Option<String>,
+
+    /// Host the trapper items belong to in Zabbix, defaults to the hostname
+    #[arg(long, env = "DSM_ZABBIX_HOST")]
+    pub zabbix_host: Option<String>,
+
+    /// Also export metrics to this OpenTelemetry collector with OTLP/HTTP every textfile
+    /// interval, like http://collector:4318
+    #[arg(long, env = "DSM_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Header to send with every OTLP export, like `Authorization=Bearer secret`. Repeat argument
+    /// for multiple headers
+    #[arg(long, env = "DSM_OTLP_HEADER", requires = "otlp_endpoint")]
+    pub otlp_header: Vec<String>,
+
+    /// Also send metrics to this Prometheus remote_write endpoint, like
+    /// http://prometheus:9090/api/v1/write
+    #[arg(long, env = "DSM_REMOTE_WRITE")]
+    pub remote_write: Option<String>,
+
+    /// Username for basic auth with --remote-write
+    #[arg(
+        long,
+        env = "DSM_REMOTE_WRITE_USERNAME",
+        requires = "remote_write_password"
+    )]
+    pub remote_write_username: Option<String>,
+
+    /// Password for basic auth with --remote-write
+    #[arg(
+        long,
+        env = "DSM_REMOTE_WRITE_PASSWORD",
+        hide_env_values = true,
+        requires = "remote_write_username"
+    )]
+    pub remote_write_password: Option<String>,
+
+    /// Interval in seconds at which to send metrics with --remote-write, rounded up to the
+    /// textfile interval
+    #[arg(long, env = "DSM_REMOTE_WRITE_INTERVAL", value_parser = parse_seconds, default_value_t = 60)]
+    pub remote_write_interval: u64,
+
+    /// Also write the state of each disk as JSON to this file every textfile interval, for
+    /// scripts and dashboards that don't speak Prometheus
+    #[arg(long, env = "DSM_JSON_STATUS")]
+    pub json_status: Option<String>,
+
+    /// Publish to this MQTT broker whenever a disk starts or stops spinning, with Home Assistant
+    /// discovery, like mqtt.local:1883
+    #[arg(long, env = "DSM_MQTT")]
+    pub mqtt: Option<String>,
+
+    /// Username for --mqtt
+    #[arg(long, env = "DSM_MQTT_USERNAME", requires = "mqtt_password")]
+    pub mqtt_username: Option<String>,
+
+    /// Password for --mqtt
+    #[arg(
+        long,
+        env = "DSM_MQTT_PASSWORD",
+        hide_env_values = true,
+        requires = "mqtt_username"
+    )]
+    pub mqtt_password: Option<String>,
+
+    /// Prefix of the MQTT topics the disk states are published on
+    #[arg(long, env = "DSM_MQTT_TOPIC", default_value_t = String::from("disk_spin_manager"))]
+    pub mqtt_topic: String,
+
+    /// Prefix Home Assistant looks for discovery configs at
+    #[arg(long, env = "DSM_MQTT_DISCOVERY_PREFIX", default_value_t = String::from("homeassistant"))]
+    pub mqtt_discovery_prefix: String,
+
+    /// Format of the textfile and the HTTP endpoint
+    #[arg(long, env = "DSM_FORMAT", value_enum, default_value_t = Format::Prometheus)]
+    pub format: Format,
 
     /// Refresh interval in seconds, how often to run hdparm to query disk status
-    #[arg(long, default_value_t = 60)]
+    #[arg(long, env = "DSM_REFRESH_INTERVAL", value_parser = parse_seconds, default_value_t = 60)]
     pub refresh_interval: u64,
 
-    /// Which directory to monitor for events. Repeat argument for multiple directories
-    #[arg(long)]
+    /// Query every disk once, export the metrics and exit instead of running as a daemon, e.g.
+    /// from cron or as a monitoring check. Exits non-zero if a disk couldn't be queried
+    #[arg(long, env = "DSM_ONCE", default_value_t = false)]
+    pub once: bool,
+
+    /// Maximum number of disks to query at the same time
+    #[arg(long, env = "DSM_QUERY_CONCURRENCY", default_value_t = 4)]
+    pub query_concurrency: usize,
+
+    /// Seconds to wait before querying or spinning down a disk again after it failed, doubled
+    /// after each consecutive failure
+    #[arg(long, env = "DSM_RETRY_INITIAL_BACKOFF", value_parser = parse_seconds, default_value_t = 60)]
+    pub retry_initial_backoff: u64,
+
+    /// Upper limit in seconds for the backoff of failing disks
+    #[arg(long, env = "DSM_RETRY_MAX_BACKOFF", value_parser = parse_seconds, default_value_t = 3600)]
+    pub retry_max_backoff: u64,
+
+    /// Stop querying or spinning down a disk after this many consecutive failures, 0 to retry
+    /// forever
+    #[arg(long, env = "DSM_MAX_FAILURES", default_value_t = 10)]
+    pub max_failures: u32,
+
+    /// Stop querying a disk after it reported an unknown power state this many times in a row,
+    /// 0 to keep querying forever
+    #[arg(long, env = "DSM_UNSUPPORTED_AFTER", default_value_t = 5)]
+    pub unsupported_after: u32,
+
+    /// Spin disks down after they didn't see any I/O for this many seconds. Disabled by default,
+    /// single disks can be configured with `spindown_after` in the config file
+    #[arg(long, env = "DSM_SPINDOWN_AFTER", value_parser = parse_seconds)]
+    pub spindown_after: Option<u64>,
+
+    /// Keep disks spinning for at least this many seconds after they spun up
+    #[arg(long, env = "DSM_MIN_SPINUP", value_parser = parse_seconds, default_value_t = 0)]
+    pub min_spinup: u64,
+
+    /// Don't spin disks down during these daily windows of local time, e.g. `02:00-05:00` or
+    /// `22:00-06:00,12:00-13:00`
+    #[arg(long, env = "DSM_KEEP_AWAKE")]
+    pub keep_awake: Option<TimeWindows>,
+
+    /// Spin disks up when a keep-awake window starts, e.g. ahead of a scheduled backup
+    #[arg(long, env = "DSM_KEEP_AWAKE_SPINUP", default_value_t = false)]
+    pub keep_awake_spinup: bool,
+
+    /// Put disks that were spun down back into standby when they wake up without any I/O showing
+    /// up within this many seconds, e.g. because of a service querying them
+    #[arg(long, env = "DSM_ENFORCE_STANDBY", value_parser = parse_seconds)]
+    pub enforce_standby: Option<u64>,
+
+    /// Don't spin a disk down more than this many times within 24 hours, to limit wear from load
+    /// cycles when the timeout is too aggressive
+    #[arg(long, env = "DSM_MAX_SPIN_CYCLES")]
+    pub max_spin_cycles: Option<u32>,
+
+    /// Briefly spin up disks that didn't see any I/O for this many seconds, e.g. 604800 to exercise
+    /// rarely used archive disks weekly so their heads and motor don't get stuck
+    #[arg(long, env = "DSM_EXERCISE_INTERVAL", value_parser = parse_seconds)]
+    pub exercise_interval: Option<u64>,
+
+    /// Sync the filesystems on a disk before spinning it down, so writing back dirty pages doesn't
+    /// wake it right up again
+    #[arg(long, env = "DSM_SYNC_BEFORE_SPINDOWN", default_value_t = false)]
+    pub sync_before_spindown: bool,
+
+    /// Count I/O within this many seconds after a disk was spun down as an early wakeup, a sign of
+    /// a too aggressive idle timeout
+    #[arg(long, env = "DSM_EARLY_WAKEUP_WINDOW", value_parser = parse_seconds, default_value_t = 300)]
+    pub early_wakeup_window: u64,
+
+    /// Estimated power draw of a spinning disk serving requests in watts, for `disk_power_watts`
+    #[arg(long, env = "DSM_ACTIVE_WATTS", default_value_t = 6.0)]
+    pub active_watts: f64,
+
+    /// Estimated power draw of a spinning disk in a low power idle mode in watts
+    #[arg(long, env = "DSM_IDLE_WATTS", default_value_t = 4.0)]
+    pub idle_watts: f64,
+
+    /// Estimated power draw of a disk in standby in watts
+    #[arg(long, env = "DSM_STANDBY_WATTS", default_value_t = 0.8)]
+    pub standby_watts: f64,
+
+    /// How many seconds after a disk was seen waking up to look for notify events and I/O that
+    /// explain it
+    #[arg(long, env = "DSM_WAKE_CAUSE_WINDOW", value_parser = parse_seconds, default_value_t = 30)]
+    pub wake_cause_window: u64,
+
+    /// Use fanotify on the mounts of the watched directories to find the processes that wake disks
+    /// up. Needs CAP_SYS_ADMIN
+    #[arg(long, env = "DSM_FANOTIFY", default_value_t = false)]
+    pub fanotify: bool,
+
+    /// Read the SMART attributes of spinning disks with smartctl every this many seconds, for the
+    /// load cycle count (attribute 193) and --smart-attributes. Disks in standby are skipped, not
+    /// woken up
+    #[arg(long, env = "DSM_SMART_INTERVAL", value_parser = parse_seconds, alias = "load-cycle-interval")]
+    pub smart_interval: Option<u64>,
+
+    /// IDs of the SMART attributes to export the raw values of, by default reallocated sectors,
+    /// power-on hours and pending sectors
+    #[arg(
+        long,
+        env = "DSM_SMART_ATTRIBUTES",
+        value_delimiter = ',',
+        default_value = "5,9,197"
+    )]
+    pub smart_attributes: Vec<u8>,
+
+    /// Warn when the load cycle count of a disk increases by more than this within 24 hours
+    #[arg(long, env = "DSM_LOAD_CYCLE_WARN_PER_DAY")]
+    pub load_cycle_warn_per_day: Option<u64>,
+
+    /// How often to check disks for I/O in `/proc/diskstats`
+    #[arg(long, env = "DSM_ACTIVITY_INTERVAL", value_parser = parse_seconds, default_value_t = 10)]
+    pub activity_interval: u64,
+
+    /// Don't listen for hotplug events, newly attached disks then only show up on the next refresh
+    #[arg(long, env = "DSM_NO_HOTPLUG", default_value_t = false)]
+    pub no_hotplug: bool,
+
+    /// Which directory to monitor for events. Repeat argument or separate with commas for multiple
+    /// directories. Glob patterns like `/srv/media/*` watch all matching directories and pick up
+    /// new matches every refresh interval
+    #[arg(long, env = "DSM_WATCH_DIRECTORIES", value_delimiter = ',')]
     pub watch_directories: Vec<String>,
+
+    /// Also watch where the filesystems of the monitored disks are mounted, following device
+    /// mapper and md. The root filesystem is left out
+    #[arg(long, env = "DSM_WATCH_MOUNT_POINTS")]
+    pub watch_mount_points: bool,
+
+    /// Ignore events for paths matching this glob, e.g. `*.tmp` or `**/.recycle/**`. Globs without
+    /// a `/` match the file name, others the path below the watched directory. Repeat argument or
+    /// separate with commas for multiple patterns
+    #[arg(long, env = "DSM_WATCH_IGNORE", value_delimiter = ',')]
+    pub watch_ignore: Vec<Pattern>,
+
+    /// Count events for the same path and kind only once within this many seconds, so a burst of
+    /// writes to one file is a single event. 0 counts every event
+    #[arg(long, env = "DSM_WATCH_DEBOUNCE", value_parser = parse_seconds_f64, default_value_t = 0.0)]
+    pub watch_debounce: f64,
+
+    /// Count only these kinds of events, e.g. `create,modify,remove` to leave out reads and
+    /// changes of permissions or timestamps. All kinds count by default
+    #[arg(long, env = "DSM_WATCH_EVENTS", value_delimiter = ',')]
+    pub watch_events: Vec<WatchEventKind>,
+
+    /// How watch directories notice changes. Single directories can have their own `mode` in the
+    /// config file
+    #[arg(long, env = "DSM_WATCH_MODE", value_enum, default_value_t = WatchMode::Auto)]
+    pub watch_mode: WatchMode,
+
+    /// Seconds between scans of watch directories on network and FUSE filesystems like NFS, CIFS
+    /// or mergerfs, where inotify misses changes
+    #[arg(long, env = "DSM_WATCH_POLL_INTERVAL", value_parser = parse_seconds, default_value_t = 30)]
+    pub watch_poll_interval: u64,
+
+    /// Append every counted event of the watch directories to this file as a line of JSON, with
+    /// the process behind it in fanotify mode. Helps to find out what keeps a disk awake
+    #[arg(long, env = "DSM_AUDIT_LOG")]
+    pub audit_log: Option<String>,
+
+    /// Write at most this many lines per second to --audit-log and count the rest. 0 writes all
+    #[arg(long, env = "DSM_AUDIT_LOG_RATE", default_value_t = 100)]
+    pub audit_log_rate: u32,
+
+    /// Rotate --audit-log once it's this many megabytes
+    #[arg(long, env = "DSM_AUDIT_LOG_MAX_MB", default_value_t = 10)]
+    pub audit_log_max_mb: u64,
+
+    /// How many rotated files of --audit-log to keep
+    #[arg(long, env = "DSM_AUDIT_LOG_KEEP", default_value_t = 3)]
+    pub audit_log_keep: usize,
+
+    #[command(flatten, next_help_heading = "Disk backend")]
+    pub backend: BackendArgs,
+
+    #[command(flatten, next_help_heading = "Disk selection")]
+    pub disks: DiskArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Monitor disks and export metrics, the default without a subcommand
+    Daemon(DaemonArgs),
+    /// Show the power state of the monitored disks, querying them once
+    Status {
+        /// How to print the disks
+        #[arg(long, env = "DSM_OUTPUT", value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+        /// Ask the running daemon instead of querying the disks, by reading the file it writes
+        /// with --json-status. Only the daemon knows when disks last changed their state
+        #[arg(long, env = "DSM_JSON_STATUS")]
+        json_status: Option<String>,
+        #[command(flatten, next_help_heading = "Disk backend")]
+        backend: BackendArgs,
+        #[command(flatten, next_help_heading = "Disk selection")]
+        disks: DiskArgs,
+    },
+    /// List the block devices, whether they would be monitored and why not
+    ListDisks {
+        /// How to print the disks
+        #[arg(long, env = "DSM_OUTPUT", value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+        #[command(flatten, next_help_heading = "Disk selection")]
+        disks: DiskArgs,
+    },
+    /// Put disks into standby right away, using the configured backend
+    Spindown {
+        #[command(flatten, next_help_heading = "Disk backend")]
+        backend: BackendArgs,
+        #[arg(required = true)]
+        disks: Vec<String>,
+    },
+    /// Wake disks up by reading from them
+    Spinup {
+        #[command(flatten, next_help_heading = "Disk backend")]
+        backend: BackendArgs,
+        #[arg(required = true)]
+        disks: Vec<String>,
+    },
+    /// Check the config file, the options, paths and programs the daemon would
+    /// run with and which disks the settings apply to. Exits non-zero on errors.
+    CheckConfig(DaemonArgs),
+    /// Write a commented starter config file with a section for each
+    /// monitored disk, named after where it's mounted
+    InitConfig {
+        /// File to write, printed to stdout without one
+        path: Option<String>,
+        /// Replace the file if it exists
+        #[arg(long)]
+        force: bool,
+        #[command(flatten, next_help_heading = "Disk selection")]
+        disks: DiskArgs,
+    },
+    /// Print the completion script for a shell, e.g.
+    /// `disk_spin_manager completions bash > /etc/bash_completion.d/disk_spin_manager`
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> (GlobalArgs, Command) {
+        Args::try_parse_from([&["disk_spin_manager"], args].concat())
+            .unwrap()
+            .command()
+    }
+
+    #[test]
+    fn test_parse() {
+        // Only this test parses arguments, so the environment is its own
+        let (global, command) = parse(&["--config", "dsm.toml", "--textfile", "disks.prom"]);
+        assert_eq!(global.config.as_deref(), Some("dsm.toml"));
+        let Command::Daemon(daemon) = command else {
+            panic!("not the daemon: {:?}", command);
+        };
+        assert_eq!(daemon.textfile, "disks.prom");
+
+        let (_, command) = parse(&["daemon", "--textfile", "disks.prom", "--dry-run"]);
+        let Command::Daemon(daemon) = command else {
+            panic!("not the daemon: {:?}", command);
+        };
+        assert_eq!(daemon.textfile, "disks.prom");
+        assert!(daemon.backend.dry_run);
+
+        let (global, command) = parse(&["spindown", "--debug", "/dev/sda", "/dev/sdb"]);
+        assert!(global.debug);
+        let Command::Spindown { disks, .. } = command else {
+            panic!("not spindown: {:?}", command);
+        };
+        assert_eq!(disks, ["/dev/sda", "/dev/sdb"]);
+
+        let (_, command) = parse(&["completions", "zsh"]);
+        assert!(matches!(
+            command,
+            Command::Completions { shell: Shell::Zsh }
+        ));
+        assert!(Args::try_parse_from(["disk_spin_manager", "completions", "tcsh"]).is_err());
+
+        // daemon options don't belong to other subcommands
+        let args = ["disk_spin_manager", "--textfile", "disks.prom", "status"];
+        assert!(Args::try_parse_from(args).is_err());
+
+        std::env::set_var("DSM_WATCH_DIRECTORIES", "/srv/media,/srv/backup");
+        std::env::set_var("DSM_REFRESH_INTERVAL", "5m");
+        std::env::set_var("DSM_DRY_RUN", "true");
+        std::env::set_var("DSM_TEXTFILE_INTERVAL", "30");
+        let (_, command) = parse(&["--textfile-interval", "5"]);
+        let Command::Daemon(daemon) = command else {
+            panic!("not the daemon: {:?}", command);
+        };
+        assert_eq!(daemon.watch_directories, ["/srv/media", "/srv/backup"]);
+        assert_eq!(daemon.refresh_interval, 300);
+        assert!(daemon.backend.dry_run);
+        // the command line wins
+        assert_eq!(daemon.textfile_interval, 5);
+        let (_, command) = parse(&["spinup", "/dev/sda"]);
+        let Command::Spinup { backend, .. } = command else {
+            panic!("not spinup: {:?}", command);
+        };
+        assert!(backend.dry_run);
+
+        std::env::set_var("DSM_REFRESH_INTERVAL", "often");
+        assert!(Args::try_parse_from(["disk_spin_manager"]).is_err());
+    }
 }
@@ -0,0 +1,242 @@
+use std::{
+    fmt,
+    io::Read,
+    process::{Command, Output, Stdio},
+    sync::Arc,
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Executes external programs on behalf of the backends. Implementations can
+/// change how commands are run (timeouts, privilege helpers, ...) or replace
+/// them altogether in tests.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output>;
+}
+
+/// Runs commands directly in the C locale, killing them after `timeout`
+pub struct SystemRunner {
+    pub timeout: Duration,
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        // None of the tools we call translate the bits we parse today, but
+        // force the C locale so parsing never depends on the environment
+        let mut command = Command::new(program);
+        command.env("LC_ALL", "C").args(args);
+        output_with_timeout(&mut command, self.timeout)
+            .with_context(|| format!("Failed to execute {}", program))
+    }
+}
+
+/// Helper used to run the commands that need root while the daemon itself runs
+/// unprivileged
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum PrivilegeHelper {
+    Sudo,
+    Doas,
+}
+
+impl PrivilegeHelper {
+    /// Program and arguments to prefix commands with. `-n` makes the helper
+    /// fail instead of prompting for a password.
+    fn prefix(&self) -> [&'static str; 2] {
+        match self {
+            PrivilegeHelper::Sudo => ["sudo", "-n"],
+            PrivilegeHelper::Doas => ["doas", "-n"],
+        }
+    }
+
+    /// The helper's program, looked up in `PATH`
+    pub fn program(&self) -> &'static str {
+        self.prefix()[0]
+    }
+}
+
+/// Runs commands through a privilege helper, e.g.
+/// `sudo -n env LC_ALL=C hdparm -C /dev/sda`. The helpers reset the
+/// environment, so the C locale is set again through `env`.
+pub struct PrivilegedRunner {
+    pub helper: PrivilegeHelper,
+    pub inner: Arc<dyn CommandRunner>,
+}
+
+impl CommandRunner for PrivilegedRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let [helper, helper_arg] = self.helper.prefix();
+        let mut helper_args = vec![helper_arg, "env", "LC_ALL=C", program];
+        helper_args.extend(args);
+        self.inner.run(helper, &helper_args)
+    }
+}
+
+/// Error returned when a command didn't finish in time. Callers can
+/// `downcast_ref` to tell timeouts apart from other failures.
+#[derive(Debug)]
+pub struct CommandTimeout {
+    pub program: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} did not finish within {:?} and was killed",
+            self.program, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for CommandTimeout {}
+
+/// Like `Command::output`, but kill the child if it runs longer than
+/// `timeout`.
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn {}", program))?;
+
+    // Read the pipes on separate threads so a chatty child can't block on a
+    // full pipe while we wait for it
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            });
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        sleep(POLL_INTERVAL);
+    }
+
+    warn!("Killing {} after {:?}", program, timeout);
+    if let Err(err) = child.kill() {
+        debug!("Failed to kill {}: {:?}", program, err);
+    }
+    // A process stuck in uninterruptible I/O only exits once the I/O returns,
+    // so reap it in the background instead of blocking the caller
+    thread::spawn(move || {
+        let _ = child.wait();
+    });
+    Err(CommandTimeout { program, timeout }.into())
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{
+        collections::HashMap, os::unix::process::ExitStatusExt, process::ExitStatus, sync::Mutex,
+    };
+
+    use super::*;
+
+    /// Returns canned stdout keyed by the full command line, e.g.
+    /// `hdparm -C /dev/sda`. Unknown commands exit with status 1.
+    #[derive(Default)]
+    pub struct FakeRunner {
+        pub outputs: HashMap<String, String>,
+        /// Program and arguments of every command run so far
+        pub calls: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl FakeRunner {
+        pub fn with_output(mut self, command: &str, stdout: &str) -> Self {
+            self.outputs.insert(command.to_string(), stdout.to_string());
+            self
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+            let argv: Vec<_> = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect();
+            let command_line = argv.join(" ");
+            self.calls
+                .lock()
+                .unwrap()
+                .push(argv.iter().map(|arg| arg.to_string()).collect());
+            let (status, stdout) = match self.outputs.get(&command_line) {
+                Some(stdout) => (0, stdout.clone()),
+                None => (1 << 8, String::new()),
+            };
+            Ok(Output {
+                status: ExitStatus::from_raw(status),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_privileged_runner() {
+        let inner = Arc::new(FakeRunner::default().with_output(
+            "doas -n env LC_ALL=C hdparm -C /dev/sda",
+            include_str!("../fixtures/hdparm/standby.txt"),
+        ));
+        let runner = PrivilegedRunner {
+            helper: PrivilegeHelper::Doas,
+            inner: inner.clone(),
+        };
+        let output = runner.run("hdparm", &["-C", "/dev/sda"]).unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            *inner.calls.lock().unwrap(),
+            [["doas", "-n", "env", "LC_ALL=C", "hdparm", "-C", "/dev/sda"]]
+        );
+    }
+
+    #[test]
+    fn test_system_runner() {
+        let runner = SystemRunner {
+            timeout: Duration::from_secs(5),
+        };
+        let output = runner.run("sh", &["-c", "echo $LC_ALL"]).unwrap();
+        assert_eq!(output.stdout, b"C\n");
+    }
+
+    #[test]
+    fn test_output_with_timeout() {
+        let output =
+            output_with_timeout(Command::new("echo").arg("hi"), Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hi\n");
+    }
+
+    #[test]
+    fn test_output_with_timeout_kills() {
+        let start = Instant::now();
+        let err = output_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(50))
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let timeout = err.downcast_ref::<CommandTimeout>().unwrap();
+        assert_eq!(timeout.program, "sleep");
+    }
+}
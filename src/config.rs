@@ -0,0 +1,402 @@
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::{
+    disk_status::PowerSettings,
+    disks::DiskFilter,
+    duration::deserialize_seconds,
+    policy::Rule,
+    power::Wattage,
+    schedule::TimeWindows,
+    watch::{WatchEventKind, WatchMode, WatchOptions},
+};
+
+/// Settings from the config file passed with `--config`. Sending SIGHUP
+/// re-reads it.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directories or glob patterns to watch in addition to
+    /// `--watch-directories`
+    #[serde(default)]
+    pub watch_directories: Vec<WatchDirectory>,
+    /// Also watch where the monitored disks are mounted, overriding
+    /// `--watch-mount-points`
+    pub watch_mount_points: Option<bool>,
+    /// Kinds of events that count, overriding `--watch-events`
+    pub watch_events: Option<Vec<WatchEventKind>>,
+    /// How watch directories notice changes, overriding `--watch-mode`
+    pub watch_mode: Option<WatchMode>,
+    /// Seconds between scans of polled watch directories, overriding
+    /// `--watch-poll-interval`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub watch_poll_interval: Option<u64>,
+    /// Globs of paths whose events are ignored in addition to
+    /// `--watch-ignore`
+    #[serde(default)]
+    pub watch_ignore: Vec<String>,
+    /// Globs of disks to monitor in addition to `--include-disks`
+    #[serde(default)]
+    pub include_disks: Vec<String>,
+    /// Globs of disks to never monitor in addition to `--exclude-disks`
+    #[serde(default)]
+    pub exclude_disks: Vec<String>,
+    /// Seconds between disk status queries, overriding `--refresh-interval`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub refresh_interval: Option<u64>,
+    /// Seconds between exports of the metrics, overriding
+    /// `--textfile-interval`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub textfile_interval: Option<u64>,
+    /// Per-disk settings, keyed by any path of the disk like
+    /// `/dev/disk/by-id/ata-WDC_...` or `/dev/sda`
+    #[serde(default)]
+    pub disks: BTreeMap<String, DiskConfig>,
+    /// Rules deciding when disks are spun down, checked in order before the
+    /// default policy
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// An entry of `watch_directories`, either just the directory or a table
+/// like `{ path = "/srv/backup", events = ["create"] }`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum WatchDirectory {
+    Path(String),
+    Settings(WatchDirectorySettings),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WatchDirectorySettings {
+    /// Directory or glob pattern
+    pub path: String,
+    /// Kinds of events that count below the directory, overriding
+    /// `watch_events`
+    pub events: Option<Vec<WatchEventKind>>,
+    /// `"poll"` to scan for changes instead of using inotify, `"inotify"` to
+    /// never do or `"fanotify"` to mark the whole mount, overriding
+    /// `watch_mode`
+    pub mode: Option<WatchMode>,
+}
+
+impl WatchDirectory {
+    pub fn path(&self) -> &str {
+        match self {
+            WatchDirectory::Path(path) => path,
+            WatchDirectory::Settings(settings) => &settings.path,
+        }
+    }
+
+    fn events(&self) -> Option<&[WatchEventKind]> {
+        match self {
+            WatchDirectory::Path(_) => None,
+            WatchDirectory::Settings(settings) => settings.events.as_deref(),
+        }
+    }
+
+    fn mode(&self) -> Option<WatchMode> {
+        match self {
+            WatchDirectory::Path(_) => None,
+            WatchDirectory::Settings(settings) => settings.mode,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DiskConfig {
+    /// Friendly name, exported as the `name` label
+    pub name: Option<String>,
+    /// Monitor the disk even if it isn't rotational, or never monitor it
+    pub monitor: Option<bool>,
+    /// Seconds without I/O after which the disk is spun down, overriding
+    /// `--spindown-after`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub spindown_after: Option<u64>,
+    /// Seconds the disk has to stay spun up before it may be spun down again
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub min_spinup: Option<u64>,
+    /// Never spin this disk down
+    #[serde(default)]
+    pub never_spindown: bool,
+    /// Daily windows like `"02:00-05:00"` during which the disk isn't spun
+    /// down, overriding `--keep-awake`
+    pub keep_awake: Option<TimeWindows>,
+    /// Spin the disk up when a keep-awake window starts
+    pub keep_awake_spinup: Option<bool>,
+    /// Spin the disk down at most this many times within 24 hours,
+    /// overriding `--max-spin-cycles`
+    pub max_spin_cycles: Option<u32>,
+    /// Seconds without I/O after which the disk is briefly spun up,
+    /// overriding `--exercise-interval`
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub exercise_interval: Option<u64>,
+    /// Advanced Power Management level to set, like `hdparm -B`
+    pub apm: Option<u8>,
+    /// Standby timer of the drive itself to set, like `hdparm -S`
+    pub standby_timer: Option<u8>,
+    /// Estimated watts while active, overriding `--active-watts`
+    pub active_watts: Option<f64>,
+    /// Estimated watts while idle, overriding `--idle-watts`
+    pub idle_watts: Option<f64>,
+    /// Estimated watts while in standby, overriding `--standby-watts`
+    pub standby_watts: Option<f64>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.to_string_lossy()))?;
+        Self::parse(&config).with_context(|| format!("Invalid config {}", path.to_string_lossy()))
+    }
+
+    pub fn parse(config: &str) -> Result<Self> {
+        Ok(toml::from_str(config)?)
+    }
+
+    /// Friendly names keyed by disk path
+    pub fn aliases(&self) -> BTreeMap<String, String> {
+        self.disks
+            .iter()
+            .filter_map(|(disk, config)| Some((disk.clone(), config.name.clone()?)))
+            .collect()
+    }
+
+    /// APM level and standby timer to apply, keyed by disk path
+    pub fn power_settings(&self) -> BTreeMap<String, PowerSettings> {
+        self.disks
+            .iter()
+            .filter(|(_, config)| config.apm.is_some() || config.standby_timer.is_some())
+            .map(|(disk, config)| {
+                let settings = PowerSettings {
+                    apm: config.apm,
+                    standby_timer: config.standby_timer,
+                };
+                (disk.clone(), settings)
+            })
+            .collect()
+    }
+
+    /// Wattages of the disks that override any of `default`, keyed by disk
+    /// path
+    pub fn wattages(&self, default: Wattage) -> BTreeMap<String, Wattage> {
+        self.disks
+            .iter()
+            .filter(|(_, config)| {
+                config.active_watts.is_some()
+                    || config.idle_watts.is_some()
+                    || config.standby_watts.is_some()
+            })
+            .map(|(disk, config)| {
+                let wattage = Wattage {
+                    active: config.active_watts.unwrap_or(default.active),
+                    idle: config.idle_watts.unwrap_or(default.idle),
+                    standby: config.standby_watts.unwrap_or(default.standby),
+                };
+                (disk.clone(), wattage)
+            })
+            .collect()
+    }
+
+    /// `filter` with the globs of the config file added
+    pub fn disk_filter(&self, filter: &DiskFilter) -> Result<DiskFilter> {
+        let mut filter = filter.clone();
+        filter.include.extend(parse_globs(&self.include_disks)?);
+        filter.exclude.extend(parse_globs(&self.exclude_disks)?);
+        Ok(filter)
+    }
+
+    /// `options` with the ignore globs, event kinds and watch modes of the
+    /// config file added
+    pub fn watch_options(&self, options: &WatchOptions) -> Result<WatchOptions> {
+        let mut options = options.clone();
+        options.ignore.extend(parse_globs(&self.watch_ignore)?);
+        if let Some(events) = &self.watch_events {
+            options.events = events.clone();
+        }
+        if let Some(mode) = self.watch_mode {
+            options.mode = mode;
+        }
+        if let Some(interval) = self.watch_poll_interval {
+            options.poll_interval = Duration::from_secs(interval);
+        }
+        for directory in &self.watch_directories {
+            if directory.events().is_none() && directory.mode().is_none() {
+                continue;
+            }
+            let pattern = parse_globs(&[directory.path().to_string()])?.remove(0);
+            if let Some(events) = directory.events() {
+                options
+                    .directory_events
+                    .push((pattern.clone(), events.to_vec()));
+            }
+            if let Some(mode) = directory.mode() {
+                options.directory_modes.push((pattern, mode));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Disks that are explicitly monitored or not, keyed by disk path
+    pub fn monitor_overrides(&self) -> BTreeMap<String, bool> {
+        self.disks
+            .iter()
+            .filter_map(|(disk, config)| Some((disk.clone(), config.monitor?)))
+            .collect()
+    }
+}
+
+fn parse_globs(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern).with_context(|| format!("Invalid glob {}", pattern)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+[disks."/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"]
+name = "media-1"
+
+spindown_after = 300
+
+[disks."/dev/sdb"]
+monitor = true
+never_spindown = true
+min_spinup = "10m"
+standby_watts = 0.5
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.disks.len(), 2);
+        assert_eq!(
+            config.disks["/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"].spindown_after,
+            Some(300)
+        );
+        assert!(config.disks["/dev/sdb"].never_spindown);
+        assert_eq!(config.disks["/dev/sdb"].min_spinup, Some(600));
+        assert_eq!(
+            config.aliases(),
+            BTreeMap::from([(
+                String::from("/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"),
+                String::from("media-1")
+            )])
+        );
+
+        assert_eq!(
+            config.monitor_overrides(),
+            BTreeMap::from([(String::from("/dev/sdb"), true)])
+        );
+
+        assert_eq!(
+            config.wattages(Wattage::default()),
+            BTreeMap::from([(
+                String::from("/dev/sdb"),
+                Wattage {
+                    standby: 0.5,
+                    ..Wattage::default()
+                }
+            )])
+        );
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("[disks.\"/dev/sda\"]\nnmae = \"typo\"").is_err());
+        let err = Config::parse("refresh_interval = \"5x\"").unwrap_err();
+        assert!(format!("{:?}", err).contains("refresh_interval = \"5x\""));
+        assert!(Config::parse("refresh_interval = -5").is_err());
+    }
+
+    #[test]
+    fn test_disk_filter() {
+        let config = Config::parse(
+            r#"
+watch_directories = ["/srv/media"]
+exclude_disks = ["/dev/sd[ab]"]
+refresh_interval = 300
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.watch_directories,
+            [WatchDirectory::Path(String::from("/srv/media"))]
+        );
+        assert_eq!(config.refresh_interval, Some(300));
+        let filter = DiskFilter {
+            include: vec![Pattern::new("/dev/sd*").unwrap()],
+            exclude: vec![Pattern::new("/dev/sdh").unwrap()],
+        };
+        let filter = config.disk_filter(&filter).unwrap();
+        assert!(filter.matches("/dev/sdc"));
+        assert!(!filter.matches("/dev/sda"));
+        assert!(!filter.matches("/dev/sdh"));
+
+        let config = Config {
+            include_disks: vec![String::from("/dev/sd[")],
+            ..Default::default()
+        };
+        assert!(config.disk_filter(&DiskFilter::default()).is_err());
+
+        let options = WatchOptions {
+            ignore: vec![Pattern::new(".DS_Store").unwrap()],
+            ..Default::default()
+        };
+        let options = Config::parse("watch_ignore = [\"*.tmp\"]")
+            .unwrap()
+            .watch_options(&options)
+            .unwrap();
+        assert_eq!(
+            options.ignore,
+            [".DS_Store", "*.tmp"].map(|p| Pattern::new(p).unwrap())
+        );
+        let config = Config {
+            watch_ignore: vec![String::from("[")],
+            ..Default::default()
+        };
+        assert!(config.watch_options(&options).is_err());
+
+        let config = Config::parse(
+            r#"
+watch_events = ["create", "modify"]
+watch_directories = [
+    "/srv/media",
+    { path = "/srv/backup/*", events = ["remove"] },
+    { path = "/srv/photos" },
+    { path = "/srv/nas", mode = "poll" },
+]
+watch_poll_interval = "1m"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.watch_directories[1].path(), "/srv/backup/*");
+        let options = config.watch_options(&WatchOptions::default()).unwrap();
+        assert_eq!(
+            options.events,
+            [WatchEventKind::Create, WatchEventKind::Modify]
+        );
+        assert_eq!(
+            options.directory_events,
+            [(
+                Pattern::new("/srv/backup/*").unwrap(),
+                vec![WatchEventKind::Remove]
+            )]
+        );
+        assert_eq!(
+            options.directory_modes,
+            [(Pattern::new("/srv/nas").unwrap(), WatchMode::Poll)]
+        );
+        assert_eq!(options.poll_interval, Duration::from_secs(60));
+        assert!(Config::parse("watch_events = [\"read\"]").is_err());
+        assert!(Config::parse("watch_directories = [{ path = \"/srv\", evnets = [] }]").is_err());
+    }
+}
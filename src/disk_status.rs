@@ -1,65 +1,871 @@
 use anyhow::{bail, Context, Result};
-use log::{debug, error};
-use std::process::Command;
-use std::sync::mpsc::Sender;
-use std::thread::sleep;
-use std::time::Duration;
+use log::{debug, error, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{
-    lsblk::{get_all_disks, Lsblk, LsblkDiskList},
+    command::{CommandRunner, CommandTimeout},
+    disks::{is_same_disk, DiskList},
+    hotplug::DiskEvent,
     metrics::MetricMessage,
+    smartctl::Smartctl,
+    stagger::Stagger,
+    udisks2::Udisks2,
 };
 
-pub fn disk_status_loop(hdparm: &str, refresh_interval: u64, tx: Sender<MetricMessage>) {
-    debug!("Created new disk monitor");
-    let disk_query = Hdparm {
-        path: String::from(hdparm),
-    };
+/// How long to wait for more hotplug events (and udev to create the device
+/// node) before refreshing after a disk was added or removed
+const HOTPLUG_SETTLE: Duration = Duration::from_secs(1);
 
-    let lsblk = Lsblk {};
+/// Query the status of the disks every `refresh_interval` seconds, which can
+/// be changed while running
+pub fn disk_status_loop(
+    disk_query: DiskBackends,
+    disk_list: impl DiskList + Sync,
+    refresh_interval: Arc<AtomicU64>,
+    concurrency: usize,
+    retry_policy: RetryPolicy,
+    hotplug: Receiver<DiskEvent>,
+    tx: Sender<MetricMessage>,
+) {
+    debug!("Created new disk monitor");
+    let retries = DiskRetries::new(retry_policy);
+    let mut reported = HashSet::new();
     loop {
-        debug!("Updating metrics");
-        if let Err(err) = update_disk_status(&disk_query, &lsblk, &tx) {
+        if let Err(err) = refresh(
+            &disk_query,
+            &disk_list,
+            &mut reported,
+            concurrency,
+            &retries,
+            &tx,
+        ) {
+            if err.is::<SendError<MetricMessage>>() {
+                debug!("Metrics channel closed, stopping the status loop");
+                return;
+            }
+            // Try again with the next refresh
             error!("Error updating disk status: {:?}", err);
-            return;
-        };
+        }
         debug!("Finished metrics update, sleeping");
-        sleep(Duration::from_secs(refresh_interval));
+        let refresh_interval = Duration::from_secs(refresh_interval.load(Ordering::Relaxed));
+        if let Err(err) = wait_for_refresh(&hotplug, refresh_interval, &retries, &mut reported, &tx)
+        {
+            error!("Error handling hotplug event: {:?}", err);
+            return;
+        }
+    }
+}
+
+/// Enumerate and query the disks a single time, for `--once`. Returns how
+/// many disks couldn't be queried.
+pub fn disk_status_once(
+    disk_query: DiskBackends,
+    disk_list: impl DiskList + Sync,
+    concurrency: usize,
+    retry_policy: RetryPolicy,
+    tx: &Sender<MetricMessage>,
+) -> Result<usize> {
+    let retries = DiskRetries::new(retry_policy);
+    refresh(
+        &disk_query,
+        &disk_list,
+        &mut HashSet::new(),
+        concurrency,
+        &retries,
+        tx,
+    )?;
+    Ok(retries.failing())
+}
+
+/// One round of enumerating and querying the disks. If the disks can't be
+/// listed, that's counted and the round skipped. Only fails if the metrics
+/// channel closed.
+fn refresh(
+    disk_query: &DiskBackends,
+    disk_list: &(impl DiskList + Sync),
+    reported: &mut HashSet<String>,
+    concurrency: usize,
+    retries: &DiskRetries,
+    tx: &Sender<MetricMessage>,
+) -> Result<()> {
+    debug!("Updating metrics");
+    let disks = match disk_list.get_all_disks() {
+        Ok(disks) => disks,
+        Err(err) => {
+            error!("Error listing disks, skipping this refresh: {:?}", err);
+            tx.send(MetricMessage::EnumerationError)?;
+            return Ok(());
+        }
+    };
+    for disk in report_disks(disk_list, &disks, reported, tx)? {
+        apply_power_settings(disk_query, &disk_query.power_settings, &disk, tx)?;
+    }
+    // Arrays can be assembled and stopped at any time, so always re-read
+    match disk_list.get_md_arrays() {
+        Ok(arrays) => tx.send(MetricMessage::MdArrays(arrays))?,
+        Err(err) => error!("Error reading md arrays: {:?}", err),
+    }
+    update_disk_status(disk_query, disk_list, disks, tx, concurrency, retries)?;
+    tx.send(MetricMessage::CycleFinished)?;
+    Ok(())
+}
+
+/// Send the set of currently enumerated disks, so series of disks that went
+/// away get dropped even without hotplug events, and the identity of disks
+/// that weren't seen before. The latter doesn't change while a disk stays
+/// attached, so it's only read once. Returns the disks that weren't seen
+/// before.
+fn report_disks(
+    disk_list: &impl DiskList,
+    disks: &[String],
+    reported: &mut HashSet<String>,
+    tx: &Sender<MetricMessage>,
+) -> Result<Vec<String>> {
+    tx.send(MetricMessage::EnumeratedDisks(disks.to_vec()))?;
+    reported.retain(|disk| disks.contains(disk));
+    let mut new_disks = Vec::new();
+    for disk in disks.iter().cloned() {
+        if reported.contains(&disk) {
+            continue;
+        }
+        match disk_list.get_disk_info(&disk) {
+            Ok(Some(info)) => tx.send(MetricMessage::DiskInfo {
+                disk: disk.clone(),
+                info,
+            })?,
+            Ok(None) => {}
+            Err(err) => warn!(disk; "Failed to read info of {}: {:?}", disk, err),
+        }
+        reported.insert(disk.clone());
+        new_disks.push(disk);
+    }
+    Ok(new_disks)
+}
+
+/// Apply the configured APM level and standby timer to a disk that just
+/// showed up. Failures are only logged, the values that were applied get
+/// reported.
+fn apply_power_settings(
+    control: &impl DiskControl,
+    power_settings: &BTreeMap<String, PowerSettings>,
+    disk: &str,
+    tx: &Sender<MetricMessage>,
+) -> Result<()> {
+    let Some((_, settings)) = power_settings
+        .iter()
+        .find(|(path, _)| is_same_disk(path, disk))
+    else {
+        return Ok(());
+    };
+    let mut applied = PowerSettings::default();
+    if let Some(level) = settings.apm {
+        match control.set_apm(disk, level) {
+            Ok(()) => applied.apm = Some(level),
+            Err(err) => error!(disk; "Failed to set APM level of {}: {:?}", disk, err),
+        }
+    }
+    if let Some(timer) = settings.standby_timer {
+        match control.set_standby_timer(disk, timer) {
+            Ok(()) => applied.standby_timer = Some(timer),
+            Err(err) => error!(disk; "Failed to set standby timer of {}: {:?}", disk, err),
+        }
+    }
+    tx.send(MetricMessage::PowerSettings {
+        disk: disk.to_string(),
+        settings: applied,
+    })?;
+    Ok(())
+}
+
+/// Sleep until the next refresh is due, returning early once a burst of
+/// hotplug events has settled
+fn wait_for_refresh(
+    hotplug: &Receiver<DiskEvent>,
+    refresh_interval: Duration,
+    retries: &DiskRetries,
+    reported: &mut HashSet<String>,
+    tx: &Sender<MetricMessage>,
+) -> Result<()> {
+    match hotplug.recv_timeout(refresh_interval) {
+        Ok(event) => {
+            handle_disk_event(event, retries, reported, tx)?;
+            while let Ok(event) = hotplug.recv_timeout(HOTPLUG_SETTLE) {
+                handle_disk_event(event, retries, reported, tx)?;
+            }
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+        // hotplug monitoring is disabled
+        Err(RecvTimeoutError::Disconnected) => sleep(refresh_interval),
+    }
+    Ok(())
+}
+
+fn handle_disk_event(
+    event: DiskEvent,
+    retries: &DiskRetries,
+    reported: &mut HashSet<String>,
+    tx: &Sender<MetricMessage>,
+) -> Result<()> {
+    match event {
+        DiskEvent::Added(disk) => debug!("{} was added", disk),
+        DiskEvent::Removed(disk) => {
+            debug!("{} was removed", disk);
+            // A different disk might show up under the same name later
+            retries.forget(&disk);
+            reported.remove(&disk);
+            tx.send(MetricMessage::DiskRemoved { disk })?;
+        }
     }
+    Ok(())
 }
 
+/// Query `disks`, running up to `concurrency` queries at the same time.
+/// Disks that recently failed are skipped according to `retries`.
 pub fn update_disk_status(
+    disk_query: &(impl DiskStatus + Sync),
+    disk_list: &(impl DiskList + Sync),
+    disks: Vec<String>,
+    tx: &Sender<MetricMessage>,
+    concurrency: usize,
+    retries: &DiskRetries,
+) -> Result<()> {
+    debug!("Querying disks: {:?}", disks);
+    let queue = Mutex::new(disks.into_iter());
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..concurrency.max(1))
+            .map(|_| {
+                s.spawn(|| -> Result<()> {
+                    loop {
+                        let disk = queue.lock().unwrap().next();
+                        match disk {
+                            Some(disk) => query_disk(disk_query, disk_list, disk, tx, retries)?,
+                            None => return Ok(()),
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("disk status worker panicked"))
+    })
+}
+
+fn query_disk(
     disk_query: &impl DiskStatus,
-    lsblk: &impl LsblkDiskList,
+    disk_list: &impl DiskList,
+    disk: String,
     tx: &Sender<MetricMessage>,
+    retries: &DiskRetries,
 ) -> Result<()> {
-    let all_disks = get_all_disks(lsblk)?;
-    debug!("Loaded all disks: {:?}", all_disks);
-    for disk in all_disks {
-        if let Some(status) = disk_query
-            .get_disk_status(&disk)
-            .context("failed to get disk status")?
-        {
-            tx.send(MetricMessage::DiskStatus { disk, status })?;
+    if !retries.should_query(&disk, Instant::now()) {
+        debug!("Skipping {} after previous failures", disk);
+        return Ok(());
+    }
+    // A failing disk shouldn't stop the others from being queried
+    let status = match disk_query.get_disk_status(&disk) {
+        Ok(status) => status,
+        Err(err) => {
+            if err.is::<CommandTimeout>() {
+                tx.send(MetricMessage::DiskStatusTimeout { disk: disk.clone() })?;
+            } else {
+                tx.send(MetricMessage::DiskStatusError { disk: disk.clone() })?;
+            }
+            match retries.record_failure(&disk, Instant::now()) {
+                Some(backoff) => error!(
+                    disk;
+                    "Failed to get disk status for {}, retrying in {:?}: {:?}",
+                    disk, backoff, err
+                ),
+                None => error!(
+                    disk;
+                    "Failed to get disk status for {} too many times, giving up: {:?}",
+                    disk, err
+                ),
+            }
+            return Ok(());
+        }
+    };
+    retries.record_success(&disk);
+    let unsupported = if status == PowerState::Unknown {
+        retries.record_unknown(&disk)
+    } else {
+        retries.record_known(&disk);
+        false
+    };
+    tx.send(MetricMessage::DiskStatus {
+        disk: disk.clone(),
+        status,
+    })?;
+    // Only ask disks that are awake, some drives spin up to answer
+    if status.is_spinning() == Some(true) {
+        match disk_list.get_temperature(&disk) {
+            Ok(Some(celsius)) => tx.send(MetricMessage::Temperature {
+                disk: disk.clone(),
+                celsius,
+            })?,
+            Ok(None) => {}
+            Err(err) => debug!("Failed to read temperature of {}: {:?}", disk, err),
         }
     }
+    if unsupported {
+        warn!(
+            disk;
+            "{} keeps reporting an unknown power state, no longer querying it",
+            disk
+        );
+        tx.send(MetricMessage::DiskUnsupported { disk })?;
+    }
     Ok(())
 }
 
+/// How failing disks are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Backoff after the first failure, doubled after each consecutive one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Stop querying a disk after this many consecutive failures, 0 to retry
+    /// forever
+    pub max_failures: u32,
+    /// Consider a disk unsupported after this many consecutive unknown
+    /// states, 0 to never give up
+    pub unsupported_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(3600),
+            max_failures: 10,
+            unsupported_after: 5,
+        }
+    }
+}
+
+struct DiskFailures {
+    consecutive: u32,
+    retry_at: Instant,
+}
+
+/// Per-disk failure bookkeeping, shared between the query workers
+pub struct DiskRetries {
+    policy: RetryPolicy,
+    failures: Mutex<HashMap<String, DiskFailures>>,
+    /// Consecutive unknown states per disk
+    unknown: Mutex<HashMap<String, u32>>,
+}
+
+impl DiskRetries {
+    pub fn new(policy: RetryPolicy) -> Self {
+        DiskRetries {
+            policy,
+            failures: Mutex::new(HashMap::new()),
+            unknown: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn unsupported(&self, unknown: u32) -> bool {
+        self.policy.unsupported_after > 0 && unknown >= self.policy.unsupported_after
+    }
+
+    fn gave_up(&self, failures: &DiskFailures) -> bool {
+        self.policy.max_failures > 0 && failures.consecutive >= self.policy.max_failures
+    }
+
+    pub fn should_query(&self, disk: &str, now: Instant) -> bool {
+        if let Some(unknown) = self.unknown.lock().unwrap().get(disk) {
+            if self.unsupported(*unknown) {
+                return false;
+            }
+        }
+        match self.failures.lock().unwrap().get(disk) {
+            Some(failures) => !self.gave_up(failures) && now >= failures.retry_at,
+            None => true,
+        }
+    }
+
+    /// Number of disks whose last query failed
+    pub fn failing(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    pub fn record_success(&self, disk: &str) {
+        self.failures.lock().unwrap().remove(disk);
+    }
+
+    /// Drop all bookkeeping for a disk, e.g. after it was unplugged
+    pub fn forget(&self, disk: &str) {
+        self.failures.lock().unwrap().remove(disk);
+        self.unknown.lock().unwrap().remove(disk);
+    }
+
+    /// Record an unknown power state and return `true` if that made the disk
+    /// unsupported. Any other state resets the count.
+    pub fn record_unknown(&self, disk: &str) -> bool {
+        let mut all_unknown = self.unknown.lock().unwrap();
+        let unknown = all_unknown.entry(disk.to_string()).or_default();
+        *unknown += 1;
+        self.unsupported(*unknown) && *unknown == self.policy.unsupported_after
+    }
+
+    pub fn record_known(&self, disk: &str) {
+        self.unknown.lock().unwrap().remove(disk);
+    }
+
+    /// Record a failed query and return how long to back off, or `None` if
+    /// the disk reached the maximum number of failures.
+    pub fn record_failure(&self, disk: &str, now: Instant) -> Option<Duration> {
+        let mut all_failures = self.failures.lock().unwrap();
+        let failures = all_failures
+            .entry(disk.to_string())
+            .or_insert(DiskFailures {
+                consecutive: 0,
+                retry_at: now,
+            });
+        failures.consecutive += 1;
+        if self.gave_up(failures) {
+            return None;
+        }
+        let backoff = self
+            .policy
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(failures.consecutive - 1))
+            .min(self.policy.max_backoff);
+        failures.retry_at = now + backoff;
+        Some(backoff)
+    }
+}
+
+/// Power state of a disk as reported by one of the backends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PowerState {
+    /// Spinning and possibly serving requests (hdparm's "active/idle")
+    Active,
+    /// Spinning, but in one of the low power idle modes
+    Idle,
+    Standby,
+    /// Deepest power mode, the disk needs a reset to wake up again
+    Sleeping,
+    /// The backend couldn't determine the state
+    Unknown,
+}
+
+impl PowerState {
+    pub const ALL: [PowerState; 5] = [
+        PowerState::Active,
+        PowerState::Idle,
+        PowerState::Standby,
+        PowerState::Sleeping,
+        PowerState::Unknown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerState::Active => "active",
+            PowerState::Idle => "idle",
+            PowerState::Standby => "standby",
+            PowerState::Sleeping => "sleeping",
+            PowerState::Unknown => "unknown",
+        }
+    }
+
+    /// Value of the `disk_status` gauge: 1 if the disk is spinning, 0 if it
+    /// isn't and -1 if the state is unknown
+    pub fn gauge_value(&self) -> f64 {
+        match self {
+            PowerState::Active | PowerState::Idle => 1.0,
+            PowerState::Standby | PowerState::Sleeping => 0.0,
+            PowerState::Unknown => -1.0,
+        }
+    }
+
+    /// Whether the platters are spinning, `None` if the state is unknown
+    pub fn is_spinning(&self) -> Option<bool> {
+        match self {
+            PowerState::Active | PowerState::Idle => Some(true),
+            PowerState::Standby | PowerState::Sleeping => Some(false),
+            PowerState::Unknown => None,
+        }
+    }
+}
+
 pub trait DiskStatus {
-    fn get_disk_status(&self, disk: &str) -> Result<Option<f64>>;
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState>;
+}
+
+/// Backends that can change the power state of a disk
+pub trait DiskControl {
+    /// Put the disk into standby right away
+    fn spindown(&self, disk: &str) -> Result<()>;
+
+    /// Spin the disk up by reading from it, bypassing the page cache
+    fn spinup(&self, disk: &str) -> Result<()> {
+        read_uncached_block(Path::new(disk))
+    }
+
+    /// Set the Advanced Power Management level, 1-127 allow spinning down,
+    /// 128-254 don't and 255 disables APM
+    fn set_apm(&self, _disk: &str, _level: u8) -> Result<()> {
+        bail!("Setting the APM level isn't supported by this backend")
+    }
+
+    /// Set the drive's own standby timer, encoded like `hdparm -S`
+    fn set_standby_timer(&self, _disk: &str, _timer: u8) -> Result<()> {
+        bail!("Setting the standby timer isn't supported by this backend")
+    }
+}
+
+/// Drive settings applied whenever a disk shows up
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerSettings {
+    pub apm: Option<u8>,
+    pub standby_timer: Option<u8>,
+}
+
+/// Read a single block from somewhere on the device with `O_DIRECT` so the
+/// read has to hit the disk
+fn read_uncached_block(device: &Path) -> Result<()> {
+    const BLOCK_SIZE: usize = 4096;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(device)
+        .with_context(|| format!("Failed to open {}", device.to_string_lossy()))?;
+    let blocks = file.seek(SeekFrom::End(0))? / BLOCK_SIZE as u64;
+    // Different offset each time so the drive's own cache doesn't answer
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u64;
+    let offset = nanos % blocks.max(1) * BLOCK_SIZE as u64;
+    file.seek(SeekFrom::Start(offset))?;
+    // O_DIRECT needs a buffer aligned to the logical block size
+    let mut buffer = vec![0u8; 2 * BLOCK_SIZE];
+    let start = buffer.as_ptr().align_offset(BLOCK_SIZE);
+    file.read_exact(&mut buffer[start..start + BLOCK_SIZE])
+        .with_context(|| format!("Failed to read from {}", device.to_string_lossy()))?;
+    Ok(())
+}
+
+/// Same as [`read_uncached_block`] but through `dd`, so backends that run
+/// their commands through a privilege helper don't need the daemon to be able
+/// to open the device itself
+pub(crate) fn read_uncached_block_with(runner: &dyn CommandRunner, disk: &str) -> Result<()> {
+    const BLOCK_SIZE: u64 = 4096;
+
+    // `/sys/block/*/size` is in 512 byte sectors and readable without root
+    let name = disk.strip_prefix("/dev/").unwrap_or(disk);
+    let blocks = std::fs::read_to_string(Path::new("/sys/block").join(name).join("size"))
+        .ok()
+        .and_then(|size| size.trim().parse::<u64>().ok())
+        .map_or(1, |sectors| sectors * 512 / BLOCK_SIZE);
+    // Different offset each time so the drive's own cache doesn't answer
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u64;
+    let skip = nanos % blocks.max(1);
+
+    let output = runner.run(
+        "dd",
+        &[
+            &format!("if={}", disk),
+            "of=/dev/null",
+            &format!("bs={}", BLOCK_SIZE),
+            "count=1",
+            &format!("skip={}", skip),
+            "iflag=direct",
+        ],
+    )?;
+    if !output.status.success() {
+        bail!("dd execution error: {:?}", output);
+    }
+    Ok(())
+}
+
+/// Which backend to use for querying a disk, as given on the command line:
+/// `hdparm`, `smartctl`, `smartctl:<device type>` or `udisks2`
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendKind {
+    Hdparm,
+    Smartctl { device_type: Option<String> },
+    Udisks2,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, device_type) = match s.split_once(':') {
+            Some((name, device_type)) => (name, Some(device_type.to_string())),
+            None => (s, None),
+        };
+        match (name, device_type) {
+            ("hdparm", None) => Ok(BackendKind::Hdparm),
+            ("smartctl", device_type) => Ok(BackendKind::Smartctl { device_type }),
+            ("udisks2", None) => Ok(BackendKind::Udisks2),
+            _ => Err(format!("unknown backend: '{}'", s)),
+        }
+    }
+}
+
+/// Per-disk backend override in the form `/dev/sdc=smartctl:sat`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskBackendOverride {
+    pub disk: String,
+    pub backend: BackendKind,
+}
+
+impl FromStr for DiskBackendOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (disk, backend) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected DISK=BACKEND, got '{}'", s))?;
+        Ok(DiskBackendOverride {
+            disk: disk.to_string(),
+            backend: backend.parse()?,
+        })
+    }
+}
+
+pub enum Backend {
+    Hdparm(Hdparm),
+    Smartctl(Smartctl),
+    Udisks2(Udisks2),
+}
+
+impl Backend {
+    pub const NAMES: [&'static str; 3] = ["hdparm", "smartctl", "udisks2"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Hdparm(_) => "hdparm",
+            Backend::Smartctl(_) => "smartctl",
+            Backend::Udisks2(_) => "udisks2",
+        }
+    }
+}
+
+impl DiskStatus for Backend {
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+        match self {
+            Backend::Hdparm(hdparm) => hdparm.get_disk_status(disk),
+            Backend::Smartctl(smartctl) => smartctl.get_disk_status(disk),
+            Backend::Udisks2(udisks2) => udisks2.get_disk_status(disk),
+        }
+    }
+}
+
+impl DiskControl for Backend {
+    fn spindown(&self, disk: &str) -> Result<()> {
+        match self {
+            Backend::Hdparm(hdparm) => hdparm.spindown(disk),
+            Backend::Smartctl(smartctl) => smartctl.spindown(disk),
+            Backend::Udisks2(udisks2) => udisks2.spindown(disk),
+        }
+    }
+
+    fn spinup(&self, disk: &str) -> Result<()> {
+        match self {
+            Backend::Hdparm(hdparm) => hdparm.spinup(disk),
+            Backend::Smartctl(smartctl) => smartctl.spinup(disk),
+            Backend::Udisks2(udisks2) => udisks2.spinup(disk),
+        }
+    }
+
+    fn set_apm(&self, disk: &str, level: u8) -> Result<()> {
+        match self {
+            Backend::Hdparm(hdparm) => hdparm.set_apm(disk, level),
+            Backend::Smartctl(smartctl) => smartctl.set_apm(disk, level),
+            Backend::Udisks2(udisks2) => udisks2.set_apm(disk, level),
+        }
+    }
+
+    fn set_standby_timer(&self, disk: &str, timer: u8) -> Result<()> {
+        match self {
+            Backend::Hdparm(hdparm) => hdparm.set_standby_timer(disk, timer),
+            Backend::Smartctl(smartctl) => smartctl.set_standby_timer(disk, timer),
+            Backend::Udisks2(udisks2) => udisks2.set_standby_timer(disk, timer),
+        }
+    }
+}
+
+/// Paths of the external commands used by the backends and how to run them
+#[derive(Clone)]
+pub struct BackendCommands {
+    pub hdparm: String,
+    pub smartctl: String,
+    pub busctl: String,
+    pub runner: Arc<dyn CommandRunner>,
+    /// Runner for commands that need direct device access (hdparm, smartctl)
+    pub privileged_runner: Arc<dyn CommandRunner>,
+}
+
+/// Dispatches status queries to the default backend unless a disk has an
+/// override configured.
+pub struct DiskBackends {
+    default: Backend,
+    overrides: HashMap<String, Backend>,
+    /// Applied by the status loop whenever a disk shows up, keyed by any
+    /// path of the disk
+    power_settings: BTreeMap<String, PowerSettings>,
+    /// Shared with other users of the disks, so accesses are spread out
+    /// across all of them
+    stagger: Arc<Stagger>,
+    /// Only log actions that change the state of a disk
+    dry_run: bool,
+    /// Disks that must never be spun down, keyed by any path of the disk
+    protected: Vec<String>,
+    /// Where to report how long status queries took
+    metrics: Option<Sender<MetricMessage>>,
+}
+
+impl DiskBackends {
+    pub fn new(
+        commands: &BackendCommands,
+        default: &BackendKind,
+        overrides: &[DiskBackendOverride],
+    ) -> Self {
+        let build = |kind: &BackendKind| match kind {
+            BackendKind::Hdparm => Backend::Hdparm(Hdparm {
+                path: commands.hdparm.clone(),
+                runner: commands.privileged_runner.clone(),
+            }),
+            BackendKind::Smartctl { device_type } => Backend::Smartctl(Smartctl {
+                path: commands.smartctl.clone(),
+                device_type: device_type.clone(),
+                runner: commands.privileged_runner.clone(),
+            }),
+            BackendKind::Udisks2 => Backend::Udisks2(Udisks2 {
+                busctl: commands.busctl.clone(),
+                runner: commands.runner.clone(),
+            }),
+        };
+        DiskBackends {
+            default: build(default),
+            overrides: overrides
+                .iter()
+                .map(|o| (o.disk.clone(), build(&o.backend)))
+                .collect(),
+            power_settings: BTreeMap::new(),
+            stagger: Arc::new(Stagger::default()),
+            dry_run: false,
+            protected: Vec::new(),
+            metrics: None,
+        }
+    }
+
+    pub fn with_metrics(mut self, tx: Sender<MetricMessage>) -> Self {
+        self.metrics = Some(tx);
+        self
+    }
+
+    pub fn with_protected(mut self, protected: Vec<String>) -> Self {
+        self.protected = protected;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_stagger(mut self, stagger: Arc<Stagger>) -> Self {
+        self.stagger = stagger;
+        self
+    }
+
+    pub fn with_power_settings(mut self, power_settings: BTreeMap<String, PowerSettings>) -> Self {
+        self.power_settings = power_settings;
+        self
+    }
+}
+
+impl DiskBackends {
+    fn backend(&self, disk: &str) -> &Backend {
+        self.overrides.get(disk).unwrap_or(&self.default)
+    }
+
+    /// Whether `action` must be skipped, logging it if so
+    fn skip(&self, action: &str, disk: &str) -> bool {
+        if self.dry_run {
+            warn!(disk; "Dry run, not going to {} {}", action, disk);
+        }
+        self.dry_run
+    }
+}
+
+impl DiskStatus for DiskBackends {
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+        // Some bridges spin the disk up to answer
+        self.stagger.wait();
+        let backend = self.backend(disk);
+        let start = Instant::now();
+        let status = backend.get_disk_status(disk);
+        if let Some(tx) = &self.metrics {
+            // Only fails once the daemon is shutting down
+            let _ = tx.send(MetricMessage::QueryDuration {
+                disk: disk.to_string(),
+                backend: backend.name(),
+                duration: start.elapsed(),
+            });
+        }
+        status
+    }
+}
+
+impl DiskControl for DiskBackends {
+    fn spindown(&self, disk: &str) -> Result<()> {
+        if self.protected.iter().any(|path| is_same_disk(path, disk)) {
+            bail!(
+                "{} holds / or swap, pass --allow-system-disk to spin it down",
+                disk
+            );
+        }
+        if self.skip("spin down", disk) {
+            return Ok(());
+        }
+        self.backend(disk).spindown(disk)
+    }
+
+    fn spinup(&self, disk: &str) -> Result<()> {
+        if self.skip("spin up", disk) {
+            return Ok(());
+        }
+        self.stagger.spinup(|| self.backend(disk).spinup(disk))
+    }
+
+    fn set_apm(&self, disk: &str, level: u8) -> Result<()> {
+        if self.skip(&format!("set APM level {} on", level), disk) {
+            return Ok(());
+        }
+        self.backend(disk).set_apm(disk, level)
+    }
+
+    fn set_standby_timer(&self, disk: &str, timer: u8) -> Result<()> {
+        if self.skip(&format!("set standby timer {} on", timer), disk) {
+            return Ok(());
+        }
+        self.backend(disk).set_standby_timer(disk, timer)
+    }
 }
 
 pub struct Hdparm {
     pub path: String,
+    pub runner: Arc<dyn CommandRunner>,
 }
 impl DiskStatus for Hdparm {
-    fn get_disk_status(&self, disk: &str) -> Result<Option<f64>> {
-        let output = Command::new(&self.path)
-            .arg("-C")
-            .arg(disk)
-            .output()
-            .context("Failed to execute hdparm")?;
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+        let output = self.runner.run(&self.path, &["-C", disk])?;
 
         if !output.status.success() {
             error!("hdparm failed to execute: {:?}", output);
@@ -73,19 +879,76 @@ impl DiskStatus for Hdparm {
             String::from_utf8_lossy(&output.stderr),
             stdout
         );
-        if stdout.contains("standby") {
-            Ok(Some(0.0))
-        } else if stdout.contains("active/idle") {
-            Ok(Some(1.0))
-        } else {
-            Ok(None)
+        parse_hdparm_output(&stdout)
+            .with_context(|| format!("Failed to parse hdparm output for {}", disk))
+    }
+}
+
+impl Hdparm {
+    fn run(&self, args: &[&str]) -> Result<()> {
+        let output = self.runner.run(&self.path, args)?;
+        if !output.status.success() {
+            bail!("hdparm execution error: {:?}", output);
         }
+        Ok(())
+    }
+}
+
+impl DiskControl for Hdparm {
+    fn spindown(&self, disk: &str) -> Result<()> {
+        self.run(&["-y", disk])
+    }
+
+    /// Read through the same runner so `--privilege-helper` applies
+    fn spinup(&self, disk: &str) -> Result<()> {
+        read_uncached_block_with(self.runner.as_ref(), disk)
+    }
+
+    fn set_apm(&self, disk: &str, level: u8) -> Result<()> {
+        self.run(&["-B", &level.to_string(), disk])
+    }
+
+    fn set_standby_timer(&self, disk: &str, timer: u8) -> Result<()> {
+        self.run(&["-S", &timer.to_string(), disk])
+    }
+}
+
+/// Parse the output of `hdparm -C` into a power state.
+///
+/// Only the token following `drive state is:` is considered so that extra
+/// diagnostics (e.g. `SG_IO: bad/missing sense data`) don't confuse the
+/// result.
+pub fn parse_hdparm_output(output: &str) -> Result<PowerState> {
+    let state = output
+        .lines()
+        .find_map(|line| line.split_once("drive state is:"))
+        .map(|(_, state)| state.trim())
+        .context("No drive state found in hdparm output")?;
+    let token = state.split_whitespace().next().unwrap_or_default();
+
+    match token {
+        "standby" | "NVcache_spindown" => Ok(PowerState::Standby),
+        "sleeping" => Ok(PowerState::Sleeping),
+        "active/idle" | "active" | "NVcache_spinup" => Ok(PowerState::Active),
+        "idle" => Ok(PowerState::Idle),
+        "unknown" => Ok(PowerState::Unknown),
+        // Newer hdparm versions report the idle/standby sub-states, e.g.
+        // "idle_a" or "standby_y"
+        _ if token.starts_with("idle_") => Ok(PowerState::Idle),
+        _ if token.starts_with("standby_") => Ok(PowerState::Standby),
+        _ => bail!("Unrecognized drive state: '{}'", state),
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::lsblk::test::FakeLsblk;
+    use crate::{
+        command::{test::FakeRunner, PrivilegeHelper, PrivilegedRunner},
+        disks::{
+            test::{fake_sysfs, FakeBlockDevice, FakeDiskList},
+            SysBlock,
+        },
+    };
 
     use super::*;
 
@@ -98,51 +961,710 @@ pub mod test {
 
     pub struct FakeHdparm {}
     impl DiskStatus for FakeHdparm {
-        fn get_disk_status(&self, _disk: &str) -> Result<Option<f64>> {
-            Ok(Some(0.0))
+        fn get_disk_status(&self, _disk: &str) -> Result<PowerState> {
+            Ok(PowerState::Standby)
+        }
+    }
+
+    struct HungHdparm {}
+    impl DiskStatus for HungHdparm {
+        fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+            if disk == "/dev/sda" {
+                return Err(anyhow::Error::new(CommandTimeout {
+                    program: String::from("hdparm"),
+                    timeout: Duration::from_secs(1),
+                })
+                .context("Failed to execute hdparm"));
+            }
+            Ok(PowerState::Active)
+        }
+    }
+
+    struct SlowHdparm {}
+    impl DiskStatus for SlowHdparm {
+        fn get_disk_status(&self, _disk: &str) -> Result<PowerState> {
+            sleep(Duration::from_millis(200));
+            Ok(PowerState::Active)
+        }
+    }
+
+    #[test]
+    fn test_parallel_queries() {
+        init();
+        let disk_list = FakeDiskList {
+            disks: vec![
+                String::from("/dev/sda"),
+                String::from("/dev/sdb"),
+                String::from("/dev/sdc"),
+                String::from("/dev/sdd"),
+            ],
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let start = std::time::Instant::now();
+        let retries = DiskRetries::new(RetryPolicy::default());
+        update_disk_status(
+            &SlowHdparm {},
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            4,
+            &retries,
+        )
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(600));
+        drop(tx);
+
+        let mut disks: Vec<String> = rx
+            .iter()
+            .map(|msg| match msg {
+                MetricMessage::DiskStatus { disk, .. } => disk,
+                _ => panic!("invalid message: {:?}", msg),
+            })
+            .collect();
+        disks.sort();
+        assert_eq!(disks, vec!["/dev/sda", "/dev/sdb", "/dev/sdc", "/dev/sdd"]);
+    }
+
+    #[test]
+    fn test_timeout_continues() {
+        init();
+        let disk_list = FakeDiskList {
+            disks: vec![String::from("/dev/sda"), String::from("/dev/sdb")],
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let retries = DiskRetries::new(RetryPolicy::default());
+
+        update_disk_status(
+            &HungHdparm {},
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
+        drop(tx);
+
+        let messages: Vec<MetricMessage> = rx.iter().collect();
+        assert!(matches!(
+            &messages[..],
+            [
+                MetricMessage::DiskStatusTimeout { disk: timed_out },
+                MetricMessage::DiskStatus { disk, status: PowerState::Active },
+            ] if timed_out == "/dev/sda" && disk == "/dev/sdb"
+        ));
+        // the hung disk is backed off, the other one is queried as usual
+        assert!(!retries.should_query("/dev/sda", Instant::now()));
+        assert!(retries.should_query("/dev/sdb", Instant::now()));
+    }
+
+    struct FailingHdparm {}
+    impl DiskStatus for FailingHdparm {
+        fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+            bail!("No such device: {}", disk)
         }
     }
 
+    #[test]
+    fn test_error_reported() {
+        init();
+        let disk_list = FakeDiskList {
+            disks: vec![String::from("/dev/sda")],
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let retries = DiskRetries::new(RetryPolicy::default());
+
+        update_disk_status(
+            &FailingHdparm {},
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
+        drop(tx);
+
+        let messages: Vec<MetricMessage> = rx.iter().collect();
+        assert!(matches!(
+            &messages[..],
+            [MetricMessage::DiskStatusError { disk }] if disk == "/dev/sda"
+        ));
+    }
+
+    struct UnknownHdparm {}
+    impl DiskStatus for UnknownHdparm {
+        fn get_disk_status(&self, _disk: &str) -> Result<PowerState> {
+            Ok(PowerState::Unknown)
+        }
+    }
+
+    #[test]
+    fn test_unsupported_disk() {
+        init();
+        let disk_list = FakeDiskList {
+            disks: vec![String::from("/dev/sda")],
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let retries = DiskRetries::new(RetryPolicy {
+            unsupported_after: 2,
+            ..RetryPolicy::default()
+        });
+
+        for _ in 0..3 {
+            update_disk_status(
+                &UnknownHdparm {},
+                &disk_list,
+                disk_list.get_all_disks().unwrap(),
+                &tx,
+                1,
+                &retries,
+            )
+            .unwrap();
+        }
+        drop(tx);
+
+        let messages: Vec<MetricMessage> = rx.iter().collect();
+        assert!(matches!(
+            &messages[..],
+            [
+                MetricMessage::DiskStatus { status: PowerState::Unknown, .. },
+                MetricMessage::DiskStatus { status: PowerState::Unknown, .. },
+                MetricMessage::DiskUnsupported { disk },
+            ] if disk == "/dev/sda"
+        ));
+    }
+
+    struct ActiveHdparm {}
+    impl DiskStatus for ActiveHdparm {
+        fn get_disk_status(&self, _disk: &str) -> Result<PowerState> {
+            Ok(PowerState::Active)
+        }
+    }
+
+    #[test]
+    fn test_temperature() {
+        init();
+        let sys_root = fake_sysfs(&[FakeBlockDevice {
+            name: "sda",
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }]);
+        let hwmon = sys_root.path().join("block/sda/device/hwmon/hwmon2");
+        std::fs::create_dir_all(&hwmon).unwrap();
+        std::fs::write(hwmon.join("temp1_input"), "36000\n").unwrap();
+        let disk_list = SysBlock::with_sys_root(sys_root.path());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let retries = DiskRetries::new(RetryPolicy::default());
+
+        // not read while the disk is in standby
+        update_disk_status(
+            &FakeHdparm {},
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
+        update_disk_status(
+            &ActiveHdparm {},
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
+        drop(tx);
+
+        let temperatures: Vec<_> = rx
+            .iter()
+            .filter_map(|msg| match msg {
+                MetricMessage::Temperature { disk, celsius } => Some((disk, celsius)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(temperatures, vec![(String::from("/dev/sda"), 36.0)]);
+    }
+
+    #[test]
+    fn test_report_disks_info_once() {
+        init();
+        let sys_root = fake_sysfs(&[FakeBlockDevice {
+            name: "sda",
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }]);
+        std::fs::write(sys_root.path().join("block/sda/size"), "1024\n").unwrap();
+        let disk_list = SysBlock::with_sys_root(sys_root.path());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let disks = disk_list.get_all_disks().unwrap();
+        let mut reported = HashSet::new();
+        report_disks(&disk_list, &disks, &mut reported, &tx).unwrap();
+        report_disks(&disk_list, &disks, &mut reported, &tx).unwrap();
+        drop(tx);
+
+        let messages: Vec<_> = rx
+            .iter()
+            .filter(|msg| !matches!(msg, MetricMessage::EnumeratedDisks(_)))
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            MetricMessage::DiskInfo { disk, info } if disk == "/dev/sda" && info.size_bytes == 512 * 1024
+        ));
+    }
+
+    #[test]
+    fn test_wait_for_refresh_hotplug() {
+        let (hotplug_tx, hotplug_rx) = std::sync::mpsc::channel();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let retries = DiskRetries::new(RetryPolicy::default());
+        retries.record_failure("/dev/sdb", Instant::now());
+        let mut reported = HashSet::from([String::from("/dev/sdb")]);
+
+        hotplug_tx
+            .send(DiskEvent::Removed(String::from("/dev/sdb")))
+            .unwrap();
+        drop(hotplug_tx);
+        let start = Instant::now();
+        wait_for_refresh(
+            &hotplug_rx,
+            Duration::from_secs(60),
+            &retries,
+            &mut reported,
+            &tx,
+        )
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        assert!(retries.should_query("/dev/sdb", Instant::now()));
+        // info and settings get applied again if it comes back
+        assert!(reported.is_empty());
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            MetricMessage::DiskRemoved { disk } if disk == "/dev/sdb"
+        ));
+    }
+
+    #[test]
+    fn test_disk_retries() {
+        let retries = DiskRetries::new(RetryPolicy {
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(25),
+            max_failures: 4,
+            unsupported_after: 0,
+        });
+        let now = Instant::now();
+        assert!(retries.should_query("/dev/sda", now));
+
+        assert_eq!(
+            retries.record_failure("/dev/sda", now),
+            Some(Duration::from_secs(10))
+        );
+        assert!(!retries.should_query("/dev/sda", now));
+        assert!(retries.should_query("/dev/sda", now + Duration::from_secs(10)));
+        assert_eq!(
+            retries.record_failure("/dev/sda", now),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            retries.record_failure("/dev/sda", now),
+            Some(Duration::from_secs(25))
+        );
+
+        // success resets the backoff
+        retries.record_success("/dev/sda");
+        assert!(retries.should_query("/dev/sda", now));
+
+        for _ in 0..3 {
+            assert!(retries.record_failure("/dev/sda", now).is_some());
+        }
+        assert_eq!(retries.record_failure("/dev/sda", now), None);
+        assert!(!retries.should_query("/dev/sda", now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_parse_hdparm_output() {
+        let fixtures = [
+            (
+                include_str!("../fixtures/hdparm/standby.txt"),
+                PowerState::Standby,
+            ),
+            (
+                include_str!("../fixtures/hdparm/sleeping.txt"),
+                PowerState::Sleeping,
+            ),
+            (
+                include_str!("../fixtures/hdparm/nvcache_spindown.txt"),
+                PowerState::Standby,
+            ),
+            (
+                include_str!("../fixtures/hdparm/sg_io_sense_standby.txt"),
+                PowerState::Standby,
+            ),
+            (
+                include_str!("../fixtures/hdparm/active_idle.txt"),
+                PowerState::Active,
+            ),
+            (
+                include_str!("../fixtures/hdparm/idle.txt"),
+                PowerState::Idle,
+            ),
+            (
+                include_str!("../fixtures/hdparm/nvcache_spinup.txt"),
+                PowerState::Active,
+            ),
+            (
+                include_str!("../fixtures/hdparm/legacy_tab.txt"),
+                PowerState::Active,
+            ),
+            (
+                include_str!("../fixtures/hdparm/unknown.txt"),
+                PowerState::Unknown,
+            ),
+        ];
+        for (output, expected) in fixtures {
+            assert_eq!(parse_hdparm_output(output).unwrap(), expected, "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_hdparm() {
+        let hdparm = Hdparm {
+            path: String::from("hdparm"),
+            runner: Arc::new(FakeRunner::default().with_output(
+                "hdparm -C /dev/sda",
+                include_str!("../fixtures/hdparm/standby.txt"),
+            )),
+        };
+        assert_eq!(
+            hdparm.get_disk_status("/dev/sda").unwrap(),
+            PowerState::Standby
+        );
+        assert!(hdparm.get_disk_status("/dev/sdb").is_err());
+    }
+
+    #[test]
+    fn test_hdparm_spindown() {
+        let hdparm = Hdparm {
+            path: String::from("hdparm"),
+            runner: Arc::new(FakeRunner::default().with_output(
+                "hdparm -y /dev/sda",
+                "\n/dev/sda:\n issuing standby command\n",
+            )),
+        };
+        hdparm.spindown("/dev/sda").unwrap();
+        assert!(hdparm.spindown("/dev/sdb").is_err());
+    }
+
+    #[test]
+    fn test_hdparm_spinup() {
+        // No such disk in /sys/block, so there's only one block to read
+        let hdparm = Hdparm {
+            path: String::from("hdparm"),
+            runner: Arc::new(PrivilegedRunner {
+                helper: PrivilegeHelper::Doas,
+                inner: Arc::new(FakeRunner::default().with_output(
+                    "doas -n env LC_ALL=C dd if=/dev/dsm-test of=/dev/null bs=4096 count=1 skip=0 iflag=direct",
+                    "",
+                )),
+            }),
+        };
+        hdparm.spinup("/dev/dsm-test").unwrap();
+        assert!(hdparm.spinup("/dev/dsm-missing").is_err());
+    }
+
+    #[test]
+    fn test_apply_power_settings() {
+        let hdparm = Hdparm {
+            path: String::from("hdparm"),
+            runner: Arc::new(
+                FakeRunner::default()
+                    .with_output("hdparm -B 127 /dev/sda", "setting APM level to 127\n")
+                    .with_output("hdparm -S 241 /dev/sdb", "setting standby to 241\n"),
+            ),
+        };
+        let power_settings = BTreeMap::from([
+            (
+                String::from("/dev/sda"),
+                PowerSettings {
+                    apm: Some(127),
+                    standby_timer: Some(241),
+                },
+            ),
+            (
+                String::from("/dev/sdb"),
+                PowerSettings {
+                    apm: None,
+                    standby_timer: Some(241),
+                },
+            ),
+        ]);
+        let (tx, rx) = std::sync::mpsc::channel();
+        for disk in ["/dev/sda", "/dev/sdb", "/dev/sdc"] {
+            apply_power_settings(&hdparm, &power_settings, disk, &tx).unwrap();
+        }
+        drop(tx);
+
+        let messages: Vec<_> = rx.iter().collect();
+        assert_eq!(messages.len(), 2);
+        // the standby timer of sda failed, so only APM is reported
+        assert!(matches!(
+            &messages[0],
+            MetricMessage::PowerSettings { disk, settings }
+                if disk == "/dev/sda" && settings.apm == Some(127) && settings.standby_timer.is_none()
+        ));
+        assert!(matches!(
+            &messages[1],
+            MetricMessage::PowerSettings { disk, settings }
+                if disk == "/dev/sdb" && settings.standby_timer == Some(241)
+        ));
+    }
+
+    #[test]
+    fn test_parse_hdparm_output_invalid() {
+        assert!(parse_hdparm_output(include_str!("../fixtures/hdparm/missing_state.txt")).is_err());
+        assert!(parse_hdparm_output(" drive state is:  spinning\n").is_err());
+    }
+
+    #[test]
+    fn test_disk_backend_override() {
+        let o: DiskBackendOverride = "/dev/sdc=smartctl:sat".parse().unwrap();
+        assert_eq!(o.disk, "/dev/sdc");
+        assert_eq!(
+            o.backend,
+            BackendKind::Smartctl {
+                device_type: Some(String::from("sat"))
+            }
+        );
+
+        let o: DiskBackendOverride = "/dev/sdd=hdparm".parse().unwrap();
+        assert_eq!(o.backend, BackendKind::Hdparm);
+
+        let o: DiskBackendOverride = "/dev/sde=udisks2".parse().unwrap();
+        assert_eq!(o.backend, BackendKind::Udisks2);
+
+        assert!("/dev/sdc".parse::<DiskBackendOverride>().is_err());
+        assert!("/dev/sdc=hdparm:sat"
+            .parse::<DiskBackendOverride>()
+            .is_err());
+        assert!("/dev/sdc=camcontrol"
+            .parse::<DiskBackendOverride>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        // every command fails
+        let runner: Arc<dyn CommandRunner> = Arc::new(FakeRunner::default());
+        let commands = BackendCommands {
+            hdparm: String::from("hdparm"),
+            smartctl: String::from("smartctl"),
+            busctl: String::from("busctl"),
+            runner: runner.clone(),
+            privileged_runner: runner,
+        };
+        let backends = DiskBackends::new(&commands, &BackendKind::Hdparm, &[]);
+        assert!(backends.spindown("/dev/sda").is_err());
+        assert!(backends.set_apm("/dev/sda", 127).is_err());
+
+        let backends = backends
+            .with_dry_run(true)
+            .with_protected(vec![String::from("/dev/sdb")]);
+        assert!(backends.spindown("/dev/sdb").is_err());
+        backends.spindown("/dev/sda").unwrap();
+        backends.spinup("/dev/sda").unwrap();
+        backends.set_apm("/dev/sda", 127).unwrap();
+        backends.set_standby_timer("/dev/sda", 241).unwrap();
+        // queries still run
+        assert!(backends.get_disk_status("/dev/sda").is_err());
+    }
+
+    #[test]
+    fn test_disk_status_once() {
+        let runner: Arc<dyn CommandRunner> = Arc::new(FakeRunner::default().with_output(
+            "hdparm -C /dev/sda",
+            include_str!("../fixtures/hdparm/standby.txt"),
+        ));
+        let commands = BackendCommands {
+            hdparm: String::from("hdparm"),
+            smartctl: String::from("smartctl"),
+            busctl: String::from("busctl"),
+            runner: runner.clone(),
+            privileged_runner: runner,
+        };
+        let disk_list = FakeDiskList {
+            disks: vec![String::from("/dev/sda"), String::from("/dev/sdb")],
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let backends = DiskBackends::new(&commands, &BackendKind::Hdparm, &[]);
+
+        let failing =
+            disk_status_once(backends, disk_list, 1, RetryPolicy::default(), &tx).unwrap();
+        assert_eq!(failing, 1);
+        drop(tx);
+        let messages: Vec<MetricMessage> = rx.iter().collect();
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            MetricMessage::DiskStatus { disk, status: PowerState::Standby } if disk == "/dev/sda"
+        )));
+        assert!(matches!(
+            messages.last(),
+            Some(MetricMessage::CycleFinished)
+        ));
+    }
+
+    /// Fails to list the disks the first `failures` times
+    struct FlakyDiskList {
+        failures: Mutex<u32>,
+    }
+    impl DiskList for FlakyDiskList {
+        fn get_all_disks(&self) -> Result<Vec<String>> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                bail!("Failed to read /sys/block");
+            }
+            Ok(vec![String::from("/dev/sda")])
+        }
+    }
+
+    #[test]
+    fn test_enumeration_error_continues() {
+        let runner: Arc<dyn CommandRunner> = Arc::new(FakeRunner::default().with_output(
+            "hdparm -C /dev/sda",
+            include_str!("../fixtures/hdparm/standby.txt"),
+        ));
+        let commands = BackendCommands {
+            hdparm: String::from("hdparm"),
+            smartctl: String::from("smartctl"),
+            busctl: String::from("busctl"),
+            runner: runner.clone(),
+            privileged_runner: runner,
+        };
+        let disk_list = FlakyDiskList {
+            failures: Mutex::new(2),
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        // keeps the hotplug channel open, so refreshes don't wait
+        let (_hotplug_tx, hotplug_rx) = std::sync::mpsc::channel();
+        let backends = DiskBackends::new(&commands, &BackendKind::Hdparm, &[]);
+        let status_loop = thread::spawn(move || {
+            disk_status_loop(
+                backends,
+                disk_list,
+                Arc::new(AtomicU64::new(0)),
+                1,
+                RetryPolicy::default(),
+                hotplug_rx,
+                tx,
+            )
+        });
+
+        let mut enumeration_errors = 0;
+        for message in rx.iter() {
+            match message {
+                MetricMessage::EnumerationError => enumeration_errors += 1,
+                MetricMessage::DiskStatus { disk, .. } if disk == "/dev/sda" => break,
+                _ => {}
+            }
+        }
+        assert_eq!(enumeration_errors, 2);
+        // the loop only stops once nobody receives anymore
+        drop(rx);
+        status_loop.join().unwrap();
+    }
+
+    #[test]
+    fn test_query_duration() {
+        let runner: Arc<dyn CommandRunner> = Arc::new(FakeRunner::default());
+        let commands = BackendCommands {
+            hdparm: String::from("hdparm"),
+            smartctl: String::from("smartctl"),
+            busctl: String::from("busctl"),
+            runner: runner.clone(),
+            privileged_runner: runner,
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let overrides = [DiskBackendOverride {
+            disk: String::from("/dev/sdb"),
+            backend: BackendKind::Udisks2,
+        }];
+        let backends =
+            DiskBackends::new(&commands, &BackendKind::Hdparm, &overrides).with_metrics(tx);
+        // failed queries are timed, too
+        assert!(backends.get_disk_status("/dev/sda").is_err());
+        assert!(backends.get_disk_status("/dev/sdb").is_err());
+        drop(backends);
+
+        let queries: Vec<_> = rx
+            .iter()
+            .map(|message| match message {
+                MetricMessage::QueryDuration { disk, backend, .. } => (disk, backend),
+                _ => panic!("invalid message: {:?}", message),
+            })
+            .collect();
+        assert_eq!(
+            queries,
+            vec![
+                (String::from("/dev/sda"), "hdparm"),
+                (String::from("/dev/sdb"), "udisks2")
+            ]
+        );
+    }
+
     #[test]
     fn it_works() {
         // prepare test
         init();
-        let lsblk_output = r#"
-{
-   "blockdevices": [
-      {
-         "name": "sda",
-         "type": "disk",
-         "rota": true
-      },
-      {
-         "name": "sdb",
-         "type": "disk",
-         "rota": false
-      },
-      {
-         "name": "sr0",
-         "type": "rom",
-         "rota": true
-      }
-   ]
-}
-"#;
-        let lsblk = FakeLsblk {
-            result: lsblk_output.to_string(),
-        };
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdb",
+                scsi_type: Some(0),
+                rotational: false,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sr0",
+                scsi_type: Some(5),
+                rotational: true,
+                removable: true,
+            },
+        ]);
+        let disk_list = SysBlock::with_sys_root(sys_root.path());
         let disk_query = FakeHdparm {};
         let (tx, rx) = std::sync::mpsc::channel();
 
         // run a single cycle
-        update_disk_status(&disk_query, &lsblk, &tx).unwrap();
+        let retries = DiskRetries::new(RetryPolicy::default());
+        update_disk_status(
+            &disk_query,
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
 
         // receive single message
         let msg = rx.recv().unwrap();
 
         if let MetricMessage::DiskStatus { disk, status } = msg {
             assert_eq!(disk, "/dev/sda");
-            assert_eq!(status, 0.0);
+            assert_eq!(status, PowerState::Standby);
         } else {
             panic!("invalid message: {:?}", msg);
         }
@@ -0,0 +1,1311 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::mounts::{find_mount, read_mounts, read_swaps};
+
+/// SCSI peripheral device type of a direct access block device, i.e. a disk
+const SCSI_TYPE_DISK: u32 = 0;
+
+/// Unit of `/sys/block/*/size`, regardless of the disk's actual sector size
+const SECTOR_SIZE: u64 = 512;
+
+pub trait DiskList {
+    fn get_all_disks(&self) -> Result<Vec<String>>;
+
+    /// Identity of a disk, `None` if the list doesn't know about it
+    fn get_disk_info(&self, _disk: &str) -> Result<Option<DiskInfo>> {
+        Ok(None)
+    }
+
+    /// Temperature in °C from the kernel's hwmon driver of the disk, `None`
+    /// if it has none
+    fn get_temperature(&self, _disk: &str) -> Result<Option<f64>> {
+        Ok(None)
+    }
+
+    /// Physical member disks of each md array, keyed by the array
+    fn get_md_arrays(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Mount points of the filesystems stored on the disk
+    fn get_mounts(&self, _disk: &str) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Human readable identity of a disk. Fields the device doesn't expose are
+/// left empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskInfo {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub size_bytes: u64,
+}
+
+/// Glob patterns selecting which disks get monitored, e.g. `/dev/sd[c-h]`
+#[derive(Clone, Debug, Default)]
+pub struct DiskFilter {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl DiskFilter {
+    /// A disk matches if it matches any include pattern (or there are none)
+    /// and none of the exclude patterns
+    pub fn matches(&self, disk: &str) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(disk));
+        included && !self.exclude.iter().any(|pattern| pattern.matches(disk))
+    }
+}
+
+/// Which block devices are considered disks
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum DiskEnumeration {
+    /// SCSI disks, including SATA and USB
+    #[default]
+    Scsi,
+    /// Any top-level block device backed by hardware, e.g. virtio (`vd*`) or
+    /// MMC disks
+    All,
+}
+
+/// Enumerates disks by scanning `/sys/block`. Clones share the settings that
+/// can be reloaded.
+#[derive(Clone)]
+pub struct SysBlock {
+    sys_root: PathBuf,
+    enumeration: DiskEnumeration,
+    /// Also monitor SSDs and other non-rotational disks
+    all_disks: bool,
+    /// Per-disk decision whether to monitor regardless of the rotational
+    /// flag, keyed by any path of the disk
+    monitor_overrides: Arc<RwLock<BTreeMap<String, bool>>>,
+    /// Monitor the disks backing these paths
+    monitor_paths: Vec<PathBuf>,
+    /// Scan for disks even though there are `monitor_paths`
+    scan: bool,
+    mounts_file: PathBuf,
+    swaps_file: PathBuf,
+    filter: Arc<RwLock<DiskFilter>>,
+}
+
+struct BlockDevice {
+    name: String,
+    /// Only SCSI (including SATA and USB) devices have a type
+    scsi_type: Option<u32>,
+    /// Virtual devices like loop, dm or md don't have a `device` link
+    physical: bool,
+    rotational: bool,
+    removable: bool,
+}
+
+impl BlockDevice {
+    fn is_disk(&self, enumeration: DiskEnumeration) -> bool {
+        match enumeration {
+            DiskEnumeration::Scsi => self.scsi_type == Some(SCSI_TYPE_DISK),
+            DiskEnumeration::All => self.physical,
+        }
+    }
+}
+
+/// Why a block device isn't monitored
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    /// Loop, device-mapper, md and other devices without hardware
+    Virtual,
+    /// Hardware that isn't a disk for `--enumerate`, like an optical drive
+    Unsupported,
+    /// Card readers and the like, which can't be spun down anyway
+    Removable,
+    /// SSDs without `--monitor-all-disks`
+    NonRotational,
+    /// `monitor = false` in the config file
+    Config,
+    /// Not scanned for and not behind any `--monitor-path`
+    NotOnMonitoredPath,
+    /// Doesn't pass `--include-disks` and `--exclude-disks`
+    Filtered,
+}
+
+impl Exclusion {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Exclusion::Virtual => "virtual device",
+            Exclusion::Unsupported => "not a disk with this --enumerate",
+            Exclusion::Removable => "removable media",
+            Exclusion::NonRotational => "not rotational, see --monitor-all-disks",
+            Exclusion::Config => "monitor = false in the config file",
+            Exclusion::NotOnMonitoredPath => "not behind any --monitor-path",
+            Exclusion::Filtered => "filtered by --include-disks or --exclude-disks",
+        }
+    }
+}
+
+/// A block device and whether it's monitored
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DiskSelection {
+    pub disk: String,
+    /// `None` if the disk is monitored
+    pub excluded: Option<Exclusion>,
+}
+
+impl SysBlock {
+    pub fn new() -> Self {
+        Self::with_sys_root(Path::new("/sys"))
+    }
+
+    pub fn with_sys_root(sys_root: &Path) -> Self {
+        SysBlock {
+            sys_root: sys_root.to_path_buf(),
+            enumeration: DiskEnumeration::default(),
+            all_disks: false,
+            monitor_overrides: Arc::default(),
+            monitor_paths: Vec::new(),
+            scan: true,
+            mounts_file: PathBuf::from("/proc/mounts"),
+            swaps_file: PathBuf::from("/proc/swaps"),
+            filter: Arc::default(),
+        }
+    }
+
+    /// Only monitor the disks backing `paths`, or also scan for disks if
+    /// `scan` is set
+    pub fn with_monitor_paths(mut self, paths: Vec<PathBuf>, scan: bool) -> Self {
+        self.monitor_paths = paths;
+        self.scan = scan;
+        self
+    }
+
+    pub fn with_mounts_file(mut self, mounts_file: &Path) -> Self {
+        self.mounts_file = mounts_file.to_path_buf();
+        self
+    }
+
+    pub fn with_swaps_file(mut self, swaps_file: &Path) -> Self {
+        self.swaps_file = swaps_file.to_path_buf();
+        self
+    }
+
+    /// Physical disks holding `path`, following the mount's device through
+    /// device-mapper and md
+    pub fn disks_for_path(&self, path: &Path) -> Result<Vec<String>> {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("Failed to resolve {}", path.to_string_lossy()))?;
+        let mounts = read_mounts(&self.mounts_file)?;
+        let mount = find_mount(&mounts, &path)
+            .with_context(|| format!("No mount found for {}", path.to_string_lossy()))?;
+        if !mount.source.starts_with("/dev/") {
+            bail!(
+                "{} is on {}, which is not a block device",
+                path.to_string_lossy(),
+                mount.source
+            );
+        }
+        self.source_disks(&mount.source)
+    }
+
+    /// Where filesystems are mounted, keyed by the physical disks holding
+    /// them. Mounts whose disks can't be resolved are skipped.
+    pub fn mount_points(&self) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+        let mut mount_points: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for mount in read_mounts(&self.mounts_file)? {
+            if !mount.source.starts_with("/dev/") {
+                continue;
+            }
+            match self.source_disks(&mount.source) {
+                Ok(disks) => {
+                    for disk in disks {
+                        mount_points
+                            .entry(disk)
+                            .or_default()
+                            .push(mount.target.clone());
+                    }
+                }
+                Err(err) => debug!("Failed to resolve mount {}: {:?}", mount.source, err),
+            }
+        }
+        Ok(mount_points)
+    }
+
+    /// Where filesystems of the monitored disks are mounted, except the root
+    /// filesystem which is too much to watch
+    pub fn monitored_mount_points(&self) -> Result<Vec<PathBuf>> {
+        let disks = self.get_all_disks()?;
+        let mut targets: Vec<PathBuf> = self
+            .mount_points()?
+            .into_iter()
+            .filter(|(disk, _)| disks.iter().any(|monitored| is_same_disk(monitored, disk)))
+            .flat_map(|(_, targets)| targets)
+            .filter(|target| target != Path::new("/"))
+            .collect();
+        targets.sort();
+        targets.dedup();
+        Ok(targets)
+    }
+
+    /// Physical disks behind a mount source like `/dev/mapper/media`
+    fn source_disks(&self, source: &str) -> Result<Vec<String>> {
+        // /dev/mapper/* are links to /dev/dm-*
+        let device = fs::canonicalize(source).unwrap_or(PathBuf::from(source));
+        let name = device
+            .file_name()
+            .context("Mount source without a name")?
+            .to_string_lossy();
+        self.physical_disks(&name)
+    }
+
+    fn scan_disks(&self) -> Result<Vec<String>> {
+        Ok(self
+            .block_devices()?
+            .into_iter()
+            .filter(|device| self.should_monitor(device))
+            .map(|device| format!("/dev/{}", device.name))
+            .collect())
+    }
+
+    pub fn with_all_disks(mut self, all_disks: bool) -> Self {
+        self.all_disks = all_disks;
+        self
+    }
+
+    pub fn with_monitor_overrides(self, monitor_overrides: BTreeMap<String, bool>) -> Self {
+        *self.monitor_overrides.write().unwrap() = monitor_overrides;
+        self
+    }
+
+    /// Replace the filter and monitor overrides of this and all clones, taking
+    /// effect with the next enumeration
+    pub fn reload(&self, filter: DiskFilter, monitor_overrides: BTreeMap<String, bool>) {
+        *self.filter.write().unwrap() = filter;
+        *self.monitor_overrides.write().unwrap() = monitor_overrides;
+    }
+
+    fn should_monitor(&self, device: &BlockDevice) -> bool {
+        self.exclusion(device).is_none()
+    }
+
+    /// Why scanning skips `device`, `None` if it doesn't
+    fn exclusion(&self, device: &BlockDevice) -> Option<Exclusion> {
+        if !device.physical {
+            return Some(Exclusion::Virtual);
+        }
+        if !device.is_disk(self.enumeration) {
+            return Some(Exclusion::Unsupported);
+        }
+        if device.removable {
+            return Some(Exclusion::Removable);
+        }
+        let disk = format!("/dev/{}", device.name);
+        let monitor_override = self
+            .monitor_overrides
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(path, _)| is_same_disk(path, &disk))
+            .map(|(_, monitor)| *monitor);
+        match monitor_override {
+            Some(true) => None,
+            Some(false) => Some(Exclusion::Config),
+            None if self.all_disks || device.rotational => None,
+            None => Some(Exclusion::NonRotational),
+        }
+    }
+
+    /// Every block device and why it isn't monitored, if it isn't
+    pub fn selection(&self) -> Result<Vec<DiskSelection>> {
+        let monitored = self.get_all_disks()?;
+        let scan = self.monitor_paths.is_empty() || self.scan;
+        let filter = self.filter.read().unwrap().clone();
+        Ok(self
+            .block_devices()?
+            .into_iter()
+            .map(|device| {
+                let disk = format!("/dev/{}", device.name);
+                let excluded = if monitored.contains(&disk) {
+                    None
+                } else if scan {
+                    Some(self.exclusion(&device).unwrap_or(Exclusion::Filtered))
+                } else if filter.matches(&disk) {
+                    Some(Exclusion::NotOnMonitoredPath)
+                } else {
+                    Some(Exclusion::Filtered)
+                };
+                DiskSelection { disk, excluded }
+            })
+            .collect())
+    }
+
+    pub fn with_enumeration(mut self, enumeration: DiskEnumeration) -> Self {
+        self.enumeration = enumeration;
+        self
+    }
+
+    pub fn with_filter(self, filter: DiskFilter) -> Self {
+        *self.filter.write().unwrap() = filter;
+        self
+    }
+
+    /// Disks holding `/` or swap, spinning those down would stall the whole
+    /// system. Disks that can't be resolved are logged and skipped.
+    pub fn system_disks(&self) -> Vec<String> {
+        let mut disks = Vec::new();
+        match self.disks_for_path(Path::new("/")) {
+            Ok(root) => disks.extend(root),
+            Err(err) => warn!("Failed to find the disks holding /: {:?}", err),
+        }
+        let swaps = read_swaps(&self.swaps_file).unwrap_or_else(|err| {
+            warn!("Failed to read swaps: {:?}", err);
+            Vec::new()
+        });
+        for swap in swaps {
+            let swap_disks = if swap.partition {
+                let device = fs::canonicalize(&swap.filename).unwrap_or(swap.filename.clone());
+                match device.file_name() {
+                    Some(name) => self.physical_disks(&name.to_string_lossy()),
+                    None => continue,
+                }
+            } else {
+                self.disks_for_path(&swap.filename)
+            };
+            match swap_disks {
+                Ok(swap_disks) => disks.extend(swap_disks),
+                Err(err) => warn!(
+                    "Failed to find the disks holding swap {}: {:?}",
+                    swap.filename.to_string_lossy(),
+                    err
+                ),
+            }
+        }
+        disks.sort();
+        disks.dedup();
+        disks
+    }
+
+    /// Physical disks backing a block device by kernel name, e.g. `dm-0`
+    pub fn physical_disks(&self, name: &str) -> Result<Vec<String>> {
+        resolve_physical_disks(&self.sys_root.join("class/block").join(name))
+    }
+
+    fn block_devices(&self) -> Result<Vec<BlockDevice>> {
+        let block = self.sys_root.join("block");
+        let entries = fs::read_dir(&block)
+            .with_context(|| format!("Failed to list {}", block.to_string_lossy()))?;
+        let mut devices = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            devices.push(
+                read_block_device(&path)
+                    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?,
+            );
+        }
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(devices)
+    }
+}
+
+impl Default for SysBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskList for SysBlock {
+    /// All rotational disks (unless configured otherwise) plus the disks
+    /// backing the monitored paths that pass the filter. Removable media (card
+    /// readers etc.) is skipped, those can't be spun down anyway.
+    fn get_all_disks(&self) -> Result<Vec<String>> {
+        let mut disks = if self.monitor_paths.is_empty() || self.scan {
+            self.scan_disks()?
+        } else {
+            Vec::new()
+        };
+        // Mounts can change at any time, so resolve them on every refresh
+        for path in &self.monitor_paths {
+            match self.disks_for_path(path) {
+                Ok(path_disks) => disks.extend(path_disks),
+                Err(err) => warn!(
+                    "Failed to find disks for {}: {:?}",
+                    path.to_string_lossy(),
+                    err
+                ),
+            }
+        }
+        let filter = self.filter.read().unwrap();
+        disks.retain(|disk| filter.matches(disk));
+        disks.sort();
+        disks.dedup();
+        Ok(disks)
+    }
+
+    /// Only reads attributes the kernel cached when the disk was attached,
+    /// so this doesn't wake up sleeping disks
+    fn get_disk_info(&self, disk: &str) -> Result<Option<DiskInfo>> {
+        let name = disk.strip_prefix("/dev/").unwrap_or(disk);
+        let path = self.sys_root.join("block").join(name);
+        let sectors: u64 = fs::read_to_string(path.join("size"))
+            .with_context(|| format!("Failed to read size of {}", disk))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid size of {}", disk))?;
+        let device = path.join("device");
+        let serial = read_attr(&device.join("serial"))
+            .or_else(|| read_vpd_serial(&device.join("vpd_pg80")))
+            .unwrap_or_default();
+        Ok(Some(DiskInfo {
+            model: read_attr(&device.join("model")).unwrap_or_default(),
+            serial,
+            // SCSI calls it rev, NVMe firmware_rev
+            firmware: read_attr(&device.join("rev"))
+                .or_else(|| read_attr(&device.join("firmware_rev")))
+                .unwrap_or_default(),
+            size_bytes: sectors * SECTOR_SIZE,
+        }))
+    }
+
+    /// Some drives spin up to answer, so only ask disks known to be spinning
+    fn get_temperature(&self, disk: &str) -> Result<Option<f64>> {
+        let name = disk.strip_prefix("/dev/").unwrap_or(disk);
+        let device = self.sys_root.join("block").join(name).join("device");
+        // drivetemp registers below the SCSI device, nvme at the controller
+        for pattern in ["hwmon/hwmon*/temp1_input", "hwmon*/temp1_input"] {
+            let pattern = device.join(pattern);
+            let Some(path) = glob::glob(&pattern.to_string_lossy())?.flatten().next() else {
+                continue;
+            };
+            let millidegrees: i64 = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read temperature of {}", disk))?
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid temperature of {}", disk))?;
+            return Ok(Some(millidegrees as f64 / 1000.0));
+        }
+        Ok(None)
+    }
+
+    /// Includes filesystems on partitions, md arrays and device mapper
+    /// targets on top of the disk
+    fn get_mounts(&self, disk: &str) -> Result<Vec<PathBuf>> {
+        Ok(read_mounts(&self.mounts_file)?
+            .into_iter()
+            .filter(|mount| {
+                mount.source.starts_with("/dev/")
+                    && self
+                        .source_disks(&mount.source)
+                        .is_ok_and(|disks| disks.iter().any(|source| source == disk))
+            })
+            .map(|mount| mount.target)
+            .collect())
+    }
+
+    fn get_md_arrays(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut arrays = BTreeMap::new();
+        for device in self.block_devices()? {
+            let path = self.sys_root.join("block").join(&device.name);
+            // Only md arrays have the `md` directory, member partitions are
+            // resolved to their disks
+            if !path.join("md").is_dir() {
+                continue;
+            }
+            arrays.insert(
+                format!("/dev/{}", device.name),
+                resolve_physical_disks(&path)?,
+            );
+        }
+        Ok(arrays)
+    }
+}
+
+/// sysfs attributes are padded with spaces and a newline
+fn read_attr(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Unit serial number VPD page: a 4 byte header followed by the ASCII serial
+fn read_vpd_serial(path: &Path) -> Option<String> {
+    let page = fs::read(path).ok()?;
+    let serial = String::from_utf8_lossy(page.get(4..)?);
+    Some(
+        serial
+            .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+            .to_string(),
+    )
+    .filter(|serial| !serial.is_empty())
+}
+
+/// Resolve a block device's sysfs directory to the `/dev` paths of the
+/// physical disks backing it. Partitions resolve to their disk and stacked
+/// devices (LUKS, LVM, ...) are followed through their `slaves`.
+pub fn resolve_physical_disks(device: &Path) -> Result<Vec<String>> {
+    let device = fs::canonicalize(device)
+        .with_context(|| format!("Failed to resolve {}", device.to_string_lossy()))?;
+    if device.join("partition").exists() {
+        let disk = device.parent().context("Partition without a disk")?;
+        return resolve_physical_disks(disk);
+    }
+    let slaves: Vec<PathBuf> = match fs::read_dir(device.join("slaves")) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?,
+        Err(_) => Vec::new(),
+    };
+    if slaves.is_empty() {
+        let name = device
+            .file_name()
+            .context("Block device without a name")?
+            .to_string_lossy();
+        return Ok(vec![format!("/dev/{}", name)]);
+    }
+    let mut disks = Vec::new();
+    for slave in slaves {
+        disks.extend(resolve_physical_disks(&slave)?);
+    }
+    disks.sort();
+    disks.dedup();
+    Ok(disks)
+}
+
+/// Whether `path` refers to the disk with the kernel name `disk`, either
+/// directly or through a symlink like the ones in `/dev/disk/by-id`
+pub fn is_same_disk(path: &str, disk: &str) -> bool {
+    if path == disk {
+        return true;
+    }
+    match fs::read_link(path) {
+        Ok(target) => target.file_name() == Path::new(disk).file_name(),
+        Err(_) => false,
+    }
+}
+
+/// Which name of a disk is used as its `disk` label
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum DiskNaming {
+    /// Kernel name like `/dev/sda`, which can change across reboots
+    Kernel,
+    /// Link in `/dev/disk/by-id`, falling back to the kernel name if there is none
+    ById,
+}
+
+/// Maps kernel disk names to the labels used in metrics
+pub struct DiskNames {
+    naming: DiskNaming,
+    by_id_dir: PathBuf,
+    /// Keep the kernel name around as a `device` label
+    device_label: bool,
+    /// Friendly names for the `name` label, keyed by any path of the disk
+    aliases: BTreeMap<String, String>,
+    /// Disks that are never spun down, for the `protected` label. No label
+    /// without protection.
+    protected: Option<Vec<String>>,
+    resolved: Mutex<HashMap<String, String>>,
+}
+
+impl DiskNames {
+    pub fn new(naming: DiskNaming, device_label: bool) -> Self {
+        DiskNames {
+            naming,
+            by_id_dir: PathBuf::from("/dev/disk/by-id"),
+            device_label,
+            aliases: BTreeMap::new(),
+            protected: None,
+            resolved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_aliases(mut self, aliases: BTreeMap<String, String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    pub fn with_protected(mut self, protected: Vec<String>) -> Self {
+        self.protected = Some(protected);
+        self
+    }
+
+    pub fn with_by_id_dir(mut self, by_id_dir: &Path) -> Self {
+        self.by_id_dir = by_id_dir.to_path_buf();
+        self
+    }
+
+    /// Names of the labels identifying a disk, in the order of `labels`
+    pub fn label_names(&self) -> Vec<&'static str> {
+        let mut names = vec!["disk"];
+        if self.device_label {
+            names.push("device");
+        }
+        if !self.aliases.is_empty() {
+            names.push("name");
+        }
+        if self.protected.is_some() {
+            names.push("protected");
+        }
+        names
+    }
+
+    pub fn labels(&self, disk: &str) -> Vec<String> {
+        let name = self.name(disk);
+        let mut labels = vec![name.clone()];
+        if self.device_label {
+            labels.push(disk.to_string());
+        }
+        if !self.aliases.is_empty() {
+            // Disks without an alias keep their regular name
+            labels.push(self.alias(disk).unwrap_or(name));
+        }
+        if let Some(protected) = &self.protected {
+            let is_protected = protected.iter().any(|path| is_same_disk(path, disk));
+            labels.push(is_protected.to_string());
+        }
+        labels
+    }
+
+    /// Friendly name of the disk from the config file
+    pub fn alias(&self, disk: &str) -> Option<String> {
+        self.aliases
+            .iter()
+            .find(|(path, _)| is_same_disk(path, disk))
+            .map(|(_, alias)| alias.clone())
+    }
+
+    /// Forget the name of a removed disk, a different one might take over
+    /// its kernel name
+    pub fn forget(&self, disk: &str) {
+        self.resolved.lock().unwrap().remove(disk);
+    }
+
+    fn name(&self, disk: &str) -> String {
+        if self.naming == DiskNaming::Kernel {
+            return disk.to_string();
+        }
+        let mut resolved = self.resolved.lock().unwrap();
+        if let Some(name) = resolved.get(disk) {
+            return name.clone();
+        }
+        match self.resolve_by_id(disk) {
+            Ok(Some(name)) => {
+                resolved.insert(disk.to_string(), name.clone());
+                name
+            }
+            // Not cached, udev might not have created the link yet
+            Ok(None) => disk.to_string(),
+            Err(err) => {
+                debug!("Failed to resolve {} by id: {:?}", disk, err);
+                disk.to_string()
+            }
+        }
+    }
+
+    /// There are usually several links per disk (`ata-*`, `wwn-*`, ...),
+    /// pick the alphabetically first one to be stable
+    fn resolve_by_id(&self, disk: &str) -> Result<Option<String>> {
+        let kernel_name = Path::new(disk).file_name();
+        let entries = fs::read_dir(&self.by_id_dir)
+            .with_context(|| format!("Failed to list {}", self.by_id_dir.to_string_lossy()))?;
+        let mut links = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Ok(target) = fs::read_link(&path) else {
+                continue;
+            };
+            if target.file_name() == kernel_name {
+                links.push(path);
+            }
+        }
+        links.sort();
+        Ok(links
+            .into_iter()
+            .next()
+            .map(|link| link.to_string_lossy().to_string()))
+    }
+}
+
+fn read_block_device(path: &Path) -> Result<BlockDevice> {
+    let name = path
+        .file_name()
+        .context("Block device without a name")?
+        .to_string_lossy()
+        .to_string();
+    let scsi_type = match fs::read_to_string(path.join("device/type")) {
+        Ok(scsi_type) => Some(scsi_type.trim().parse()?),
+        Err(_) => None,
+    };
+    Ok(BlockDevice {
+        name,
+        scsi_type,
+        physical: path.join("device").exists(),
+        rotational: read_flag(&path.join("queue/rotational"))?,
+        removable: read_flag(&path.join("removable"))?,
+    })
+}
+
+fn read_flag(path: &Path) -> Result<bool> {
+    let flag = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+    Ok(flag.trim() == "1")
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    pub struct FakeDiskList {
+        pub disks: Vec<String>,
+    }
+
+    impl DiskList for FakeDiskList {
+        fn get_all_disks(&self) -> Result<Vec<String>> {
+            Ok(self.disks.clone())
+        }
+    }
+
+    pub struct FakeBlockDevice {
+        pub name: &'static str,
+        pub scsi_type: Option<u32>,
+        pub rotational: bool,
+        pub removable: bool,
+    }
+
+    /// Create a minimal `/sys/block` tree
+    pub fn fake_sysfs(devices: &[FakeBlockDevice]) -> TempDir {
+        let sys_root = TempDir::new().unwrap();
+        for device in devices {
+            let path = sys_root.path().join("block").join(device.name);
+            fs::create_dir_all(path.join("queue")).unwrap();
+            fs::write(
+                path.join("queue/rotational"),
+                format!("{}\n", device.rotational as u8),
+            )
+            .unwrap();
+            fs::write(
+                path.join("removable"),
+                format!("{}\n", device.removable as u8),
+            )
+            .unwrap();
+            if let Some(scsi_type) = device.scsi_type {
+                fs::create_dir_all(path.join("device")).unwrap();
+                fs::write(path.join("device/type"), format!("{}\n", scsi_type)).unwrap();
+            }
+        }
+        sys_root
+    }
+
+    #[test]
+    fn it_works() {
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdb",
+                scsi_type: Some(0),
+                rotational: false,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdc",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: true,
+            },
+            FakeBlockDevice {
+                name: "sr0",
+                scsi_type: Some(5),
+                rotational: true,
+                removable: true,
+            },
+            FakeBlockDevice {
+                name: "vda",
+                scsi_type: None,
+                rotational: true,
+                removable: false,
+            },
+        ]);
+
+        let disks = SysBlock::with_sys_root(sys_root.path())
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sda"]);
+    }
+
+    #[test]
+    fn test_monitor_all_disks() {
+        let sys_root = fake_sysfs(&["sda", "sdb", "sdc"].map(|name| FakeBlockDevice {
+            name,
+            scsi_type: Some(0),
+            rotational: name == "sda",
+            removable: false,
+        }));
+
+        let disks = SysBlock::with_sys_root(sys_root.path())
+            .with_all_disks(true)
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sda", "/dev/sdb", "/dev/sdc"]);
+
+        let overrides = BTreeMap::from([
+            (String::from("/dev/sda"), false),
+            (String::from("/dev/sdc"), true),
+        ]);
+        let disks = SysBlock::with_sys_root(sys_root.path())
+            .with_monitor_overrides(overrides)
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sdc"]);
+    }
+
+    #[test]
+    fn test_enumerate_all() {
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sr0",
+                scsi_type: Some(5),
+                rotational: true,
+                removable: true,
+            },
+            FakeBlockDevice {
+                name: "vda",
+                scsi_type: None,
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "vdb",
+                scsi_type: None,
+                rotational: false,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "loop0",
+                scsi_type: None,
+                rotational: true,
+                removable: false,
+            },
+        ]);
+        for name in ["vda", "vdb"] {
+            fs::create_dir(sys_root.path().join("block").join(name).join("device")).unwrap();
+        }
+
+        let disks = SysBlock::with_sys_root(sys_root.path())
+            .with_enumeration(DiskEnumeration::All)
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sda", "/dev/vda"]);
+    }
+
+    #[test]
+    fn test_physical_disks() {
+        let sys_root = TempDir::new().unwrap();
+        let root = sys_root.path();
+        let sda = root.join("devices/pci0000:00/block/sda");
+        let sdb = root.join("devices/pci0000:00/block/sdb");
+        let dm0 = root.join("devices/virtual/block/dm-0");
+        let dm1 = root.join("devices/virtual/block/dm-1");
+        fs::create_dir_all(sda.join("sda2")).unwrap();
+        fs::write(sda.join("sda2/partition"), "2\n").unwrap();
+        fs::create_dir_all(&sdb).unwrap();
+        // LUKS on sda2, LVM spanning the LUKS device and sdb
+        fs::create_dir_all(dm0.join("slaves")).unwrap();
+        symlink(sda.join("sda2"), dm0.join("slaves/sda2")).unwrap();
+        fs::create_dir_all(dm1.join("slaves")).unwrap();
+        symlink(&dm0, dm1.join("slaves/dm-0")).unwrap();
+        symlink(&sdb, dm1.join("slaves/sdb")).unwrap();
+        fs::create_dir_all(root.join("class/block")).unwrap();
+        symlink(&dm0, root.join("class/block/dm-0")).unwrap();
+        symlink(&dm1, root.join("class/block/dm-1")).unwrap();
+        symlink(sda.join("sda2"), root.join("class/block/sda2")).unwrap();
+
+        let sys_block = SysBlock::with_sys_root(root);
+        assert_eq!(sys_block.physical_disks("sda2").unwrap(), vec!["/dev/sda"]);
+        assert_eq!(sys_block.physical_disks("dm-0").unwrap(), vec!["/dev/sda"]);
+        assert_eq!(
+            sys_block.physical_disks("dm-1").unwrap(),
+            vec!["/dev/sda", "/dev/sdb"]
+        );
+        assert!(sys_block.physical_disks("dm-2").is_err());
+    }
+
+    #[test]
+    fn test_md_arrays() {
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdb",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "md0",
+                scsi_type: None,
+                rotational: false,
+                removable: false,
+            },
+        ]);
+        let block = sys_root.path().join("block");
+        fs::create_dir_all(block.join("sda/sda1")).unwrap();
+        fs::write(block.join("sda/sda1/partition"), "1\n").unwrap();
+        fs::create_dir_all(block.join("md0/md")).unwrap();
+        fs::create_dir_all(block.join("md0/slaves")).unwrap();
+        symlink(block.join("sda/sda1"), block.join("md0/slaves/sda1")).unwrap();
+        symlink(block.join("sdb"), block.join("md0/slaves/sdb")).unwrap();
+
+        let arrays = SysBlock::with_sys_root(sys_root.path())
+            .get_md_arrays()
+            .unwrap();
+        assert_eq!(
+            arrays,
+            BTreeMap::from([(
+                String::from("/dev/md0"),
+                vec![String::from("/dev/sda"), String::from("/dev/sdb")]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_monitor_paths() {
+        let sys_root = fake_sysfs(&["sda", "sdb", "sdc"].map(|name| FakeBlockDevice {
+            name,
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }));
+        let root = sys_root.path();
+        fs::create_dir_all(root.join("block/sda/sda1")).unwrap();
+        fs::write(root.join("block/sda/sda1/partition"), "1\n").unwrap();
+        fs::create_dir_all(root.join("class/block")).unwrap();
+        symlink(root.join("block/sda/sda1"), root.join("class/block/sda1")).unwrap();
+        symlink(root.join("block/sdb"), root.join("class/block/sdb")).unwrap();
+
+        let media = TempDir::new().unwrap();
+        fs::create_dir(media.path().join("movies")).unwrap();
+        let mounts_file = root.join("mounts");
+        fs::write(
+            &mounts_file,
+            format!(
+                "/dev/sdb / ext4 rw 0 0\n/dev/sda1 {} ext4 rw 0 0\ntmpfs /tmp tmpfs rw 0 0\n",
+                media.path().to_string_lossy()
+            ),
+        )
+        .unwrap();
+
+        let disks = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .with_monitor_paths(vec![media.path().join("movies")], false)
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sda"]);
+
+        let disks = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .with_monitor_paths(vec![media.path().to_path_buf()], true)
+            .get_all_disks()
+            .unwrap();
+        assert_eq!(disks, vec!["/dev/sda", "/dev/sdb", "/dev/sdc"]);
+
+        let mount_points = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .mount_points()
+            .unwrap();
+        assert_eq!(
+            mount_points,
+            BTreeMap::from([
+                (String::from("/dev/sda"), vec![media.path().to_path_buf()]),
+                (String::from("/dev/sdb"), vec![PathBuf::from("/")]),
+            ])
+        );
+        let targets = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .monitored_mount_points()
+            .unwrap();
+        assert_eq!(targets, [media.path()]);
+        let targets = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .with_filter(DiskFilter {
+                exclude: vec![Pattern::new("/dev/sda").unwrap()],
+                ..Default::default()
+            })
+            .monitored_mount_points()
+            .unwrap();
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn test_disk_info() {
+        let sys_root = fake_sysfs(&[FakeBlockDevice {
+            name: "sda",
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }]);
+        let path = sys_root.path().join("block/sda");
+        fs::write(path.join("size"), "7814037168\n").unwrap();
+        fs::write(path.join("device/model"), "WDC WD40EFRX-68N\n").unwrap();
+        fs::write(path.join("device/rev"), "0A82\n").unwrap();
+        fs::write(
+            path.join("device/vpd_pg80"),
+            b"\0\x80\0\x14     WD-WCC7K1234567",
+        )
+        .unwrap();
+
+        let info = SysBlock::with_sys_root(sys_root.path())
+            .get_disk_info("/dev/sda")
+            .unwrap();
+        assert_eq!(
+            info,
+            Some(DiskInfo {
+                model: String::from("WDC WD40EFRX-68N"),
+                serial: String::from("WD-WCC7K1234567"),
+                firmware: String::from("0A82"),
+                size_bytes: 4000787030016,
+            })
+        );
+    }
+
+    #[test]
+    fn test_temperature() {
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdb",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+        ]);
+        let hwmon = sys_root.path().join("block/sda/device/hwmon/hwmon3");
+        fs::create_dir_all(&hwmon).unwrap();
+        fs::write(hwmon.join("temp1_input"), "34000\n").unwrap();
+
+        let sys_block = SysBlock::with_sys_root(sys_root.path());
+        assert_eq!(sys_block.get_temperature("/dev/sda").unwrap(), Some(34.0));
+        assert_eq!(sys_block.get_temperature("/dev/sdb").unwrap(), None);
+    }
+
+    #[test]
+    fn test_disk_names() {
+        let by_id_dir = TempDir::new().unwrap();
+        for (link, target) in [
+            ("wwn-0x5000c500a1b2c3d4", "../../sda"),
+            ("ata-ST4000VN008_ZDH1ABCD", "../../sda"),
+            ("ata-ST4000VN008_ZDH1ABCD-part1", "../../sda1"),
+        ] {
+            std::os::unix::fs::symlink(target, by_id_dir.path().join(link)).unwrap();
+        }
+
+        let names = DiskNames::new(DiskNaming::ById, true).with_by_id_dir(by_id_dir.path());
+        assert_eq!(names.label_names(), vec!["disk", "device"]);
+        let expected = by_id_dir.path().join("ata-ST4000VN008_ZDH1ABCD");
+        assert_eq!(
+            names.labels("/dev/sda"),
+            vec![
+                expected.to_string_lossy().to_string(),
+                String::from("/dev/sda")
+            ]
+        );
+        // No link, fall back to the kernel name
+        assert_eq!(names.labels("/dev/sdb"), vec!["/dev/sdb", "/dev/sdb"]);
+
+        let names = DiskNames::new(DiskNaming::Kernel, false).with_by_id_dir(by_id_dir.path());
+        assert_eq!(names.labels("/dev/sda"), vec!["/dev/sda"]);
+    }
+
+    #[test]
+    fn test_protected_label() {
+        let names = DiskNames::new(DiskNaming::Kernel, false)
+            .with_protected(vec![String::from("/dev/sda")]);
+        assert_eq!(names.label_names(), vec!["disk", "protected"]);
+        assert_eq!(names.labels("/dev/sda"), vec!["/dev/sda", "true"]);
+        assert_eq!(names.labels("/dev/sdb"), vec!["/dev/sdb", "false"]);
+    }
+
+    #[test]
+    fn test_system_disks() {
+        let sys_root = fake_sysfs(&["sda", "sdb", "sdc"].map(|name| FakeBlockDevice {
+            name,
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }));
+        let root = sys_root.path();
+        fs::create_dir_all(root.join("block/sdc/sdc2")).unwrap();
+        fs::write(root.join("block/sdc/sdc2/partition"), "2\n").unwrap();
+        fs::create_dir_all(root.join("class/block")).unwrap();
+        symlink(root.join("block/sdb"), root.join("class/block/sdb")).unwrap();
+        symlink(root.join("block/sdc/sdc2"), root.join("class/block/sdc2")).unwrap();
+        let mounts_file = root.join("mounts");
+        fs::write(&mounts_file, "/dev/sdb / ext4 rw 0 0\n").unwrap();
+        let swaps_file = root.join("swaps");
+        fs::write(
+            &swaps_file,
+            "Filename Type Size Used Priority\n/dev/sdc2 partition 8388604 0 -2\n",
+        )
+        .unwrap();
+
+        let disks = SysBlock::with_sys_root(root)
+            .with_mounts_file(&mounts_file)
+            .with_swaps_file(&swaps_file)
+            .system_disks();
+        assert_eq!(disks, vec!["/dev/sdb", "/dev/sdc"]);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let sys_root = fake_sysfs(&["sda", "sdb"].map(|name| FakeBlockDevice {
+            name,
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }));
+        let root = sys_root.path();
+        fs::create_dir_all(root.join("block/sda/sda1")).unwrap();
+        fs::write(root.join("block/sda/sda1/partition"), "1\n").unwrap();
+        fs::create_dir_all(root.join("class/block")).unwrap();
+        symlink(root.join("block/sda/sda1"), root.join("class/block/sda1")).unwrap();
+        symlink(root.join("block/sdb"), root.join("class/block/sdb")).unwrap();
+        let mounts_file = root.join("mounts");
+        fs::write(
+            &mounts_file,
+            "/dev/sdb / ext4 rw 0 0\n/dev/sda1 /mnt/media ext4 rw 0 0\ntmpfs /tmp tmpfs rw 0 0\n",
+        )
+        .unwrap();
+
+        let sys_block = SysBlock::with_sys_root(root).with_mounts_file(&mounts_file);
+        assert_eq!(
+            sys_block.get_mounts("/dev/sda").unwrap(),
+            vec![PathBuf::from("/mnt/media")]
+        );
+        assert_eq!(
+            sys_block.get_mounts("/dev/sdb").unwrap(),
+            vec![PathBuf::from("/")]
+        );
+    }
+
+    #[test]
+    fn test_disk_aliases() {
+        let by_id_dir = TempDir::new().unwrap();
+        let by_id = by_id_dir.path().join("ata-WDC_WD40EFRX_1234");
+        symlink("../../sda", &by_id).unwrap();
+        let aliases = BTreeMap::from([
+            (by_id.to_string_lossy().to_string(), String::from("media-1")),
+            (String::from("/dev/sdc"), String::from("parity")),
+        ]);
+
+        let names = DiskNames::new(DiskNaming::Kernel, false).with_aliases(aliases);
+        assert_eq!(names.label_names(), vec!["disk", "name"]);
+        assert_eq!(names.labels("/dev/sda"), vec!["/dev/sda", "media-1"]);
+        assert_eq!(names.labels("/dev/sdb"), vec!["/dev/sdb", "/dev/sdb"]);
+        assert_eq!(names.labels("/dev/sdc"), vec!["/dev/sdc", "parity"]);
+    }
+
+    #[test]
+    fn test_disk_filter() {
+        let sys_root = fake_sysfs(&["sda", "sdb", "sdc", "sdd"].map(|name| FakeBlockDevice {
+            name,
+            scsi_type: Some(0),
+            rotational: true,
+            removable: false,
+        }));
+        let filter = DiskFilter {
+            include: vec![Pattern::new("/dev/sd[b-z]").unwrap()],
+            exclude: vec![Pattern::new("/dev/sdc").unwrap()],
+        };
+
+        let disk_list = SysBlock::with_sys_root(sys_root.path()).with_filter(filter);
+        assert_eq!(
+            disk_list.get_all_disks().unwrap(),
+            vec!["/dev/sdb", "/dev/sdd"]
+        );
+
+        // reloading applies to all clones
+        let filter = DiskFilter {
+            include: Vec::new(),
+            exclude: vec![Pattern::new("/dev/sdb").unwrap()],
+        };
+        let overrides = BTreeMap::from([(String::from("/dev/sdd"), false)]);
+        disk_list.clone().reload(filter, overrides);
+        assert_eq!(
+            disk_list.get_all_disks().unwrap(),
+            vec!["/dev/sda", "/dev/sdc"]
+        );
+    }
+
+    #[test]
+    fn test_selection() {
+        let device = |name, scsi_type, rotational, removable| FakeBlockDevice {
+            name,
+            scsi_type,
+            rotational,
+            removable,
+        };
+        let sys_root = fake_sysfs(&[
+            device("loop0", None, false, false),
+            device("sda", Some(0), true, false),
+            device("sdb", Some(0), false, false),
+            device("sdc", Some(0), true, true),
+            device("sdd", Some(0), true, false),
+            device("sde", Some(0), true, false),
+            device("sr0", Some(5), true, true),
+        ]);
+        let filter = DiskFilter {
+            include: Vec::new(),
+            exclude: vec![Pattern::new("/dev/sdd").unwrap()],
+        };
+        let selection = SysBlock::with_sys_root(sys_root.path())
+            .with_monitor_overrides(BTreeMap::from([(String::from("/dev/sde"), false)]))
+            .with_filter(filter)
+            .selection()
+            .unwrap();
+        let excluded: Vec<_> = selection
+            .iter()
+            .map(|selection| (selection.disk.as_str(), selection.excluded))
+            .collect();
+        assert_eq!(
+            excluded,
+            [
+                ("/dev/loop0", Some(Exclusion::Virtual)),
+                ("/dev/sda", None),
+                ("/dev/sdb", Some(Exclusion::NonRotational)),
+                ("/dev/sdc", Some(Exclusion::Removable)),
+                ("/dev/sdd", Some(Exclusion::Filtered)),
+                ("/dev/sde", Some(Exclusion::Config)),
+                ("/dev/sr0", Some(Exclusion::Unsupported)),
+            ]
+        );
+    }
+}
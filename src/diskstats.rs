@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{SendError, Sender},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+
+use crate::{disks::DiskList, metrics::MetricMessage, own_io::OwnIo};
+
+/// Counters of a block device from `/proc/diskstats`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskStats {
+    pub reads_completed: u64,
+    pub sectors_read: u64,
+    pub writes_completed: u64,
+    pub sectors_written: u64,
+    /// Not a counter, the number of requests currently in flight
+    pub in_flight: u64,
+    pub io_time_ms: u64,
+}
+
+/// I/O a disk saw since the previous poll
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActivityEvent {
+    pub disk: String,
+    pub reads: u64,
+    pub writes: u64,
+    pub sectors_read: u64,
+    /// Without the daemon's own writes
+    pub sectors_written: u64,
+    /// Current totals
+    pub stats: DiskStats,
+}
+
+impl ActivityEvent {
+    pub fn is_active(&self) -> bool {
+        self.sectors_read > 0 || self.sectors_written > 0
+    }
+}
+
+/// Computes per-disk I/O deltas between reads of `/proc/diskstats`
+pub struct DiskstatsPoller {
+    diskstats: PathBuf,
+    own_io: OwnIo,
+    previous: HashMap<String, DiskStats>,
+}
+
+impl DiskstatsPoller {
+    pub fn new(own_io: OwnIo) -> Self {
+        DiskstatsPoller {
+            diskstats: PathBuf::from("/proc/diskstats"),
+            own_io,
+            previous: HashMap::new(),
+        }
+    }
+
+    pub fn with_diskstats(mut self, diskstats: &Path) -> Self {
+        self.diskstats = diskstats.to_path_buf();
+        self
+    }
+
+    /// Return the activity of `disks` since the last poll. Disks that are
+    /// polled the first time have nothing to compare to and are left out.
+    pub fn poll(&mut self, disks: &[String]) -> Result<Vec<ActivityEvent>> {
+        let all_stats = read_diskstats(&self.diskstats)?;
+        self.previous.retain(|disk, _| disks.contains(disk));
+        let mut events = Vec::new();
+        for disk in disks {
+            let name = disk.strip_prefix("/dev/").unwrap_or(disk);
+            let Some(stats) = all_stats.get(name) else {
+                debug!("{} not found in diskstats", disk);
+                continue;
+            };
+            let Some(previous) = self.previous.insert(disk.clone(), stats.clone()) else {
+                continue;
+            };
+            // Counters reset when a disk is re-attached under the same name
+            events.push(ActivityEvent {
+                disk: disk.clone(),
+                reads: stats
+                    .reads_completed
+                    .saturating_sub(previous.reads_completed),
+                writes: stats
+                    .writes_completed
+                    .saturating_sub(previous.writes_completed),
+                sectors_read: stats.sectors_read.saturating_sub(previous.sectors_read),
+                // Our own writes (e.g. the textfile) shouldn't count as
+                // activity
+                sectors_written: self.own_io.subtract(
+                    disk,
+                    stats
+                        .sectors_written
+                        .saturating_sub(previous.sectors_written),
+                    Instant::now(),
+                ),
+                stats: stats.clone(),
+            });
+        }
+        Ok(events)
+    }
+}
+
+/// Poll diskstats every `interval`, sending the activity to the metrics and
+/// handing it to `on_activity`, e.g. the spindown policy. Errors are counted
+/// and polling goes on until the metrics channel closes.
+pub fn activity_loop(
+    mut poller: DiskstatsPoller,
+    disk_list: impl DiskList,
+    interval: Duration,
+    tx: Sender<MetricMessage>,
+    mut on_activity: impl FnMut(&[ActivityEvent], Instant) -> Result<()>,
+) {
+    loop {
+        let result = disk_list.get_all_disks().and_then(|disks| {
+            let events = poller.poll(&disks)?;
+            for event in &events {
+                tx.send(MetricMessage::Activity(event.clone()))?;
+            }
+            on_activity(&events, Instant::now())
+        });
+        if let Err(err) = result {
+            if err.is::<SendError<MetricMessage>>() {
+                debug!("Metrics channel closed, stopping the activity loop");
+                return;
+            }
+            error!("Error polling disk activity: {:?}", err);
+            if tx.send(MetricMessage::ActivityError).is_err() {
+                return;
+            }
+        }
+        sleep(interval);
+    }
+}
+
+pub fn read_diskstats(diskstats: &Path) -> Result<HashMap<String, DiskStats>> {
+    let content = fs::read_to_string(diskstats)
+        .with_context(|| format!("Failed to read {}", diskstats.to_string_lossy()))?;
+    parse_diskstats(&content)
+}
+
+/// Parse `/proc/diskstats` into stats per device name, see
+/// Documentation/admin-guide/iostats.rst for the fields
+pub fn parse_diskstats(content: &str) -> Result<HashMap<String, DiskStats>> {
+    let mut all_stats = HashMap::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 14 {
+            continue;
+        }
+        let field = |index: usize| -> Result<u64> {
+            fields[index]
+                .parse()
+                .with_context(|| format!("Invalid diskstats field {} of {}", index, fields[2]))
+        };
+        let stats = DiskStats {
+            reads_completed: field(3)?,
+            sectors_read: field(5)?,
+            writes_completed: field(7)?,
+            sectors_written: field(9)?,
+            in_flight: field(11)?,
+            io_time_ms: field(12)?,
+        };
+        all_stats.insert(fields[2].to_string(), stats);
+    }
+    Ok(all_stats)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc::channel, thread};
+
+    use anyhow::anyhow;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::disks::test::FakeDiskList;
+
+    fn write_diskstats(path: &Path, sda_written: u64) {
+        fs::write(
+            path,
+            format!(
+                "   8       0 sda 1000 10 80000 500 200 5 {} 300 0 700 800 0 0 0 0 0 0\n   8       1 sda1 900 10 70000 400 200 5 {} 300 0 600 700 0 0 0 0 0 0\n",
+                sda_written, sda_written
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_diskstats() {
+        let stats = parse_diskstats(
+            "   8       0 sda 1000 10 80000 500 200 5 1600 300 2 700 800 0 0 0 0 0 0
+   7       0 loop0 0 0 0 0 0 0 0 0 0 0 0
+",
+        )
+        .unwrap();
+        assert_eq!(
+            stats["sda"],
+            DiskStats {
+                reads_completed: 1000,
+                sectors_read: 80000,
+                writes_completed: 200,
+                sectors_written: 1600,
+                in_flight: 2,
+                io_time_ms: 700,
+            }
+        );
+        assert!(stats.contains_key("loop0"));
+    }
+
+    #[test]
+    fn test_poll() {
+        let dir = TempDir::new().unwrap();
+        let diskstats = dir.path().join("diskstats");
+        let own_io = OwnIo::new();
+        let mut poller = DiskstatsPoller::new(own_io.clone()).with_diskstats(&diskstats);
+        let disks = vec![String::from("/dev/sda"), String::from("/dev/sdz")];
+
+        write_diskstats(&diskstats, 1000);
+        assert!(poller.poll(&disks).unwrap().is_empty());
+
+        write_diskstats(&diskstats, 1016);
+        let events = poller.poll(&disks).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].disk, "/dev/sda");
+        assert_eq!(events[0].sectors_written, 16);
+        assert!(events[0].is_active());
+
+        // only our own textfile was written
+        own_io.record_disk_write("/dev/sda", 4096);
+        write_diskstats(&diskstats, 1024);
+        let events = poller.poll(&disks).unwrap();
+        assert!(!events[0].is_active());
+        // its journal commit showing up with the next poll
+        write_diskstats(&diskstats, 1040);
+        let events = poller.poll(&disks).unwrap();
+        assert!(!events[0].is_active());
+    }
+
+    #[test]
+    fn test_activity_loop_continues() {
+        let dir = TempDir::new().unwrap();
+        let diskstats = dir.path().join("diskstats");
+        write_diskstats(&diskstats, 1000);
+        let poller = DiskstatsPoller::new(OwnIo::new()).with_diskstats(&diskstats);
+        let disk_list = FakeDiskList {
+            disks: vec![String::from("/dev/sda")],
+        };
+        let (tx, rx) = channel();
+        // e.g. the spindown command failing every time
+        let handle = thread::spawn(move || {
+            activity_loop(poller, disk_list, Duration::ZERO, tx, |_, _| {
+                Err(anyhow!("spindown failed"))
+            })
+        });
+        let errors = rx
+            .iter()
+            .filter(|message| matches!(message, MetricMessage::ActivityError))
+            .take(3)
+            .count();
+        assert_eq!(errors, 3);
+        // stops once nobody receives the metrics anymore
+        drop(rx);
+        handle.join().unwrap();
+    }
+}
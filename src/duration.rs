@@ -0,0 +1,89 @@
+use std::{fmt, time::Duration};
+
+use serde::{de, Deserializer};
+
+/// Seconds given as a plain number like `300` or with units like `5m` or
+/// `1h 30m`
+pub fn parse_seconds(s: &str) -> Result<u64, String> {
+    if let Ok(seconds) = s.parse() {
+        return Ok(seconds);
+    }
+    let duration = parse_duration(s)?;
+    if duration.subsec_nanos() != 0 {
+        return Err(format!("'{}' isn't a whole number of seconds", s));
+    }
+    Ok(duration.as_secs())
+}
+
+/// Like `parse_seconds`, but fractions like `1.5` or `500ms` are fine too
+pub fn parse_seconds_f64(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(seconds),
+        Ok(_) => Err(format!("'{}' isn't a positive number of seconds", s)),
+        Err(_) => Ok(parse_duration(s)?.as_secs_f64()),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s).map_err(|err| {
+        format!(
+            "'{}' isn't a duration like 30s, 5m or 2h ({})",
+            s,
+            err.to_string().to_lowercase()
+        )
+    })
+}
+
+/// For optional config keys in seconds, accepting a number or a string like
+/// `"5m"`
+pub fn deserialize_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_any(SecondsVisitor).map(Some)
+}
+
+struct SecondsVisitor;
+
+impl de::Visitor<'_> for SecondsVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("seconds or a duration like \"5m\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<u64, E> {
+        Ok(seconds)
+    }
+
+    fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<u64, E> {
+        u64::try_from(seconds).map_err(|_| E::invalid_value(de::Unexpected::Signed(seconds), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<u64, E> {
+        parse_seconds(s).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("300"), Ok(300));
+        assert_eq!(parse_seconds("30s"), Ok(30));
+        assert_eq!(parse_seconds("5m"), Ok(300));
+        assert_eq!(parse_seconds("1h 30m"), Ok(5400));
+        assert_eq!(parse_seconds("7days"), Ok(604800));
+        assert!(parse_seconds("1500ms")
+            .unwrap_err()
+            .contains("whole number"));
+        assert!(parse_seconds("5 minutes ago").is_err());
+        assert!(parse_seconds("-5").is_err());
+
+        assert_eq!(parse_seconds_f64("1.5"), Ok(1.5));
+        assert_eq!(parse_seconds_f64("500ms"), Ok(0.5));
+        assert!(parse_seconds_f64("-1").is_err());
+        assert!(parse_seconds_f64("soon").is_err());
+    }
+}
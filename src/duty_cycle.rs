@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::disk_status::PowerState;
+
+/// Recent status query results per disk, to tell how much of a rolling
+/// window a disk spent in standby
+pub struct DutyCycle {
+    window: Duration,
+    samples: HashMap<String, VecDeque<(Instant, PowerState)>>,
+}
+
+impl DutyCycle {
+    pub fn new(window: Duration) -> Self {
+        DutyCycle {
+            window,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, disk: &str, state: PowerState, now: Instant) {
+        let samples = self.samples.entry(disk.to_string()).or_default();
+        samples.push_back((now, state));
+        // Keep the last sample before the window, its state lasted into it
+        while samples
+            .get(1)
+            .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+        {
+            samples.pop_front();
+        }
+    }
+
+    /// Fraction of the window up to `now` the disk spent in standby or
+    /// sleeping. Like `disk_state_seconds_total`, a disk is assumed to have
+    /// stayed in each state until the next query. Time in an unknown state
+    /// doesn't count, without any other the ratio is unknown, too.
+    pub fn standby_ratio(&self, disk: &str, now: Instant) -> Option<f64> {
+        let samples = self.samples.get(disk)?;
+        let start = now.checked_sub(self.window);
+        let mut standby = Duration::ZERO;
+        let mut known = Duration::ZERO;
+        let ends = samples.iter().skip(1).map(|(at, _)| *at).chain([now]);
+        for ((at, state), end) in samples.iter().zip(ends) {
+            let from = start.map_or(*at, |start| start.max(*at));
+            let duration = end.saturating_duration_since(from);
+            match state.is_spinning() {
+                Some(false) => {
+                    standby += duration;
+                    known += duration;
+                }
+                Some(true) => known += duration,
+                None => {}
+            }
+        }
+        (!known.is_zero()).then(|| standby.as_secs_f64() / known.as_secs_f64())
+    }
+
+    pub fn forget(&mut self, disk: &str) {
+        self.samples.remove(disk);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_standby_ratio() {
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut duty_cycle = DutyCycle::new(Duration::from_secs(3600));
+        duty_cycle.record("/dev/sda", PowerState::Active, at(0));
+        assert_eq!(duty_cycle.standby_ratio("/dev/sda", at(0)), None);
+        assert_eq!(duty_cycle.standby_ratio("/dev/sdb", at(0)), None);
+
+        duty_cycle.record("/dev/sda", PowerState::Standby, at(30));
+        duty_cycle.record("/dev/sda", PowerState::Unknown, at(45));
+        duty_cycle.record("/dev/sda", PowerState::Standby, at(60));
+        // 15 of 45 known minutes
+        assert_eq!(
+            duty_cycle.standby_ratio("/dev/sda", at(60)),
+            Some(1.0 / 3.0)
+        );
+
+        // the first half hour of activity slides out of the window
+        duty_cycle.record("/dev/sda", PowerState::Active, at(75));
+        duty_cycle.record("/dev/sda", PowerState::Idle, at(90));
+        assert_eq!(
+            duty_cycle.standby_ratio("/dev/sda", at(90)),
+            Some(2.0 / 3.0)
+        );
+        assert_eq!(duty_cycle.samples["/dev/sda"].len(), 5);
+
+        duty_cycle.forget("/dev/sda");
+        assert_eq!(duty_cycle.standby_ratio("/dev/sda", at(90)), None);
+    }
+}
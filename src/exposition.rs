@@ -0,0 +1,291 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use prometheus::{
+    proto::{LabelPair, MetricFamily, MetricType},
+    Encoder, TextEncoder,
+};
+
+/// Text format metrics are written in
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// Prometheus text format 0.0.4, understood by node_exporter's textfile
+    /// collector
+    #[default]
+    Prometheus,
+    /// OpenMetrics 1.0, ending with `# EOF`
+    OpenMetrics,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Prometheus => prometheus::TEXT_FORMAT,
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+
+    pub fn encode(&self, families: &[MetricFamily]) -> Result<Vec<u8>> {
+        match self {
+            Format::Prometheus => {
+                let mut buffer = Vec::new();
+                TextEncoder::new().encode(families, &mut buffer)?;
+                Ok(buffer)
+            }
+            Format::OpenMetrics => Ok(encode_openmetrics(families)?.into_bytes()),
+        }
+    }
+}
+
+/// One line of the text format, for sinks that have no notion of metric
+/// types
+#[derive(Debug, PartialEq)]
+pub struct Sample<'a> {
+    /// With the suffix, like `_bucket` or `_count` for histograms
+    pub name: String,
+    /// Including `le` of histogram buckets and `quantile` of summaries
+    pub labels: Vec<(&'a str, String)>,
+    pub value: f64,
+}
+
+/// All samples of `family` like the Prometheus text format has them
+pub fn samples(family: &MetricFamily) -> Vec<Sample<'_>> {
+    let name = family.get_name();
+    let mut samples = Vec::new();
+    for metric in family.get_metric() {
+        let mut add = |suffix: &str, extra: Option<(&'static str, String)>, value: f64| {
+            let mut labels: Vec<(&str, String)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value().to_string()))
+                .collect();
+            labels.extend(extra);
+            samples.push(Sample {
+                name: format!("{}{}", name, suffix),
+                labels,
+                value,
+            });
+        };
+        match family.get_field_type() {
+            MetricType::COUNTER => add("", None, metric.get_counter().get_value()),
+            MetricType::GAUGE => add("", None, metric.get_gauge().get_value()),
+            MetricType::UNTYPED => add("", None, metric.get_untyped().get_value()),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                let count = histogram.get_sample_count() as f64;
+                let mut inf_written = false;
+                for bucket in histogram.get_bucket() {
+                    let upper_bound = bucket.get_upper_bound();
+                    inf_written |= upper_bound == f64::INFINITY;
+                    let le = Some(("le", bound(upper_bound)));
+                    add("_bucket", le, bucket.get_cumulative_count() as f64);
+                }
+                if !inf_written {
+                    add("_bucket", Some(("le", bound(f64::INFINITY))), count);
+                }
+                add("_sum", None, histogram.get_sample_sum());
+                add("_count", None, count);
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    let label = Some(("quantile", bound(quantile.get_quantile())));
+                    add("", label, quantile.get_value());
+                }
+                add("_sum", None, summary.get_sample_sum());
+                add("_count", None, summary.get_sample_count() as f64);
+            }
+        }
+    }
+    samples
+}
+
+/// Bucket bounds and quantiles like the text format writes them
+fn bound(value: f64) -> String {
+    match value {
+        f64::INFINITY => String::from("+Inf"),
+        value => value.to_string(),
+    }
+}
+
+fn encode_openmetrics(families: &[MetricFamily]) -> Result<String> {
+    let mut out = String::new();
+    for family in families {
+        let metric_type = family.get_field_type();
+        let name = family.get_name();
+        // Counter samples get the suffix, the family doesn't have it
+        let family_name = match metric_type {
+            MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        writeln!(out, "# TYPE {} {}", family_name, type_name)?;
+        let help = escape(family.get_help(), false);
+        writeln!(out, "# HELP {} {}", family_name, help)?;
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match metric_type {
+                MetricType::COUNTER => {
+                    let total = format!("{}_total", family_name);
+                    let value = metric.get_counter().get_value();
+                    write_sample(&mut out, &total, labels, None, value)?;
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    write_sample(&mut out, name, labels, None, value)?;
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    write_sample(&mut out, name, labels, None, value)?;
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    let mut inf_written = false;
+                    for b in histogram.get_bucket() {
+                        let upper_bound = b.get_upper_bound();
+                        inf_written |= upper_bound == f64::INFINITY;
+                        let le = ("le", float(upper_bound));
+                        let count = b.get_cumulative_count() as f64;
+                        write_sample(&mut out, &bucket, labels, Some(le), count)?;
+                    }
+                    if !inf_written {
+                        let le = ("le", float(f64::INFINITY));
+                        let count = histogram.get_sample_count() as f64;
+                        write_sample(&mut out, &bucket, labels, Some(le), count)?;
+                    }
+                    let sum = format!("{}_sum", name);
+                    write_sample(&mut out, &sum, labels, None, histogram.get_sample_sum())?;
+                    let count = format!("{}_count", name);
+                    let sample_count = histogram.get_sample_count() as f64;
+                    write_sample(&mut out, &count, labels, None, sample_count)?;
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        let quantile = ("quantile", float(q.get_quantile()));
+                        write_sample(&mut out, name, labels, Some(quantile), q.get_value())?;
+                    }
+                    let sum = format!("{}_sum", name);
+                    write_sample(&mut out, &sum, labels, None, summary.get_sample_sum())?;
+                    let count = format!("{}_count", name);
+                    let sample_count = summary.get_sample_count() as f64;
+                    write_sample(&mut out, &count, labels, None, sample_count)?;
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    Ok(out)
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, String)>,
+    value: f64,
+) -> Result<()> {
+    out.push_str(name);
+    let mut pairs: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+        .collect();
+    if let Some((name, value)) = &extra {
+        pairs.push((name, value));
+    }
+    if !pairs.is_empty() {
+        let pairs: Vec<String> = pairs
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+            .collect();
+        write!(out, "{{{}}}", pairs.join(","))?;
+    }
+    writeln!(out, " {}", float(value))?;
+    Ok(())
+}
+
+/// Floats like OpenMetrics wants them, with `.0` for whole numbers
+fn float(value: f64) -> String {
+    if value == f64::INFINITY {
+        String::from("+Inf")
+    } else if value == f64::NEG_INFINITY {
+        String::from("-Inf")
+    } else if value.is_nan() {
+        String::from("NaN")
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(text: &str, quote: bool) -> String {
+    let mut escaped = text.replace('\\', "\\\\").replace('\n', "\\n");
+    if quote {
+        escaped = escaped.replace('"', "\\\"");
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus::{
+        Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_openmetrics() {
+        let registry = Registry::new();
+        let spinups = IntCounter::new("disk_spinups_total", "Spin-ups").unwrap();
+        let events = IntCounterVec::new(Opts::new("notify_events", "Events"), &["path"]).unwrap();
+        let ratio = Gauge::new("disk_standby_ratio", "Ratio\nin standby").unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new("query_duration_seconds", "Duration").buckets(vec![0.5, 1.0]),
+            &["disk"],
+        )
+        .unwrap();
+        registry.register(Box::new(spinups.clone())).unwrap();
+        registry.register(Box::new(events.clone())).unwrap();
+        registry.register(Box::new(ratio.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        spinups.inc_by(3);
+        events.with_label_values(&["/srv/\"media\""]).inc();
+        ratio.set(0.25);
+        duration.with_label_values(&["/dev/sda"]).observe(0.75);
+
+        let encoded = Format::OpenMetrics.encode(&registry.gather()).unwrap();
+        let expected = r#"# TYPE disk_spinups counter
+# HELP disk_spinups Spin-ups
+disk_spinups_total 3.0
+# TYPE disk_standby_ratio gauge
+# HELP disk_standby_ratio Ratio\nin standby
+disk_standby_ratio 0.25
+# TYPE notify_events counter
+# HELP notify_events Events
+notify_events_total{path="/srv/\"media\""} 1.0
+# TYPE query_duration_seconds histogram
+# HELP query_duration_seconds Duration
+query_duration_seconds_bucket{disk="/dev/sda",le="0.5"} 0.0
+query_duration_seconds_bucket{disk="/dev/sda",le="1.0"} 1.0
+query_duration_seconds_bucket{disk="/dev/sda",le="+Inf"} 1.0
+query_duration_seconds_sum{disk="/dev/sda"} 0.75
+query_duration_seconds_count{disk="/dev/sda"} 1.0
+# EOF
+"#;
+        assert_eq!(String::from_utf8(encoded).unwrap(), expected);
+
+        let encoded = Format::Prometheus.encode(&registry.gather()).unwrap();
+        assert!(String::from_utf8(encoded)
+            .unwrap()
+            .contains("\nnotify_events{path=\"/srv/\\\"media\\\"\"} 1\n"));
+    }
+}
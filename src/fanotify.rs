@@ -0,0 +1,441 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+use notify::{
+    event::{AccessKind, AccessMode, DataChange, ModifyKind},
+    EventKind,
+};
+
+use crate::metrics::MetricMessage;
+
+/// Report the same process accessing the same disk at most this often
+const ACCESS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events that can make a disk spin up
+const ACCESS_EVENTS: u64 = libc::FAN_ACCESS | libc::FAN_MODIFY | libc::FAN_OPEN;
+
+/// Events of the watch backend, `ACCESS_EVENTS` and files written to being
+/// closed
+const WATCH_EVENTS: u64 = ACCESS_EVENTS | libc::FAN_CLOSE_WRITE;
+
+/// How long the watch backend waits for events before checking whether it
+/// was dropped
+const WATCH_POLL_TIMEOUT_MS: libc::c_int = 500;
+
+/// A new fanotify group reporting events with an open file descriptor
+fn init() -> Result<OwnedFd> {
+    // SAFETY: the fd is owned by OwnedFd right away
+    unsafe {
+        let fd = libc::fanotify_init(
+            libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC,
+            (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create fanotify group");
+        }
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+/// Report `mask` events for the whole mount `path` is on
+fn mark_mount(fd: &OwnedFd, path: &Path, mask: u64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid NUL terminated string
+    let res = unsafe {
+        libc::fanotify_mark(
+            fd.as_raw_fd(),
+            libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+            mask,
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to watch {}", path.to_string_lossy()));
+    }
+    Ok(())
+}
+
+/// Read events from `fd` into `buffer`
+fn read_events(fd: &OwnedFd, buffer: &mut [u8]) -> Result<Vec<FanotifyEvent>> {
+    // SAFETY: the buffer is valid for its whole length
+    let len = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to read fanotify events");
+    }
+    Ok(parse_events(&buffer[..len as usize]))
+}
+
+/// fanotify group reporting which processes access files on the mounts of
+/// the watched directories. Needs `CAP_SYS_ADMIN`.
+pub struct Fanotify {
+    fd: OwnedFd,
+    /// Disks behind each marked mount, keyed by its device number
+    devices: HashMap<u64, Vec<String>>,
+}
+
+impl Fanotify {
+    /// Watch the mounts of the given paths, each with the disks it's on
+    pub fn new(mounts: &[(PathBuf, Vec<String>)]) -> Result<Self> {
+        let fd = init()?;
+        let mut devices = HashMap::new();
+        for (path, disks) in mounts {
+            mark_mount(&fd, path, ACCESS_EVENTS)?;
+            let dev = fs::metadata(path)
+                .with_context(|| format!("Failed to stat {}", path.to_string_lossy()))?
+                .dev();
+            devices.insert(dev, disks.clone());
+        }
+        Ok(Fanotify { fd, devices })
+    }
+
+    /// Block until events arrive and return the pid and device of each
+    /// accessed file
+    pub fn read(&self, buffer: &mut [u8]) -> Result<Vec<(i32, u64)>> {
+        Ok(read_events(&self.fd, buffer)?
+            .into_iter()
+            .filter_map(|event| {
+                if event.fd == libc::FAN_NOFD {
+                    debug!("fanotify event without a file, mask {:#x}", event.mask);
+                    return None;
+                }
+                // SAFETY: the kernel opened the fd for us, closing it is up
+                // to us
+                let file = unsafe { File::from_raw_fd(event.fd) };
+                Some((event.pid, file.metadata().ok()?.dev()))
+            })
+            .collect())
+    }
+}
+
+/// Watch backend marking the whole mounts of the watched directories, which
+/// takes a single mark per mount instead of an inotify watch per directory.
+/// Only reads, writes and opens are reported, not files being created or
+/// removed. Needs `CAP_SYS_ADMIN`, stops once dropped.
+pub struct FanotifyWatcher {
+    fd: Arc<OwnedFd>,
+    directories: Arc<Mutex<Vec<PathBuf>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FanotifyWatcher {
+    /// Pass the events below the watched directories to `handler` like
+    /// notify's watchers do
+    pub fn new<F>(handler: F) -> Result<Self>
+    where
+        F: FnMut(notify::Result<notify::Event>) + Send + 'static,
+    {
+        let watcher = FanotifyWatcher {
+            fd: Arc::new(init()?),
+            directories: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let fd = watcher.fd.clone();
+        let directories = watcher.directories.clone();
+        let stop = watcher.stop.clone();
+        thread::spawn(move || watch_loop(&fd, &directories, &stop, handler));
+        Ok(watcher)
+    }
+
+    /// Report events below `directory`, which has to be absolute
+    pub fn watch(&mut self, directory: &Path) -> Result<()> {
+        mark_mount(&self.fd, directory, WATCH_EVENTS)?;
+        self.directories
+            .lock()
+            .unwrap()
+            .push(directory.to_path_buf());
+        Ok(())
+    }
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn watch_loop<F>(fd: &OwnedFd, directories: &Mutex<Vec<PathBuf>>, stop: &AtomicBool, mut handler: F)
+where
+    F: FnMut(notify::Result<notify::Event>),
+{
+    let own_pid = std::process::id() as i32;
+    let mut buffer = vec![0; 8192];
+    while !stop.load(Ordering::Relaxed) {
+        let mut pollfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: a single valid pollfd
+        let ready = unsafe { libc::poll(&mut pollfd, 1, WATCH_POLL_TIMEOUT_MS) };
+        if ready == 0
+            || (ready < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted)
+        {
+            continue;
+        }
+        let events = match read_events(fd, &mut buffer) {
+            Ok(events) => events,
+            Err(err) => {
+                error!("Error reading fanotify events, stopping: {:?}", err);
+                // Lets the directories be watched again
+                handler(Err(notify::Error::generic(&format!("{:#}", err))));
+                return;
+            }
+        };
+        for event in events {
+            if event.fd == libc::FAN_NOFD {
+                if event.mask & libc::FAN_Q_OVERFLOW != 0 {
+                    handler(Err(notify::Error::generic(
+                        "fanotify queue overflowed, events were lost",
+                    )));
+                }
+                continue;
+            }
+            // SAFETY: the kernel opened the fd for us, closing it is up to us
+            let file = unsafe { File::from_raw_fd(event.fd) };
+            if event.pid == own_pid {
+                continue;
+            }
+            let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) else {
+                continue;
+            };
+            // The mark covers the whole mount
+            let watched = directories
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|directory| path.starts_with(directory));
+            if watched {
+                let mut notify_event = notify::Event::new(event_kind(event.mask))
+                    .add_path(path)
+                    .set_process_id(event.pid as u32);
+                if let Some(comm) = comm(event.pid) {
+                    notify_event = notify_event.set_info(&comm);
+                }
+                handler(Ok(notify_event));
+            }
+        }
+    }
+}
+
+/// The notify kind of an event with `mask`. The kernel merges events of the
+/// same file, the one that says most wins.
+fn event_kind(mask: u64) -> EventKind {
+    if mask & libc::FAN_MODIFY != 0 {
+        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+    } else if mask & libc::FAN_CLOSE_WRITE != 0 {
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+    } else if mask & libc::FAN_OPEN != 0 {
+        EventKind::Access(AccessKind::Open(AccessMode::Any))
+    } else if mask & libc::FAN_ACCESS != 0 {
+        EventKind::Access(AccessKind::Read)
+    } else {
+        EventKind::Other
+    }
+}
+
+/// An event read from a fanotify group
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FanotifyEvent {
+    /// Open file descriptor of the file, to be closed by the reader
+    pub fd: i32,
+    pub pid: i32,
+    pub mask: u64,
+}
+
+/// Split a buffer read from a fanotify group into its events. Events
+/// without a file, like queue overflows, have `FAN_NOFD` as their `fd`.
+pub fn parse_events(buffer: &[u8]) -> Vec<FanotifyEvent> {
+    let header_len = mem::size_of::<libc::fanotify_event_metadata>();
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + header_len <= buffer.len() {
+        // SAFETY: there are enough bytes left for the header, which might
+        // not be aligned within the buffer
+        let metadata: libc::fanotify_event_metadata = unsafe {
+            std::ptr::read_unaligned(
+                buffer[offset..].as_ptr() as *const libc::fanotify_event_metadata
+            )
+        };
+        if metadata.vers != libc::FANOTIFY_METADATA_VERSION || metadata.event_len == 0 {
+            break;
+        }
+        events.push(FanotifyEvent {
+            fd: metadata.fd,
+            pid: metadata.pid,
+            mask: metadata.mask,
+        });
+        offset += metadata.event_len as usize;
+    }
+    events
+}
+
+/// The name of process `pid`, unless it's already gone
+fn comm(pid: i32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|comm| comm.trim_end().to_string())
+}
+
+/// Forward which processes access the watched disks to `tx` until the
+/// receiving side goes away
+pub fn fanotify_loop(fanotify: Fanotify, tx: Sender<MetricMessage>) {
+    let own_pid = std::process::id() as i32;
+    let mut reported: HashMap<(String, String), Instant> = HashMap::new();
+    let mut buffer = vec![0; 8192];
+    loop {
+        let accesses = match fanotify.read(&mut buffer) {
+            Ok(accesses) => accesses,
+            Err(err) => {
+                error!("Error reading fanotify events, stopping: {:?}", err);
+                return;
+            }
+        };
+        let now = Instant::now();
+        for (pid, dev) in accesses {
+            if pid == own_pid {
+                continue;
+            }
+            let Some(disks) = fanotify.devices.get(&dev) else {
+                continue;
+            };
+            let comm = comm(pid).unwrap_or_else(|| String::from("unknown"));
+            for disk in disks {
+                let key = (disk.clone(), comm.clone());
+                if let Some(at) = reported.get(&key) {
+                    if now.duration_since(*at) < ACCESS_REPORT_INTERVAL {
+                        continue;
+                    }
+                }
+                debug!("{} accessed {}", comm, disk);
+                reported.insert(key, now);
+                let access = MetricMessage::ProcessAccess {
+                    disk: disk.clone(),
+                    comm: comm.clone(),
+                };
+                if tx.send(access).is_err() {
+                    return;
+                }
+            }
+        }
+        reported.retain(|_, at| now.duration_since(*at) < ACCESS_REPORT_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, os::unix::net::UnixStream, sync::mpsc::channel};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn event(fd: i32, pid: i32, mask: u64) -> Vec<u8> {
+        let metadata = libc::fanotify_event_metadata {
+            event_len: mem::size_of::<libc::fanotify_event_metadata>() as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: mem::size_of::<libc::fanotify_event_metadata>() as u16,
+            mask,
+            fd,
+            pid,
+        };
+        // SAFETY: plain old data
+        unsafe {
+            std::slice::from_raw_parts(
+                &metadata as *const _ as *const u8,
+                mem::size_of::<libc::fanotify_event_metadata>(),
+            )
+        }
+        .to_vec()
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let buffer = [
+            event(5, 100, libc::FAN_OPEN),
+            event(libc::FAN_NOFD, 0, libc::FAN_Q_OVERFLOW),
+            event(6, 200, libc::FAN_MODIFY | libc::FAN_CLOSE_WRITE),
+        ]
+        .concat();
+        let events = parse_events(&buffer);
+        assert_eq!(
+            events.iter().map(|e| (e.fd, e.pid)).collect::<Vec<_>>(),
+            vec![(5, 100), (libc::FAN_NOFD, 0), (6, 200)]
+        );
+        assert_eq!(events[1].mask, libc::FAN_Q_OVERFLOW);
+        // truncated event
+        assert_eq!(parse_events(&buffer[..10]), vec![]);
+
+        assert_eq!(
+            event_kind(events[2].mask),
+            EventKind::Modify(ModifyKind::Data(DataChange::Any))
+        );
+        assert_eq!(
+            event_kind(libc::FAN_OPEN | libc::FAN_ACCESS),
+            EventKind::Access(AccessKind::Open(AccessMode::Any))
+        );
+        assert_eq!(
+            event_kind(libc::FAN_ACCESS),
+            EventKind::Access(AccessKind::Read)
+        );
+    }
+
+    #[test]
+    fn test_watch_loop_overflow() {
+        let (mut kernel, fd) = UnixStream::pair().unwrap();
+        kernel
+            .write_all(&event(libc::FAN_NOFD, 0, libc::FAN_Q_OVERFLOW))
+            .unwrap();
+        let (tx, rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            watch_loop(&OwnedFd::from(fd), &Mutex::default(), &stopped, |res| {
+                tx.send(res).unwrap()
+            })
+        });
+        let err = rx.recv().unwrap().unwrap_err();
+        assert!(err.to_string().contains("overflowed"), "{}", err);
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_watch_loop_read_error() {
+        // reading a directory fails with EISDIR
+        let dir = TempDir::new().unwrap();
+        let fd = OwnedFd::from(File::open(dir.path()).unwrap());
+        let mut results = Vec::new();
+        watch_loop(&fd, &Mutex::default(), &AtomicBool::new(false), |res| {
+            results.push(res)
+        });
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+}
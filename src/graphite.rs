@@ -0,0 +1,138 @@
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use prometheus::{proto::MetricFamily, Registry};
+
+use crate::{exposition::samples, net, sink::ExportSink};
+
+/// Sends metrics to Graphite's Carbon in the plaintext protocol, with labels
+/// as tags like `disk_spin_manager.disk_status;disk=/dev/sda 1 1700000000`
+pub struct GraphiteSink {
+    addr: String,
+    prefix: String,
+    interval: Duration,
+    last_sent: Mutex<Option<Instant>>,
+}
+
+impl GraphiteSink {
+    /// Send to Carbon at `addr` like `graphite:2003`
+    pub fn new(addr: &str) -> Self {
+        GraphiteSink {
+            addr: addr.to_string(),
+            prefix: String::from("disk_spin_manager"),
+            interval: Duration::ZERO,
+            last_sent: Mutex::new(None),
+        }
+    }
+
+    /// Put in front of every metric name, separated by a dot. Empty for
+    /// none.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('.').to_string();
+        self
+    }
+
+    /// Send at most once per `interval` instead of on every flush, to match
+    /// the retention of the Carbon schema
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn send(&self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let lines = encode_plaintext(families, &self.prefix, timestamp);
+        let mut stream =
+            net::connect(&self.addr, Duration::from_secs(10)).context("Carbon unavailable")?;
+        stream
+            .write_all(lines.as_bytes())
+            .with_context(|| format!("Failed to send metrics to Carbon at {}", self.addr))
+    }
+}
+
+impl ExportSink for GraphiteSink {
+    fn name(&self) -> &str {
+        "graphite"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if last_sent.is_some_and(|at| at.elapsed() < self.interval) {
+            return Ok(());
+        }
+        *last_sent = Some(Instant::now());
+        self.send(&registry.gather())
+    }
+}
+
+/// One line per sample of `families` at `timestamp` in seconds. Graphite
+/// can't store samples that aren't finite, they're left out.
+pub fn encode_plaintext(families: &[MetricFamily], prefix: &str, timestamp: u64) -> String {
+    let mut lines = String::new();
+    for sample in families.iter().flat_map(samples) {
+        if !sample.value.is_finite() {
+            continue;
+        }
+        if !prefix.is_empty() {
+            lines.push_str(prefix);
+            lines.push('.');
+        }
+        lines.push_str(&sample.name);
+        lines.push_str(&tags(&sample.labels));
+        lines.push_str(&format!(" {} {}\n", sample.value, timestamp));
+    }
+    lines
+}
+
+/// Graphite doesn't allow empty tag values or `;`, `~` and spaces in them
+fn tags(labels: &[(&str, String)]) -> String {
+    labels
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| format!(";{}={}", name, value.replace([';', '~', ' '], "_")))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus::{GaugeVec, Histogram, HistogramOpts, IntCounter, Opts};
+
+    use super::*;
+
+    #[test]
+    fn test_plaintext() {
+        let registry = Registry::new();
+        let spinups = IntCounter::new("disk_spinups_total", "Spin-ups").unwrap();
+        let status = GaugeVec::new(Opts::new("disk_status", "Status"), &["disk", "name"]).unwrap();
+        let duration = Histogram::with_opts(
+            HistogramOpts::new("query_duration_seconds", "Duration").buckets(vec![0.5]),
+        )
+        .unwrap();
+        registry.register(Box::new(spinups.clone())).unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        spinups.inc_by(3);
+        status.with_label_values(&["/dev/sda", "media 1"]).set(1.0);
+        status.with_label_values(&["/dev/sdb", ""]).set(f64::NAN);
+        duration.observe(0.25);
+
+        let lines = encode_plaintext(&registry.gather(), "nas", 1700000000);
+        assert_eq!(
+            lines,
+            "nas.disk_spinups_total 3 1700000000
+nas.disk_status;disk=/dev/sda;name=media_1 1 1700000000
+nas.query_duration_seconds_bucket;le=0.5 1 1700000000
+nas.query_duration_seconds_bucket;le=+Inf 1 1700000000
+nas.query_duration_seconds_sum 0.25 1700000000
+nas.query_duration_seconds_count 1 1700000000
+"
+        );
+    }
+}
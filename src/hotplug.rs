@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::mpsc::Sender,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+
+/// Multicast group the kernel sends uevents to (udev re-broadcasts on 2)
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum DiskEvent {
+    Added(String),
+    Removed(String),
+}
+
+/// Netlink socket receiving kernel uevents
+pub struct UeventSocket {
+    fd: OwnedFd,
+}
+
+impl UeventSocket {
+    pub fn new() -> Result<Self> {
+        // SAFETY: plain socket syscalls, the fd is owned by OwnedFd right away
+        // and the address struct is fully initialized
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).context("Failed to create netlink socket");
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            let mut addr: libc::sockaddr_nl = mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = KERNEL_UEVENT_GROUP;
+            let res = libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            );
+            if res < 0 {
+                return Err(io::Error::last_os_error()).context("Failed to bind netlink socket");
+            }
+            Ok(UeventSocket { fd })
+        }
+    }
+
+    /// Block until the next uevent arrives and return its raw payload
+    pub fn recv(&self, buffer: &mut [u8]) -> Result<usize> {
+        // SAFETY: the buffer is valid for its whole length
+        let len = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to receive uevent");
+        }
+        Ok(len as usize)
+    }
+}
+
+/// Forward disk add/remove events to `tx` until the receiving side goes away
+pub fn hotplug_loop(socket: UeventSocket, tx: Sender<DiskEvent>) {
+    let mut buffer = vec![0; 8192];
+    loop {
+        let len = match socket.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(err) => {
+                error!("Error receiving hotplug events, stopping: {:?}", err);
+                return;
+            }
+        };
+        let Some(event) = parse_uevent(&buffer[..len]) else {
+            continue;
+        };
+        debug!("Hotplug event: {:?}", event);
+        if tx.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+/// Parse a kernel uevent, i.e. a header like `add@/devices/...` followed by
+/// NUL separated `KEY=VALUE` pairs. Only events for whole disks are returned.
+pub fn parse_uevent(payload: &[u8]) -> Option<DiskEvent> {
+    let properties: HashMap<&str, &str> = payload
+        .split(|b| *b == 0)
+        .filter_map(|field| std::str::from_utf8(field).ok())
+        .filter_map(|field| field.split_once('='))
+        .collect();
+    if properties.get("SUBSYSTEM") != Some(&"block") || properties.get("DEVTYPE") != Some(&"disk") {
+        return None;
+    }
+    let disk = format!("/dev/{}", properties.get("DEVNAME")?);
+    match *properties.get("ACTION")? {
+        "add" => Some(DiskEvent::Added(disk)),
+        "remove" => Some(DiskEvent::Removed(disk)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uevent() {
+        let add = b"add@/devices/pci0000:00/0000:00:17.0/ata3/host2/target2:0:0/2:0:0:0/block/sdb\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:17.0/ata3/host2/target2:0:0/2:0:0:0/block/sdb\0SUBSYSTEM=block\0MAJOR=8\0MINOR=16\0DEVNAME=sdb\0DEVTYPE=disk\0DISKSEQ=12\0SEQNUM=4242\0";
+        assert_eq!(
+            parse_uevent(add),
+            Some(DiskEvent::Added(String::from("/dev/sdb")))
+        );
+
+        let remove = b"remove@/devices/virtual/block/sdb\0ACTION=remove\0SUBSYSTEM=block\0DEVNAME=sdb\0DEVTYPE=disk\0";
+        assert_eq!(
+            parse_uevent(remove),
+            Some(DiskEvent::Removed(String::from("/dev/sdb")))
+        );
+
+        let partition =
+            b"add@/block/sdb/sdb1\0ACTION=add\0SUBSYSTEM=block\0DEVNAME=sdb1\0DEVTYPE=partition\0";
+        assert_eq!(parse_uevent(partition), None);
+
+        let change =
+            b"change@/block/sdb\0ACTION=change\0SUBSYSTEM=block\0DEVNAME=sdb\0DEVTYPE=disk\0";
+        assert_eq!(parse_uevent(change), None);
+
+        let usb = b"add@/devices/usb1\0ACTION=add\0SUBSYSTEM=usb\0DEVTYPE=usb_device\0";
+        assert_eq!(parse_uevent(usb), None);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fs, io, net::SocketAddr, os::unix::fs::FileTypeExt, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error};
+use prometheus::Registry;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::exposition::Format;
+
+/// Serves the metrics on `/metrics`, for setups without node_exporter's
+/// textfile collector
+pub struct MetricsServer {
+    server: Server,
+    format: Format,
+}
+
+impl MetricsServer {
+    /// Listen on an address like `0.0.0.0:9144`
+    pub fn bind(addr: &str) -> Result<Self> {
+        let server = Server::http(addr)
+            .map_err(|err| anyhow!(err))
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        Ok(MetricsServer {
+            server,
+            format: Format::default(),
+        })
+    }
+
+    /// Listen on a unix socket at `path`, for local scrapers like
+    /// `curl --unix-socket`. A socket left behind by an earlier run is
+    /// replaced, anything else at `path` is an error.
+    pub fn bind_unix(path: &Path) -> Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.to_string_lossy()))?,
+            Ok(_) => bail!("{} exists and isn't a socket", path.to_string_lossy()),
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err)
+                    .with_context(|| format!("Failed to check {}", path.to_string_lossy()));
+            }
+            Err(_) => {}
+        }
+        let server = Server::http_unix(path)
+            .map_err(|err| anyhow!(err))
+            .with_context(|| format!("Failed to listen on {}", path.to_string_lossy()))?;
+        Ok(MetricsServer {
+            server,
+            format: Format::default(),
+        })
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answer requests with the current state of `registry`, forever
+    pub fn serve(&self, registry: &Registry) {
+        for request in self.server.incoming_requests() {
+            debug!("{} {}", request.method(), request.url());
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = match (request.method(), path) {
+                (Method::Get, "/metrics") => match encode(registry, self.format) {
+                    Ok((buffer, content_type)) => {
+                        Response::from_data(buffer).with_header(content_type)
+                    }
+                    Err(err) => {
+                        error!("Failed to encode metrics: {:?}", err);
+                        Response::from_string("Failed to encode metrics").with_status_code(500)
+                    }
+                },
+                _ => Response::from_string("Metrics are at /metrics").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                debug!("Failed to respond: {:?}", err);
+            }
+        }
+    }
+}
+
+fn encode(registry: &Registry, format: Format) -> Result<(Vec<u8>, Header)> {
+    let buffer = format.encode(&registry.gather())?;
+    let content_type = Header::from_bytes("Content-Type", format.content_type())
+        .map_err(|()| anyhow!("Invalid content type {}", format.content_type()))?;
+    Ok((buffer, content_type))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        os::unix::net::{UnixListener, UnixStream},
+        sync::Arc,
+        thread,
+    };
+
+    use prometheus::IntCounter;
+
+    use super::*;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_total", "Test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+
+        let server = Arc::new(MetricsServer::bind("127.0.0.1:0").unwrap());
+        let addr = server.local_addr().unwrap();
+        let serving = server.clone();
+        let serving_registry = registry.clone();
+        thread::spawn(move || serving.serve(&serving_registry));
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.ends_with("\ntest_total 1\n"));
+
+        // always the current values
+        counter.inc();
+        assert!(get(addr, "/metrics?x=1").ends_with("\ntest_total 2\n"));
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+        server.server.unblock();
+    }
+
+    #[test]
+    fn test_serve_unix() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("metrics.sock");
+        fs::write(&path, "not a socket").unwrap();
+        assert!(MetricsServer::bind_unix(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+
+        // left behind by an earlier run
+        fs::remove_file(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        let registry = Registry::new();
+        let server = Arc::new(MetricsServer::bind_unix(&path).unwrap());
+        let serving = server.clone();
+        thread::spawn(move || serving.serve(&registry));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        server.server.unblock();
+    }
+
+    #[test]
+    fn test_serve_openmetrics() {
+        let registry = Registry::new();
+        let server = MetricsServer::bind("127.0.0.1:0")
+            .unwrap()
+            .with_format(Format::OpenMetrics);
+        let server = Arc::new(server);
+        let addr = server.local_addr().unwrap();
+        let serving = server.clone();
+        thread::spawn(move || serving.serve(&registry));
+
+        let response = get(addr, "/metrics");
+        assert!(response.contains("Content-Type: application/openmetrics-text; version=1.0.0"));
+        assert!(response.ends_with("\r\n\r\n# EOF\n"));
+        server.server.unblock();
+    }
+}
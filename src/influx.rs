@@ -0,0 +1,225 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+use prometheus::{
+    proto::{LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+
+use crate::{own_io::OwnIo, sink::ExportSink};
+
+/// Where line protocol is written to
+enum Target {
+    /// Replaced on every write, like the textfile
+    File(PathBuf),
+    /// Write endpoint like `http://influxdb:8086/api/v2/write?org=home&bucket=disks`
+    Http {
+        agent: ureq::Agent,
+        url: String,
+        token: Option<String>,
+    },
+}
+
+/// Writes metrics in InfluxDB line protocol, shaped like Telegraf's
+/// prometheus input does: one measurement per metric with the labels as tags
+/// and `counter`, `gauge` or `value` as the field. Histograms get `count`,
+/// `sum` and a field per bucket bound.
+pub struct InfluxWriter {
+    target: Target,
+    own_io: Option<OwnIo>,
+}
+
+impl InfluxWriter {
+    pub fn file(path: PathBuf) -> Self {
+        InfluxWriter {
+            target: Target::File(path),
+            own_io: None,
+        }
+    }
+
+    /// Post to the write endpoint `url`, authenticated with an InfluxDB 2
+    /// `token` if there is one
+    pub fn http(url: &str, token: Option<String>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        InfluxWriter {
+            target: Target::Http {
+                agent,
+                url: url.to_string(),
+                token,
+            },
+            own_io: None,
+        }
+    }
+
+    /// Record the writes to the file, so they aren't mistaken for disk
+    /// activity
+    pub fn with_own_io(mut self, own_io: OwnIo) -> Self {
+        self.own_io = Some(own_io);
+        self
+    }
+
+    pub fn write(&self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let lines = encode_line_protocol(families, timestamp);
+        match &self.target {
+            Target::File(path) => {
+                fs::write(path, &lines).with_context(|| {
+                    format!(
+                        "Failed to write line protocol to {}",
+                        path.to_string_lossy()
+                    )
+                })?;
+                if let Some(own_io) = &self.own_io {
+                    if let Err(err) = own_io.record_write(path, lines.len() as u64) {
+                        debug!("Failed to record line protocol write: {:?}", err);
+                    }
+                }
+            }
+            Target::Http { agent, url, token } => {
+                let mut request = agent
+                    .post(url)
+                    .set("Content-Type", "text/plain; charset=utf-8");
+                if let Some(token) = token {
+                    request = request.set("Authorization", &format!("Token {}", token));
+                }
+                request
+                    .send_string(&lines)
+                    .with_context(|| format!("Failed to write line protocol to {}", url))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ExportSink for InfluxWriter {
+    fn name(&self) -> &str {
+        "influx"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        self.write(&registry.gather())
+    }
+}
+
+/// All samples of `families` at `timestamp` in nanoseconds. Samples that
+/// aren't finite are left out, InfluxDB doesn't take them.
+pub fn encode_line_protocol(families: &[MetricFamily], timestamp: u128) -> String {
+    let mut lines = String::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let fields: Vec<(String, f64)> = match family.get_field_type() {
+                MetricType::COUNTER => vec![("counter".into(), metric.get_counter().get_value())],
+                MetricType::GAUGE => vec![("gauge".into(), metric.get_gauge().get_value())],
+                MetricType::UNTYPED => vec![("value".into(), metric.get_untyped().get_value())],
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut fields = vec![
+                        ("count".into(), histogram.get_sample_count() as f64),
+                        ("sum".into(), histogram.get_sample_sum()),
+                    ];
+                    for bucket in histogram.get_bucket() {
+                        let count = bucket.get_cumulative_count() as f64;
+                        fields.push((bucket.get_upper_bound().to_string(), count));
+                    }
+                    fields
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    let mut fields = vec![
+                        ("count".into(), summary.get_sample_count() as f64),
+                        ("sum".into(), summary.get_sample_sum()),
+                    ];
+                    for quantile in summary.get_quantile() {
+                        fields.push((quantile.get_quantile().to_string(), quantile.get_value()));
+                    }
+                    fields
+                }
+            };
+            let fields: Vec<String> = fields
+                .into_iter()
+                .filter(|(_, value)| value.is_finite())
+                .map(|(key, value)| format!("{}={}", escape(&key), value))
+                .collect();
+            if fields.is_empty() {
+                continue;
+            }
+            lines.push_str(&escape(family.get_name()));
+            lines.push_str(&tags(metric.get_label()));
+            lines.push_str(&format!(" {} {}\n", fields.join(","), timestamp));
+        }
+    }
+    lines
+}
+
+fn tags(labels: &[LabelPair]) -> String {
+    labels
+        .iter()
+        // Empty tag values aren't allowed
+        .filter(|label| !label.get_value().is_empty())
+        .map(|label| {
+            format!(
+                ",{}={}",
+                escape(label.get_name()),
+                escape(label.get_value())
+            )
+        })
+        .collect()
+}
+
+/// Escape commas, equal signs and spaces in measurements, tags and field
+/// keys
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounter, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn test_line_protocol() {
+        let registry = Registry::new();
+        let spinups = IntCounter::new("disk_spinups_total", "Spin-ups").unwrap();
+        let status = GaugeVec::new(Opts::new("disk_status", "Status"), &["disk", "name"]).unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new("query_duration_seconds", "Duration").buckets(vec![0.5, 1.0]),
+            &["disk"],
+        )
+        .unwrap();
+        registry.register(Box::new(spinups.clone())).unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        spinups.inc_by(3);
+        status.with_label_values(&["/dev/sda", "media 1"]).set(1.0);
+        status.with_label_values(&["/dev/sdb", ""]).set(f64::NAN);
+        duration.with_label_values(&["/dev/sda"]).observe(0.75);
+
+        let lines = encode_line_protocol(&registry.gather(), 1700000000000000000);
+        assert_eq!(
+            lines,
+            "disk_spinups_total counter=3 1700000000000000000
+disk_status,disk=/dev/sda,name=media\\ 1 gauge=1 1700000000000000000
+query_duration_seconds,disk=/dev/sda count=1,sum=0.75,0.5=0,1=1 1700000000000000000
+"
+        );
+    }
+}
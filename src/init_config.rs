@@ -0,0 +1,157 @@
+use std::{collections::BTreeSet, fmt::Write, path::PathBuf};
+
+/// A disk that gets its own section in the starter config
+#[derive(Clone, Debug, PartialEq)]
+pub struct StarterDisk {
+    /// Stable path like `/dev/disk/by-id/ata-WDC_...`, or the kernel name
+    /// if there is none
+    pub path: String,
+    /// Kernel name like `/dev/sda`
+    pub device: String,
+    /// Where filesystems on the disk are mounted
+    pub mount_points: Vec<PathBuf>,
+}
+
+const HEADER: &str = "\
+# Config file for disk_spin_manager, pass it with --config. Sending SIGHUP
+# to the daemon reloads it. Everything commented out is optional.
+
+# Directories to watch for access in addition to --watch-directories
+";
+
+const RULES: &str = "
+# Rules are checked in order before the default policy. The first rule with a
+# spindown or keep-awake action decides.
+#
+# [[rules]]
+# name = \"nightly\"
+# names = [\"*\"]
+# during = \"22:00-07:00\"
+# idle_for = 600
+# action = \"spindown\"
+";
+
+/// A commented config file with a `[disks]` section for each of `disks`,
+/// named after where they are mounted
+pub fn starter_config(disks: &[StarterDisk]) -> String {
+    let mut config = String::from(HEADER);
+    let mount_points: BTreeSet<String> = disks
+        .iter()
+        .flat_map(|disk| &disk.mount_points)
+        .map(|target| quote(&target.to_string_lossy()))
+        .collect();
+    if mount_points.is_empty() {
+        config.push_str("# watch_directories = []\n");
+    } else {
+        let mount_points: Vec<String> = mount_points.into_iter().collect();
+        writeln!(
+            config,
+            "# watch_directories = [{}]",
+            mount_points.join(", ")
+        )
+        .unwrap();
+    }
+
+    let mut names = BTreeSet::new();
+    for disk in disks {
+        let name = unique_name(&mut names, &base_name(disk));
+        writeln!(config, "\n[disks.{}]", quote(&disk.path)).unwrap();
+        if disk.path != disk.device {
+            writeln!(config, "# {}", disk.device).unwrap();
+        }
+        for target in &disk.mount_points {
+            writeln!(config, "# Mounted on {}", target.to_string_lossy()).unwrap();
+        }
+        writeln!(config, "name = {}", quote(&name)).unwrap();
+        config.push_str(
+            "# spindown_after = 1800
+# keep_awake = \"02:00-05:00\"
+# never_spindown = false
+",
+        );
+    }
+    config.push_str(RULES);
+    config
+}
+
+/// The last part of the first mount point, the kernel name without one
+fn base_name(disk: &StarterDisk) -> String {
+    disk.mount_points
+        .iter()
+        .find_map(|target| target.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| disk.device.trim_start_matches("/dev/").to_string())
+}
+
+/// `name`, or with a number appended if another disk has it already, e.g.
+/// two disks of the same RAID
+fn unique_name(names: &mut BTreeSet<String>, name: &str) -> String {
+    let mut unique = name.to_string();
+    let mut number = 1;
+    while !names.insert(unique.clone()) {
+        number += 1;
+        unique = format!("{}-{}", name, number);
+    }
+    unique
+}
+
+/// A TOML basic string
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_starter_config() {
+        let disks = [
+            StarterDisk {
+                path: String::from("/dev/disk/by-id/ata-WDC_1"),
+                device: String::from("/dev/sda"),
+                mount_points: vec![PathBuf::from("/srv/media")],
+            },
+            StarterDisk {
+                path: String::from("/dev/disk/by-id/ata-WDC_2"),
+                device: String::from("/dev/sdb"),
+                mount_points: vec![PathBuf::from("/srv/media")],
+            },
+            StarterDisk {
+                path: String::from("/dev/sdd"),
+                device: String::from("/dev/sdd"),
+                mount_points: vec![PathBuf::from("/srv/backup")],
+            },
+            StarterDisk {
+                path: String::from("/dev/sdc"),
+                device: String::from("/dev/sdc"),
+                mount_points: vec![],
+            },
+        ];
+        let starter = starter_config(&disks);
+        assert!(starter.contains("# watch_directories = [\"/srv/backup\", \"/srv/media\"]"));
+        assert!(starter.contains(
+            "[disks.\"/dev/disk/by-id/ata-WDC_1\"]\n# /dev/sda\n# Mounted on /srv/media\nname = \"media\"\n"
+        ));
+        assert!(starter.contains("[disks.\"/dev/sdc\"]\nname = \"sdc\"\n"));
+
+        let config = Config::parse(&starter).unwrap();
+        assert_eq!(
+            config.aliases().into_values().collect::<Vec<_>>(),
+            ["media", "media-2", "sdc", "backup"]
+        );
+        assert!(config.rules.is_empty());
+        let uncommented = ["spindown_after", "keep_awake", "never_spindown"]
+            .iter()
+            .fold(starter.clone(), |starter, key| {
+                starter.replace(&format!("# {}", key), key)
+            });
+        assert_eq!(
+            Config::parse(&uncommented).unwrap().disks["/dev/sdc"].spindown_after,
+            Some(1800)
+        );
+        assert!(Config::parse(&starter_config(&[])).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::disk_status::PowerState;
+
+/// What the JSON status document says about a disk
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DiskSummary {
+    /// Same labels as in the metrics, like `disk` and `name`
+    pub labels: BTreeMap<String, String>,
+    /// Result of the latest status query
+    pub state: Option<&'static str>,
+    pub spinning: Option<bool>,
+    /// Unix timestamp of the latest status query
+    pub last_update: Option<f64>,
+    /// Unix timestamp of the last time the disk changed its state
+    pub last_change: Option<f64>,
+    pub temperature_celsius: Option<f64>,
+    /// Failed status queries by kind (timeout or error)
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+impl DiskSummary {
+    pub fn set_state(&mut self, state: PowerState) {
+        self.state = Some(state.as_str());
+        self.spinning = state.is_spinning();
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Document<'a> {
+    /// Unix timestamp the document was written at
+    updated: f64,
+    /// Keyed by kernel name of the disk
+    disks: &'a BTreeMap<String, DiskSummary>,
+}
+
+/// Write `disks` as a JSON document to `path`, for tools that don't speak
+/// Prometheus
+pub fn write_json_status(
+    path: &Path,
+    updated: f64,
+    disks: &BTreeMap<String, DiskSummary>,
+) -> Result<()> {
+    let document = serde_json::to_vec_pretty(&Document { updated, disks })?;
+    fs::write(path, document)
+        .with_context(|| format!("Failed to write JSON status {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_write_json_status() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("status.json");
+        let mut summary = DiskSummary {
+            labels: BTreeMap::from([(String::from("disk"), String::from("/dev/sda"))]),
+            ..Default::default()
+        };
+        summary.set_state(PowerState::Standby);
+        summary.errors.insert("timeout", 2);
+        let disks = BTreeMap::from([(String::from("/dev/sda"), summary)]);
+        write_json_status(&path, 1700000000.0, &disks).unwrap();
+
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "updated": 1700000000.0,
+                "disks": {
+                    "/dev/sda": {
+                        "labels": {"disk": "/dev/sda"},
+                        "state": "standby",
+                        "spinning": false,
+                        "last_update": null,
+                        "last_change": null,
+                        "temperature_celsius": null,
+                        "errors": {"timeout": 2},
+                    }
+                }
+            })
+        );
+    }
+}
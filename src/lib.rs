@@ -1,5 +1,45 @@
+pub mod audit;
+pub mod check;
 pub mod cli;
+pub mod command;
+pub mod config;
 pub mod disk_status;
-pub mod lsblk;
+pub mod disks;
+pub mod diskstats;
+pub mod duration;
+pub mod duty_cycle;
+pub mod exposition;
+pub mod fanotify;
+pub mod graphite;
+pub mod hotplug;
+pub mod http;
+pub mod influx;
+pub mod init_config;
+pub mod json_status;
+pub mod load_cycles;
+pub mod logging;
 pub mod metrics;
+pub mod mounts;
+pub mod mqtt;
+pub mod net;
+pub mod otlp;
+pub mod own_io;
+pub mod permissions;
+pub mod policy;
+pub mod power;
+pub mod push;
+pub mod remote_write;
+pub mod schedule;
+pub mod signals;
+pub mod sink;
+pub mod smart;
+pub mod smartctl;
+pub mod spindown;
+pub mod stagger;
+pub mod status;
+pub mod systemd;
+pub mod textfile;
+pub mod udisks2;
+pub mod wake_cause;
 pub mod watch;
+pub mod zabbix;
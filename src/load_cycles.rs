@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+/// SMART attribute counting how often the heads were unloaded
+pub const LOAD_CYCLE_COUNT: u8 = 193;
+
+/// Period over which the increase of the load cycle count is checked
+const LOAD_CYCLE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Load cycle counts seen within the last 24 hours, to tell when a disk
+/// parks its heads too often
+#[derive(Default)]
+pub struct LoadCycles {
+    /// Warn if the count increased by more than this within 24 hours
+    warn_per_day: Option<u64>,
+    samples: HashMap<String, VecDeque<(Instant, u64)>>,
+    /// Disks that were already warned about
+    excessive: HashSet<String>,
+}
+
+impl LoadCycles {
+    pub fn new(warn_per_day: Option<u64>) -> Self {
+        LoadCycles {
+            warn_per_day,
+            ..Default::default()
+        }
+    }
+
+    /// Record the current count of `disk`, returning how much it increased
+    /// within the last 24 hours and whether that's too much
+    pub fn record(&mut self, disk: &str, count: u64, now: Instant) -> (u64, bool) {
+        let samples = self.samples.entry(disk.to_string()).or_default();
+        samples.push_back((now, count));
+        while let Some(&(at, _)) = samples.front() {
+            if now.duration_since(at) < LOAD_CYCLE_WINDOW {
+                break;
+            }
+            samples.pop_front();
+        }
+        let oldest = samples.front().map_or(count, |&(_, oldest)| oldest);
+        let last_day = count.saturating_sub(oldest);
+        let excessive = self.warn_per_day.is_some_and(|max| last_day > max);
+        if !excessive {
+            self.excessive.remove(disk);
+        } else if self.excessive.insert(disk.to_string()) {
+            warn!(
+                disk;
+                "{} parked its heads {} times within 24 hours, the spindown policy might be too aggressive",
+                disk, last_day
+            );
+        }
+        (last_day, excessive)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_cycles() {
+        let mut load_cycles = LoadCycles::new(Some(100));
+        let start = Instant::now();
+        let hours = |hours: u64| start + Duration::from_secs(hours * 60 * 60);
+
+        assert_eq!(load_cycles.record("/dev/sda", 1000, hours(0)), (0, false));
+        assert_eq!(load_cycles.record("/dev/sda", 1060, hours(12)), (60, false));
+        assert_eq!(load_cycles.record("/dev/sda", 1120, hours(23)), (120, true));
+        // the first sample left the window
+        assert_eq!(load_cycles.record("/dev/sda", 1130, hours(25)), (70, false));
+        assert_eq!(load_cycles.record("/dev/sdb", 5000, hours(25)), (0, false));
+
+        let mut load_cycles = LoadCycles::new(None);
+        load_cycles.record("/dev/sda", 0, hours(0));
+        assert_eq!(
+            load_cycles.record("/dev/sda", 1000, hours(1)),
+            (1000, false)
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt::Display;
+
+use log::{
+    kv::{Error, Key, Value, VisitSource},
+    Record,
+};
+use serde_json::json;
+
+/// How log lines are written to stderr
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// env_logger's human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki, Elasticsearch and the like
+    Json,
+}
+
+/// Key-values of a record like `disk`, as strings
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// A record as a line of JSON with the fields `timestamp`, `level`,
+/// `module`, any key-values of the record like `disk` and `message`, in
+/// that order
+pub fn json_line(timestamp: impl Display, record: &Record) -> String {
+    let mut fields = Fields(Vec::new());
+    // Visiting only fails if the visitor does
+    let _ = record.key_values().visit(&mut fields);
+    let mut line = format!(
+        "{{\"timestamp\":{},\"level\":{},\"module\":{}",
+        json!(timestamp.to_string()),
+        json!(record.level().as_str()),
+        json!(record.module_path().unwrap_or_else(|| record.target())),
+    );
+    for (key, value) in fields.0 {
+        line.push_str(&format!(",{}:{}", json!(key), json!(value)));
+    }
+    line.push_str(&format!(
+        ",\"message\":{}}}",
+        json!(record.args().to_string())
+    ));
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use log::Level;
+    use serde_json::Value as JsonValue;
+
+    use super::*;
+
+    #[test]
+    fn test_json_line() {
+        let kvs = [("disk", "/dev/sda")];
+        let line = json_line(
+            "2024-06-01T12:00:00.000Z",
+            &Record::builder()
+                .args(format_args!("Spun down {} after \"idle\"", "/dev/sda"))
+                .level(Level::Info)
+                .module_path(Some("disk_spin_manager::spindown"))
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(
+            line,
+            r#"{"timestamp":"2024-06-01T12:00:00.000Z","level":"INFO","module":"disk_spin_manager::spindown","disk":"/dev/sda","message":"Spun down /dev/sda after \"idle\""}"#
+        );
+
+        let line = json_line(
+            "now",
+            &Record::builder()
+                .args(format_args!("Reloaded config"))
+                .level(Level::Warn)
+                .target("disk_spin_manager")
+                .build(),
+        );
+        let line: JsonValue = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["module"], "disk_spin_manager");
+        assert_eq!(line["disk"], JsonValue::Null);
+    }
+}
@@ -1,53 +1,918 @@
-use clap::Parser;
-use log::{debug, error};
-use std::thread;
-use std::{path::Path, time::Duration};
+use clap::{CommandFactory, Parser};
+use log::{debug, error, info, warn};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::Sender,
+    Arc,
+};
+use std::{fs, io::Write, thread};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use disk_spin_manager::{
-    cli::Args,
-    disk_status::disk_status_loop,
+    audit::AuditLog,
+    check::{
+        check_directory, check_disk_settings, check_programs, check_watch_directory,
+        check_writable_dir,
+    },
+    cli::{Args, BackendArgs, Command, DaemonArgs, DiskArgs, GlobalArgs},
+    command::{CommandRunner, PrivilegedRunner, SystemRunner},
+    config::Config,
+    disk_status::{
+        disk_status_loop, disk_status_once, BackendCommands, BackendKind, DiskBackends,
+        DiskControl, DiskStatus, PowerState, RetryPolicy,
+    },
+    disks::{DiskFilter, DiskList, DiskNames, DiskNaming, SysBlock},
+    diskstats::{activity_loop, DiskstatsPoller},
+    fanotify::{fanotify_loop, Fanotify},
+    graphite::GraphiteSink,
+    hotplug::{hotplug_loop, UeventSocket},
+    http::MetricsServer,
+    influx::InfluxWriter,
+    init_config::{starter_config, StarterDisk},
+    load_cycles::LoadCycles,
+    logging::{json_line, LogFormat},
     metrics::{MetricMessage, Metrics},
-    watch,
+    mqtt::{MqttPublisher, MqttQueue},
+    otlp::OtlpExporter,
+    permissions::FilePermissions,
+    power::{PowerModel, Wattage},
+    push::{hostname, Pushgateway},
+    remote_write::RemoteWriter,
+    schedule::local_minute_of_day,
+    signals::Signals,
+    smart::smart_loop,
+    smartctl::Smartctl,
+    spindown::{Spindown, SpindownPolicy},
+    stagger::Stagger,
+    status::{read_json_status, render, DiskRow, OutputFormat},
+    systemd::SystemdNotifier,
+    textfile::TextfileSink,
+    wake_cause::WakeCauses,
+    watch::{self, WatchOptions, WatchRetries},
+    zabbix::ZabbixSender,
 };
 
-fn configure_logging(args: &Args) {
+fn configure_logging(args: &GlobalArgs) {
     let level = if args.debug {
         log::LevelFilter::Debug
     } else {
         log::LevelFilter::Warn
     };
 
-    env_logger::builder().filter_level(level).init();
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
+    if args.log_format == LogFormat::Json {
+        builder
+            .format(|buf, record| writeln!(buf, "{}", json_line(buf.timestamp_millis(), record)));
+    }
+    builder.init();
+}
+
+/// The commands the backends run and what all of them share
+struct Backend {
+    commands: BackendCommands,
+    /// Disks that are never spun down
+    protected: Vec<String>,
+    stagger: Arc<Stagger>,
+}
+
+impl Backend {
+    fn new(args: &BackendArgs) -> Self {
+        let runner: Arc<dyn CommandRunner> = Arc::new(SystemRunner {
+            timeout: Duration::from_secs(args.command_timeout),
+        });
+        let privileged_runner: Arc<dyn CommandRunner> = match args.privilege_helper {
+            Some(helper) => Arc::new(PrivilegedRunner {
+                helper,
+                inner: runner.clone(),
+            }),
+            None => runner.clone(),
+        };
+        let commands = BackendCommands {
+            hdparm: args.hdparm.clone(),
+            smartctl: args.smartctl.clone(),
+            busctl: args.busctl.clone(),
+            runner,
+            privileged_runner,
+        };
+        let protected = if args.allow_system_disk {
+            Vec::new()
+        } else {
+            SysBlock::new().system_disks()
+        };
+        debug!("Protected system disks: {:?}", protected);
+        let stagger = Arc::new(
+            Stagger::new(
+                Duration::from_secs_f64(args.stagger),
+                Duration::from_secs_f64(args.stagger_jitter),
+            )
+            .with_max_spinups(args.max_concurrent_spinups),
+        );
+        Backend {
+            commands,
+            protected,
+            stagger,
+        }
+    }
+
+    fn disk_backends(&self, args: &BackendArgs) -> DiskBackends {
+        DiskBackends::new(&self.commands, &args.backend, &args.disk_backend)
+            .with_stagger(self.stagger.clone())
+            .with_dry_run(args.dry_run)
+            .with_protected(self.protected.clone())
+    }
+}
+
+/// The disks selected by `args` and the config file
+fn disk_list(args: &DiskArgs, config: &Config) -> Result<SysBlock> {
+    Ok(SysBlock::new()
+        .with_enumeration(args.enumerate)
+        .with_all_disks(args.monitor_all_disks)
+        .with_monitor_overrides(config.monitor_overrides())
+        .with_monitor_paths(
+            args.monitor_path.iter().map(PathBuf::from).collect(),
+            args.scan_disks,
+        )
+        .with_filter(config.disk_filter(&cli_filter(args))?))
+}
+
+fn cli_filter(args: &DiskArgs) -> DiskFilter {
+    DiskFilter {
+        include: args.include_disks.clone(),
+        exclude: args.exclude_disks.clone(),
+    }
+}
+
+/// Print the power state of each monitored disk, as the daemon last saw it
+/// if there's a JSON status to read
+fn print_status(
+    output: OutputFormat,
+    json_status: Option<&str>,
+    backend: &BackendArgs,
+    disks: &DiskArgs,
+    config: &Config,
+) -> Result<()> {
+    let rows = match json_status {
+        Some(path) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            read_json_status(Path::new(path), now)?
+        }
+        None => {
+            let disk_query = Backend::new(backend).disk_backends(backend);
+            let disk_list = disk_list(disks, config)?;
+            let names = DiskNames::new(DiskNaming::Kernel, false).with_aliases(config.aliases());
+            disk_list
+                .get_all_disks()?
+                .into_iter()
+                .map(|disk| {
+                    let state = disk_query.get_disk_status(&disk).unwrap_or_else(|err| {
+                        error!("Failed to query {}: {:?}", disk, err);
+                        PowerState::Unknown
+                    });
+                    DiskRow {
+                        name: names.alias(&disk),
+                        state: state.as_str().to_string(),
+                        since_change_seconds: None,
+                        temperature_celsius: disk_list.get_temperature(&disk).ok().flatten(),
+                        device: disk,
+                    }
+                })
+                .collect()
+        }
+    };
+    print!("{}", render(&rows, output));
+    Ok(())
+}
+
+/// Print which disks would be monitored and why the others wouldn't
+fn list_disks(output: OutputFormat, args: &DiskArgs, config: &Config) -> Result<()> {
+    let selection = disk_list(args, config)?.selection()?;
+    print!("{}", render(&selection, output));
+    Ok(())
+}
+
+/// The config file was already loaded, check what else the daemon would
+/// derive from it and whether the paths it needs are usable
+fn check_config(args: &DaemonArgs, config: &Config) -> Result<()> {
+    FilePermissions::parse(
+        args.textfile_mode.as_deref(),
+        args.textfile_owner.as_deref(),
+        args.textfile_group.as_deref(),
+    )?;
+    config.watch_options(&cli_watch_options(args))?;
+    let mut findings = Vec::new();
+    if !args.no_textfile {
+        findings.extend(check_writable_dir(Path::new(&args.textfile), "--textfile"));
+    }
+    if let Some(json_status) = &args.json_status {
+        findings.extend(check_writable_dir(Path::new(json_status), "--json-status"));
+    }
+    if let Some(audit_log) = &args.audit_log {
+        findings.extend(check_writable_dir(Path::new(audit_log), "--audit-log"));
+    }
+    findings.extend(check_programs(&args.backend, args.smart_interval.is_some()));
+    for directory in watch_patterns(&args.watch_directories, config) {
+        findings.extend(check_watch_directory(&directory));
+    }
+    for path in &args.disks.monitor_path {
+        findings.extend(check_directory(Path::new(path), "--monitor-path"));
+    }
+    let monitored = disk_list(&args.disks, config)?.get_all_disks()?;
+    findings.extend(check_disk_settings(config, &monitored));
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let errors = findings.iter().filter(|f| f.is_error()).count();
+    if errors > 0 {
+        bail!("Found {} problems with the config", errors);
+    }
+    println!("Config is valid, {} disks are monitored", monitored.len());
+    Ok(())
+}
+
+/// Write a starter config for the disks `args` selects
+fn init_config(path: Option<&str>, force: bool, args: &DiskArgs, config: &Config) -> Result<()> {
+    let disk_list = disk_list(args, config)?;
+    let mut mount_points = disk_list.mount_points()?;
+    let names = DiskNames::new(DiskNaming::ById, false);
+    let disks: Vec<StarterDisk> = disk_list
+        .get_all_disks()?
+        .into_iter()
+        .map(|device| StarterDisk {
+            path: names.labels(&device).remove(0),
+            mount_points: mount_points.remove(&device).unwrap_or_default(),
+            device,
+        })
+        .collect();
+    let starter = starter_config(&disks);
+    match path {
+        Some(path) => {
+            if !force && Path::new(path).exists() {
+                bail!("{} exists already, pass --force to replace it", path);
+            }
+            fs::write(path, starter).with_context(|| format!("Failed to write {}", path))?;
+            println!("Wrote {} with {} disks", path, disks.len());
+        }
+        None => print!("{}", starter),
+    }
+    Ok(())
+}
+
+/// Spin disks down or up, trying all of them even if one fails
+fn run_command(spindown: bool, disks: &[String], control: &impl DiskControl) -> Result<()> {
+    let verb = if spindown { "spin down" } else { "spin up" };
+    let mut failed = 0;
+    for disk in disks {
+        let result = if spindown {
+            control.spindown(disk)
+        } else {
+            control.spinup(disk)
+        };
+        if let Err(err) = result {
+            error!("Failed to {} {}: {:?}", verb, disk, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("Failed to {} {} of {} disks", verb, failed, disks.len());
+    }
+    Ok(())
+}
+
+/// The `DiskFilter` message for the `disk_filter_info` metric
+fn filter_message(filter: &DiskFilter) -> MetricMessage {
+    MetricMessage::DiskFilter {
+        include: filter.include.iter().map(|p| p.to_string()).collect(),
+        exclude: filter.exclude.iter().map(|p| p.to_string()).collect(),
+    }
+}
+
+/// Watch the directories from the command line and the config file, `disks`
+/// are the ones each is on. Tells which ones are watched.
+fn watch_directories(
+    watches: &[PathBuf],
+    disks: Vec<(String, Vec<String>)>,
+    options: &WatchOptions,
+    tx: &Sender<MetricMessage>,
+) -> Result<watch::Watchers> {
+    let watcher = watch::watch(
+        watches.iter().map(PathBuf::as_path).collect(),
+        options.clone(),
+        tx.clone(),
+    )?;
+    let lost = watcher.lost();
+    for (directory, _) in &disks {
+        tx.send(MetricMessage::WatchActive {
+            directory: directory.clone(),
+            active: !lost.contains(&PathBuf::from(directory)),
+        })?;
+    }
+    tx.send(MetricMessage::WatchedDirectories(disks))?;
+    Ok(watcher)
+}
+
+/// The disks each of `watches` is on, by its absolute path like notify events
+/// are reported with. None if they can't be found, e.g. on a network share.
+fn watch_disks(watches: &[PathBuf], disk_list: &SysBlock) -> Vec<(String, Vec<String>)> {
+    watches
+        .iter()
+        .filter_map(|dir| {
+            let path = std::path::absolute(dir).ok()?;
+            let disks = disk_list.disks_for_path(&path).unwrap_or_else(|err| {
+                warn!(
+                    "Can't find the disks of {}: {:?}",
+                    dir.to_string_lossy(),
+                    err
+                );
+                Vec::new()
+            });
+            Some((path.to_string_lossy().to_string(), disks))
+        })
+        .collect()
+}
+
+fn cli_watch_options(args: &DaemonArgs) -> WatchOptions {
+    WatchOptions {
+        ignore: args.watch_ignore.clone(),
+        debounce: Duration::from_secs_f64(args.watch_debounce),
+        events: args.watch_events.clone(),
+        directory_events: Vec::new(),
+        mode: args.watch_mode,
+        directory_modes: Vec::new(),
+        poll_interval: Duration::from_secs(args.watch_poll_interval),
+    }
+}
+
+/// The directories and glob patterns from the command line and the config
+/// file
+fn watch_patterns(args: &[String], config: &Config) -> Vec<String> {
+    args.iter()
+        .cloned()
+        .chain(
+            config
+                .watch_directories
+                .iter()
+                .map(|d| d.path().to_string()),
+        )
+        .collect()
+}
+
+/// What to watch: the directories `patterns` match now and, with
+/// `mount_points`, where the monitored disks are mounted
+fn watch_list(
+    patterns: &[String],
+    mount_points: bool,
+    disk_list: &SysBlock,
+) -> Result<Vec<PathBuf>> {
+    let mut watches = watch::expand_directories(patterns)?;
+    if mount_points {
+        for target in disk_list.monitored_mount_points()? {
+            if !watches.contains(&target) {
+                watches.push(target);
+            }
+        }
+    }
+    Ok(watches)
+}
+
+fn log_watch_changes(old: &[PathBuf], new: &[PathBuf]) {
+    for watch in new.iter().filter(|watch| !old.contains(watch)) {
+        info!("Started watching {}", watch.to_string_lossy());
+    }
+    for watch in old.iter().filter(|watch| !new.contains(watch)) {
+        info!("Stopped watching {}", watch.to_string_lossy());
+    }
+}
+
+/// Applies the config file again on SIGHUP, combined with the settings from
+/// the command line. Names, wattages, power settings and wake-cause
+/// attribution only change with a restart.
+struct Reload {
+    path: Option<PathBuf>,
+    args: DaemonArgs,
+    filter: DiskFilter,
+    spindown_policy: SpindownPolicy,
+    disk_list: SysBlock,
+    refresh_interval: Arc<AtomicU64>,
+    textfile_interval: Arc<AtomicU64>,
+    policy_tx: Sender<SpindownPolicy>,
+    tx: Sender<MetricMessage>,
+    watch_patterns: Vec<String>,
+    watch_mount_points: bool,
+    /// What `watch_patterns` and the mount points were when the watcher was
+    /// set up
+    watches: Vec<PathBuf>,
+    watch_options: WatchOptions,
+    watcher: watch::Watchers,
+    /// Directories that were deleted or mounted over since they were watched
+    retries: WatchRetries,
+}
+
+impl Reload {
+    /// Nothing changes unless the whole config could be applied
+    fn reload(&mut self) -> Result<()> {
+        let config = match &self.path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        let filter = config.disk_filter(&self.filter)?;
+        let watch_patterns = watch_patterns(&self.args.watch_directories, &config);
+        let watch_mount_points = config
+            .watch_mount_points
+            .unwrap_or(self.args.watch_mount_points);
+        // Mount points of disks the new filter selects are added with the
+        // next refresh
+        let watches = watch_list(&watch_patterns, watch_mount_points, &self.disk_list)?;
+        let watch_options = config.watch_options(&cli_watch_options(&self.args))?;
+        // The old watches stay until the new ones are set up
+        let disks = watch_disks(&watches, &self.disk_list);
+        self.watcher = watch_directories(&watches, disks, &watch_options, &self.tx)?;
+        log_watch_changes(&self.watches, &watches);
+        self.watch_patterns = watch_patterns;
+        self.watch_mount_points = watch_mount_points;
+        self.watches = watches;
+        self.watch_options = watch_options;
+        self.tx.send(filter_message(&filter))?;
+        self.disk_list.reload(filter, config.monitor_overrides());
+        self.refresh_interval.store(
+            config
+                .refresh_interval
+                .unwrap_or(self.args.refresh_interval),
+            Ordering::Relaxed,
+        );
+        self.textfile_interval.store(
+            config
+                .textfile_interval
+                .unwrap_or(self.args.textfile_interval),
+            Ordering::Relaxed,
+        );
+        self.policy_tx
+            .send(self.spindown_policy.clone().with_config(&config))?;
+        Ok(())
+    }
+
+    /// Watch the directories glob patterns match and the monitored disks are
+    /// mounted on now, if they changed
+    fn expand_again(&mut self) -> Result<()> {
+        let watches = watch_list(
+            &self.watch_patterns,
+            self.watch_mount_points,
+            &self.disk_list,
+        )?;
+        if watches != self.watches {
+            log_watch_changes(&self.watches, &watches);
+            let disks = watch_disks(&watches, &self.disk_list);
+            self.watcher = watch_directories(&watches, disks, &self.watch_options, &self.tx)?;
+            self.watches = watches;
+        }
+        Ok(())
+    }
+
+    /// Watch the directories again that aren't watched anymore, once their
+    /// backoff is over
+    fn watch_again(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for directory in self.retries.update(&self.watcher.lost(), now) {
+            warn!(
+                "{} isn't watched, e.g. because it was deleted, moved or mounted over. \
+                 Trying to watch it again",
+                directory.to_string_lossy()
+            );
+            self.tx.send(MetricMessage::WatchActive {
+                directory: directory.to_string_lossy().to_string(),
+                active: false,
+            })?;
+        }
+        let due = self.retries.due(now);
+        if due.is_empty() {
+            return Ok(());
+        }
+        // Nothing to watch until a directory is back
+        if due.iter().any(|directory| directory.is_dir()) {
+            let disks = watch_disks(&self.watches, &self.disk_list);
+            self.watcher = watch_directories(&self.watches, disks, &self.watch_options, &self.tx)?;
+        }
+        let lost = self.watcher.lost();
+        for directory in due {
+            if lost.contains(&directory) {
+                let backoff = self.retries.failed(&directory, now);
+                debug!(
+                    "Still can't watch {}, trying again in {:?}",
+                    directory.to_string_lossy(),
+                    backoff
+                );
+            } else {
+                info!("Watching {} again", directory.to_string_lossy());
+            }
+        }
+        self.retries.update(&lost, now);
+        Ok(())
+    }
+
+    /// Reload on SIGHUP, in between expand the watch directories again every
+    /// refresh interval and watch lost ones again. Returns on SIGTERM or
+    /// SIGINT.
+    fn run(mut self, signals: Signals) -> Result<()> {
+        let refresh_interval = self.refresh_interval.clone();
+        let refresh_interval =
+            move || Duration::from_secs(refresh_interval.load(Ordering::Relaxed));
+        let mut next_expand = Instant::now() + refresh_interval();
+        loop {
+            let wake = self
+                .retries
+                .next_retry()
+                .map_or(next_expand, |retry_at| retry_at.min(next_expand));
+            match signals.wait_timeout(wake.saturating_duration_since(Instant::now()))? {
+                None => {
+                    if Instant::now() >= next_expand {
+                        if let Err(err) = self.expand_again() {
+                            error!("Failed to update the watch directories: {:?}", err);
+                        }
+                        next_expand = Instant::now() + refresh_interval();
+                    }
+                    if let Err(err) = self.watch_again() {
+                        error!("Failed to watch the lost directories again: {:?}", err);
+                    }
+                    continue;
+                }
+                Some(libc::SIGHUP) => {}
+                Some(signal) => {
+                    let name = if signal == libc::SIGINT {
+                        "SIGINT"
+                    } else {
+                        "SIGTERM"
+                    };
+                    info!("Received {}, shutting down", name);
+                    return Ok(());
+                }
+            }
+            let result = self.reload();
+            match &result {
+                Ok(()) => info!("Reloaded config"),
+                Err(err) => error!("Failed to reload config, keeping the old one: {:?}", err),
+            }
+            self.tx.send(MetricMessage::ConfigReloaded {
+                success: result.is_ok(),
+            })?;
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let (global, command) = Args::parse().command();
 
-    configure_logging(&args);
+    if let Command::Completions { shell } = command {
+        clap_complete::generate(
+            shell,
+            &mut <Args as CommandFactory>::command(),
+            env!("CARGO_BIN_NAME"),
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+
+    configure_logging(&global);
+
+    let config = match &global.config {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
 
+    match command {
+        Command::Daemon(args) => run_daemon(global, args, config),
+        Command::Status {
+            output,
+            json_status,
+            backend,
+            disks,
+        } => print_status(output, json_status.as_deref(), &backend, &disks, &config),
+        Command::ListDisks { output, disks } => list_disks(output, &disks, &config),
+        Command::Spindown { backend, disks } => run_command(
+            true,
+            &disks,
+            &Backend::new(&backend).disk_backends(&backend),
+        ),
+        Command::Spinup { backend, disks } => run_command(
+            false,
+            &disks,
+            &Backend::new(&backend).disk_backends(&backend),
+        ),
+        Command::CheckConfig(args) => check_config(&args, &config),
+        Command::InitConfig { path, force, disks } => {
+            init_config(path.as_deref(), force, &disks, &config)
+        }
+        Command::Completions { .. } => unreachable!("handled before loading the config"),
+    }
+}
+
+/// Query the disks a single time and export the metrics to the sinks
+fn run_once(
+    args: &DaemonArgs,
+    config: &Config,
+    monitor: Metrics,
+    disk_query: DiskBackends,
+    retry_policy: RetryPolicy,
+    tx: Sender<MetricMessage>,
+) -> Result<()> {
+    tx.send(filter_message(
+        &config.disk_filter(&cli_filter(&args.disks))?,
+    ))?;
+    let failing = disk_status_once(
+        disk_query,
+        disk_list(&args.disks, config)?,
+        args.query_concurrency,
+        retry_policy,
+        &tx,
+    )?;
+    tx.send(MetricMessage::Flush)?;
+    // Lets receiving the metrics end after the flush
+    drop(tx);
+    monitor.receive_metrics()?;
+    if failing > 0 {
+        bail!("Failed to query {} disks", failing);
+    }
+    Ok(())
+}
+
+/// Monitor the disks until SIGTERM or SIGINT
+fn run_daemon(global: GlobalArgs, args: DaemonArgs, config: Config) -> Result<()> {
+    // Before any thread is spawned, so only the reload thread gets them
+    let signals = Signals::block(&[libc::SIGHUP, libc::SIGTERM, libc::SIGINT])?;
+
+    let backend = Backend::new(&args.backend);
+    let protected = backend.protected.clone();
     let (tx, rx) = std::sync::mpsc::channel();
-    let monitor = Metrics::new(Path::new(&args.textfile).to_path_buf(), rx)?;
+    let disk_query = backend
+        .disk_backends(&args.backend)
+        .with_power_settings(config.power_settings())
+        .with_metrics(tx.clone());
+    let mut disk_names = DiskNames::new(args.disks.disk_names, args.disks.device_label)
+        .with_aliases(config.aliases());
+    if !args.backend.allow_system_disk {
+        disk_names = disk_names.with_protected(protected.clone());
+    }
+    let wattage = Wattage {
+        active: args.active_watts,
+        idle: args.idle_watts,
+        standby: args.standby_watts,
+    };
+    let mut monitor = Metrics::with_disk_names(rx, disk_names)?
+        .with_power_model(PowerModel::new(wattage, config.wattages(wattage)));
+    if !args.no_textfile {
+        let textfile = TextfileSink::new(PathBuf::from(&args.textfile), &monitor.registry())?
+            .with_format(args.format)
+            .with_permissions(FilePermissions::parse(
+                args.textfile_mode.as_deref(),
+                args.textfile_owner.as_deref(),
+                args.textfile_group.as_deref(),
+            )?)
+            .with_own_io(monitor.own_io());
+        monitor = monitor.with_sink(textfile);
+    }
+    if let Some(url) = &args.pushgateway {
+        let instance = match &args.push_instance {
+            Some(instance) => instance.clone(),
+            None => hostname()?,
+        };
+        let grouping = [(String::from("instance"), instance)];
+        monitor = monitor.with_sink(Pushgateway::new(url, &args.push_job, &grouping));
+    }
+    if let Some(path) = &args.influx_file {
+        let influx = InfluxWriter::file(PathBuf::from(path)).with_own_io(monitor.own_io());
+        monitor = monitor.with_sink(influx);
+    }
+    if let Some(url) = &args.influx_url {
+        monitor = monitor.with_sink(InfluxWriter::http(url, args.influx_token.clone()));
+    }
+    if let Some(addr) = &args.graphite {
+        let graphite = GraphiteSink::new(addr)
+            .with_prefix(&args.graphite_prefix)
+            .with_interval(Duration::from_secs(args.graphite_interval));
+        monitor = monitor.with_sink(graphite);
+    }
+    let zabbix_host = match &args.zabbix_host {
+        Some(host) => host.clone(),
+        None if args.zabbix_server.is_some() || args.zabbix_file.is_some() => hostname()?,
+        None => String::new(),
+    };
+    if let Some(addr) = &args.zabbix_server {
+        monitor = monitor.with_sink(ZabbixSender::server(addr, &zabbix_host));
+    }
+    if let Some(path) = &args.zabbix_file {
+        let zabbix =
+            ZabbixSender::file(PathBuf::from(path), &zabbix_host).with_own_io(monitor.own_io());
+        monitor = monitor.with_sink(zabbix);
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let mut otlp =
+            OtlpExporter::new(endpoint).with_resource_attribute("host.name", &hostname()?);
+        for header in &args.otlp_header {
+            let (name, value) = header
+                .split_once('=')
+                .with_context(|| format!("OTLP header {} isn't NAME=VALUE", header))?;
+            otlp = otlp.with_header(name, value);
+        }
+        monitor = monitor.with_sink(otlp);
+    }
+    if let Some(url) = &args.remote_write {
+        let mut writer = RemoteWriter::new(url)
+            .with_interval(Duration::from_secs(args.remote_write_interval))
+            .with_label("job", "disk_spin_manager")
+            .with_label("instance", &hostname()?);
+        if let (Some(username), Some(password)) =
+            (&args.remote_write_username, &args.remote_write_password)
+        {
+            writer = writer.with_basic_auth(username, password);
+        }
+        monitor = monitor.with_sink(writer);
+    }
+    if let Some(path) = &args.json_status {
+        monitor = monitor.with_json_status(PathBuf::from(path));
+    }
+    if let Some(systemd) = SystemdNotifier::from_env()? {
+        monitor = monitor.with_systemd(systemd);
+    }
+    if let Some(path) = &args.audit_log {
+        let audit_log = AuditLog::new(PathBuf::from(path))
+            .with_rate(args.audit_log_rate)
+            .with_max_bytes(args.audit_log_max_mb.saturating_mul(1024 * 1024))
+            .with_keep(args.audit_log_keep);
+        monitor = monitor.with_audit_log(audit_log);
+    }
+    if let Some(broker) = &args.mqtt {
+        let mut mqtt = MqttPublisher::new(broker)
+            .with_topic_prefix(&args.mqtt_topic)
+            .with_discovery_prefix(&args.mqtt_discovery_prefix);
+        if let (Some(username), Some(password)) = (&args.mqtt_username, &args.mqtt_password) {
+            mqtt = mqtt.with_credentials(username, password);
+        }
+        monitor = monitor.with_mqtt(MqttQueue::spawn(mqtt));
+    }
+    let retry_policy = RetryPolicy {
+        initial_backoff: Duration::from_secs(args.retry_initial_backoff),
+        max_backoff: Duration::from_secs(args.retry_max_backoff),
+        max_failures: args.max_failures,
+        unsupported_after: args.unsupported_after,
+    };
+    if args.once {
+        // Nothing waits for the signals, Ctrl-C and SIGTERM should just stop
+        signals.unblock()?;
+        return run_once(&args, &config, monitor, disk_query, retry_policy, tx);
+    }
+
+    if let Some(path) = &args.listen_unix {
+        let server = MetricsServer::bind_unix(Path::new(path))?.with_format(args.format);
+        let registry = monitor.registry();
+        thread::spawn(move || server.serve(&registry));
+    }
+    if let Some(listen) = &args.listen {
+        let server = MetricsServer::bind(listen)?.with_format(args.format);
+        let registry = monitor.registry();
+        thread::spawn(move || server.serve(&registry));
+    }
 
     let tx_disk_status = tx.clone();
+    let (hotplug_tx, hotplug_rx) = std::sync::mpsc::channel();
+    if !args.no_hotplug {
+        match UeventSocket::new() {
+            Ok(socket) => {
+                thread::spawn(move || hotplug_loop(socket, hotplug_tx));
+            }
+            Err(err) => warn!("Hotplug monitoring unavailable: {:?}", err),
+        }
+    }
+    tx.send(filter_message(
+        &config.disk_filter(&cli_filter(&args.disks))?,
+    ))?;
+    let disk_list = disk_list(&args.disks, &config)?;
+    let watch_patterns = watch_patterns(&args.watch_directories, &config);
+    let watch_mount_points = config.watch_mount_points.unwrap_or(args.watch_mount_points);
+    let watches = watch_list(&watch_patterns, watch_mount_points, &disk_list)?;
+    let watch_disks = watch_disks(&watches, &disk_list);
+    // Only directories on known disks can explain wakeups
+    let attributable: Vec<(String, Vec<String>)> = watch_disks
+        .iter()
+        .filter(|(_, disks)| !disks.is_empty())
+        .cloned()
+        .collect();
+    if args.fanotify {
+        let mounts: Vec<_> = attributable
+            .iter()
+            .map(|(path, disks)| (PathBuf::from(path), disks.clone()))
+            .collect();
+        match Fanotify::new(&mounts) {
+            Ok(fanotify) => {
+                let tx_fanotify = tx.clone();
+                thread::spawn(move || fanotify_loop(fanotify, tx_fanotify));
+            }
+            Err(err) => warn!("Process attribution unavailable: {:?}", err),
+        }
+    }
+    let monitor = monitor.with_wake_causes(
+        WakeCauses::new(Duration::from_secs(args.wake_cause_window)).with_watches(attributable),
+    );
+    let spindown_policy = SpindownPolicy {
+        idle_timeout: args.spindown_after.map(Duration::from_secs),
+        min_spinup: Duration::from_secs(args.min_spinup),
+        keep_awake: args.keep_awake.clone().unwrap_or_default(),
+        keep_awake_spinup: args.keep_awake_spinup,
+        enforce_standby: args.enforce_standby.map(Duration::from_secs),
+        max_cycles: args.max_spin_cycles,
+        exercise: args.exercise_interval.map(Duration::from_secs),
+        sync_filesystems: args.sync_before_spindown,
+        early_wakeup: Duration::from_secs(args.early_wakeup_window),
+        protected: protected.clone(),
+        ..Default::default()
+    };
+    let control = backend
+        .disk_backends(&args.backend)
+        .with_metrics(tx.clone());
+    // Even if nothing is spun down yet, a reload could change that
+    let mut spindown = Spindown::new(control, spindown_policy.clone().with_config(&config))
+        .with_disk_list(disk_list.clone())
+        .with_retry_policy(retry_policy.clone());
+    let (policy_tx, policy_rx) = std::sync::mpsc::channel();
+    if let Some(interval) = args.smart_interval {
+        let smartctl = Smartctl {
+            path: args.backend.smartctl.clone(),
+            device_type: match &args.backend.backend {
+                BackendKind::Smartctl { device_type } => device_type.clone(),
+                _ => None,
+            },
+            runner: backend.commands.privileged_runner.clone(),
+        };
+        let load_cycles = LoadCycles::new(args.load_cycle_warn_per_day);
+        let smart_disk_list = disk_list.clone();
+        let smart_attributes = args.smart_attributes.clone();
+        let tx_smart = tx.clone();
+        thread::spawn(move || {
+            smart_loop(
+                smartctl,
+                smart_disk_list,
+                Duration::from_secs(interval),
+                load_cycles,
+                smart_attributes,
+                tx_smart,
+            )
+        });
+    }
+    let poller = DiskstatsPoller::new(monitor.own_io());
+    let activity_disk_list = disk_list.clone();
+    let activity_interval = Duration::from_secs(args.activity_interval);
+    let tx_activity = tx.clone();
     thread::spawn(move || {
-        disk_status_loop(&args.hdparm, args.refresh_interval, tx_disk_status);
+        let tx_spindown = tx_activity.clone();
+        activity_loop(
+            poller,
+            activity_disk_list,
+            activity_interval,
+            tx_activity,
+            |events, now| {
+                if let Some(policy) = policy_rx.try_iter().last() {
+                    spindown.set_policy(policy);
+                }
+                spindown.handle_activity(events, now, local_minute_of_day(), &tx_spindown)
+            },
+        )
+    });
+    let refresh_interval = Arc::new(AtomicU64::new(
+        config.refresh_interval.unwrap_or(args.refresh_interval),
+    ));
+    let status_refresh_interval = refresh_interval.clone();
+    let status_disk_list = disk_list.clone();
+    let query_concurrency = args.query_concurrency;
+    thread::spawn(move || {
+        disk_status_loop(
+            disk_query,
+            status_disk_list,
+            status_refresh_interval,
+            query_concurrency,
+            retry_policy,
+            hotplug_rx,
+            tx_disk_status,
+        );
     });
 
-    let tx_watch = tx.clone();
-
-    let watches: Vec<&Path> = args
-        .watch_directories
-        .iter()
-        .map(|s| Path::new(s.as_str()))
-        .collect();
-    // Ensure watcher isn't dropped until the end
-    let _watcher = watch::watch(watches, tx_watch)?;
+    // Owned by the reload thread, which replaces it on reload
+    let watch_options = config.watch_options(&cli_watch_options(&args))?;
+    let watcher = watch_directories(&watches, watch_disks, &watch_options, &tx)?;
 
     // Start thread to regularly save textfile
+    let textfile_interval = Arc::new(AtomicU64::new(
+        config.textfile_interval.unwrap_or(args.textfile_interval),
+    ));
+    let save_interval = textfile_interval.clone();
     let tx_save = tx.clone();
     thread::spawn(move || loop {
-        if let Err(err) = tx_save.send(MetricMessage::SaveFile) {
+        if let Err(err) = tx_save.send(MetricMessage::Flush) {
             error!(
                 "Error send message to save file, existing thread: {:?}",
                 err
@@ -55,10 +920,39 @@ fn main() -> Result<()> {
             break;
         };
         debug!("Saved textfile");
-        thread::sleep(Duration::from_secs(args.textfile_interval));
+        thread::sleep(Duration::from_secs(save_interval.load(Ordering::Relaxed)));
+    });
+
+    let reload = Reload {
+        path: global.config.as_ref().map(PathBuf::from),
+        filter: cli_filter(&args.disks),
+        args,
+        spindown_policy,
+        disk_list,
+        refresh_interval,
+        textfile_interval,
+        policy_tx,
+        tx: tx.clone(),
+        watch_patterns,
+        watch_mount_points,
+        watches,
+        watch_options,
+        watcher,
+        retries: WatchRetries::default(),
+    };
+    let shutdown_tx = tx.clone();
+    thread::spawn(move || {
+        if let Err(err) = reload.run(signals) {
+            error!("Config reloading stopped, shutting down: {:?}", err);
+        }
+        // Nothing would handle SIGTERM anymore otherwise
+        if let Err(err) = shutdown_tx.send(MetricMessage::Shutdown) {
+            error!("Error sending message: {:?}", err);
+        }
     });
 
-    // Start receiving metrics
+    // Start receiving metrics until the shutdown, which writes them one last
+    // time
     monitor.receive_metrics()?;
 
     // Drop unused tx so it doesn't stay around
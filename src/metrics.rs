@@ -1,148 +1,2518 @@
-use anyhow::{anyhow, Context, Result};
-use log::{debug, error};
-use prometheus::{Encoder, GaugeVec, IntCounterVec, Opts, Registry, TextEncoder};
-use std::fs::{self};
-use std::io::BufWriter;
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use prometheus::{
+    process_collector::ProcessCollector, CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, Opts, Registry,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    audit::AuditLog,
+    disk_status::{Backend, PowerSettings, PowerState},
+    disks::{DiskInfo, DiskNames, DiskNaming},
+    diskstats::ActivityEvent,
+    duty_cycle::DutyCycle,
+    json_status::{write_json_status, DiskSummary},
+    mqtt::MqttQueue,
+    own_io::OwnIo,
+    power::PowerModel,
+    sink::ExportSink,
+    smartctl::SmartAttribute,
+    spindown::SpindownResult,
+    systemd::SystemdNotifier,
+    textfile::TextfileSink,
+    wake_cause::WakeCauses,
+    watch::WatchEvent,
+};
 
 #[derive(Debug)]
 pub enum MetricMessage {
-    DiskStatus { disk: String, status: f64 },
-    NotifyEvent(anyhow::Result<String>),
-    SaveFile,
+    DiskStatus {
+        disk: String,
+        status: PowerState,
+    },
+    DiskStatusTimeout {
+        disk: String,
+    },
+    /// Temperature of a disk that was seen spinning
+    Temperature {
+        disk: String,
+        celsius: f64,
+    },
+    /// A status query failed for any other reason than a timeout
+    DiskStatusError {
+        disk: String,
+    },
+    /// Listing the disks failed
+    EnumerationError,
+    /// Polling diskstats or acting on the activity failed
+    ActivityError,
+    /// The status loop queried all disks
+    CycleFinished,
+    DiskUnsupported {
+        disk: String,
+    },
+    DiskRemoved {
+        disk: String,
+    },
+    /// A disk was due for a spin-down after being idle
+    SpindownAttempt {
+        disk: String,
+        result: SpindownResult,
+    },
+    /// A disk that woke up without any I/O was put back into standby
+    StandbyEnforced {
+        disk: String,
+        success: bool,
+    },
+    /// Spin-downs left before the disk's daily cycle budget is exhausted
+    CycleBudget {
+        disk: String,
+        remaining: u32,
+    },
+    /// SMART load cycle count, `excessive` if it increased too much within
+    /// the last 24 hours
+    LoadCycles {
+        disk: String,
+        count: u64,
+        excessive: bool,
+    },
+    /// Selected SMART attributes of a disk that was spinning anyway
+    SmartAttributes {
+        disk: String,
+        attributes: BTreeMap<u8, SmartAttribute>,
+    },
+    /// A process accessed a file on the disk, from fanotify
+    ProcessAccess {
+        disk: String,
+        comm: String,
+    },
+    /// How long a backend took to answer a status query, successful or not
+    QueryDuration {
+        disk: String,
+        backend: &'static str,
+        duration: Duration,
+    },
+    /// A disk saw I/O shortly after it was spun down
+    EarlyWakeup {
+        disk: String,
+    },
+    /// A disk that was idle for long was spun up to exercise it
+    Exercised {
+        disk: String,
+    },
+    /// A notify rule of the config file started matching the disk
+    PolicyNotification {
+        disk: String,
+        rule: String,
+    },
+    /// APM level and standby timer that were applied to the disk
+    PowerSettings {
+        disk: String,
+        settings: PowerSettings,
+    },
+    /// I/O since the last diskstats poll
+    Activity(ActivityEvent),
+    /// All disks found by the latest enumeration, any others are gone
+    EnumeratedDisks(Vec<String>),
+    /// The directories the notify watcher watches, with the disks each is on
+    WatchedDirectories(Vec<(String, Vec<String>)>),
+    DiskInfo {
+        disk: String,
+        info: DiskInfo,
+    },
+    /// Member disks of each md array
+    MdArrays(BTreeMap<String, Vec<String>>),
+    DiskFilter {
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+    /// An event of the watched directories, or an error that made them
+    /// lost until they're watched again
+    NotifyEvent(anyhow::Result<WatchEvent>),
+    /// The config file was re-read, successfully or not
+    ConfigReloaded {
+        success: bool,
+    },
+    /// A watch directory couldn't be watched, or only partly because the
+    /// inotify watch limit was reached
+    WatchSetupFailed {
+        watch_limit: bool,
+    },
+    /// Whether a watch directory is watched right now, it isn't after it
+    /// was deleted or mounted over until it's watched again
+    WatchActive {
+        directory: String,
+        active: bool,
+    },
+    /// Export metrics to all sinks
+    Flush,
+    /// Handle what was sent before, export one last time and stop receiving
+    Shutdown,
 }
 
 pub struct Metrics {
     registry: Registry,
     disk_status: GaugeVec,
+    disk_power_state: GaugeVec,
+    disk_status_last_change: GaugeVec,
+    disk_status_last_update: GaugeVec,
+    disk_temperature: GaugeVec,
+    last_cycle: Gauge,
+    shutdown: Gauge,
+    monitored_disks: Gauge,
+    watched_directories: Gauge,
+    /// Last known power state per disk, to detect changes
+    power_states: Mutex<HashMap<String, PowerState>>,
+    disk_standby_duration: HistogramVec,
+    disk_standby_ratio: GaugeVec,
+    duty_cycle: Mutex<DutyCycle>,
+    /// When disks were seen spinning down, for `disk_standby_duration`
+    standby_since: Mutex<HashMap<String, Instant>>,
+    disk_spinups: IntCounterVec,
+    disk_spindowns: IntCounterVec,
+    disk_state_seconds: CounterVec,
+    /// Time and result of the latest status query per disk
+    state_samples: Mutex<HashMap<String, (Instant, PowerState)>>,
+    disk_power_watts: GaugeVec,
+    disk_energy: CounterVec,
+    power_model: PowerModel,
+    disk_status_timeouts: IntCounterVec,
+    disk_status_errors: IntCounterVec,
+    disk_enumeration_errors: IntCounter,
+    activity_errors: IntCounter,
+    disk_status_query_duration: HistogramVec,
+    disk_status_unsupported: GaugeVec,
+    disk_filter_info: GaugeVec,
+    config_reloads: IntCounterVec,
+    watch_setup_failures: IntCounterVec,
+    notify_errors: IntCounter,
+    watch_active: GaugeVec,
+    watched_directory_last_event: GaugeVec,
+    disk_info: GaugeVec,
+    /// Label values of the current `disk_info` series per disk, needed to
+    /// remove them again
+    disk_info_labels: Mutex<HashMap<String, Vec<String>>>,
+    disk_md_array: GaugeVec,
+    disk_spindown_attempts: IntCounterVec,
+    /// Kept from before `disk_spindown_attempts` so dashboards and alerts
+    /// using it don't break
+    disk_spindown_actions: IntCounterVec,
+    disk_standby_enforcements: IntCounterVec,
+    disk_early_wakeups: IntCounterVec,
+    disk_cycle_budget: GaugeVec,
+    disk_apm_level: GaugeVec,
+    disk_standby_timer: GaugeVec,
+    disk_load_cycles: IntCounterVec,
+    disk_load_cycles_excessive: GaugeVec,
+    disk_smart_attribute: GaugeVec,
+    /// Label values of the current `disk_smart_attribute_raw_value` series
+    /// per disk, needed to remove them again
+    smart_attribute_labels: Mutex<HashMap<String, Vec<Vec<String>>>>,
+    disk_last_io: GaugeVec,
+    disk_reads_completed: IntCounterVec,
+    disk_writes_completed: IntCounterVec,
+    disk_sectors_read: IntCounterVec,
+    disk_sectors_written: IntCounterVec,
+    disk_io_in_flight: GaugeVec,
+    disk_last_exercise: GaugeVec,
+    disk_wakeups: IntCounterVec,
+    disk_wakeups_by_process: IntCounterVec,
+    /// Processes that have a `disk_wake_by_process_total` series per disk
+    wakeup_processes: Mutex<HashMap<String, HashSet<String>>>,
+    disk_policy_notifications: IntCounterVec,
+    /// Rules that have a `disk_policy_notifications_total` series per disk
+    notified_rules: Mutex<HashMap<String, HashSet<String>>>,
+    wake_causes: Mutex<WakeCauses>,
     notify_counter: IntCounterVec,
-    textfile: PathBuf,
+    /// Disks each watched directory is on, for the `disk` label of
+    /// `notify_events`
+    watch_disks: Mutex<HashMap<String, Vec<String>>>,
+    disk_names: DiskNames,
+    /// Disks that currently have series
+    disks: Mutex<HashSet<String>>,
+    /// Where metrics go on every flush, none if they're only served over
+    /// HTTP
+    sinks: Vec<Box<dyn ExportSink>>,
+    json_status: Option<PathBuf>,
+    mqtt: Option<MqttQueue>,
+    audit_log: Option<Mutex<AuditLog>>,
+    systemd: Option<SystemdNotifier>,
+    /// What the JSON status says about each disk
+    disk_summaries: Mutex<BTreeMap<String, DiskSummary>>,
     rx: Receiver<MetricMessage>,
+    own_io: OwnIo,
 }
 
 impl Metrics {
+    /// Label disks by their kernel name and write them to `textfile`
     pub fn new(textfile: PathBuf, rx: Receiver<MetricMessage>) -> Result<Self> {
+        Self::with_disk_names(rx, DiskNames::new(DiskNaming::Kernel, false))?
+            .with_textfile(textfile)
+    }
+
+    /// Without any sinks, add them with `with_sink`
+    pub fn with_disk_names(rx: Receiver<MetricMessage>, disk_names: DiskNames) -> Result<Self> {
         let registry = Registry::new();
+        let disk_labels = disk_names.label_names();
+        let disk_state_labels = [disk_labels.as_slice(), &["state"]].concat();
         let disk_status = GaugeVec::new(
-            Opts::new("disk_status", "Status of the disk (1=active, 0=standby)"),
-            &["disk"],
+            Opts::new(
+                "disk_status",
+                "Status of the disk (1=active, 0=standby, -1=unknown)",
+            ),
+            &disk_labels,
         )?;
         registry
             .register(Box::new(disk_status.clone()))
             .context("Failed to register disk_status")?;
 
-        let notify_counter = IntCounterVec::new(
-            Opts::new("notify_events", "Number of events for watched directories"),
-            &["path"],
-        )?;
+        let disk_power_state = GaugeVec::new(
+            Opts::new(
+                "disk_power_state",
+                "Power state of the disk as reported by the backend (1 for the current state)",
+            ),
+            &disk_state_labels,
+        )?;
+        registry
+            .register(Box::new(disk_power_state.clone()))
+            .context("Failed to register disk_power_state")?;
+
+        let disk_status_last_change = GaugeVec::new(
+            Opts::new(
+                "disk_status_last_change_timestamp_seconds",
+                "Unix timestamp of the last observed change of the disk's power state",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_status_last_change.clone()))
+            .context("Failed to register disk_status_last_change")?;
+
+        let disk_status_last_update = GaugeVec::new(
+            Opts::new(
+                "disk_status_last_update_timestamp_seconds",
+                "Unix timestamp of the last successful status query of the disk",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_status_last_update.clone()))
+            .context("Failed to register disk_status_last_update")?;
+
+        let disk_temperature = GaugeVec::new(
+            Opts::new(
+                "disk_temperature_celsius",
+                "Temperature of the disk from its hwmon driver, only read while it's spinning",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_temperature.clone()))
+            .context("Failed to register disk_temperature")?;
+
+        let build_info = GaugeVec::new(
+            Opts::new(
+                "disk_spin_manager_build_info",
+                "Always 1, labeled with the version of the daemon and how it was built",
+            ),
+            &["version", "commit", "rustc"],
+        )?;
+        registry
+            .register(Box::new(build_info.clone()))
+            .context("Failed to register build_info")?;
+        build_info
+            .with_label_values(&[
+                env!("CARGO_PKG_VERSION"),
+                env!("BUILD_GIT_COMMIT"),
+                env!("BUILD_RUSTC_VERSION"),
+            ])
+            .set(1.0);
+
+        let last_cycle = Gauge::new(
+            "disk_spin_manager_last_cycle_timestamp_seconds",
+            "Unix timestamp of the last time the status loop finished querying all disks",
+        )?;
+        registry
+            .register(Box::new(last_cycle.clone()))
+            .context("Failed to register last_cycle")?;
+
+        let shutdown = Gauge::new(
+            "disk_spin_manager_shutdown_timestamp_seconds",
+            "Unix timestamp of when the daemon was asked to stop, 0 while it runs",
+        )?;
+        registry
+            .register(Box::new(shutdown.clone()))
+            .context("Failed to register shutdown")?;
+
+        let monitored_disks = Gauge::new(
+            "monitored_disks",
+            "Number of disks found by the latest enumeration",
+        )?;
+        registry
+            .register(Box::new(monitored_disks.clone()))
+            .context("Failed to register monitored_disks")?;
+
+        let watched_directories = Gauge::new(
+            "watched_directories",
+            "Number of directories watched for notify events",
+        )?;
+        registry
+            .register(Box::new(watched_directories.clone()))
+            .context("Failed to register watched_directories")?;
+
+        let disk_spinups = IntCounterVec::new(
+            Opts::new(
+                "disk_spinups_total",
+                "Number of times the disk was seen spinning after it was in standby",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_spinups.clone()))
+            .context("Failed to register disk_spinups")?;
+
+        let disk_spindowns = IntCounterVec::new(
+            Opts::new(
+                "disk_spindowns_total",
+                "Number of times the disk was seen in standby after it was spinning",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_spindowns.clone()))
+            .context("Failed to register disk_spindowns")?;
+
+        let disk_state_seconds = CounterVec::new(
+            Opts::new(
+                "disk_state_seconds_total",
+                "Seconds the disk spent in each power state, counted between status queries",
+            ),
+            &disk_state_labels,
+        )?;
+        registry
+            .register(Box::new(disk_state_seconds.clone()))
+            .context("Failed to register disk_state_seconds")?;
+
+        let disk_power_watts = GaugeVec::new(
+            Opts::new(
+                "disk_power_watts",
+                "Estimated power draw of the disk in its current state",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_power_watts.clone()))
+            .context("Failed to register disk_power_watts")?;
+
+        let disk_energy = CounterVec::new(
+            Opts::new(
+                "disk_energy_joules_total",
+                "Estimated energy used by the disk, counted between status queries",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_energy.clone()))
+            .context("Failed to register disk_energy")?;
+
+        let disk_status_timeouts = IntCounterVec::new(
+            Opts::new(
+                "disk_status_timeouts_total",
+                "Number of disk status queries that were killed after timing out",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_status_timeouts.clone()))
+            .context("Failed to register disk_status_timeouts")?;
+
+        let disk_status_errors = IntCounterVec::new(
+            Opts::new(
+                "disk_status_errors_total",
+                "Number of failed disk status queries by kind (timeout or error)",
+            ),
+            &[disk_labels.as_slice(), &["kind"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_status_errors.clone()))
+            .context("Failed to register disk_status_errors")?;
+
+        let disk_enumeration_errors = IntCounter::new(
+            "disk_enumeration_errors_total",
+            "Number of times listing the disks failed",
+        )?;
+        registry
+            .register(Box::new(disk_enumeration_errors.clone()))
+            .context("Failed to register disk_enumeration_errors")?;
+
+        let activity_errors = IntCounter::new(
+            "activity_errors_total",
+            "Number of times polling disk activity or acting on it failed",
+        )?;
+        registry
+            .register(Box::new(activity_errors.clone()))
+            .context("Failed to register activity_errors")?;
+
+        let disk_status_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "disk_status_query_duration_seconds",
+                "Time the backend took to answer a disk status query, including failed ones",
+            )
+            .buckets(vec![
+                0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+            &[disk_labels.as_slice(), &["backend"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_status_query_duration.clone()))
+            .context("Failed to register disk_status_query_duration")?;
+
+        let disk_standby_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "disk_standby_duration_seconds",
+                "Length of the standby periods that ended with the disk spinning up again",
+            )
+            .buckets(vec![
+                60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 86400.0,
+            ]),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_standby_duration.clone()))
+            .context("Failed to register disk_standby_duration")?;
+
+        let disk_standby_ratio = GaugeVec::new(
+            Opts::new(
+                "disk_standby_ratio_1h",
+                "Fraction of the last hour the disk spent in standby, of the time its state was known",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_standby_ratio.clone()))
+            .context("Failed to register disk_standby_ratio")?;
+
+        let disk_status_unsupported = GaugeVec::new(
+            Opts::new(
+                "disk_status_unsupported",
+                "Disks that are no longer queried because they don't report a power state",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_status_unsupported.clone()))
+            .context("Failed to register disk_status_unsupported")?;
+
+        let disk_filter_info = GaugeVec::new(
+            Opts::new(
+                "disk_filter_info",
+                "Configured include/exclude patterns for monitored disks, comma separated",
+            ),
+            &["include", "exclude"],
+        )?;
+        registry
+            .register(Box::new(disk_filter_info.clone()))
+            .context("Failed to register disk_filter_info")?;
+
+        let config_reloads = IntCounterVec::new(
+            Opts::new(
+                "config_reloads_total",
+                "Number of times the config file was re-read on SIGHUP",
+            ),
+            &["result"],
+        )?;
+        registry
+            .register(Box::new(config_reloads.clone()))
+            .context("Failed to register config_reloads")?;
+
+        let watch_setup_failures = IntCounterVec::new(
+            Opts::new(
+                "watch_setup_failures_total",
+                "Number of times a watch directory couldn't be watched, by reason (watch_limit for fs.inotify.max_user_watches or error)",
+            ),
+            &["reason"],
+        )?;
+        registry
+            .register(Box::new(watch_setup_failures.clone()))
+            .context("Failed to register watch_setup_failures")?;
+
+        let notify_errors = IntCounter::new(
+            "notify_errors_total",
+            "Number of errors reported while watching the watch directories",
+        )?;
+        registry
+            .register(Box::new(notify_errors.clone()))
+            .context("Failed to register notify_errors")?;
+
+        let watch_active = GaugeVec::new(
+            Opts::new(
+                "watch_active",
+                "Whether the watch directory is watched (1) or not (0), e.g. after it was deleted or mounted over and until watching it again succeeds",
+            ),
+            &["directory"],
+        )?;
+        registry
+            .register(Box::new(watch_active.clone()))
+            .context("Failed to register watch_active")?;
+
+        let watched_directory_last_event = GaugeVec::new(
+            Opts::new(
+                "watched_directory_last_event_timestamp_seconds",
+                "Unix timestamp of the last counted event below the watch directory, after debouncing",
+            ),
+            &["directory"],
+        )?;
+        registry
+            .register(Box::new(watched_directory_last_event.clone()))
+            .context("Failed to register watched_directory_last_event")?;
+
+        let disk_info = GaugeVec::new(
+            Opts::new("disk_info", "Identity of the disk, always 1"),
+            &[
+                disk_labels.as_slice(),
+                &["model", "serial", "firmware", "size_bytes"],
+            ]
+            .concat(),
+        )?;
+        registry
+            .register(Box::new(disk_info.clone()))
+            .context("Failed to register disk_info")?;
+
+        let disk_md_array = GaugeVec::new(
+            Opts::new(
+                "disk_md_array",
+                "md arrays the disk is a member of, always 1",
+            ),
+            &[disk_labels.as_slice(), &["array"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_md_array.clone()))
+            .context("Failed to register disk_md_array")?;
+
+        let disk_spindown_attempts = IntCounterVec::new(
+            Opts::new(
+                "disk_spindown_attempts_total",
+                "Number of times the disk was due for a spin-down after being idle by result (success, failure or skipped_policy)",
+            ),
+            &[disk_labels.as_slice(), &["result"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_spindown_attempts.clone()))
+            .context("Failed to register disk_spindown_attempts")?;
+
+        let disk_spindown_actions = IntCounterVec::new(
+            Opts::new(
+                "disk_spindown_actions_total",
+                "Number of times the disk was spun down after being idle by result (success or error)",
+            ),
+            &[disk_labels.as_slice(), &["result"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_spindown_actions.clone()))
+            .context("Failed to register disk_spindown_actions")?;
+
+        let disk_standby_enforcements = IntCounterVec::new(
+            Opts::new(
+                "disk_standby_enforcements_total",
+                "Number of times the disk was put back into standby after waking up without I/O",
+            ),
+            &[disk_labels.as_slice(), &["result"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_standby_enforcements.clone()))
+            .context("Failed to register disk_standby_enforcements")?;
+
+        let disk_early_wakeups = IntCounterVec::new(
+            Opts::new(
+                "disk_early_wakeups_total",
+                "Number of times the disk saw I/O within the early wakeup window after it was spun down",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_early_wakeups.clone()))
+            .context("Failed to register disk_early_wakeups")?;
+
+        let disk_cycle_budget = GaugeVec::new(
+            Opts::new(
+                "disk_spin_cycle_budget_remaining",
+                "Number of times the disk may still be spun down within the last 24 hours",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_cycle_budget.clone()))
+            .context("Failed to register disk_cycle_budget")?;
+
+        let disk_apm_level = GaugeVec::new(
+            Opts::new(
+                "disk_apm_level",
+                "Advanced Power Management level applied to the disk",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_apm_level.clone()))
+            .context("Failed to register disk_apm_level")?;
+
+        let disk_standby_timer = GaugeVec::new(
+            Opts::new(
+                "disk_standby_timer",
+                "Standby timer applied to the disk, encoded like hdparm -S",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_standby_timer.clone()))
+            .context("Failed to register disk_standby_timer")?;
+
+        let disk_load_cycles = IntCounterVec::new(
+            Opts::new(
+                "disk_load_cycles_total",
+                "Load cycle count of the disk from SMART attribute 193",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_load_cycles.clone()))
+            .context("Failed to register disk_load_cycles")?;
+
+        let disk_load_cycles_excessive = GaugeVec::new(
+            Opts::new(
+                "disk_load_cycles_excessive",
+                "1 if the load cycle count increased by more than the warning threshold within 24 hours",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_load_cycles_excessive.clone()))
+            .context("Failed to register disk_load_cycles_excessive")?;
+
+        let disk_smart_attribute = GaugeVec::new(
+            Opts::new(
+                "disk_smart_attribute_raw_value",
+                "Raw value of a SMART attribute, only read while the disk is spinning",
+            ),
+            &[disk_labels.as_slice(), &["id", "attribute"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_smart_attribute.clone()))
+            .context("Failed to register disk_smart_attribute")?;
+
+        let disk_last_io = GaugeVec::new(
+            Opts::new(
+                "disk_last_io_timestamp_seconds",
+                "Unix timestamp of the last diskstats poll that saw I/O on the disk",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_last_io.clone()))
+            .context("Failed to register disk_last_io")?;
+
+        let disk_reads_completed = IntCounterVec::new(
+            Opts::new(
+                "disk_reads_completed_total",
+                "Number of reads completed by the disk according to /proc/diskstats",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_reads_completed.clone()))
+            .context("Failed to register disk_reads_completed")?;
+
+        let disk_writes_completed = IntCounterVec::new(
+            Opts::new(
+                "disk_writes_completed_total",
+                "Number of writes completed by the disk according to /proc/diskstats",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_writes_completed.clone()))
+            .context("Failed to register disk_writes_completed")?;
+
+        let disk_sectors_read = IntCounterVec::new(
+            Opts::new(
+                "disk_sectors_read_total",
+                "Number of 512 byte sectors read from the disk according to /proc/diskstats",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_sectors_read.clone()))
+            .context("Failed to register disk_sectors_read")?;
+
+        let disk_sectors_written = IntCounterVec::new(
+            Opts::new(
+                "disk_sectors_written_total",
+                "Number of 512 byte sectors written to the disk according to /proc/diskstats",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_sectors_written.clone()))
+            .context("Failed to register disk_sectors_written")?;
+
+        let disk_io_in_flight = GaugeVec::new(
+            Opts::new(
+                "disk_io_in_flight",
+                "Number of I/O requests currently in flight on the disk",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_io_in_flight.clone()))
+            .context("Failed to register disk_io_in_flight")?;
+
+        let disk_last_exercise = GaugeVec::new(
+            Opts::new(
+                "disk_last_exercise_timestamp_seconds",
+                "Unix timestamp of the last time the disk was spun up after being idle for the exercise interval",
+            ),
+            &disk_labels,
+        )?;
+        registry
+            .register(Box::new(disk_last_exercise.clone()))
+            .context("Failed to register disk_last_exercise")?;
+
+        let disk_wakeups = IntCounterVec::new(
+            Opts::new(
+                "disk_wakeups_total",
+                "Number of times the disk woke up from standby, by probable cause",
+            ),
+            &[disk_labels.as_slice(), &["cause"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_wakeups.clone()))
+            .context("Failed to register disk_wakeups")?;
+
+        let disk_wakeups_by_process = IntCounterVec::new(
+            Opts::new(
+                "disk_wake_by_process_total",
+                "Number of times the disk woke up from standby, by the process that accessed it first",
+            ),
+            &[disk_labels.as_slice(), &["comm"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_wakeups_by_process.clone()))
+            .context("Failed to register disk_wakeups_by_process")?;
+
+        let disk_policy_notifications = IntCounterVec::new(
+            Opts::new(
+                "disk_policy_notifications_total",
+                "Number of times a notify rule of the config file started matching the disk",
+            ),
+            &[disk_labels.as_slice(), &["rule"]].concat(),
+        )?;
+        registry
+            .register(Box::new(disk_policy_notifications.clone()))
+            .context("Failed to register disk_policy_notifications")?;
+
+        // RSS, CPU time, open fds and threads of the daemon itself
+        registry
+            .register(Box::new(ProcessCollector::for_self()))
+            .context("Failed to register process collector")?;
+
+        let notify_counter = IntCounterVec::new(
+            Opts::new(
+                "notify_events",
+                "Number of events for watched directories by kind (create, modify, remove, access or other), counted for each disk the directory is on",
+            ),
+            &["path", "kind", "disk"],
+        )?;
+
+        registry
+            .register(Box::new(notify_counter.clone()))
+            .context("Failed to register notify_counter")?;
+
+        Ok(Metrics {
+            registry,
+            disk_status,
+            disk_power_state,
+            disk_status_last_change,
+            disk_status_last_update,
+            disk_temperature,
+            last_cycle,
+            shutdown,
+            monitored_disks,
+            watched_directories,
+            power_states: Mutex::new(HashMap::new()),
+            disk_standby_duration,
+            disk_standby_ratio,
+            duty_cycle: Mutex::new(DutyCycle::new(Duration::from_secs(3600))),
+            standby_since: Mutex::new(HashMap::new()),
+            disk_spinups,
+            disk_spindowns,
+            disk_state_seconds,
+            state_samples: Mutex::new(HashMap::new()),
+            disk_power_watts,
+            disk_energy,
+            power_model: PowerModel::default(),
+            disk_status_timeouts,
+            disk_status_errors,
+            disk_enumeration_errors,
+            activity_errors,
+            disk_status_query_duration,
+            disk_status_unsupported,
+            disk_filter_info,
+            config_reloads,
+            watch_setup_failures,
+            notify_errors,
+            watch_active,
+            watched_directory_last_event,
+            disk_info,
+            disk_info_labels: Mutex::new(HashMap::new()),
+            disk_md_array,
+            disk_spindown_attempts,
+            disk_spindown_actions,
+            disk_standby_enforcements,
+            disk_early_wakeups,
+            disk_cycle_budget,
+            disk_apm_level,
+            disk_standby_timer,
+            disk_load_cycles,
+            disk_load_cycles_excessive,
+            disk_smart_attribute,
+            smart_attribute_labels: Mutex::new(HashMap::new()),
+            disk_last_io,
+            disk_reads_completed,
+            disk_writes_completed,
+            disk_sectors_read,
+            disk_sectors_written,
+            disk_io_in_flight,
+            disk_last_exercise,
+            disk_wakeups,
+            disk_wakeups_by_process,
+            wakeup_processes: Mutex::new(HashMap::new()),
+            disk_policy_notifications,
+            notified_rules: Mutex::new(HashMap::new()),
+            wake_causes: Mutex::new(WakeCauses::new(Duration::from_secs(30))),
+            notify_counter,
+            watch_disks: Mutex::new(HashMap::new()),
+            disk_names,
+            disks: Mutex::new(HashSet::new()),
+            sinks: Vec::new(),
+            json_status: None,
+            mqtt: None,
+            audit_log: None,
+            systemd: None,
+            disk_summaries: Mutex::new(BTreeMap::new()),
+            rx,
+            own_io: OwnIo::new(),
+        })
+    }
+
+    pub fn with_power_model(mut self, power_model: PowerModel) -> Self {
+        self.power_model = power_model;
+        self
+    }
+
+    pub fn with_wake_causes(mut self, wake_causes: WakeCauses) -> Self {
+        self.wake_causes = Mutex::new(wake_causes);
+        self
+    }
+
+    /// Export metrics to `sink` on every flush, in addition to any other
+    /// sinks
+    pub fn with_sink(mut self, sink: impl ExportSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Write metrics to `textfile` in the default format
+    pub fn with_textfile(self, textfile: PathBuf) -> Result<Self> {
+        let sink = TextfileSink::new(textfile, &self.registry)?.with_own_io(self.own_io());
+        Ok(self.with_sink(sink))
+    }
+
+    /// Also write a JSON document with the state of each disk on every flush
+    pub fn with_json_status(mut self, path: PathBuf) -> Self {
+        self.json_status = Some(path);
+        self
+    }
+
+    /// Publish to MQTT whenever a disk starts or stops spinning
+    pub fn with_mqtt(mut self, mqtt: MqttQueue) -> Self {
+        self.mqtt = Some(mqtt);
+        self
+    }
+
+    /// Also write each event of the watch directories to an audit log
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(Mutex::new(audit_log.with_own_io(self.own_io())));
+        self
+    }
+
+    /// Tell systemd when the daemon is ready and stopping, and how the disks
+    /// are doing after every cycle
+    pub fn with_systemd(mut self, systemd: SystemdNotifier) -> Self {
+        self.systemd = Some(systemd);
+        self
+    }
+
+    /// The registry with all metrics, e.g. to serve it over HTTP
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// Handle to the bookkeeping of I/O caused by writing metrics
+    pub fn own_io(&self) -> OwnIo {
+        self.own_io.clone()
+    }
+
+    /// Handle messages until the channel closes or a shutdown is requested.
+    /// With the systemd watchdog, this loop pings it, so it notices when
+    /// handling a message hangs.
+    pub fn receive_metrics(&self) -> Result<()> {
+        // Everything else was started before
+        self.notify_systemd("READY=1");
+        let watchdog = self
+            .systemd
+            .as_ref()
+            .and_then(SystemdNotifier::watchdog_interval);
+        let mut next_ping = Instant::now();
+        loop {
+            let res = match watchdog {
+                None => match self.rx.recv() {
+                    Ok(res) => res,
+                    Err(_) => break,
+                },
+                Some(interval) => {
+                    if Instant::now() >= next_ping {
+                        self.notify_systemd("WATCHDOG=1");
+                        next_ping = Instant::now() + interval;
+                    }
+                    match self
+                        .rx
+                        .recv_timeout(next_ping.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(res) => res,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            };
+            if let MetricMessage::Shutdown = res {
+                self.shut_down();
+                break;
+            }
+            self.handle_metrics_message(res)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the messages that are already queued and export one last time
+    fn shut_down(&self) {
+        self.notify_systemd("STOPPING=1");
+        for msg in self.rx.try_iter() {
+            if let Err(err) = self.handle_metrics_message(msg) {
+                error!("Failed to handle message while shutting down: {:?}", err);
+            }
+        }
+        self.shutdown.set(unix_time());
+        self.flush();
+    }
+
+    fn handle_metrics_message(&self, msg: MetricMessage) -> Result<()> {
+        debug!("Received metrics message {:?}", msg);
+        match &msg {
+            MetricMessage::DiskStatus { disk, .. }
+            | MetricMessage::DiskStatusTimeout { disk }
+            | MetricMessage::DiskStatusError { disk }
+            | MetricMessage::Temperature { disk, .. }
+            | MetricMessage::DiskUnsupported { disk }
+            | MetricMessage::DiskInfo { disk, .. }
+            | MetricMessage::SpindownAttempt { disk, .. }
+            | MetricMessage::StandbyEnforced { disk, .. }
+            | MetricMessage::PowerSettings { disk, .. }
+            | MetricMessage::CycleBudget { disk, .. }
+            | MetricMessage::LoadCycles { disk, .. }
+            | MetricMessage::SmartAttributes { disk, .. }
+            | MetricMessage::PolicyNotification { disk, .. }
+            | MetricMessage::Exercised { disk }
+            | MetricMessage::EarlyWakeup { disk }
+            | MetricMessage::QueryDuration { disk, .. }
+            | MetricMessage::Activity(ActivityEvent { disk, .. }) => {
+                self.disks.lock().unwrap().insert(disk.clone());
+            }
+            _ => {}
+        }
+        self.record_wake_causes(&msg);
+        match msg {
+            MetricMessage::DiskStatus { disk, status } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_status
+                    .with_label_values(&label_refs(&labels))
+                    .set(status.gauge_value());
+                self.update_summary(&disk, |summary| {
+                    summary.set_state(status);
+                    summary.last_update = Some(unix_time());
+                });
+                self.disk_status_last_update
+                    .with_label_values(&label_refs(&labels))
+                    .set(unix_time());
+                for state in PowerState::ALL {
+                    let value = if state == status { 1.0 } else { 0.0 };
+                    self.disk_power_state
+                        .with_label_values(&state_label_refs(&labels, state))
+                        .set(value);
+                }
+                // The disk is assumed to have stayed in the previous state
+                // until this query
+                let now = Instant::now();
+                let sample = (now, status);
+                let wattage = self.power_model.wattage(&disk);
+                let energy = self.disk_energy.with_label_values(&label_refs(&labels));
+                if let Some((at, state)) = self
+                    .state_samples
+                    .lock()
+                    .unwrap()
+                    .insert(disk.clone(), sample)
+                {
+                    let seconds = now.duration_since(at).as_secs_f64();
+                    self.disk_state_seconds
+                        .with_label_values(&state_label_refs(&labels, state))
+                        .inc_by(seconds);
+                    if let Some(watts) = wattage.watts(state) {
+                        energy.inc_by(watts * seconds);
+                    }
+                }
+                let standby_ratio = {
+                    let mut duty_cycle = self.duty_cycle.lock().unwrap();
+                    duty_cycle.record(&disk, status, now);
+                    duty_cycle.standby_ratio(&disk, now)
+                };
+                match standby_ratio {
+                    Some(ratio) => self
+                        .disk_standby_ratio
+                        .with_label_values(&label_refs(&labels))
+                        .set(ratio),
+                    None => {
+                        let _ = self
+                            .disk_standby_ratio
+                            .remove_label_values(&label_refs(&labels));
+                    }
+                }
+                match wattage.watts(status) {
+                    Some(watts) => self
+                        .disk_power_watts
+                        .with_label_values(&label_refs(&labels))
+                        .set(watts),
+                    None => {
+                        let _ = self
+                            .disk_power_watts
+                            .remove_label_values(&label_refs(&labels));
+                    }
+                }
+                // A failed query doesn't mean the disk changed its state
+                if let Some(spinning) = status.is_spinning() {
+                    let previous = self
+                        .power_states
+                        .lock()
+                        .unwrap()
+                        .insert(disk.clone(), status);
+                    if previous.is_some_and(|previous| previous != status) {
+                        self.disk_status_last_change
+                            .with_label_values(&label_refs(&labels))
+                            .set(unix_time());
+                        self.update_summary(&disk, |summary| {
+                            summary.last_change = Some(unix_time())
+                        });
+                    }
+                    let was_spinning = previous.and_then(|previous| previous.is_spinning());
+                    if let Some(mqtt) = self
+                        .mqtt
+                        .as_ref()
+                        .filter(|_| was_spinning != Some(spinning))
+                    {
+                        if let Err(err) = mqtt.publish_state(&disk, &labels[0], spinning) {
+                            error!("{:?}", err);
+                        }
+                    }
+                    let spinups = self.disk_spinups.with_label_values(&label_refs(&labels));
+                    let spindowns = self.disk_spindowns.with_label_values(&label_refs(&labels));
+                    match (
+                        previous.and_then(|previous| previous.is_spinning()),
+                        spinning,
+                    ) {
+                        (Some(false), true) => {
+                            spinups.inc();
+                            // Only periods that were seen starting
+                            if let Some(since) = self.standby_since.lock().unwrap().remove(&disk) {
+                                self.disk_standby_duration
+                                    .with_label_values(&label_refs(&labels))
+                                    .observe(now.duration_since(since).as_secs_f64());
+                            }
+                        }
+                        (Some(true), false) => {
+                            spindowns.inc();
+                            self.standby_since.lock().unwrap().insert(disk, now);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            MetricMessage::DiskStatusTimeout { disk } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_status_timeouts
+                    .with_label_values(&label_refs(&labels))
+                    .inc();
+                self.count_status_error(&disk, labels, "timeout");
+            }
+            MetricMessage::Temperature { disk, celsius } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_temperature
+                    .with_label_values(&label_refs(&labels))
+                    .set(celsius);
+                self.update_summary(&disk, |summary| summary.temperature_celsius = Some(celsius));
+            }
+            MetricMessage::DiskStatusError { disk } => {
+                let labels = self.disk_names.labels(&disk);
+                self.count_status_error(&disk, labels, "error");
+            }
+            MetricMessage::EnumerationError => self.disk_enumeration_errors.inc(),
+            MetricMessage::ActivityError => self.activity_errors.inc(),
+            MetricMessage::CycleFinished => {
+                self.last_cycle.set(unix_time());
+                self.notify_systemd(&self.systemd_status());
+            }
+            MetricMessage::DiskUnsupported { disk } => {
+                let labels = self.disk_names.labels(&disk);
+                // The state series would be stuck at unknown forever
+                self.remove_disk_status(&labels);
+                self.disk_status_unsupported
+                    .with_label_values(&label_refs(&labels))
+                    .set(1.0);
+            }
+            MetricMessage::DiskRemoved { disk } => {
+                self.remove_disk(&disk);
+                self.flush();
+            }
+            MetricMessage::SpindownAttempt { disk, result } => {
+                let mut labels = self.disk_names.labels(&disk);
+                labels.push(String::from(result.as_str()));
+                self.disk_spindown_attempts
+                    .with_label_values(&label_refs(&labels))
+                    .inc();
+                let action = match result {
+                    SpindownResult::Success => Some("success"),
+                    SpindownResult::Failure => Some("error"),
+                    SpindownResult::SkippedPolicy => None,
+                };
+                if let Some(action) = action {
+                    labels.pop();
+                    labels.push(String::from(action));
+                    self.disk_spindown_actions
+                        .with_label_values(&label_refs(&labels))
+                        .inc();
+                }
+            }
+            MetricMessage::StandbyEnforced { disk, success } => {
+                let mut labels = self.disk_names.labels(&disk);
+                labels.push(String::from(if success { "success" } else { "error" }));
+                self.disk_standby_enforcements
+                    .with_label_values(&label_refs(&labels))
+                    .inc();
+            }
+            MetricMessage::LoadCycles {
+                disk,
+                count,
+                excessive,
+            } => {
+                let labels = self.disk_names.labels(&disk);
+                let labels = label_refs(&labels);
+                // The counter mirrors the raw value from the disk
+                let counter = self.disk_load_cycles.with_label_values(&labels);
+                counter.inc_by(count.saturating_sub(counter.get()));
+                self.disk_load_cycles_excessive
+                    .with_label_values(&labels)
+                    .set(if excessive { 1.0 } else { 0.0 });
+            }
+            MetricMessage::QueryDuration {
+                disk,
+                backend,
+                duration,
+            } => {
+                let mut labels = self.disk_names.labels(&disk);
+                labels.push(String::from(backend));
+                self.disk_status_query_duration
+                    .with_label_values(&label_refs(&labels))
+                    .observe(duration.as_secs_f64());
+            }
+            MetricMessage::EarlyWakeup { disk } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_early_wakeups
+                    .with_label_values(&label_refs(&labels))
+                    .inc();
+            }
+            MetricMessage::Exercised { disk } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_last_exercise
+                    .with_label_values(&label_refs(&labels))
+                    .set(unix_time());
+            }
+            MetricMessage::PolicyNotification { disk, rule } => {
+                let mut labels = self.disk_names.labels(&disk);
+                labels.push(rule.clone());
+                self.disk_policy_notifications
+                    .with_label_values(&label_refs(&labels))
+                    .inc();
+                self.notified_rules
+                    .lock()
+                    .unwrap()
+                    .entry(disk)
+                    .or_default()
+                    .insert(rule);
+            }
+            MetricMessage::SmartAttributes { disk, attributes } => {
+                let disk_labels = self.disk_names.labels(&disk);
+                let mut all_labels = self.smart_attribute_labels.lock().unwrap();
+                for labels in all_labels.remove(&disk).into_iter().flatten() {
+                    let _ = self
+                        .disk_smart_attribute
+                        .remove_label_values(&label_refs(&labels));
+                }
+                let mut attribute_labels = Vec::new();
+                for (id, attribute) in attributes {
+                    let mut labels = disk_labels.clone();
+                    labels.extend([id.to_string(), attribute.name]);
+                    self.disk_smart_attribute
+                        .with_label_values(&label_refs(&labels))
+                        .set(attribute.raw as f64);
+                    attribute_labels.push(labels);
+                }
+                all_labels.insert(disk, attribute_labels);
+            }
+            MetricMessage::CycleBudget { disk, remaining } => {
+                let labels = self.disk_names.labels(&disk);
+                self.disk_cycle_budget
+                    .with_label_values(&label_refs(&labels))
+                    .set(remaining.into());
+            }
+            MetricMessage::PowerSettings { disk, settings } => {
+                let labels = self.disk_names.labels(&disk);
+                let labels = label_refs(&labels);
+                for (gauge, value) in [
+                    (&self.disk_apm_level, settings.apm),
+                    (&self.disk_standby_timer, settings.standby_timer),
+                ] {
+                    match value {
+                        Some(value) => gauge.with_label_values(&labels).set(value.into()),
+                        None => {
+                            let _ = gauge.remove_label_values(&labels);
+                        }
+                    }
+                }
+            }
+            MetricMessage::Activity(event) => {
+                let labels = self.disk_names.labels(&event.disk);
+                let labels = label_refs(&labels);
+                if event.is_active() {
+                    self.disk_last_io
+                        .with_label_values(&labels)
+                        .set(unix_time());
+                }
+                // The counters mirror the totals from diskstats, including
+                // our own writes
+                let stats = &event.stats;
+                for (counter, total) in [
+                    (&self.disk_reads_completed, stats.reads_completed),
+                    (&self.disk_writes_completed, stats.writes_completed),
+                    (&self.disk_sectors_read, stats.sectors_read),
+                    (&self.disk_sectors_written, stats.sectors_written),
+                ] {
+                    let counter = counter.with_label_values(&labels);
+                    counter.inc_by(total.saturating_sub(counter.get()));
+                }
+                self.disk_io_in_flight
+                    .with_label_values(&labels)
+                    .set(stats.in_flight as f64);
+            }
+            MetricMessage::EnumeratedDisks(enumerated) => {
+                self.monitored_disks.set(enumerated.len() as f64);
+                let stale: Vec<String> = self
+                    .disks
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|disk| !enumerated.contains(disk))
+                    .cloned()
+                    .collect();
+                for disk in &stale {
+                    debug!("{} is no longer enumerated, removing its metrics", disk);
+                    self.remove_disk(disk);
+                }
+                // Don't leave the stale series around until the next flush
+                if !stale.is_empty() {
+                    self.flush();
+                }
+            }
+            MetricMessage::DiskInfo { disk, info } => {
+                let mut labels = self.disk_names.labels(&disk);
+                labels.extend([
+                    info.model,
+                    info.serial,
+                    info.firmware,
+                    info.size_bytes.to_string(),
+                ]);
+                let mut all_labels = self.disk_info_labels.lock().unwrap();
+                if let Some(previous) = all_labels.remove(&disk) {
+                    let _ = self.disk_info.remove_label_values(&label_refs(&previous));
+                }
+                self.disk_info
+                    .with_label_values(&label_refs(&labels))
+                    .set(1.0);
+                all_labels.insert(disk, labels);
+            }
+            MetricMessage::MdArrays(arrays) => {
+                self.disk_md_array.reset();
+                for (array, disks) in arrays {
+                    for disk in disks {
+                        let mut labels = self.disk_names.labels(&disk);
+                        labels.push(array.clone());
+                        self.disk_md_array
+                            .with_label_values(&label_refs(&labels))
+                            .set(1.0);
+                    }
+                }
+            }
+            MetricMessage::WatchedDirectories(watches) => {
+                self.watched_directories.set(watches.len() as f64);
+                let watches: HashMap<String, Vec<String>> = watches.iter().cloned().collect();
+                let mut watch_disks = self.watch_disks.lock().unwrap();
+                // Directories that aren't watched anymore, or are on other
+                // disks after a remount
+                for (path, disks) in watch_disks.iter() {
+                    if watches.get(path) != Some(disks) {
+                        self.remove_notify_series(path, disks);
+                    }
+                    if !watches.contains_key(path) {
+                        let _ = self.watch_active.remove_label_values(&[path]);
+                        let _ = self
+                            .watched_directory_last_event
+                            .remove_label_values(&[path]);
+                    }
+                }
+                *watch_disks = watches;
+            }
+            MetricMessage::DiskFilter { include, exclude } => {
+                self.disk_filter_info.reset();
+                self.disk_filter_info
+                    .with_label_values(&[&include.join(","), &exclude.join(",")])
+                    .set(1.0);
+            }
+            MetricMessage::ConfigReloaded { success } => self
+                .config_reloads
+                .with_label_values(&[if success { "success" } else { "failure" }])
+                .inc(),
+            MetricMessage::WatchSetupFailed { watch_limit } => self
+                .watch_setup_failures
+                .with_label_values(&[if watch_limit { "watch_limit" } else { "error" }])
+                .inc(),
+            MetricMessage::WatchActive { directory, active } => self
+                .watch_active
+                .with_label_values(&[&directory])
+                .set(if active { 1.0 } else { 0.0 }),
+            MetricMessage::NotifyEvent(Ok(event)) => {
+                let disks = self
+                    .watch_disks
+                    .lock()
+                    .unwrap()
+                    .get(&event.path)
+                    .cloned()
+                    .unwrap_or_default();
+                for disk in self.notify_disk_labels(&disks) {
+                    self.notify_counter
+                        .with_label_values(&[event.path.as_str(), event.kind_label(), &disk])
+                        .inc();
+                }
+                self.watched_directory_last_event
+                    .with_label_values(&[&event.path])
+                    .set(unix_time());
+                if let Some(audit_log) = &self.audit_log {
+                    let mut audit_log = audit_log.lock().unwrap();
+                    if let Err(err) = audit_log.record(&event, SystemTime::now(), Instant::now()) {
+                        error!("Failed to write audit log: {:?}", err);
+                    }
+                }
+            }
+            // The directories are watched again by the reload thread
+            MetricMessage::NotifyEvent(Err(err)) => {
+                error!("Error from notify event: {:?}", err);
+                self.notify_errors.inc();
+            }
+            // Only feeds the wake cause correlation
+            MetricMessage::ProcessAccess { .. } => {}
+            MetricMessage::Flush => self.flush(),
+            // Only ends receive_metrics
+            MetricMessage::Shutdown => {}
+        }
+        Ok(())
+    }
+
+    /// Feed `msg` to the wake cause correlation and count the wakeups that
+    /// could be explained by now
+    fn record_wake_causes(&self, msg: &MetricMessage) {
+        let now = Instant::now();
+        let mut wake_causes = self.wake_causes.lock().unwrap();
+        match msg {
+            MetricMessage::DiskStatus { disk, status } => {
+                wake_causes.record_state(disk, *status, now)
+            }
+            MetricMessage::Activity(event) if event.is_active() => {
+                wake_causes.record_io(&event.disk, now)
+            }
+            MetricMessage::NotifyEvent(Ok(event)) => wake_causes.record_notify(&event.path, now),
+            MetricMessage::ProcessAccess { disk, comm } => {
+                wake_causes.record_process(disk, comm, now)
+            }
+            MetricMessage::DiskRemoved { disk } => wake_causes.forget(disk),
+            _ => {}
+        }
+        for wakeup in wake_causes.resolve(now) {
+            let disk_labels = self.disk_names.labels(&wakeup.disk);
+            let cause = wakeup.cause.to_string();
+            let mut labels = disk_labels.clone();
+            labels.push(cause.clone());
+            self.disk_wakeups
+                .with_label_values(&label_refs(&labels))
+                .inc();
+            let Some(comm) = wakeup.process else {
+                info!(
+                    disk = wakeup.disk.as_str();
+                    "Disk woke up: disk={} cause={}", wakeup.disk, cause
+                );
+                continue;
+            };
+            info!(
+                disk = wakeup.disk.as_str();
+                "Disk woke up: disk={} cause={} process={}",
+                wakeup.disk, cause, comm
+            );
+            let mut labels = disk_labels;
+            labels.push(comm.clone());
+            self.disk_wakeups_by_process
+                .with_label_values(&label_refs(&labels))
+                .inc();
+            self.wakeup_processes
+                .lock()
+                .unwrap()
+                .entry(wakeup.disk)
+                .or_default()
+                .insert(comm);
+        }
+    }
+
+    fn count_status_error(&self, disk: &str, mut labels: Vec<String>, kind: &'static str) {
+        labels.push(String::from(kind));
+        self.disk_status_errors
+            .with_label_values(&label_refs(&labels))
+            .inc();
+        self.update_summary(disk, |summary| {
+            *summary.errors.entry(kind).or_default() += 1
+        });
+    }
+
+    /// Change what the JSON status says about `disk`
+    fn update_summary(&self, disk: &str, update: impl FnOnce(&mut DiskSummary)) {
+        let mut summaries = self.disk_summaries.lock().unwrap();
+        let summary = summaries.entry(disk.to_string()).or_insert_with(|| {
+            let names = self.disk_names.label_names().into_iter().map(String::from);
+            DiskSummary {
+                labels: names.zip(self.disk_names.labels(disk)).collect(),
+                ..Default::default()
+            }
+        });
+        update(summary);
+    }
+
+    /// Values of the `disk` label of `notify_events` for a directory on
+    /// `disks`. Directories on unknown disks are counted without one.
+    fn notify_disk_labels(&self, disks: &[String]) -> Vec<String> {
+        if disks.is_empty() {
+            return vec![String::new()];
+        }
+        disks
+            .iter()
+            .map(|disk| self.disk_names.labels(disk).remove(0))
+            .collect()
+    }
+
+    /// Drop the `notify_events` series of a directory that was on `disks`
+    fn remove_notify_series(&self, path: &str, disks: &[String]) {
+        for disk in self.notify_disk_labels(disks) {
+            for kind in WatchEvent::KIND_LABELS {
+                let _ = self
+                    .notify_counter
+                    .remove_label_values(&[path, kind, &disk]);
+            }
+        }
+    }
+
+    /// Drop all series of a disk that's gone
+    fn remove_disk(&self, disk: &str) {
+        let labels = self.disk_names.labels(disk);
+        self.disk_summaries.lock().unwrap().remove(disk);
+        let causes = self.wake_causes.lock().unwrap().cause_labels();
+        for cause in causes {
+            let mut labels = labels.clone();
+            labels.push(cause);
+            let _ = self.disk_wakeups.remove_label_values(&label_refs(&labels));
+        }
+        let processes = self.wakeup_processes.lock().unwrap().remove(disk);
+        for comm in processes.into_iter().flatten() {
+            let mut labels = labels.clone();
+            labels.push(comm);
+            let _ = self
+                .disk_wakeups_by_process
+                .remove_label_values(&label_refs(&labels));
+        }
+        let rules = self.notified_rules.lock().unwrap().remove(disk);
+        for rule in rules.into_iter().flatten() {
+            let mut labels = labels.clone();
+            labels.push(rule);
+            let _ = self
+                .disk_policy_notifications
+                .remove_label_values(&label_refs(&labels));
+        }
+        for kind in ["timeout", "error"] {
+            let mut labels = labels.clone();
+            labels.push(String::from(kind));
+            let _ = self
+                .disk_status_errors
+                .remove_label_values(&label_refs(&labels));
+        }
+        for backend in Backend::NAMES {
+            let mut labels = labels.clone();
+            labels.push(String::from(backend));
+            let _ = self
+                .disk_status_query_duration
+                .remove_label_values(&label_refs(&labels));
+        }
+        self.remove_disk_status(&labels);
+        let _ = self
+            .disk_status_timeouts
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_status_unsupported
+            .remove_label_values(&label_refs(&labels));
+        let _ = self.disk_last_io.remove_label_values(&label_refs(&labels));
+        for counter in [
+            &self.disk_reads_completed,
+            &self.disk_writes_completed,
+            &self.disk_sectors_read,
+            &self.disk_sectors_written,
+        ] {
+            let _ = counter.remove_label_values(&label_refs(&labels));
+        }
+        let _ = self
+            .disk_io_in_flight
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_temperature
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_last_exercise
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_early_wakeups
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_apm_level
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_cycle_budget
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_load_cycles
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_load_cycles_excessive
+            .remove_label_values(&label_refs(&labels));
+        let _ = self
+            .disk_standby_timer
+            .remove_label_values(&label_refs(&labels));
+        for result in SpindownResult::ALL {
+            let mut labels = labels.clone();
+            labels.push(String::from(result.as_str()));
+            let _ = self
+                .disk_spindown_attempts
+                .remove_label_values(&label_refs(&labels));
+        }
+        for result in ["success", "error"] {
+            let mut labels = labels.clone();
+            labels.push(String::from(result));
+            let _ = self
+                .disk_spindown_actions
+                .remove_label_values(&label_refs(&labels));
+            let _ = self
+                .disk_standby_enforcements
+                .remove_label_values(&label_refs(&labels));
+        }
+        let smart_labels = self.smart_attribute_labels.lock().unwrap().remove(disk);
+        for labels in smart_labels.into_iter().flatten() {
+            let _ = self
+                .disk_smart_attribute
+                .remove_label_values(&label_refs(&labels));
+        }
+        if let Some(labels) = self.disk_info_labels.lock().unwrap().remove(disk) {
+            let _ = self.disk_info.remove_label_values(&label_refs(&labels));
+        }
+        self.power_states.lock().unwrap().remove(disk);
+        self.standby_since.lock().unwrap().remove(disk);
+        self.duty_cycle.lock().unwrap().forget(disk);
+        self.state_samples.lock().unwrap().remove(disk);
+        self.disk_names.forget(disk);
+        self.disks.lock().unwrap().remove(disk);
+    }
+
+    fn remove_disk_status(&self, labels: &[String]) {
+        let _ = self.disk_status.remove_label_values(&label_refs(labels));
+        let _ = self
+            .disk_status_last_change
+            .remove_label_values(&label_refs(labels));
+        let _ = self
+            .disk_status_last_update
+            .remove_label_values(&label_refs(labels));
+        let _ = self.disk_spinups.remove_label_values(&label_refs(labels));
+        let _ = self.disk_spindowns.remove_label_values(&label_refs(labels));
+        let _ = self
+            .disk_power_watts
+            .remove_label_values(&label_refs(labels));
+        let _ = self.disk_energy.remove_label_values(&label_refs(labels));
+        let _ = self
+            .disk_standby_duration
+            .remove_label_values(&label_refs(labels));
+        let _ = self
+            .disk_standby_ratio
+            .remove_label_values(&label_refs(labels));
+        for state in PowerState::ALL {
+            let _ = self
+                .disk_power_state
+                .remove_label_values(&state_label_refs(labels, state));
+            let _ = self
+                .disk_state_seconds
+                .remove_label_values(&state_label_refs(labels, state));
+        }
+    }
+
+    /// Send `state` to systemd if it supervises us
+    fn notify_systemd(&self, state: &str) {
+        if let Some(systemd) = &self.systemd {
+            if let Err(err) = systemd.notify(state) {
+                error!("{:?}", err);
+            }
+        }
+    }
+
+    /// The status `systemctl status` shows, like `STATUS=Monitoring 4 disks,
+    /// 1 spinning. Last cycle at 2024-06-01T12:00:00Z`
+    fn systemd_status(&self) -> String {
+        let spinning = self
+            .power_states
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|state| state.is_spinning() == Some(true))
+            .count();
+        format!(
+            "STATUS=Monitoring {} disks, {} spinning. Last cycle at {}",
+            self.monitored_disks.get(),
+            spinning,
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        )
+    }
+
+    /// Export to all sinks. A failing sink doesn't keep the others from
+    /// getting the metrics.
+    fn flush(&self) {
+        for sink in &self.sinks {
+            if let Err(err) = sink.export(&self.registry) {
+                error!("Failed to export metrics to {}: {:?}", sink.name(), err);
+            }
+        }
+        if let Some(path) = &self.json_status {
+            let summaries = self.disk_summaries.lock().unwrap();
+            if let Err(err) = write_json_status(path, unix_time(), &summaries) {
+                error!("{:?}", err);
+            } else if let Err(err) = self.own_io.record_file(path) {
+                debug!("Failed to record JSON status write: {:?}", err);
+            }
+        }
+    }
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn label_refs(labels: &[String]) -> Vec<&str> {
+    labels.iter().map(String::as_str).collect()
+}
+
+fn state_label_refs(labels: &[String], state: PowerState) -> Vec<&str> {
+    let mut labels = label_refs(labels);
+    labels.push(state.as_str());
+    labels
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{fs, path::Path};
+
+    use anyhow::anyhow;
+    use tempfile::TempDir;
+
+    use crate::{
+        disk_status::{test::FakeHdparm, update_disk_status, DiskRetries, RetryPolicy},
+        disks::{
+            test::{fake_sysfs, FakeBlockDevice},
+            DiskList, SysBlock,
+        },
+        diskstats::DiskStats,
+        watch,
+    };
+
+    use super::*;
+
+    pub fn init() {
+        let _ = env_logger::builder()
+            .filter_level(log::LevelFilter::Debug)
+            .is_test(true)
+            .try_init();
+    }
+
+    /// The textfile without the series that depend on the current time, the
+    /// build or the test process
+    fn read_comparable(textfile: &Path) -> String {
+        fs::read_to_string(textfile)
+            .unwrap()
+            .lines()
+            .filter(|line| {
+                !line.contains("_timestamp_seconds")
+                    && !line.contains("build_info")
+                    && !line.contains("textfile_write_duration")
+                    && !line.starts_with("process_")
+                    && !line.starts_with("# HELP process_")
+                    && !line.starts_with("# TYPE process_")
+            })
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    #[test]
+    fn test_metrics() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        tx.send(MetricMessage::DiskStatus {
+            disk: String::from("/dev/sda"),
+            status: PowerState::Active,
+        })
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+
+        // Close sender
+        drop(tx);
+
+        // receive single metric
+        metrics.receive_metrics().unwrap();
+
+        // compare results
+        let disk_metrics = read_comparable(&textfile);
+        let expected = String::from(
+            "# HELP activity_errors_total Number of times polling disk activity or acting on it failed
+# TYPE activity_errors_total counter
+activity_errors_total 0
+# HELP disk_energy_joules_total Estimated energy used by the disk, counted between status queries
+# TYPE disk_energy_joules_total counter
+disk_energy_joules_total{disk=\"/dev/sda\"} 0
+# HELP disk_enumeration_errors_total Number of times listing the disks failed
+# TYPE disk_enumeration_errors_total counter
+disk_enumeration_errors_total 0
+# HELP disk_power_state Power state of the disk as reported by the backend (1 for the current state)
+# TYPE disk_power_state gauge
+disk_power_state{disk=\"/dev/sda\",state=\"active\"} 1
+disk_power_state{disk=\"/dev/sda\",state=\"idle\"} 0
+disk_power_state{disk=\"/dev/sda\",state=\"sleeping\"} 0
+disk_power_state{disk=\"/dev/sda\",state=\"standby\"} 0
+disk_power_state{disk=\"/dev/sda\",state=\"unknown\"} 0
+# HELP disk_power_watts Estimated power draw of the disk in its current state
+# TYPE disk_power_watts gauge
+disk_power_watts{disk=\"/dev/sda\"} 6
+# HELP disk_spindowns_total Number of times the disk was seen in standby after it was spinning
+# TYPE disk_spindowns_total counter
+disk_spindowns_total{disk=\"/dev/sda\"} 0
+# HELP disk_spinups_total Number of times the disk was seen spinning after it was in standby
+# TYPE disk_spinups_total counter
+disk_spinups_total{disk=\"/dev/sda\"} 0
+# HELP disk_status Status of the disk (1=active, 0=standby, -1=unknown)
+# TYPE disk_status gauge
+disk_status{disk=\"/dev/sda\"} 1
+# HELP monitored_disks Number of disks found by the latest enumeration
+# TYPE monitored_disks gauge
+monitored_disks 0
+# HELP notify_errors_total Number of errors reported while watching the watch directories
+# TYPE notify_errors_total counter
+notify_errors_total 0
+# HELP textfile_write_failures_total Number of times writing the textfile failed
+# TYPE textfile_write_failures_total counter
+textfile_write_failures_total 0
+# HELP watched_directories Number of directories watched for notify events
+# TYPE watched_directories gauge
+watched_directories 0\n",
+        );
+        assert_eq!(disk_metrics, expected);
+        let raw = fs::read_to_string(&textfile).unwrap();
+        let build_info = format!("version=\"{}\"}} 1", env!("CARGO_PKG_VERSION"));
+        assert!(raw.contains(&build_info));
+        assert!(raw.contains("\nprocess_threads "));
+        assert!(raw.contains("\nprocess_resident_memory_bytes "));
+    }
+
+    #[test]
+    fn test_state_changes() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+        let status = |status| MetricMessage::DiskStatus {
+            disk: String::from("/dev/sda"),
+            status,
+        };
+        let last_change = || {
+            metrics
+                .disk_status_last_change
+                .get_metric_with_label_values(&["/dev/sda"])
+                .map(|gauge| gauge.get())
+                .unwrap()
+        };
+
+        // nothing changed yet
+        metrics
+            .handle_metrics_message(status(PowerState::Active))
+            .unwrap();
+        metrics
+            .handle_metrics_message(status(PowerState::Active))
+            .unwrap();
+        metrics
+            .handle_metrics_message(status(PowerState::Unknown))
+            .unwrap();
+        assert_eq!(last_change(), 0.0);
+
+        metrics
+            .handle_metrics_message(status(PowerState::Standby))
+            .unwrap();
+        let changed = last_change();
+        assert!(changed > 0.0);
+        metrics
+            .handle_metrics_message(status(PowerState::Unknown))
+            .unwrap();
+        metrics
+            .handle_metrics_message(status(PowerState::Standby))
+            .unwrap();
+        assert_eq!(last_change(), changed);
+        metrics
+            .handle_metrics_message(status(PowerState::Idle))
+            .unwrap();
+        let count = |counter: &IntCounterVec| counter.with_label_values(&["/dev/sda"]).get();
+        assert_eq!(count(&metrics.disk_spindowns), 1);
+        assert_eq!(count(&metrics.disk_spinups), 1);
+        let standby = metrics
+            .disk_standby_duration
+            .with_label_values(&["/dev/sda"]);
+        assert_eq!(standby.get_sample_count(), 1);
+        assert!(standby.get_sample_sum() > 0.0);
 
-        registry
-            .register(Box::new(notify_counter.clone()))
-            .context("Failed to register notify_counter")?;
+        let seconds = |state: PowerState| {
+            metrics
+                .disk_state_seconds
+                .with_label_values(&["/dev/sda", state.as_str()])
+                .get()
+        };
+        assert!(seconds(PowerState::Active) > 0.0);
+        assert!(seconds(PowerState::Standby) > 0.0);
+        assert!(seconds(PowerState::Unknown) > 0.0);
+        assert_eq!(seconds(PowerState::Idle), 0.0);
 
-        Ok(Metrics {
-            registry,
-            disk_status,
-            notify_counter,
-            textfile,
-            rx,
-        })
+        let last_update = metrics
+            .disk_status_last_update
+            .with_label_values(&["/dev/sda"])
+            .get();
+        assert!(last_update >= changed);
+        drop(tx);
     }
 
-    pub fn receive_metrics(&self) -> Result<()> {
-        for res in self.rx.iter() {
-            self.handle_metrics_message(res)?;
+    #[test]
+    fn test_disk_removed() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        for disk in ["/dev/sda", "/dev/sdb"] {
+            tx.send(MetricMessage::DiskStatus {
+                disk: String::from(disk),
+                status: PowerState::Standby,
+            })
+            .unwrap();
+            tx.send(MetricMessage::DiskInfo {
+                disk: String::from(disk),
+                info: DiskInfo {
+                    model: String::from("WDC WD40EFRX-68N"),
+                    size_bytes: 4000787030016,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
         }
-        Ok(())
+        tx.send(MetricMessage::DiskRemoved {
+            disk: String::from("/dev/sdb"),
+        })
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk_info{disk=\"/dev/sda\",firmware=\"\",model=\"WDC WD40EFRX-68N\",serial=\"\",size_bytes=\"4000787030016\"} 1"));
+        assert!(!disk_metrics.contains("disk=\"/dev/sdb\""));
     }
 
-    fn handle_metrics_message(&self, msg: MetricMessage) -> Result<()> {
-        debug!("Received metrics message {:?}", msg);
-        match msg {
-            MetricMessage::DiskStatus { disk, status } => {
-                self.disk_status.with_label_values(&[&disk]).set(status)
-            }
-            MetricMessage::NotifyEvent(Ok(base_path)) => self
-                .notify_counter
-                .with_label_values(&[base_path.as_str()])
-                .inc(),
-            MetricMessage::NotifyEvent(Err(err)) => {
-                error!("Error from notify event: {:?}", err);
-                return Err(anyhow!(err));
-            }
-            MetricMessage::SaveFile => self.write_textfile()?,
+    #[test]
+    fn test_spindown_attempts() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        for result in SpindownResult::ALL {
+            tx.send(MetricMessage::SpindownAttempt {
+                disk: String::from("/dev/sda"),
+                result,
+            })
+            .unwrap();
         }
-        Ok(())
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        for line in [
+            "disk_spindown_attempts_total{disk=\"/dev/sda\",result=\"success\"} 1",
+            "disk_spindown_attempts_total{disk=\"/dev/sda\",result=\"failure\"} 1",
+            "disk_spindown_attempts_total{disk=\"/dev/sda\",result=\"skipped_policy\"} 1",
+            "disk_spindown_actions_total{disk=\"/dev/sda\",result=\"success\"} 1",
+            "disk_spindown_actions_total{disk=\"/dev/sda\",result=\"error\"} 1",
+        ] {
+            assert!(disk_metrics.contains(line), "{} missing", line);
+        }
+        assert!(!disk_metrics
+            .contains("disk_spindown_actions_total{disk=\"/dev/sda\",result=\"skipped_policy\"}"));
     }
 
-    fn write_textfile(&self) -> Result<()> {
-        let textfile = fs::File::create(&self.textfile).with_context(|| {
-            format!(
-                "Failed to create textfile: {}",
-                &self.textfile.to_string_lossy()
-            )
-        })?;
-        let mut textfile = BufWriter::new(textfile);
-        let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
-        encoder
-            .encode(&metric_families, &mut textfile)
-            .context("Failed to encode metrics into textfile")?;
-        Ok(())
+    #[test]
+    fn test_disk_names() {
+        init();
+        let by_id_dir = TempDir::new().unwrap();
+        std::os::unix::fs::symlink("../../sda", by_id_dir.path().join("ata-WDC_WD40EFRX_1234"))
+            .unwrap();
+        let disk_names = DiskNames::new(DiskNaming::ById, true).with_by_id_dir(by_id_dir.path());
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::with_disk_names(rx, disk_names)
+            .unwrap()
+            .with_textfile(textfile.to_path_buf())
+            .unwrap();
+
+        tx.send(MetricMessage::DiskStatus {
+            disk: String::from("/dev/sda"),
+            status: PowerState::Standby,
+        })
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        let by_id = by_id_dir.path().join("ata-WDC_WD40EFRX_1234");
+        assert!(disk_metrics.contains(&format!(
+            "disk_status{{device=\"/dev/sda\",disk=\"{}\"}} 0",
+            by_id.to_string_lossy()
+        )));
     }
-}
 
-#[cfg(test)]
-pub mod test {
-    use std::fs;
+    #[test]
+    fn test_stale_disks_removed() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
 
-    use tempfile::TempDir;
+        for disk in ["/dev/sda", "/dev/sdb"] {
+            tx.send(MetricMessage::DiskStatus {
+                disk: String::from(disk),
+                status: PowerState::Active,
+            })
+            .unwrap();
+        }
+        tx.send(MetricMessage::Flush).unwrap();
+        tx.send(MetricMessage::EnumeratedDisks(vec![String::from(
+            "/dev/sda",
+        )]))
+        .unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
 
-    use crate::{
-        disk_status::{test::FakeHdparm, update_disk_status},
-        lsblk::test::FakeLsblk,
-        watch,
-    };
+        // rewritten without waiting for the next save
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk=\"/dev/sda\""));
+        assert!(!disk_metrics.contains("disk=\"/dev/sdb\""));
+        assert!(disk_metrics.contains("\nmonitored_disks 1\n"));
+    }
 
-    use super::*;
+    #[test]
+    fn test_smart_attributes() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+        let attribute = |id: u8, name: &str, raw: u64| {
+            let attribute = SmartAttribute {
+                name: String::from(name),
+                raw,
+            };
+            (id, attribute)
+        };
 
-    pub fn init() {
-        let _ = env_logger::builder()
-            .filter_level(log::LevelFilter::Debug)
-            .is_test(true)
-            .try_init();
+        tx.send(MetricMessage::SmartAttributes {
+            disk: String::from("/dev/sda"),
+            attributes: BTreeMap::from([
+                attribute(5, "Reallocated_Sector_Ct", 0),
+                attribute(9, "Power_On_Hours", 28011),
+            ]),
+        })
+        .unwrap();
+        // the vendor renamed the attribute in a firmware update
+        tx.send(MetricMessage::SmartAttributes {
+            disk: String::from("/dev/sda"),
+            attributes: BTreeMap::from([
+                attribute(5, "Reallocated_Sector_Count", 8),
+                attribute(9, "Power_On_Hours", 28012),
+            ]),
+        })
+        .unwrap();
+        tx.send(MetricMessage::SmartAttributes {
+            disk: String::from("/dev/sdb"),
+            attributes: BTreeMap::from([attribute(9, "Power_On_Hours", 100)]),
+        })
+        .unwrap();
+        tx.send(MetricMessage::DiskRemoved {
+            disk: String::from("/dev/sdb"),
+        })
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk_smart_attribute_raw_value{attribute=\"Reallocated_Sector_Count\",disk=\"/dev/sda\",id=\"5\"} 8"));
+        assert!(disk_metrics.contains("disk_smart_attribute_raw_value{attribute=\"Power_On_Hours\",disk=\"/dev/sda\",id=\"9\"} 28012"));
+        assert!(!disk_metrics.contains("Reallocated_Sector_Ct\""));
+        assert!(!disk_metrics.contains("/dev/sdb"));
     }
 
     #[test]
-    fn test_metrics() {
+    fn test_diskstats() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+        let activity = |reads_completed: u64, sectors_written: u64, in_flight: u64| {
+            MetricMessage::Activity(ActivityEvent {
+                disk: String::from("/dev/sda"),
+                reads: 0,
+                writes: 0,
+                sectors_read: 0,
+                sectors_written: 0,
+                stats: DiskStats {
+                    reads_completed,
+                    sectors_written,
+                    in_flight,
+                    ..Default::default()
+                },
+            })
+        };
+
+        tx.send(activity(10, 800, 2)).unwrap();
+        tx.send(activity(12, 1600, 0)).unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk_reads_completed_total{disk=\"/dev/sda\"} 12\n"));
+        assert!(disk_metrics.contains("disk_writes_completed_total{disk=\"/dev/sda\"} 0\n"));
+        assert!(disk_metrics.contains("disk_sectors_written_total{disk=\"/dev/sda\"} 1600\n"));
+        assert!(disk_metrics.contains("disk_io_in_flight{disk=\"/dev/sda\"} 0\n"));
+    }
+
+    #[test]
+    fn test_shutdown() {
         init();
         let textfile_dir = TempDir::new().unwrap();
         let textfile = textfile_dir.path().join("disk_status.prom");
         let (tx, rx) = std::sync::mpsc::channel();
         let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+        tx.send(MetricMessage::Shutdown).unwrap();
+        // queued before the receiver got to the shutdown
+        tx.send(MetricMessage::DiskStatus {
+            disk: String::from("/dev/sda"),
+            status: PowerState::Standby,
+        })
+        .unwrap();
+        // returns although the channel is still open
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk_status{disk=\"/dev/sda\"} 0"));
+        assert!(disk_metrics.contains("disk_spin_manager_shutdown_timestamp_seconds "));
+        drop(tx);
+    }
 
+    #[test]
+    fn test_systemd() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let socket = textfile_dir.path().join("notify");
+        let systemd = std::os::unix::net::UnixDatagram::bind(&socket).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx)
+            .unwrap()
+            .with_systemd(SystemdNotifier::new(socket.as_os_str()).unwrap());
+        tx.send(MetricMessage::EnumeratedDisks(vec![
+            String::from("/dev/sda"),
+            String::from("/dev/sdb"),
+        ]))
+        .unwrap();
         tx.send(MetricMessage::DiskStatus {
             disk: String::from("/dev/sda"),
-            status: 1.0,
+            status: PowerState::Idle,
         })
         .unwrap();
-        tx.send(MetricMessage::SaveFile).unwrap();
+        tx.send(MetricMessage::CycleFinished).unwrap();
+        tx.send(MetricMessage::Shutdown).unwrap();
+        metrics.receive_metrics().unwrap();
 
-        // Close sender
+        let mut buffer = [0; 256];
+        let mut states = Vec::new();
+        for _ in 0..3 {
+            let len = systemd.recv(&mut buffer).unwrap();
+            states.push(String::from_utf8_lossy(&buffer[..len]).to_string());
+        }
+        assert_eq!(states[0], "READY=1");
+        assert!(
+            states[1].starts_with("STATUS=Monitoring 2 disks, 1 spinning. Last cycle at "),
+            "{}",
+            states[1]
+        );
+        assert_eq!(states[2], "STOPPING=1");
+    }
+
+    #[test]
+    fn test_systemd_watchdog() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let socket = textfile_dir.path().join("notify");
+        let systemd = std::os::unix::net::UnixDatagram::bind(&socket).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let notifier = SystemdNotifier::new(socket.as_os_str())
+            .unwrap()
+            .with_watchdog(Duration::from_millis(100));
+        let metrics = Metrics::new(textfile.to_path_buf(), rx)
+            .unwrap()
+            .with_systemd(notifier);
+        std::thread::spawn(move || {
+            // no messages for a while, pings are due anyway
+            std::thread::sleep(Duration::from_millis(180));
+            tx.send(MetricMessage::Shutdown).unwrap();
+        });
+        metrics.receive_metrics().unwrap();
+
+        systemd.set_nonblocking(true).unwrap();
+        let mut buffer = [0; 256];
+        let mut states = Vec::new();
+        while let Ok(len) = systemd.recv(&mut buffer) {
+            states.push(String::from_utf8_lossy(&buffer[..len]).to_string());
+        }
+        assert_eq!(states.first().unwrap(), "READY=1");
+        assert_eq!(states.last().unwrap(), "STOPPING=1");
+        let pings = states.iter().filter(|state| *state == "WATCHDOG=1").count();
+        assert!(pings >= 3, "{:?}", states);
+    }
+
+    #[test]
+    fn test_json_status() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let json_status = textfile_dir.path().join("status.json");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx)
+            .unwrap()
+            .with_json_status(json_status.clone());
+        for (disk, status) in [
+            ("/dev/sda", PowerState::Active),
+            ("/dev/sda", PowerState::Standby),
+            ("/dev/sdb", PowerState::Idle),
+        ] {
+            tx.send(MetricMessage::DiskStatus {
+                disk: String::from(disk),
+                status,
+            })
+            .unwrap();
+        }
+        tx.send(MetricMessage::DiskStatusTimeout {
+            disk: String::from("/dev/sda"),
+        })
+        .unwrap();
+        tx.send(MetricMessage::DiskRemoved {
+            disk: String::from("/dev/sdb"),
+        })
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
         drop(tx);
+        metrics.receive_metrics().unwrap();
 
-        // receive single metric
+        let status: serde_json::Value =
+            serde_json::from_slice(&fs::read(&json_status).unwrap()).unwrap();
+        let disks = status["disks"].as_object().unwrap();
+        assert_eq!(disks.keys().collect::<Vec<_>>(), ["/dev/sda"]);
+        let sda = &disks["/dev/sda"];
+        assert_eq!(sda["labels"], serde_json::json!({"disk": "/dev/sda"}));
+        assert_eq!(sda["state"], "standby");
+        assert_eq!(sda["spinning"], false);
+        assert!(sda["last_change"].is_f64());
+        assert_eq!(sda["errors"], serde_json::json!({"timeout": 1}));
+    }
+
+    #[test]
+    fn test_textfile_write_failure() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("missing").join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        tx.send(MetricMessage::Flush).unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        // the daemon keeps running
         metrics.receive_metrics().unwrap();
 
-        // compare results
+        fs::create_dir(textfile.parent().unwrap()).unwrap();
+        metrics.flush();
         let disk_metrics = fs::read_to_string(&textfile).unwrap();
-        let expected = String::from(
-            "# HELP disk_status Status of the disk (1=active, 0=standby)
-# TYPE disk_status gauge
-disk_status{disk=\"/dev/sda\"} 1\n",
+        assert!(disk_metrics.contains("textfile_write_failures_total 2\n"));
+    }
+
+    #[test]
+    fn test_md_arrays() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        tx.send(MetricMessage::MdArrays(BTreeMap::from([(
+            String::from("/dev/md0"),
+            vec![String::from("/dev/sda"), String::from("/dev/sdb")],
+        )])))
+        .unwrap();
+        // md0 was stopped, md1 assembled
+        tx.send(MetricMessage::MdArrays(BTreeMap::from([(
+            String::from("/dev/md1"),
+            vec![String::from("/dev/sda")],
+        )])))
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics.contains("disk_md_array{array=\"/dev/md1\",disk=\"/dev/sda\"} 1"));
+        assert!(!disk_metrics.contains("/dev/md0"));
+    }
+
+    #[test]
+    fn test_notify_disks() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        tx.send(MetricMessage::WatchedDirectories(vec![
+            (
+                String::from("/srv/media"),
+                vec![String::from("/dev/sda"), String::from("/dev/sdb")],
+            ),
+            (String::from("/srv/nas"), Vec::new()),
+        ]))
+        .unwrap();
+        tx.send(MetricMessage::WatchSetupFailed { watch_limit: true })
+            .unwrap();
+        // doesn't stop receiving the metrics
+        tx.send(MetricMessage::NotifyEvent(Err(anyhow!("queue overflow"))))
+            .unwrap();
+        for path in ["/srv/media", "/srv/nas"] {
+            tx.send(MetricMessage::NotifyEvent(Ok(WatchEvent {
+                path: String::from(path),
+                kind: notify::EventKind::Any,
+                paths: vec![PathBuf::from(path).join("a.mkv")],
+                ..Default::default()
+            })))
+            .unwrap();
+        }
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        for disk in ["/dev/sda", "/dev/sdb"] {
+            assert!(disk_metrics.contains(&format!(
+                "notify_events{{disk=\"{}\",kind=\"other\",path=\"/srv/media\"}} 1",
+                disk
+            )));
+        }
+        assert!(
+            disk_metrics.contains("notify_events{disk=\"\",kind=\"other\",path=\"/srv/nas\"} 1")
         );
-        assert_eq!(disk_metrics, expected);
+        assert!(disk_metrics.contains("watched_directories 2"));
+        assert!(disk_metrics.contains("watch_setup_failures_total{reason=\"watch_limit\"} 1"));
+        assert!(disk_metrics.contains("notify_errors_total 1"));
+        let last_event: f64 = disk_metrics
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix(
+                    "watched_directory_last_event_timestamp_seconds{directory=\"/srv/nas\"} ",
+                )
+            })
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((unix_time() - last_event).abs() < 60.0);
+    }
+
+    #[test]
+    fn test_unwatched_directories_removed() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+        let watched = |paths: &[(&str, &str)]| {
+            MetricMessage::WatchedDirectories(
+                paths
+                    .iter()
+                    .map(|(path, disk)| (path.to_string(), vec![disk.to_string()]))
+                    .collect(),
+            )
+        };
+        let event = |path: &str| {
+            MetricMessage::NotifyEvent(Ok(WatchEvent {
+                path: String::from(path),
+                kind: notify::EventKind::Any,
+                paths: vec![PathBuf::from(path)],
+                ..Default::default()
+            }))
+        };
+
+        tx.send(watched(&[
+            ("/srv/media", "/dev/sda"),
+            ("/srv/backup", "/dev/sdb"),
+            ("/srv/photos", "/dev/sdc"),
+        ]))
+        .unwrap();
+        for path in ["/srv/media", "/srv/backup", "/srv/photos"] {
+            tx.send(event(path)).unwrap();
+            tx.send(MetricMessage::WatchActive {
+                directory: String::from(path),
+                active: path != "/srv/media",
+            })
+            .unwrap();
+        }
+        // backup was removed from the config and photos moved to another disk
+        tx.send(watched(&[
+            ("/srv/media", "/dev/sda"),
+            ("/srv/photos", "/dev/sdd"),
+        ]))
+        .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics
+            .contains("notify_events{disk=\"/dev/sda\",kind=\"other\",path=\"/srv/media\"} 1"));
+        assert!(disk_metrics.contains("watch_active{directory=\"/srv/media\"} 0"));
+        assert!(disk_metrics.contains("watch_active{directory=\"/srv/photos\"} 1"));
+        assert!(!disk_metrics.contains("/srv/backup"));
+        assert!(!disk_metrics.contains("notify_events{disk=\"/dev/sdc\""));
+        assert!(disk_metrics.contains("watched_directories 2"));
+    }
+
+    #[test]
+    fn test_disk_filter_info() {
+        init();
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
+
+        tx.send(MetricMessage::DiskFilter {
+            include: vec![String::from("/dev/sd[c-h]")],
+            exclude: vec![String::from("/dev/sda"), String::from("/dev/sdb")],
+        })
+        .unwrap();
+        // a reload replaces the patterns
+        tx.send(MetricMessage::DiskFilter {
+            include: vec![String::from("/dev/sd[c-h]")],
+            exclude: vec![String::from("/dev/sda")],
+        })
+        .unwrap();
+        tx.send(MetricMessage::ConfigReloaded { success: true })
+            .unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
+        drop(tx);
+        metrics.receive_metrics().unwrap();
+
+        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        assert!(disk_metrics
+            .contains("disk_filter_info{exclude=\"/dev/sda\",include=\"/dev/sd[c-h]\"} 1"));
+        assert!(!disk_metrics.contains("/dev/sdb"));
+        assert!(disk_metrics.contains("config_reloads_total{result=\"success\"} 1"));
     }
 
     #[test]
@@ -152,37 +2522,34 @@ disk_status{disk=\"/dev/sda\"} 1\n",
         let (tx, rx) = std::sync::mpsc::channel();
 
         // set up disk spin resources
-        let lsblk_output = r#"
-{
-   "blockdevices": [
-      {
-         "name": "sda",
-         "type": "disk",
-         "rota": true
-      },
-      {
-         "name": "sdb",
-         "type": "disk",
-         "rota": false
-      },
-      {
-         "name": "sr0",
-         "type": "rom",
-         "rota": true
-      }
-   ]
-}
-"#;
-        let lsblk = FakeLsblk {
-            result: lsblk_output.to_string(),
-        };
+        let sys_root = fake_sysfs(&[
+            FakeBlockDevice {
+                name: "sda",
+                scsi_type: Some(0),
+                rotational: true,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sdb",
+                scsi_type: Some(0),
+                rotational: false,
+                removable: false,
+            },
+            FakeBlockDevice {
+                name: "sr0",
+                scsi_type: Some(5),
+                rotational: true,
+                removable: true,
+            },
+        ]);
+        let disk_list = SysBlock::with_sys_root(sys_root.path());
         let disk_query = FakeHdparm {};
 
         // set up notify resources
         let monitored_dir = TempDir::new().unwrap();
         let event_file = monitored_dir.path().join("text.txt");
         let watches = vec![monitored_dir.path()];
-        let watcher = watch::watch(watches, tx.clone()).unwrap();
+        let watcher = watch::watch(watches, watch::WatchOptions::default(), tx.clone()).unwrap();
 
         // emit some events by changing a file
         let _ = std::fs::remove_file(&event_file);
@@ -197,7 +2564,16 @@ disk_status{disk=\"/dev/sda\"} 1\n",
         let metrics = Metrics::new(textfile.to_path_buf(), rx).unwrap();
 
         // run a single disk_status cycle
-        update_disk_status(&disk_query, &lsblk, &tx).unwrap();
+        let retries = DiskRetries::new(RetryPolicy::default());
+        update_disk_status(
+            &disk_query,
+            &disk_list,
+            disk_list.get_all_disks().unwrap(),
+            &tx,
+            1,
+            &retries,
+        )
+        .unwrap();
 
         // manually receive some metrics as inotify times can be unpredictable
         // need to know exactly how many events to expect
@@ -207,7 +2583,7 @@ disk_status{disk=\"/dev/sda\"} 1\n",
         }
 
         // Send message to save the file
-        tx.send(MetricMessage::SaveFile).unwrap();
+        tx.send(MetricMessage::Flush).unwrap();
 
         // close this transmitter, too
         drop(tx);
@@ -216,16 +2592,55 @@ disk_status{disk=\"/dev/sda\"} 1\n",
         metrics.receive_metrics().unwrap();
 
         // compare results
-        let disk_metrics = fs::read_to_string(&textfile).unwrap();
+        let disk_metrics = read_comparable(&textfile);
         // it's 3 events for file create, write & close from inotify
         let expected = format!(
-            "# HELP disk_status Status of the disk (1=active, 0=standby)
+            "# HELP activity_errors_total Number of times polling disk activity or acting on it failed
+# TYPE activity_errors_total counter
+activity_errors_total 0
+# HELP disk_energy_joules_total Estimated energy used by the disk, counted between status queries
+# TYPE disk_energy_joules_total counter
+disk_energy_joules_total{{disk=\"/dev/sda\"}} 0
+# HELP disk_enumeration_errors_total Number of times listing the disks failed
+# TYPE disk_enumeration_errors_total counter
+disk_enumeration_errors_total 0
+# HELP disk_power_state Power state of the disk as reported by the backend (1 for the current state)
+# TYPE disk_power_state gauge
+disk_power_state{{disk=\"/dev/sda\",state=\"active\"}} 0
+disk_power_state{{disk=\"/dev/sda\",state=\"idle\"}} 0
+disk_power_state{{disk=\"/dev/sda\",state=\"sleeping\"}} 0
+disk_power_state{{disk=\"/dev/sda\",state=\"standby\"}} 1
+disk_power_state{{disk=\"/dev/sda\",state=\"unknown\"}} 0
+# HELP disk_power_watts Estimated power draw of the disk in its current state
+# TYPE disk_power_watts gauge
+disk_power_watts{{disk=\"/dev/sda\"}} 0.8
+# HELP disk_spindowns_total Number of times the disk was seen in standby after it was spinning
+# TYPE disk_spindowns_total counter
+disk_spindowns_total{{disk=\"/dev/sda\"}} 0
+# HELP disk_spinups_total Number of times the disk was seen spinning after it was in standby
+# TYPE disk_spinups_total counter
+disk_spinups_total{{disk=\"/dev/sda\"}} 0
+# HELP disk_status Status of the disk (1=active, 0=standby, -1=unknown)
 # TYPE disk_status gauge
 disk_status{{disk=\"/dev/sda\"}} 0
-# HELP notify_events Number of events for watched directories
+# HELP monitored_disks Number of disks found by the latest enumeration
+# TYPE monitored_disks gauge
+monitored_disks 0
+# HELP notify_errors_total Number of errors reported while watching the watch directories
+# TYPE notify_errors_total counter
+notify_errors_total 0
+# HELP notify_events Number of events for watched directories by kind (create, modify, remove, access or other), counted for each disk the directory is on
 # TYPE notify_events counter
-notify_events{{path=\"{}\"}} 3\n",
-            monitored_dir.path().to_string_lossy()
+notify_events{{disk=\"\",kind=\"access\",path=\"{path}\"}} 1
+notify_events{{disk=\"\",kind=\"create\",path=\"{path}\"}} 1
+notify_events{{disk=\"\",kind=\"modify\",path=\"{path}\"}} 1
+# HELP textfile_write_failures_total Number of times writing the textfile failed
+# TYPE textfile_write_failures_total counter
+textfile_write_failures_total 0
+# HELP watched_directories Number of directories watched for notify events
+# TYPE watched_directories gauge
+watched_directories 0\n",
+            path = monitored_dir.path().to_string_lossy()
         );
         assert_eq!(disk_metrics, expected);
     }
@@ -0,0 +1,176 @@
+use std::{
+    fs,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// A line of `/proc/mounts`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+    /// Device or other source, e.g. `/dev/mapper/media` or `tmpfs`
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+}
+
+pub fn read_mounts(mounts_file: &Path) -> Result<Vec<Mount>> {
+    let mounts = fs::read_to_string(mounts_file)
+        .with_context(|| format!("Failed to read {}", mounts_file.to_string_lossy()))?;
+    Ok(parse_mounts(&mounts))
+}
+
+pub fn parse_mounts(mounts: &str) -> Vec<Mount> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Mount {
+                source: unescape(fields.next()?),
+                target: PathBuf::from(unescape(fields.next()?)),
+                fstype: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// A line of `/proc/swaps`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Swap {
+    /// Device or file, e.g. `/dev/sda2` or `/swapfile`
+    pub filename: PathBuf,
+    /// Whether `filename` is a block device rather than a file
+    pub partition: bool,
+}
+
+pub fn read_swaps(swaps_file: &Path) -> Result<Vec<Swap>> {
+    let swaps = fs::read_to_string(swaps_file)
+        .with_context(|| format!("Failed to read {}", swaps_file.to_string_lossy()))?;
+    Ok(parse_swaps(&swaps))
+}
+
+pub fn parse_swaps(swaps: &str) -> Vec<Swap> {
+    swaps
+        .lines()
+        // Header
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Swap {
+                filename: PathBuf::from(unescape(fields.next()?)),
+                partition: fields.next()? == "partition",
+            })
+        })
+        .collect()
+}
+
+/// The mount `path` lives on, i.e. the one with the longest matching target.
+/// Later mounts hide earlier ones on the same target.
+pub fn find_mount<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .rev()
+        .filter(|mount| path.starts_with(&mount.target))
+        .max_by_key(|mount| mount.target.components().count())
+}
+
+/// Write back the dirty pages of the filesystem mounted at `target`
+pub fn syncfs(target: &Path) -> Result<()> {
+    let dir = fs::File::open(target)
+        .with_context(|| format!("Failed to open {}", target.to_string_lossy()))?;
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to sync {}", target.to_string_lossy()));
+    }
+    Ok(())
+}
+
+/// Spaces, tabs, newlines and backslashes are escaped as octal, e.g. `\040`
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        unescaped.push_str(&rest[..pos]);
+        let escaped = rest.get(pos + 1..pos + 4).unwrap_or_default();
+        match u8::from_str_radix(escaped, 8) {
+            Ok(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            Err(_) => {
+                unescaped.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOUNTS: &str = "/dev/sda2 / ext4 rw,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/mapper/media /srv/media ext4 rw,relatime 0 0
+/dev/md0 /srv/media/Old\\040Photos xfs rw,relatime 0 0
+";
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = parse_mounts(MOUNTS);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(
+            mounts[3],
+            Mount {
+                source: String::from("/dev/md0"),
+                target: PathBuf::from("/srv/media/Old Photos"),
+                fstype: String::from("xfs"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_swaps() {
+        let swaps = parse_swaps(
+            "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda3                               partition\t8388604\t\t0\t\t-2
+/var/swap\\040file                       file\t\t1048572\t\t0\t\t-3
+",
+        );
+        assert_eq!(
+            swaps,
+            vec![
+                Swap {
+                    filename: PathBuf::from("/dev/sda3"),
+                    partition: true,
+                },
+                Swap {
+                    filename: PathBuf::from("/var/swap file"),
+                    partition: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_mount() {
+        let mounts = parse_mounts(MOUNTS);
+        let source =
+            |path: &str| find_mount(&mounts, Path::new(path)).map(|mount| mount.source.as_str());
+        assert_eq!(source("/srv/media/movies"), Some("/dev/mapper/media"));
+        assert_eq!(source("/srv/media/Old Photos/2019"), Some("/dev/md0"));
+        // not a path prefix, only a string prefix
+        assert_eq!(source("/srv/mediacenter"), Some("/dev/sda2"));
+        assert_eq!(source("/proc/1"), Some("proc"));
+    }
+
+    #[test]
+    fn test_syncfs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        syncfs(dir.path()).unwrap();
+        assert!(syncfs(&dir.path().join("missing")).is_err());
+    }
+}
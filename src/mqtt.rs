@@ -0,0 +1,551 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::{Read, Write},
+    net::TcpStream,
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use crate::net;
+
+/// How long connecting, reading from and writing to the broker may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Seconds after which the broker drops a connection it didn't hear from,
+/// which publishes the last will
+const KEEP_ALIVE: u16 = 60;
+
+/// How often to ping the broker, well within [`KEEP_ALIVE`]. Also how often to
+/// try again while states couldn't be published.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// States waiting for the publishing thread before new ones are dropped
+const QUEUE_SIZE: usize = 64;
+
+/// Publishes whether disks are spinning to an MQTT broker, with Home
+/// Assistant discovery so each disk shows up as a binary sensor. Messages are
+/// retained, a (re)started Home Assistant sees the last state right away.
+///
+/// Only speaks as much MQTT 3.1.1 as needed for that: QoS 0 publishes and
+/// pings, reconnecting when a publish or ping fails. The latest state of each
+/// disk is kept and published again after reconnecting.
+pub struct MqttPublisher {
+    broker: String,
+    client_id: String,
+    credentials: Option<(String, String)>,
+    topic_prefix: String,
+    discovery_prefix: String,
+    connection: Option<TcpStream>,
+    /// Disks whose discovery config was published
+    announced: HashSet<String>,
+    /// Latest name and whether it's spinning by disk
+    states: BTreeMap<String, (String, bool)>,
+    /// Disks whose latest state wasn't published yet
+    pending: BTreeSet<String>,
+}
+
+impl MqttPublisher {
+    /// Publish to the broker at `broker` like `mqtt.local:1883`
+    pub fn new(broker: &str) -> Self {
+        MqttPublisher {
+            broker: broker.to_string(),
+            client_id: String::from("disk_spin_manager"),
+            credentials: None,
+            topic_prefix: String::from("disk_spin_manager"),
+            discovery_prefix: String::from("homeassistant"),
+            connection: None,
+            announced: HashSet::new(),
+            states: BTreeMap::new(),
+            pending: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Prefix of the state topics, also the client id
+    pub fn with_topic_prefix(mut self, prefix: &str) -> Self {
+        self.topic_prefix = prefix.trim_end_matches('/').to_string();
+        self.client_id = self.topic_prefix.replace('/', "_");
+        self
+    }
+
+    pub fn with_discovery_prefix(mut self, prefix: &str) -> Self {
+        self.discovery_prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Publish that `disk` (shown as `name`) started or stopped spinning.
+    /// If that fails, it's published with the next state of any disk.
+    pub fn publish_state(&mut self, disk: &str, name: &str, spinning: bool) -> Result<()> {
+        self.states
+            .insert(disk.to_string(), (name.to_string(), spinning));
+        self.pending.insert(disk.to_string());
+        self.publish_pending()
+    }
+
+    /// Publish the states received from `rx` until it disconnects. Every
+    /// [`PING_INTERVAL`] the connection is checked and the states that failed
+    /// are tried again.
+    pub fn run(mut self, rx: Receiver<DiskState>) {
+        let mut next_ping = Instant::now() + PING_INTERVAL;
+        loop {
+            let received = rx.recv_timeout(next_ping.saturating_duration_since(Instant::now()));
+            let disconnected = match received {
+                Ok(state) => {
+                    self.queue(state);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            // Only the latest state of each disk matters
+            for state in rx.try_iter() {
+                self.queue(state);
+            }
+            if !disconnected && Instant::now() >= next_ping {
+                self.check_connection();
+                next_ping = Instant::now() + PING_INTERVAL;
+            }
+            if !self.pending.is_empty() {
+                if let Err(err) = self.publish_pending() {
+                    error!("{:?}", err);
+                }
+            }
+            if disconnected {
+                return;
+            }
+        }
+    }
+
+    /// Ping the broker, as QoS 0 publishes into a dead connection seem to
+    /// succeed. If it doesn't answer, all states are published again on a new
+    /// connection.
+    fn check_connection(&mut self) {
+        if let Err(err) = self.ping() {
+            warn!("Lost connection to MQTT broker: {:?}", err);
+            self.connection = None;
+            self.pending.extend(self.states.keys().cloned());
+        }
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        let Some(stream) = &mut self.connection else {
+            return Ok(());
+        };
+        stream
+            .write_all(&packet(0xc0, Vec::new()))
+            .context("Failed to ping MQTT broker")?;
+        let mut pingresp = [0; 2];
+        stream
+            .read_exact(&mut pingresp)
+            .context("MQTT broker didn't answer the ping")?;
+        if pingresp != [0xd0, 0x00] {
+            bail!("Unexpected answer from MQTT broker: {:?}", pingresp);
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, state: DiskState) {
+        self.states
+            .insert(state.disk.clone(), (state.name, state.spinning));
+        self.pending.insert(state.disk);
+    }
+
+    /// One reconnect is tried if the connection broke in the meantime
+    fn publish_pending(&mut self) -> Result<()> {
+        let result = match self.try_publish_pending() {
+            Err(err) if self.connection.is_some() => {
+                debug!("Reconnecting to MQTT broker after {:?}", err);
+                self.connection = None;
+                self.try_publish_pending()
+            }
+            result => result,
+        };
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn try_publish_pending(&mut self) -> Result<()> {
+        if self.connection.is_none() {
+            self.connect()?;
+        }
+        while let Some(disk) = self.pending.first().cloned() {
+            let (name, spinning) = self.states[&disk].clone();
+            self.try_publish_state(&disk, &name, spinning)?;
+            self.pending.remove(&disk);
+        }
+        Ok(())
+    }
+
+    fn try_publish_state(&mut self, disk: &str, name: &str, spinning: bool) -> Result<()> {
+        let object_id = object_id(disk);
+        let state_topic = format!("{}/{}/state", self.topic_prefix, object_id);
+        if !self.announced.contains(disk) {
+            let config_topic = format!(
+                "{}/binary_sensor/{}/{}/config",
+                self.discovery_prefix, self.client_id, object_id
+            );
+            let unique_id = format!("{}_{}", self.client_id, object_id);
+            let config = json!({
+                "name": name,
+                "unique_id": unique_id,
+                "object_id": unique_id,
+                "device_class": "running",
+                "state_topic": state_topic,
+                "availability_topic": self.availability_topic(),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "device": {
+                    "identifiers": [unique_id],
+                    "name": name,
+                },
+            });
+            self.publish(&config_topic, config.to_string().as_bytes())?;
+            self.announced.insert(disk.to_string());
+        }
+        let payload = if spinning { "ON" } else { "OFF" };
+        self.publish(&state_topic, payload.as_bytes())
+    }
+
+    /// Goes `offline` through the last will if the connection drops
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        let mut stream = net::connect(&self.broker, TIMEOUT).context("MQTT broker unavailable")?;
+        let will = (self.availability_topic(), b"offline".as_slice());
+        let credentials = self
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        stream.write_all(&connect_packet(&self.client_id, will, credentials))?;
+        let mut connack = [0; 4];
+        stream
+            .read_exact(&mut connack)
+            .context("MQTT broker didn't acknowledge the connection")?;
+        match connack {
+            [0x20, 0x02, _, 0] => {}
+            [0x20, 0x02, _, code] => bail!("MQTT broker refused the connection with code {}", code),
+            _ => bail!("Unexpected answer from MQTT broker: {:?}", connack),
+        }
+        info!("Connected to MQTT broker {}", self.broker);
+        self.connection = Some(stream);
+        // The broker may have been restarted without keeping retained messages
+        self.announced.clear();
+        self.pending.extend(self.states.keys().cloned());
+        self.publish(&self.availability_topic(), b"online")
+    }
+
+    /// Retained QoS 0 publish on the current connection
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        let Some(stream) = &mut self.connection else {
+            bail!("Not connected to MQTT broker");
+        };
+        stream
+            .write_all(&publish_packet(topic, payload))
+            .with_context(|| format!("Failed to publish to MQTT topic {}", topic))
+    }
+}
+
+/// Whether a disk is spinning, for [`MqttQueue`]
+pub struct DiskState {
+    pub disk: String,
+    /// Shown in Home Assistant
+    pub name: String,
+    pub spinning: bool,
+}
+
+/// Hands states to an [`MqttPublisher`] on its own thread, so a slow or
+/// unreachable broker doesn't hold up the metrics. Dropping it waits for the
+/// queued states to be published.
+pub struct MqttQueue {
+    tx: Option<SyncSender<DiskState>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MqttQueue {
+    pub fn spawn(publisher: MqttPublisher) -> Self {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let thread = thread::spawn(move || publisher.run(rx));
+        MqttQueue {
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queue publishing that `disk` (shown as `name`) started or stopped
+    /// spinning
+    pub fn publish_state(&self, disk: &str, name: &str, spinning: bool) -> Result<()> {
+        let state = DiskState {
+            disk: disk.to_string(),
+            name: name.to_string(),
+            spinning,
+        };
+        match self.tx.as_ref().map(|tx| tx.try_send(state)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(TrySendError::Full(_))) => {
+                bail!("MQTT queue is full, not publishing the state of {}", disk)
+            }
+            _ => bail!("MQTT publisher stopped"),
+        }
+    }
+}
+
+impl Drop for MqttQueue {
+    fn drop(&mut self) {
+        // Lets the thread finish after publishing what's queued
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("MQTT publisher panicked");
+            }
+        }
+    }
+}
+
+/// Topic level and id for a disk, `/dev/sda` becomes `dev_sda`
+fn object_id(disk: &str) -> String {
+    disk.trim_start_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn connect_packet(
+    client_id: &str,
+    will: (String, &[u8]),
+    credentials: Option<(&str, &str)>,
+) -> Vec<u8> {
+    // Clean session, retained will with QoS 0
+    let mut flags = 0x02 | 0x04 | 0x20;
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT");
+    body.push(4);
+    let flags_at = body.len();
+    body.push(0);
+    body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    put_bytes(&mut body, client_id.as_bytes());
+    put_bytes(&mut body, will.0.as_bytes());
+    put_bytes(&mut body, will.1);
+    if let Some((username, password)) = credentials {
+        flags |= 0x80 | 0x40;
+        put_bytes(&mut body, username.as_bytes());
+        put_bytes(&mut body, password.as_bytes());
+    }
+    body[flags_at] = flags;
+    packet(0x10, body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_bytes(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    // QoS 0, retained
+    packet(0x31, body)
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+/// Fixed header with the remaining length as variable byte integer
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Header byte and body of the next packet
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    fn read_publish(stream: &mut TcpStream) -> (String, String) {
+        let (header, body) = read_packet(stream);
+        assert_eq!(header, 0x31);
+        let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+        let payload = String::from_utf8(body[2 + length..].to_vec()).unwrap();
+        (topic, payload)
+    }
+
+    #[test]
+    fn test_publish_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (header, connect) = read_packet(&mut stream);
+            assert_eq!(header, 0x10);
+            // username, password, will retain and will flag, clean session
+            assert_eq!(connect[7], 0xe6);
+            assert_eq!(connect[8..10], KEEP_ALIVE.to_be_bytes());
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            (0..4)
+                .map(|_| read_publish(&mut stream))
+                .collect::<Vec<_>>()
+        });
+
+        let mut publisher = MqttPublisher::new(&addr.to_string())
+            .with_credentials("user", "secret")
+            .with_topic_prefix("nas/");
+        publisher
+            .publish_state("/dev/sda", "/dev/sda", true)
+            .unwrap();
+        publisher
+            .publish_state("/dev/sda", "/dev/sda", false)
+            .unwrap();
+
+        let published = broker.join().unwrap();
+        assert_eq!(published[0], ("nas/status".into(), "online".into()));
+        let (topic, config) = &published[1];
+        assert_eq!(topic, "homeassistant/binary_sensor/nas/dev_sda/config");
+        let config: serde_json::Value = serde_json::from_str(config).unwrap();
+        assert_eq!(config["state_topic"], "nas/dev_sda/state");
+        assert_eq!(config["availability_topic"], "nas/status");
+        assert_eq!(config["unique_id"], "nas_dev_sda");
+        assert_eq!(published[2], ("nas/dev_sda/state".into(), "ON".into()));
+        assert_eq!(published[3], ("nas/dev_sda/state".into(), "OFF".into()));
+    }
+
+    /// Accept one connection and read `count` publishes
+    fn broker(listener: TcpListener, count: usize) -> thread::JoinHandle<Vec<(String, String)>> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            (0..count)
+                .map(|_| read_publish(&mut stream))
+                .filter(|(topic, _)| topic.ends_with("/state"))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_republish() {
+        // Nothing listens there
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut publisher = MqttPublisher::new(&unreachable);
+        assert!(publisher.publish_state("/dev/sda", "sda", true).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        publisher.broker = listener.local_addr().unwrap().to_string();
+        let received = broker(listener, 5);
+        publisher.publish_state("/dev/sdb", "sdb", false).unwrap();
+        // The state that failed is published with the next one
+        assert_eq!(
+            received.join().unwrap(),
+            [
+                ("disk_spin_manager/dev_sda/state".into(), "ON".into()),
+                ("disk_spin_manager/dev_sdb/state".into(), "OFF".into()),
+            ]
+        );
+
+        // A new connection gets the current states again
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        publisher.broker = listener.local_addr().unwrap().to_string();
+        publisher.connection = None;
+        let received = broker(listener, 5);
+        publisher.publish_state("/dev/sdb", "sdb", true).unwrap();
+        assert_eq!(
+            received.join().unwrap(),
+            [
+                ("disk_spin_manager/dev_sda/state".into(), "ON".into()),
+                ("disk_spin_manager/dev_sdb/state".into(), "ON".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut publisher = MqttPublisher::new(&listener.local_addr().unwrap().to_string());
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            for _ in 0..3 {
+                read_publish(&mut stream);
+            }
+            assert_eq!(read_packet(&mut stream), (0xc0, Vec::new()));
+            stream.write_all(&[0xd0, 0x00]).unwrap();
+            // gone without a word, like after a power outage
+            read_packet(&mut stream);
+        });
+        publisher.publish_state("/dev/sda", "sda", true).unwrap();
+        publisher.check_connection();
+        assert!(publisher.connection.is_some());
+        assert!(publisher.pending.is_empty());
+
+        publisher.check_connection();
+        broker.join().unwrap();
+        assert!(publisher.connection.is_none());
+        // published again on the next connection
+        assert_eq!(
+            publisher.pending,
+            BTreeSet::from([String::from("/dev/sda")])
+        );
+    }
+
+    #[test]
+    fn test_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let queue = MqttQueue::spawn(MqttPublisher::new(
+            &listener.local_addr().unwrap().to_string(),
+        ));
+        let received = broker(listener, 3);
+        queue.publish_state("/dev/sda", "sda", true).unwrap();
+        // Waits for the state to be published
+        drop(queue);
+        assert_eq!(
+            received.join().unwrap(),
+            [("disk_spin_manager/dev_sda/state".into(), "ON".into())]
+        );
+    }
+}
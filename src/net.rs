@@ -0,0 +1,53 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Connect to `addr` like `graphite:2003`, trying each address it resolves
+/// to for at most `timeout`. Reads and writes time out after `timeout`, too,
+/// so an unreachable server can't hang the caller.
+pub fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = None;
+    for resolved in addr
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", addr))?
+    {
+        match TcpStream::connect_timeout(&resolved, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(match last_err {
+        Some(err) => anyhow!(err),
+        None => anyhow!("No address found"),
+    })
+    .with_context(|| format!("Failed to connect to {}", addr))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let stream = connect(&addr, Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            stream.write_timeout().unwrap(),
+            Some(Duration::from_secs(1))
+        );
+
+        drop(listener);
+        let err = connect(&addr, Duration::from_secs(1)).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to connect to"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use prometheus::{
+    proto::{LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use serde_json::{json, Value};
+
+use crate::sink::ExportSink;
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`, counters count since the start
+const CUMULATIVE: u8 = 2;
+
+/// Exports metrics to an OpenTelemetry collector with OTLP/HTTP in its JSON
+/// encoding. Counters become monotonic cumulative sums without the `_total`
+/// suffix, gauges stay gauges and histograms become explicit bucket
+/// histograms.
+pub struct OtlpExporter {
+    agent: ureq::Agent,
+    url: String,
+    headers: Vec<(String, String)>,
+    resource: Vec<(String, String)>,
+    /// Start of all cumulative series
+    started: u128,
+}
+
+impl OtlpExporter {
+    /// Export to the collector at `endpoint` like `http://collector:4318`,
+    /// the metrics path is added unless it's there already
+    pub fn new(endpoint: &str) -> Self {
+        let endpoint = endpoint.trim_end_matches('/');
+        let url = match endpoint.ends_with("/v1/metrics") {
+            true => endpoint.to_string(),
+            false => format!("{}/v1/metrics", endpoint),
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        OtlpExporter {
+            agent,
+            url,
+            headers: Vec::new(),
+            resource: vec![(
+                String::from("service.name"),
+                String::from("disk_spin_manager"),
+            )],
+            started: unix_nanos(),
+        }
+    }
+
+    /// Send `name: value` with every export, e.g. for authentication
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Describe the exporting machine with a resource attribute like
+    /// `host.name`
+    pub fn with_resource_attribute(mut self, key: &str, value: &str) -> Self {
+        self.resource.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn send(&self, families: &[MetricFamily]) -> Result<()> {
+        let request = encode_metrics(families, &self.resource, self.started, unix_nanos());
+        let mut post = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            post = post.set(name, value);
+        }
+        post.send_string(&request.to_string())
+            .with_context(|| format!("Failed to export metrics to {}", self.url))?;
+        Ok(())
+    }
+}
+
+impl ExportSink for OtlpExporter {
+    fn name(&self) -> &str {
+        "otlp"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        self.send(&registry.gather())
+    }
+}
+
+/// `ExportMetricsServiceRequest` for all of `families`, as of `now` in
+/// nanoseconds since the epoch
+pub fn encode_metrics(
+    families: &[MetricFamily],
+    resource: &[(String, String)],
+    started: u128,
+    now: u128,
+) -> Value {
+    let (started, now) = (started.to_string(), now.to_string());
+    let metrics: Vec<Value> = families
+        .iter()
+        .filter_map(|family| {
+            let points = family.get_metric().iter();
+            let number_point = |labels: &[LabelPair], value: f64| {
+                json!({
+                    "attributes": attributes(labels.iter().map(|l| (l.get_name(), l.get_value()))),
+                    "startTimeUnixNano": started,
+                    "timeUnixNano": now,
+                    "asDouble": value,
+                })
+            };
+            let (name, kind, data) = match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let name = family.get_name();
+                    let points: Vec<Value> = points
+                        .map(|m| number_point(m.get_label(), m.get_counter().get_value()))
+                        .collect();
+                    let sum = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": CUMULATIVE,
+                        "isMonotonic": true,
+                    });
+                    (name.strip_suffix("_total").unwrap_or(name), "sum", sum)
+                }
+                MetricType::GAUGE | MetricType::UNTYPED => {
+                    let points: Vec<Value> = points
+                        .map(|m| {
+                            let value = match family.get_field_type() {
+                                MetricType::GAUGE => m.get_gauge().get_value(),
+                                _ => m.get_untyped().get_value(),
+                            };
+                            number_point(m.get_label(), value)
+                        })
+                        .collect();
+                    let gauge = json!({"dataPoints": points});
+                    (family.get_name(), "gauge", gauge)
+                }
+                MetricType::HISTOGRAM => {
+                    let points: Vec<Value> = points
+                        .map(|m| {
+                            let histogram = m.get_histogram();
+                            // OTLP buckets aren't cumulative and the last one
+                            // is implicitly unbounded
+                            let mut bounds = Vec::new();
+                            let mut counts = Vec::new();
+                            let mut below = 0;
+                            for bucket in histogram.get_bucket() {
+                                if bucket.get_upper_bound().is_finite() {
+                                    bounds.push(bucket.get_upper_bound());
+                                    let count = bucket.get_cumulative_count();
+                                    counts.push((count - below).to_string());
+                                    below = count;
+                                }
+                            }
+                            counts.push((histogram.get_sample_count() - below).to_string());
+                            let labels = m.get_label().iter();
+                            json!({
+                                "attributes": attributes(labels.map(|l| (l.get_name(), l.get_value()))),
+                                "startTimeUnixNano": started,
+                                "timeUnixNano": now,
+                                "count": histogram.get_sample_count().to_string(),
+                                "sum": histogram.get_sample_sum(),
+                                "bucketCounts": counts,
+                                "explicitBounds": bounds,
+                            })
+                        })
+                        .collect();
+                    let histogram = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": CUMULATIVE,
+                    });
+                    (family.get_name(), "histogram", histogram)
+                }
+                // Not used by any metric here
+                MetricType::SUMMARY => return None,
+            };
+            Some(json!({"name": name, "description": family.get_help(), kind: data}))
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": attributes(resource.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+            },
+            "scopeMetrics": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "metrics": metrics,
+            }],
+        }]
+    })
+}
+
+fn attributes<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<Value> {
+    pairs
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus::{GaugeVec, Histogram, HistogramOpts, IntCounter, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn test_encode_metrics() {
+        let registry = Registry::new();
+        let spinups = IntCounter::new("disk_spinups_total", "Spin-ups").unwrap();
+        let status = GaugeVec::new(Opts::new("disk_status", "Status"), &["disk"]).unwrap();
+        let duration = Histogram::with_opts(
+            HistogramOpts::new("query_duration_seconds", "Duration").buckets(vec![0.5, 1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(spinups.clone())).unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        spinups.inc_by(3);
+        status.with_label_values(&["/dev/sda"]).set(1.0);
+        for seconds in [0.25, 0.75, 2.0] {
+            duration.observe(seconds);
+        }
+
+        let resource = [(String::from("host.name"), String::from("nas"))];
+        let request = encode_metrics(&registry.gather(), &resource, 1, 2);
+        let resource_metrics = &request["resourceMetrics"][0];
+        assert_eq!(
+            resource_metrics["resource"]["attributes"],
+            json!([{"key": "host.name", "value": {"stringValue": "nas"}}])
+        );
+        let metrics = &resource_metrics["scopeMetrics"][0]["metrics"];
+        assert_eq!(
+            metrics[0],
+            json!({
+                "name": "disk_spinups",
+                "description": "Spin-ups",
+                "sum": {
+                    "dataPoints": [{
+                        "attributes": [],
+                        "startTimeUnixNano": "1",
+                        "timeUnixNano": "2",
+                        "asDouble": 3.0,
+                    }],
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                },
+            })
+        );
+        assert_eq!(
+            metrics[1]["gauge"]["dataPoints"][0]["attributes"],
+            json!([{"key": "disk", "value": {"stringValue": "/dev/sda"}}])
+        );
+        let histogram = &metrics[2]["histogram"]["dataPoints"][0];
+        assert_eq!(histogram["count"], "3");
+        assert_eq!(histogram["bucketCounts"], json!(["1", "1", "1"]));
+        assert_eq!(histogram["explicitBounds"], json!([0.5, 1.0]));
+    }
+}
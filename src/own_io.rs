@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+
+use crate::disks::resolve_physical_disks;
+
+/// Size of a sector as used by `/proc/diskstats`, independent of the disk
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Writes end up on the disk in whole pages
+const PAGE_SIZE: u64 = 4096;
+
+/// Written on top of the data of each file write, like the inode, the
+/// directory entry of a rename and the filesystem's journal commit
+const METADATA_ALLOWANCE: u64 = 32 * 1024;
+
+/// How long it may take until a write shows up in `/proc/diskstats`. The
+/// kernel writes back dirty pages after 30s by default
+/// (`vm.dirty_expire_centisecs`), plus the writeback and journal intervals.
+const WRITEBACK_WINDOW: Duration = Duration::from_secs(60);
+
+/// Bookkeeping of the writes the daemon itself performs (textfile, JSON
+/// status, audit log, ...), keyed by the disk holding the written file.
+///
+/// Activity detection subtracts these so the daemon's own output never counts
+/// as user activity that keeps a disk awake. Clones share the same counters so
+/// writers and the activity poller can live on different threads.
+///
+/// The kernel decides when writes reach the disk, so this is an estimate:
+/// each write is allowed for with [`METADATA_ALLOWANCE`] on top and can be
+/// subtracted until it's older than [`WRITEBACK_WINDOW`]. Writes that show up
+/// later, or metadata beyond the allowance, still count as activity. In turn,
+/// other I/O small enough to fit in what's left of the allowance doesn't.
+/// Keep the daemon's output off the disks it spins down to be sure.
+#[derive(Clone)]
+pub struct OwnIo {
+    sys_root: PathBuf,
+    /// Writes that didn't show up in diskstats yet, oldest first
+    pending: Arc<Mutex<HashMap<String, VecDeque<OwnWrite>>>>,
+}
+
+struct OwnWrite {
+    at: Instant,
+    /// Left to subtract
+    sectors: u64,
+}
+
+impl OwnIo {
+    pub fn new() -> Self {
+        Self::with_sys_root(Path::new("/sys"))
+    }
+
+    pub fn with_sys_root(sys_root: &Path) -> Self {
+        OwnIo {
+            sys_root: sys_root.to_path_buf(),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Record that `bytes` were written to `path`. Files that don't live on a
+    /// block device (tmpfs, overlayfs, ...) are ignored. On stacked devices
+    /// like LVM the write is attributed to every disk underneath, as we can't
+    /// tell which one it ends up on.
+    pub fn record_write(&self, path: &Path, bytes: u64) -> Result<()> {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Failed to stat {}", path.to_string_lossy()))?;
+        let dev = metadata.dev();
+        let disks = disks_for_dev(&self.sys_root, major(dev), minor(dev));
+        if disks.is_empty() {
+            debug!(
+                "{} is not backed by a block device, not tracking own I/O",
+                path.to_string_lossy()
+            );
+        }
+        for disk in disks {
+            self.record_disk_write(&disk, bytes);
+        }
+        Ok(())
+    }
+
+    /// Record that `path` was replaced as a whole
+    pub fn record_file(&self, path: &Path) -> Result<()> {
+        let len = fs::metadata(path)
+            .with_context(|| format!("Failed to stat {}", path.to_string_lossy()))?
+            .len();
+        self.record_write(path, len)
+    }
+
+    pub fn record_disk_write(&self, disk: &str, bytes: u64) {
+        self.record_disk_write_at(disk, bytes, Instant::now());
+    }
+
+    pub fn record_disk_write_at(&self, disk: &str, bytes: u64, now: Instant) {
+        let sectors = (bytes.next_multiple_of(PAGE_SIZE) + METADATA_ALLOWANCE) / SECTOR_SIZE;
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(disk.to_string())
+            .or_default()
+            .push_back(OwnWrite { at: now, sectors });
+    }
+
+    /// Subtract our own writes from the `sectors` written to `disk` since the
+    /// last poll. What isn't used up is kept for the next poll, in case the
+    /// write didn't reach the disk yet.
+    pub fn subtract(&self, disk: &str, mut sectors: u64, now: Instant) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(writes) = pending.get_mut(disk) else {
+            return sectors;
+        };
+        writes.retain(|write| now.saturating_duration_since(write.at) <= WRITEBACK_WINDOW);
+        while let Some(write) = writes.front_mut() {
+            let subtracted = sectors.min(write.sectors);
+            sectors -= subtracted;
+            write.sectors -= subtracted;
+            if write.sectors > 0 {
+                break;
+            }
+            writes.pop_front();
+        }
+        if writes.is_empty() {
+            pending.remove(disk);
+        }
+        sectors
+    }
+}
+
+impl Default for OwnIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Same encoding as glibc's gnu_dev_major/gnu_dev_minor
+fn major(dev: u64) -> u64 {
+    ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff)
+}
+
+fn minor(dev: u64) -> u64 {
+    ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff)
+}
+
+/// Resolve a device number to the `/dev` paths of the physical disks, i.e. a
+/// partition like `sda1` resolves to `/dev/sda` and a LUKS device to the disk
+/// it's on.
+fn disks_for_dev(sys_root: &Path, major: u64, minor: u64) -> Vec<String> {
+    let device = sys_root
+        .join("dev/block")
+        .join(format!("{}:{}", major, minor));
+    resolve_physical_disks(&device).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn fake_sysfs() -> TempDir {
+        let sys_root = TempDir::new().unwrap();
+        let sda = sys_root.path().join("devices/pci0000:00/block/sda");
+        fs::create_dir_all(sda.join("sda1")).unwrap();
+        fs::write(sda.join("sda1/partition"), "1\n").unwrap();
+        fs::create_dir_all(sys_root.path().join("dev/block")).unwrap();
+        symlink(&sda, sys_root.path().join("dev/block/8:0")).unwrap();
+        symlink(sda.join("sda1"), sys_root.path().join("dev/block/8:1")).unwrap();
+        let dm0 = sys_root.path().join("devices/virtual/block/dm-0");
+        fs::create_dir_all(dm0.join("slaves")).unwrap();
+        symlink(sda.join("sda1"), dm0.join("slaves/sda1")).unwrap();
+        symlink(&dm0, sys_root.path().join("dev/block/253:0")).unwrap();
+        sys_root
+    }
+
+    #[test]
+    fn test_disks_for_dev() {
+        let sys_root = fake_sysfs();
+        assert_eq!(disks_for_dev(sys_root.path(), 8, 0), vec!["/dev/sda"]);
+        assert_eq!(disks_for_dev(sys_root.path(), 8, 1), vec!["/dev/sda"]);
+        assert_eq!(disks_for_dev(sys_root.path(), 253, 0), vec!["/dev/sda"]);
+        assert!(disks_for_dev(sys_root.path(), 0, 42).is_empty());
+    }
+
+    #[test]
+    fn test_subtract() {
+        let own_io = OwnIo::with_sys_root(fake_sysfs().path());
+        let now = Instant::now();
+        // a page each, plus the metadata
+        own_io.record_disk_write_at("/dev/sda", 100, now);
+        own_io.clone().record_disk_write_at("/dev/sda", 1000, now);
+        let own = 2 * (PAGE_SIZE + METADATA_ALLOWANCE) / SECTOR_SIZE;
+
+        assert_eq!(own_io.subtract("/dev/sda", own + 8, now), 8);
+        assert_eq!(own_io.subtract("/dev/sda", 8, now), 8);
+        assert_eq!(own_io.subtract("/dev/sdb", 8, now), 8);
+    }
+
+    #[test]
+    fn test_subtract_writeback() {
+        let own_io = OwnIo::with_sys_root(fake_sysfs().path());
+        let now = Instant::now();
+        own_io.record_disk_write_at("/dev/sda", 4096, now);
+
+        // still in the page cache
+        assert_eq!(own_io.subtract("/dev/sda", 0, now), 0);
+        // written back on the next poll, data and journal separately
+        let later = now + Duration::from_secs(30);
+        assert_eq!(own_io.subtract("/dev/sda", 8, later), 0);
+        assert_eq!(own_io.subtract("/dev/sda", 16, later), 0);
+
+        // too late to be ours
+        own_io.record_disk_write_at("/dev/sda", 4096, now);
+        let too_late = now + WRITEBACK_WINDOW + Duration::from_secs(1);
+        assert_eq!(own_io.subtract("/dev/sda", 8, too_late), 8);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::fs::{chown, PermissionsExt},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Mode and ownership to give written files, so a reader running as another
+/// user can rely on them instead of on the umask
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilePermissions {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    /// Mode as octal like `0644`, owner and group as names or numeric ids
+    pub fn parse(mode: Option<&str>, owner: Option<&str>, group: Option<&str>) -> Result<Self> {
+        Ok(FilePermissions {
+            mode: mode.map(parse_mode).transpose()?,
+            uid: owner.map(resolve_user).transpose()?,
+            gid: group.map(resolve_group).transpose()?,
+        })
+    }
+
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).with_context(|| {
+                format!(
+                    "Failed to set mode {:o} on {}",
+                    mode,
+                    path.to_string_lossy()
+                )
+            })?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            chown(path, self.uid, self.gid)
+                .with_context(|| format!("Failed to change owner of {}", path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let parsed = u32::from_str_radix(mode, 8)
+        .with_context(|| format!("Mode {} isn't an octal number like 0644", mode))?;
+    if parsed > 0o7777 {
+        bail!("Mode {} has more than permission bits", mode);
+    }
+    Ok(parsed)
+}
+
+fn resolve_user(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user)?;
+    // SAFETY: the name is a valid NUL terminated string and the entry is
+    // read before any other call could overwrite it
+    let uid = unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        passwd.as_ref().map(|passwd| passwd.pw_uid)
+    };
+    uid.ok_or_else(|| anyhow!("No user named {}", user))
+}
+
+fn resolve_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    // SAFETY: like in resolve_user
+    let gid = unsafe {
+        let entry = libc::getgrnam(name.as_ptr());
+        entry.as_ref().map(|entry| entry.gr_gid)
+    };
+    gid.ok_or_else(|| anyhow!("No group named {}", group))
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_permissions() {
+        let permissions = FilePermissions::parse(Some("0640"), Some("root"), Some("0")).unwrap();
+        assert_eq!(
+            permissions,
+            FilePermissions {
+                mode: Some(0o640),
+                uid: Some(0),
+                gid: Some(0),
+            }
+        );
+        assert!(FilePermissions::parse(Some("644x"), None, None).is_err());
+        assert!(FilePermissions::parse(Some("17777"), None, None).is_err());
+        assert!(FilePermissions::parse(None, Some("no-such-user"), None).is_err());
+
+        let file = NamedTempFile::new().unwrap();
+        let permissions = FilePermissions::parse(Some("604"), None, None).unwrap();
+        permissions.apply(file.path()).unwrap();
+        let mode = fs::metadata(file.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o604);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::{disks::is_same_disk, duration::deserialize_seconds, schedule::TimeWindows};
+
+/// What happens to a disk while a rule matches
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Spin the disk down, once until it was active again
+    Spindown,
+    /// Don't spin the disk down
+    KeepAwake,
+    /// Log a warning and count it, once each time the rule starts matching.
+    /// Later rules are still checked.
+    Notify,
+}
+
+/// Glob pattern from the config file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Glob(Pattern);
+
+impl TryFrom<String> for Glob {
+    type Error = glob::PatternError;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Ok(Glob(Pattern::new(&pattern)?))
+    }
+}
+
+/// A `[[rules]]` entry of the config file. All of its conditions have to be
+/// met for the rule to match, e.g.
+///
+/// ```toml
+/// [[rules]]
+/// disks = ["/dev/disk/by-id/ata-WDC_*"]
+/// during = "22:00-07:00"
+/// idle_for = 300
+/// action = "spindown"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Shown in logs and the `rule` label, defaults to `rule-<position>`
+    pub name: Option<String>,
+    /// Glob patterns of disk paths like `/dev/sd[a-c]` or
+    /// `/dev/disk/by-id/ata-WDC_*`. Without any `disks` or `names`, the rule
+    /// applies to all disks.
+    #[serde(default)]
+    pub disks: Vec<Glob>,
+    /// Glob patterns of the friendly names from `[disks]`
+    #[serde(default)]
+    pub names: Vec<Glob>,
+    /// Seconds the disk has been without I/O at least
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub idle_for: Option<u64>,
+    /// Daily windows of local time
+    pub during: Option<TimeWindows>,
+    /// Temperature in °C the disk has at least, never met if it's unknown
+    pub min_temperature: Option<f64>,
+    /// Temperature in °C the disk has at most, never met if it's unknown
+    pub max_temperature: Option<f64>,
+    pub action: Action,
+}
+
+/// What rules are checked against
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Situation {
+    /// Time since the disk's last I/O
+    pub idle: Duration,
+    /// Local time in minutes since midnight
+    pub minute_of_day: u32,
+    pub temperature: Option<f64>,
+}
+
+impl Rule {
+    fn matches_disk(&self, disk: &str, alias: Option<&str>) -> bool {
+        if self.disks.is_empty() && self.names.is_empty() {
+            return true;
+        }
+        let by_path = self.disks.iter().any(|Glob(pattern)| {
+            pattern.matches(disk)
+                // Links like the ones in /dev/disk/by-id
+                || glob::glob(pattern.as_str())
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .any(|path| is_same_disk(&path.to_string_lossy(), disk))
+        });
+        let by_name = alias.is_some_and(|alias| {
+            self.names
+                .iter()
+                .any(|Glob(pattern)| pattern.matches(alias))
+        });
+        by_path || by_name
+    }
+
+    fn matches_situation(&self, situation: &Situation) -> bool {
+        self.idle_for
+            .is_none_or(|secs| situation.idle >= Duration::from_secs(secs))
+            && self
+                .during
+                .as_ref()
+                .is_none_or(|during| during.contains(situation.minute_of_day))
+            && self
+                .min_temperature
+                .is_none_or(|min| situation.temperature.is_some_and(|t| t >= min))
+            && self
+                .max_temperature
+                .is_none_or(|max| situation.temperature.is_some_and(|t| t <= max))
+    }
+
+    fn uses_temperature(&self) -> bool {
+        self.min_temperature.is_some() || self.max_temperature.is_some()
+    }
+}
+
+/// The rules of the config file, checked in order. The first matching rule
+/// with a spindown or keep-awake action decides, without one the default
+/// policy from the command line and `[disks]` applies.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Friendly names keyed by disk path
+    aliases: BTreeMap<String, String>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>, aliases: BTreeMap<String, String>) -> Self {
+        Rules { rules, aliases }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the temperature of disks is needed to check the rules
+    pub fn uses_temperature(&self) -> bool {
+        self.rules.iter().any(Rule::uses_temperature)
+    }
+
+    /// Name of the rule at `index` for logs and metrics
+    pub fn name(&self, index: usize) -> String {
+        self.rules[index]
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("rule-{}", index + 1))
+    }
+
+    /// Positions and actions of the rules matching `disk` in `situation`,
+    /// in order
+    pub fn matching(&self, disk: &str, situation: &Situation) -> Vec<(usize, Action)> {
+        let alias = self.alias(disk);
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches_disk(disk, alias) && rule.matches_situation(situation))
+            .map(|(index, rule)| (index, rule.action))
+            .collect()
+    }
+
+    /// Whether the rule at `index` is about any of `disks`, whatever the
+    /// situation
+    pub fn applies_to_any(&self, index: usize, disks: &[String]) -> bool {
+        disks
+            .iter()
+            .any(|disk| self.rules[index].matches_disk(disk, self.alias(disk)))
+    }
+
+    fn alias(&self, disk: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|(path, _)| is_same_disk(path, disk))
+            .map(|(_, alias)| alias.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let config = Config::parse(
+            r#"
+[disks."/dev/sdb"]
+name = "media-1"
+
+[[rules]]
+name = "hot"
+min_temperature = 50.0
+action = "notify"
+
+[[rules]]
+names = ["media-*"]
+during = "22:00-07:00"
+idle_for = 60
+action = "spindown"
+
+[[rules]]
+disks = ["/dev/sd[ab]"]
+action = "keep-awake"
+"#,
+        )
+        .unwrap();
+        let rules = Rules::new(config.rules.clone(), config.aliases());
+        assert!(rules.uses_temperature());
+        assert_eq!(rules.name(0), "hot");
+        assert_eq!(rules.name(1), "rule-2");
+
+        let night = Situation {
+            idle: Duration::from_secs(120),
+            minute_of_day: 23 * 60,
+            temperature: None,
+        };
+        assert_eq!(
+            rules.matching("/dev/sdb", &night),
+            vec![(1, Action::Spindown), (2, Action::KeepAwake)]
+        );
+        assert_eq!(
+            rules.matching("/dev/sda", &night),
+            vec![(2, Action::KeepAwake)]
+        );
+        assert_eq!(rules.matching("/dev/sdc", &night), vec![]);
+
+        let hot_day = Situation {
+            idle: Duration::from_secs(120),
+            minute_of_day: 12 * 60,
+            temperature: Some(55.0),
+        };
+        assert_eq!(
+            rules.matching("/dev/sdb", &hot_day),
+            vec![(0, Action::Notify), (2, Action::KeepAwake)]
+        );
+        let busy_night = Situation {
+            idle: Duration::from_secs(30),
+            ..night
+        };
+        assert_eq!(rules.matching("/dev/sdc", &busy_night), vec![]);
+        assert_eq!(
+            rules.matching("/dev/sdb", &busy_night),
+            vec![(2, Action::KeepAwake)]
+        );
+
+        assert!(Config::parse("[[rules]]\naction = \"explode\"").is_err());
+        assert!(Config::parse("[[rules]]\ndisks = [\"[\"]\naction = \"notify\"").is_err());
+    }
+}
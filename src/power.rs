@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+
+use crate::{disk_status::PowerState, disks::is_same_disk};
+
+/// Estimated power draw of a disk in watts, by power state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Wattage {
+    pub active: f64,
+    pub idle: f64,
+    /// Also used while the disk is sleeping
+    pub standby: f64,
+}
+
+impl Default for Wattage {
+    /// Roughly a 3.5" NAS drive
+    fn default() -> Self {
+        Wattage {
+            active: 6.0,
+            idle: 4.0,
+            standby: 0.8,
+        }
+    }
+}
+
+impl Wattage {
+    /// Watts drawn in `state`, `None` if the state is unknown
+    pub fn watts(&self, state: PowerState) -> Option<f64> {
+        match state {
+            PowerState::Active => Some(self.active),
+            PowerState::Idle => Some(self.idle),
+            PowerState::Standby | PowerState::Sleeping => Some(self.standby),
+            PowerState::Unknown => None,
+        }
+    }
+}
+
+/// Wattages of all disks, from the command line and `[disks]`
+#[derive(Clone, Debug, Default)]
+pub struct PowerModel {
+    default: Wattage,
+    /// Overrides keyed by disk path
+    disks: BTreeMap<String, Wattage>,
+}
+
+impl PowerModel {
+    pub fn new(default: Wattage, disks: BTreeMap<String, Wattage>) -> Self {
+        PowerModel { default, disks }
+    }
+
+    pub fn wattage(&self, disk: &str) -> Wattage {
+        self.disks
+            .iter()
+            .find(|(path, _)| is_same_disk(path, disk))
+            .map(|(_, wattage)| *wattage)
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wattage() {
+        let quiet = Wattage {
+            active: 3.0,
+            idle: 2.0,
+            standby: 0.5,
+        };
+        let model = PowerModel::new(
+            Wattage::default(),
+            BTreeMap::from([(String::from("/dev/sdb"), quiet)]),
+        );
+        assert_eq!(model.wattage("/dev/sda"), Wattage::default());
+        assert_eq!(model.wattage("/dev/sdb").watts(PowerState::Idle), Some(2.0));
+        assert_eq!(
+            model.wattage("/dev/sdb").watts(PowerState::Sleeping),
+            Some(0.5)
+        );
+        assert_eq!(model.wattage("/dev/sdb").watts(PowerState::Unknown), None);
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use prometheus::{proto::MetricFamily, Registry};
+
+use crate::{exposition::Format, sink::ExportSink};
+
+/// Pushes metrics to a Prometheus Pushgateway, for machines that can't be
+/// scraped. Each push replaces everything pushed before under the same
+/// grouping key.
+pub struct Pushgateway {
+    agent: ureq::Agent,
+    url: String,
+}
+
+impl Pushgateway {
+    /// Push to the gateway at `base` like `http://pushgateway:9091`, grouped
+    /// by `job` and the other `grouping` labels
+    pub fn new(base: &str, job: &str, grouping: &[(String, String)]) -> Self {
+        let mut url = format!(
+            "{}/metrics/{}",
+            base.trim_end_matches('/'),
+            segment("job", job)
+        );
+        for (name, value) in grouping {
+            url.push('/');
+            url.push_str(&segment(name, value));
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        Pushgateway { agent, url }
+    }
+
+    pub fn push(&self, families: &[MetricFamily]) -> Result<()> {
+        let format = Format::Prometheus;
+        let body = format.encode(families)?;
+        self.agent
+            .put(&self.url)
+            .set("Content-Type", format.content_type())
+            .send_bytes(&body)
+            .with_context(|| format!("Failed to push metrics to {}", self.url))?;
+        Ok(())
+    }
+}
+
+impl ExportSink for Pushgateway {
+    fn name(&self) -> &str {
+        "pushgateway"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        self.push(&registry.gather())
+    }
+}
+
+/// Path segment for a grouping label, base64 encoded if the value can't be
+/// part of a path as is
+fn segment(name: &str, value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    match plain {
+        true => format!("{}/{}", name, value),
+        false => format!("{}@base64/{}", name, URL_SAFE_NO_PAD.encode(value)),
+    }
+}
+
+/// Name of this machine, the default `instance` to push as
+pub fn hostname() -> Result<String> {
+    let hostname =
+        std::fs::read_to_string("/proc/sys/kernel/hostname").context("Failed to read hostname")?;
+    Ok(hostname.trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use prometheus::{IntCounter, Registry};
+    use tiny_http::{Method, Response, Server};
+
+    use super::*;
+
+    #[test]
+    fn test_push() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let received = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let received = (request.method().clone(), request.url().to_string(), body);
+            request.respond(Response::empty(200)).unwrap();
+            received
+        });
+
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_total", "Test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let grouping = [
+            (String::from("instance"), String::from("nas")),
+            (String::from("path"), String::from("/srv")),
+        ];
+        let pushgateway =
+            Pushgateway::new(&format!("http://{}/", addr), "disk_spin_manager", &grouping);
+        pushgateway.push(&registry.gather()).unwrap();
+
+        let (method, url, body) = received.join().unwrap();
+        assert_eq!(method, Method::Put);
+        assert_eq!(
+            url,
+            "/metrics/job/disk_spin_manager/instance/nas/path@base64/L3Nydg"
+        );
+        assert!(body.ends_with("\ntest_total 1\n"));
+    }
+}
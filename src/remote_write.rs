@@ -0,0 +1,234 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prometheus::{proto::MetricFamily, Registry};
+
+use crate::{exposition::samples, sink::ExportSink};
+
+/// Sends metrics straight to a Prometheus remote_write endpoint, for
+/// machines nothing scrapes. All series are sent as one snappy compressed
+/// `WriteRequest` per interval.
+pub struct RemoteWriter {
+    agent: ureq::Agent,
+    url: String,
+    authorization: Option<String>,
+    /// Added to every series, like `instance`
+    labels: Vec<(String, String)>,
+    interval: Duration,
+    last_sent: Mutex<Option<Instant>>,
+}
+
+impl RemoteWriter {
+    /// Write to an endpoint like `http://prometheus:9090/api/v1/write`
+    pub fn new(url: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        RemoteWriter {
+            agent,
+            url: url.to_string(),
+            authorization: None,
+            labels: Vec::new(),
+            interval: Duration::ZERO,
+            last_sent: Mutex::new(None),
+        }
+    }
+
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        self.authorization = Some(format!("Basic {}", credentials));
+        self
+    }
+
+    pub fn with_label(mut self, name: &str, value: &str) -> Self {
+        self.labels.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send at most once per `interval` instead of on every flush
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn write(&self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let request = encode_write_request(families, &self.labels, timestamp);
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request)
+            .context("Failed to compress remote write request")?;
+        let mut post = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/x-protobuf")
+            .set("Content-Encoding", "snappy")
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(authorization) = &self.authorization {
+            post = post.set("Authorization", authorization);
+        }
+        post.send_bytes(&body)
+            .with_context(|| format!("Failed to remote write to {}", self.url))?;
+        Ok(())
+    }
+}
+
+impl ExportSink for RemoteWriter {
+    fn name(&self) -> &str {
+        "remote_write"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if last_sent.is_some_and(|at| at.elapsed() < self.interval) {
+            return Ok(());
+        }
+        *last_sent = Some(Instant::now());
+        self.write(&registry.gather())
+    }
+}
+
+/// Protobuf encoded `WriteRequest` with one sample at `timestamp` in
+/// milliseconds per series
+pub fn encode_write_request(
+    families: &[MetricFamily],
+    extra_labels: &[(String, String)],
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut request = Vec::new();
+    for sample in families.iter().flat_map(samples) {
+        let mut labels = vec![("__name__", sample.name.as_str())];
+        labels.extend(sample.labels.iter().map(|(n, v)| (*n, v.as_str())));
+        labels.extend(extra_labels.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        // Receivers want labels sorted by name
+        labels.sort();
+        let series = encode_series(&labels, sample.value, timestamp);
+        put_bytes(&mut request, 1, &series);
+    }
+    request
+}
+
+/// `TimeSeries` with a single `Sample`
+fn encode_series(labels: &[(&str, &str)], value: f64, timestamp: i64) -> Vec<u8> {
+    let mut series = Vec::new();
+    for (name, value) in labels {
+        let mut label = Vec::new();
+        put_bytes(&mut label, 1, name.as_bytes());
+        put_bytes(&mut label, 2, value.as_bytes());
+        put_bytes(&mut series, 1, &label);
+    }
+    let mut sample = Vec::new();
+    put_varint(&mut sample, 1 << 3 | 1);
+    sample.extend_from_slice(&value.to_le_bytes());
+    put_varint(&mut sample, 2 << 3);
+    put_varint(&mut sample, timestamp as u64);
+    put_bytes(&mut series, 2, &sample);
+    series
+}
+
+/// Length delimited field
+fn put_bytes(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buffer, field << 3 | 2);
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use prometheus::IntCounter;
+    use tiny_http::{Request, Response, Server};
+
+    use super::*;
+
+    fn header(request: &Request, name: &'static str) -> Option<String> {
+        let header = request.headers().iter().find(|h| h.field.equiv(name));
+        header.map(|h| h.value.to_string())
+    }
+
+    #[test]
+    fn test_remote_write() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let received = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let headers = (
+                header(&request, "Content-Encoding"),
+                header(&request, "Authorization"),
+            );
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            request.respond(Response::empty(204)).unwrap();
+            (headers, body)
+        });
+
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_total", "Test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let writer = RemoteWriter::new(&format!("http://{}/api/v1/write", addr))
+            .with_basic_auth("user", "secret")
+            .with_label("instance", "nas");
+        writer.write(&registry.gather()).unwrap();
+
+        let ((encoding, authorization), body) = received.join().unwrap();
+        assert_eq!(encoding.as_deref(), Some("snappy"));
+        assert_eq!(authorization.as_deref(), Some("Basic dXNlcjpzZWNyZXQ="));
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert!(request.windows(10).any(|name| name == b"test_total"));
+
+        let expected = [
+            &[0x0a, 55, 0x0a, 22, 0x0a, 8][..],
+            b"__name__",
+            &[0x12, 10],
+            b"test_total",
+            &[0x0a, 15, 0x0a, 8],
+            b"instance",
+            &[0x12, 3],
+            b"nas",
+            &[0x12, 12, 0x09],
+            &1.0f64.to_le_bytes(),
+            &[0x10, 0xe8, 0x07],
+        ]
+        .concat();
+        let labels = [(String::from("instance"), String::from("nas"))];
+        assert_eq!(
+            encode_write_request(&registry.gather(), &labels, 1000),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_export_interval() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let writer = RemoteWriter::new(&format!("http://{}/api/v1/write", addr))
+            .with_interval(Duration::from_secs(3600));
+        let registry = Registry::new();
+
+        let exported = thread::spawn(move || {
+            writer.export(&registry).unwrap();
+            // Too soon, so nothing is sent
+            writer.export(&registry).unwrap();
+        });
+        let request = server.recv().unwrap();
+        request.respond(Response::empty(204)).unwrap();
+        exported.join().unwrap();
+        assert!(server.try_recv().unwrap().is_none());
+    }
+}
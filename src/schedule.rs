@@ -0,0 +1,150 @@
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Daily window of local time like `02:00-05:00`. Windows ending before they
+/// start wrap around midnight, e.g. `22:00-06:00`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeWindow {
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// Whether the minute of the day is inside the window, the end is
+    /// exclusive
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", s))?;
+        Ok(TimeWindow {
+            start: parse_time(start.trim())?,
+            end: parse_time(end.trim())?,
+        })
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .ok_or_else(|| format!("expected HH:MM, got '{}'", time))?;
+    let hours: u32 = hours
+        .parse()
+        .map_err(|_| format!("invalid hour in '{}'", time))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|_| format!("invalid minute in '{}'", time))?;
+    // 24:00 is allowed as the end of the day
+    if minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(format!("time out of range: '{}'", time));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Comma separated list of windows, e.g. `02:00-05:00,12:00-13:00`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct TimeWindows(pub Vec<TimeWindow>);
+
+impl TimeWindows {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        self.0.iter().any(|window| window.contains(minute_of_day))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for TimeWindows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|window| !window.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(TimeWindows)
+    }
+}
+
+impl TryFrom<String> for TimeWindows {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Minutes since local midnight
+pub fn local_minute_of_day() -> u32 {
+    // SAFETY: localtime_r only writes to the tm struct we own
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_window() {
+        let night: TimeWindow = "02:00-05:00".parse().unwrap();
+        assert!(!night.contains(119));
+        assert!(night.contains(120));
+        assert!(night.contains(299));
+        assert!(!night.contains(300));
+        assert_eq!(night.to_string(), "02:00-05:00");
+
+        let wrapping: TimeWindow = "22:30-06:00".parse().unwrap();
+        assert!(wrapping.contains(23 * 60));
+        assert!(wrapping.contains(0));
+        assert!(!wrapping.contains(12 * 60));
+
+        assert!("02:00".parse::<TimeWindow>().is_err());
+        assert!("25:00-26:00".parse::<TimeWindow>().is_err());
+        assert!("02:60-03:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let windows: TimeWindows = "02:00-05:00, 12:00-13:00".parse().unwrap();
+        assert!(windows.contains(12 * 60 + 30));
+        assert!(!windows.contains(6 * 60));
+        assert!("".parse::<TimeWindows>().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use std::{io, mem, time::Duration};
+
+use anyhow::{Context, Result};
+
+/// Signals that are waited for by a thread instead of interrupting whatever
+/// thread the kernel picks. Blocking them has to happen before any other
+/// thread is spawned, as threads inherit the signal mask.
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    /// Block `signals` in the calling thread and every thread spawned from
+    /// it later on
+    pub fn block(signals: &[libc::c_int]) -> Result<Self> {
+        // SAFETY: the set is initialized by sigemptyset before it's used
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                if libc::sigaddset(&mut set, *signal) < 0 {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("Invalid signal {}", signal));
+                }
+            }
+            let err = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err)).context("Failed to block signals");
+            }
+            Ok(Signals { set })
+        }
+    }
+
+    /// Block until one of the signals arrives and return it
+    pub fn wait(&self) -> Result<libc::c_int> {
+        let mut signal = 0;
+        // SAFETY: the set was initialized in block
+        let err = unsafe { libc::sigwait(&self.set, &mut signal) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err)).context("Failed to wait for signals");
+        }
+        Ok(signal)
+    }
+
+    /// Like `wait`, but give up after `timeout` and return `None`
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Option<libc::c_int>> {
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        // SAFETY: the set was initialized in block, the info may be null
+        let signal = unsafe { libc::sigtimedwait(&self.set, std::ptr::null_mut(), &timeout) };
+        if signal < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EAGAIN) => Ok(None),
+                // Interrupted by a signal that isn't in the set
+                Some(libc::EINTR) => Ok(None),
+                _ => Err(err).context("Failed to wait for signals"),
+            };
+        }
+        Ok(Some(signal))
+    }
+
+    /// Let the signals interrupt the calling thread again, for when nothing
+    /// is going to wait for them. Threads spawned before keep blocking them.
+    pub fn unblock(self) -> Result<()> {
+        // SAFETY: the set was initialized in block
+        let err =
+            unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &self.set, std::ptr::null_mut()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err)).context("Failed to unblock signals");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_wait() {
+        // Only this thread blocks the signal, so it can't hit another test
+        thread::spawn(|| {
+            let signals = Signals::block(&[libc::SIGHUP]).unwrap();
+            // SAFETY: the signal is blocked, so it stays pending
+            unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGHUP) };
+            assert_eq!(signals.wait().unwrap(), libc::SIGHUP);
+
+            let timeout = Duration::from_millis(10);
+            assert_eq!(signals.wait_timeout(timeout).unwrap(), None);
+            // SAFETY: as above
+            unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGHUP) };
+            assert_eq!(signals.wait_timeout(timeout).unwrap(), Some(libc::SIGHUP));
+        })
+        .join()
+        .unwrap();
+    }
+
+    fn is_blocked(signal: libc::c_int) -> bool {
+        // SAFETY: the mask is filled in by pthread_sigmask before it's read
+        unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask);
+            libc::sigismember(&mask, signal) == 1
+        }
+    }
+
+    #[test]
+    fn test_unblock() {
+        thread::spawn(|| {
+            let signals = Signals::block(&[libc::SIGHUP]).unwrap();
+            assert!(is_blocked(libc::SIGHUP));
+            signals.unblock().unwrap();
+            assert!(!is_blocked(libc::SIGHUP));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+use anyhow::Result;
+use prometheus::Registry;
+
+/// Somewhere the metrics go on every flush, like the textfile or a
+/// Pushgateway. Any number of sinks can be configured at once.
+pub trait ExportSink: Send {
+    /// Short name to tell sinks apart in logs
+    fn name(&self) -> &str;
+
+    /// Export the current state of `registry`. Sinks get the registry rather
+    /// than gathered metrics, so they can update metrics about themselves
+    /// first.
+    fn export(&self, registry: &Registry) -> Result<()>;
+}
@@ -0,0 +1,72 @@
+use std::{
+    sync::mpsc::Sender,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+
+use crate::{
+    disks::DiskList,
+    load_cycles::{LoadCycles, LOAD_CYCLE_COUNT},
+    metrics::MetricMessage,
+    smartctl::Smartctl,
+};
+
+/// Regularly read the SMART attributes of all disks that are spinning anyway
+/// and send their load cycle count and the `attributes` to export to `tx`
+/// until the receiving side goes away
+pub fn smart_loop(
+    smartctl: Smartctl,
+    disk_list: impl DiskList,
+    interval: Duration,
+    mut load_cycles: LoadCycles,
+    attributes: Vec<u8>,
+    tx: Sender<MetricMessage>,
+) {
+    loop {
+        let disks = match disk_list.get_all_disks() {
+            Ok(disks) => disks,
+            Err(err) => {
+                error!("Error listing disks for SMART attributes: {:?}", err);
+                Vec::new()
+            }
+        };
+        for disk in disks {
+            let mut all_attributes = match smartctl.read_attributes(&disk) {
+                Ok(Some(attributes)) => attributes,
+                Ok(None) => {
+                    debug!("Not reading SMART attributes of {} in standby", disk);
+                    continue;
+                }
+                Err(err) => {
+                    debug!("Failed to read SMART attributes of {}: {:?}", disk, err);
+                    continue;
+                }
+            };
+            if let Some(count) = all_attributes.get(&LOAD_CYCLE_COUNT).map(|a| a.raw) {
+                let (_, excessive) = load_cycles.record(&disk, count, Instant::now());
+                let message = MetricMessage::LoadCycles {
+                    disk: disk.clone(),
+                    count,
+                    excessive,
+                };
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+            if attributes.is_empty() {
+                continue;
+            }
+            all_attributes.retain(|id, _| attributes.contains(id));
+            let message = MetricMessage::SmartAttributes {
+                disk,
+                attributes: all_attributes,
+            };
+            if tx.send(message).is_err() {
+                return;
+            }
+        }
+        sleep(interval);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+
+use crate::{
+    command::CommandRunner,
+    disk_status::{read_uncached_block_with, DiskControl, DiskStatus, PowerState},
+};
+
+/// A row of the `smartctl -A` attribute table
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmartAttribute {
+    /// Like `Reallocated_Sector_Ct`, names vary between vendors
+    pub name: String,
+    pub raw: u64,
+}
+
+pub struct Smartctl {
+    pub path: String,
+    /// Value passed to `-d`, e.g. `sat` for USB bridges
+    pub device_type: Option<String>,
+    pub runner: Arc<dyn CommandRunner>,
+}
+
+impl Smartctl {
+    fn run(&self, args: &[&str], disk: &str) -> Result<std::process::Output> {
+        let mut args = args.to_vec();
+        if let Some(device_type) = &self.device_type {
+            args.extend(["-d", device_type]);
+        }
+        args.push(disk);
+        self.runner.run(&self.path, &args)
+    }
+}
+
+impl DiskStatus for Smartctl {
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+        // `-n standby` makes smartctl bail out instead of waking up the disk
+        let output = self.run(&["-i", "-n", "standby"], disk)?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!(
+            "smartctl finished with exit_code: {}, stderr: '{}', stdout: '{}'",
+            output.status,
+            String::from_utf8_lossy(&output.stderr),
+            stdout
+        );
+
+        // smartctl exits with a non-zero status when it skipped a device in
+        // standby, so only treat the exit code as an error if there's no
+        // state in the output.
+        match parse_smartctl_output(&stdout) {
+            Ok(status) => Ok(status),
+            Err(err) if !output.status.success() => {
+                bail!("smartctl execution error: {:?}: {:?}", err, output)
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to parse smartctl output for {}", disk))
+            }
+        }
+    }
+}
+
+impl Smartctl {
+    /// SMART attributes keyed by their ID, `None` if the disk is in standby,
+    /// it isn't woken up to read them
+    pub fn read_attributes(&self, disk: &str) -> Result<Option<BTreeMap<u8, SmartAttribute>>> {
+        let output = self.run(&["-A", "-n", "standby"], disk)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if let Ok(PowerState::Standby | PowerState::Sleeping) = parse_smartctl_output(&stdout) {
+            return Ok(None);
+        }
+        if !output.status.success() {
+            bail!("smartctl execution error: {:?}", output);
+        }
+        let attributes = parse_smartctl_attributes(&stdout);
+        if attributes.is_empty() {
+            bail!("No SMART attributes in smartctl output for {}", disk);
+        }
+        Ok(Some(attributes))
+    }
+
+    /// Change a device setting with `-s`
+    fn set(&self, setting: &str, disk: &str) -> Result<()> {
+        let output = self.run(&["-s", setting], disk)?;
+        if !output.status.success() {
+            bail!("smartctl execution error: {:?}", output);
+        }
+        Ok(())
+    }
+}
+
+impl DiskControl for Smartctl {
+    fn spindown(&self, disk: &str) -> Result<()> {
+        self.set("standby,now", disk)
+    }
+
+    /// Read through the same runner so `--privilege-helper` applies
+    fn spinup(&self, disk: &str) -> Result<()> {
+        read_uncached_block_with(self.runner.as_ref(), disk)
+    }
+
+    fn set_apm(&self, disk: &str, level: u8) -> Result<()> {
+        match level {
+            255 => self.set("apm,off", disk),
+            level => self.set(&format!("apm,{}", level), disk),
+        }
+    }
+
+    fn set_standby_timer(&self, disk: &str, timer: u8) -> Result<()> {
+        self.set(&format!("standby,{}", timer), disk)
+    }
+}
+
+/// Parse the attribute table of `smartctl -A`. Raw values with extra
+/// details like `33 (Min/Max 18/45)` only keep the first number.
+pub fn parse_smartctl_attributes(output: &str) -> BTreeMap<u8, SmartAttribute> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let id = fields.first()?.parse().ok()?;
+            let attribute = SmartAttribute {
+                name: fields.get(1)?.to_string(),
+                raw: fields.get(9)?.parse().ok()?,
+            };
+            Some((id, attribute))
+        })
+        .collect()
+}
+
+/// Parse the output of `smartctl -i -n standby` into a power state.
+pub fn parse_smartctl_output(output: &str) -> Result<PowerState> {
+    for line in output.lines() {
+        if let Some((_, mode)) = line.split_once("Device is in") {
+            let mode = mode.split_whitespace().next().unwrap_or_default();
+            return match mode {
+                "STANDBY" => Ok(PowerState::Standby),
+                "SLEEP" => Ok(PowerState::Sleeping),
+                _ => bail!("Unrecognized power mode: '{}'", mode),
+            };
+        }
+        if let Some((_, mode)) = line
+            .split_once("Power mode is:")
+            .or_else(|| line.split_once("Power mode was:"))
+        {
+            let mode = mode.trim();
+            return match mode.split(['_', ' ']).next().unwrap_or_default() {
+                // "ACTIVE or IDLE" is hdparm's "active/idle"
+                "ACTIVE" => Ok(PowerState::Active),
+                "IDLE" => Ok(PowerState::Idle),
+                "STANDBY" => Ok(PowerState::Standby),
+                "SLEEP" => Ok(PowerState::Sleeping),
+                _ => bail!("Unrecognized power mode: '{}'", mode),
+            };
+        }
+    }
+    bail!("No power mode found in smartctl output")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::test::FakeRunner;
+
+    use super::*;
+
+    #[test]
+    fn test_smartctl() {
+        let smartctl = Smartctl {
+            path: String::from("smartctl"),
+            device_type: Some(String::from("sat")),
+            runner: Arc::new(FakeRunner::default().with_output(
+                "smartctl -i -n standby -d sat /dev/sdc",
+                include_str!("../fixtures/smartctl/active.txt"),
+            )),
+        };
+        assert_eq!(
+            smartctl.get_disk_status("/dev/sdc").unwrap(),
+            PowerState::Active
+        );
+    }
+
+    #[test]
+    fn test_smartctl_spindown() {
+        let smartctl = Smartctl {
+            path: String::from("smartctl"),
+            device_type: None,
+            runner: Arc::new(
+                FakeRunner::default().with_output("smartctl -s standby,now /dev/sdc", ""),
+            ),
+        };
+        smartctl.spindown("/dev/sdc").unwrap();
+    }
+
+    #[test]
+    fn test_parse_smartctl_output() {
+        let fixtures = [
+            (
+                include_str!("../fixtures/smartctl/standby.txt"),
+                PowerState::Standby,
+            ),
+            (
+                include_str!("../fixtures/smartctl/sleep.txt"),
+                PowerState::Sleeping,
+            ),
+            (
+                include_str!("../fixtures/smartctl/active.txt"),
+                PowerState::Active,
+            ),
+            (
+                include_str!("../fixtures/smartctl/idle_b.txt"),
+                PowerState::Idle,
+            ),
+        ];
+        for (output, expected) in fixtures {
+            assert_eq!(
+                parse_smartctl_output(output).unwrap(),
+                expected,
+                "{}",
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_attributes() {
+        let smartctl = Smartctl {
+            path: String::from("smartctl"),
+            device_type: None,
+            runner: Arc::new(
+                FakeRunner::default()
+                    .with_output(
+                        "smartctl -A -n standby /dev/sda",
+                        include_str!("../fixtures/smartctl/attributes.txt"),
+                    )
+                    .with_output(
+                        "smartctl -A -n standby /dev/sdb",
+                        include_str!("../fixtures/smartctl/standby.txt"),
+                    ),
+            ),
+        };
+        let attributes = smartctl.read_attributes("/dev/sda").unwrap().unwrap();
+        assert_eq!(attributes.len(), 17);
+        assert_eq!(
+            attributes[&193],
+            SmartAttribute {
+                name: String::from("Load_Cycle_Count"),
+                raw: 17342
+            }
+        );
+        assert_eq!(attributes[&194].raw, 33);
+        assert_eq!(smartctl.read_attributes("/dev/sdb").unwrap(), None);
+        assert!(smartctl.read_attributes("/dev/sdc").is_err());
+    }
+
+    #[test]
+    fn test_parse_smartctl_output_invalid() {
+        assert!(
+            parse_smartctl_output(include_str!("../fixtures/smartctl/missing_state.txt")).is_err()
+        );
+    }
+}
@@ -0,0 +1,1140 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+
+use crate::{
+    config::Config,
+    disk_status::{DiskControl, DiskRetries, DiskStatus, PowerState, RetryPolicy},
+    disks::{is_same_disk, DiskList},
+    diskstats::ActivityEvent,
+    metrics::MetricMessage,
+    mounts::syncfs,
+    policy::{Action, Rules, Situation},
+    schedule::TimeWindows,
+};
+
+/// Outcome of a disk becoming due for a spin-down after being idle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpindownResult {
+    Success,
+    /// The backend failed to spin the disk down
+    Failure,
+    /// A keep-awake window or rule, the minimum spin-up time or the cycle
+    /// budget kept the disk spinning
+    SkippedPolicy,
+}
+
+impl SpindownResult {
+    pub const ALL: [SpindownResult; 3] = [
+        SpindownResult::Success,
+        SpindownResult::Failure,
+        SpindownResult::SkippedPolicy,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpindownResult::Success => "success",
+            SpindownResult::Failure => "failure",
+            SpindownResult::SkippedPolicy => "skipped_policy",
+        }
+    }
+}
+
+/// Period over which spin-down cycles count against the budget
+const CYCLE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When disks get spun down
+#[derive(Clone, Debug, Default)]
+pub struct SpindownPolicy {
+    /// Spin down a disk after it didn't see any I/O for this long, `None` to
+    /// only spin down disks that have their own timeout
+    pub idle_timeout: Option<Duration>,
+    /// Keep a disk spinning for at least this long after it spun up
+    pub min_spinup: Duration,
+    /// Daily windows during which disks aren't spun down
+    pub keep_awake: TimeWindows,
+    /// Spin disks up when a keep-awake window starts
+    pub keep_awake_spinup: bool,
+    /// Spin disks down again that woke up without any I/O being seen for
+    /// this long, `None` to leave them alone
+    pub enforce_standby: Option<Duration>,
+    /// Spin a disk down at most this many times within 24 hours
+    pub max_cycles: Option<u32>,
+    /// Spin disks up that didn't see any I/O for this long
+    pub exercise: Option<Duration>,
+    /// Sync the filesystems on a disk before spinning it down
+    pub sync_filesystems: bool,
+    /// I/O within this long after a spin-down counts as an early wakeup
+    pub early_wakeup: Duration,
+    /// Disks that are never spun down, like the one holding `/`
+    pub protected: Vec<String>,
+    /// Per-disk settings, keyed by any path of the disk
+    pub disks: BTreeMap<String, DiskSpindown>,
+    /// Rules from the config file, checked before the settings above
+    pub rules: Rules,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskSpindown {
+    pub idle_timeout: Option<Duration>,
+    pub min_spinup: Option<Duration>,
+    pub never: bool,
+    pub keep_awake: Option<TimeWindows>,
+    pub keep_awake_spinup: Option<bool>,
+    pub max_cycles: Option<u32>,
+    pub exercise: Option<Duration>,
+}
+
+/// Effective settings of a single disk
+#[derive(Debug, PartialEq)]
+struct DiskPolicy<'a> {
+    /// `None` if the disk isn't spun down after being idle
+    idle_timeout: Option<Duration>,
+    /// False for disks that must not be spun down, not even by rules
+    may_spindown: bool,
+    min_spinup: Duration,
+    keep_awake: &'a TimeWindows,
+    keep_awake_spinup: bool,
+    max_cycles: Option<u32>,
+    exercise: Option<Duration>,
+}
+
+impl SpindownPolicy {
+    /// Combine the global settings with the per-disk ones from `config`
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.disks = config
+            .disks
+            .iter()
+            .map(|(disk, disk_config)| {
+                let spindown = DiskSpindown {
+                    idle_timeout: disk_config.spindown_after.map(Duration::from_secs),
+                    min_spinup: disk_config.min_spinup.map(Duration::from_secs),
+                    never: disk_config.never_spindown,
+                    keep_awake: disk_config.keep_awake.clone(),
+                    keep_awake_spinup: disk_config.keep_awake_spinup,
+                    max_cycles: disk_config.max_spin_cycles,
+                    exercise: disk_config.exercise_interval.map(Duration::from_secs),
+                };
+                (disk.clone(), spindown)
+            })
+            .collect();
+        self.rules = Rules::new(config.rules.clone(), config.aliases());
+        self
+    }
+
+    /// Whether any disk could ever be spun down or up
+    pub fn is_enabled(&self) -> bool {
+        self.idle_timeout.is_some()
+            || self.keep_awake_spinup
+            || self.exercise.is_some()
+            || !self.rules.is_empty()
+            || self.disks.values().any(|disk| {
+                (!disk.never && disk.idle_timeout.is_some())
+                    || disk.keep_awake_spinup == Some(true)
+                    || disk.exercise.is_some()
+            })
+    }
+
+    fn for_disk(&self, disk: &str) -> DiskPolicy<'_> {
+        let protected = self.protected.iter().any(|path| is_same_disk(path, disk));
+        let global = DiskPolicy {
+            idle_timeout: self.idle_timeout.filter(|_| !protected),
+            may_spindown: !protected,
+            min_spinup: self.min_spinup,
+            keep_awake: &self.keep_awake,
+            keep_awake_spinup: self.keep_awake_spinup,
+            max_cycles: self.max_cycles,
+            exercise: self.exercise,
+        };
+        let Some((_, config)) = self.disks.iter().find(|(path, _)| is_same_disk(path, disk)) else {
+            return global;
+        };
+        DiskPolicy {
+            idle_timeout: match config.never || protected {
+                true => None,
+                false => config.idle_timeout.or(global.idle_timeout),
+            },
+            may_spindown: !config.never && global.may_spindown,
+            min_spinup: config.min_spinup.unwrap_or(global.min_spinup),
+            keep_awake: config.keep_awake.as_ref().unwrap_or(global.keep_awake),
+            keep_awake_spinup: config.keep_awake_spinup.unwrap_or(global.keep_awake_spinup),
+            max_cycles: config.max_cycles.or(global.max_cycles),
+            exercise: config.exercise.or(global.exercise),
+        }
+    }
+}
+
+struct IdleState {
+    active_at: Instant,
+    /// First activity after the disk was spun down (or seen the first time)
+    spun_up_at: Instant,
+    /// Already spun down since the last activity, don't do it again
+    spun_down: bool,
+    /// Last successful spin-down
+    spun_down_at: Option<Instant>,
+    /// Inside a keep-awake window during the last check
+    keep_awake: bool,
+    /// When the disk was first seen spinning again after it was spun down,
+    /// without any I/O since
+    woke_at: Option<Instant>,
+    /// Spin-downs within the last 24 hours, oldest first
+    cycles: VecDeque<Instant>,
+    /// Remaining cycle budget that was last reported
+    reported_budget: Option<u32>,
+    /// Notify rules that matched during the last check
+    notified: HashSet<usize>,
+    /// Last time the disk was spun up to exercise it
+    exercised_at: Option<Instant>,
+    /// A skipped spin-down was reported since the last activity
+    skip_reported: bool,
+}
+
+impl IdleState {
+    /// Spin-downs left within the last 24 hours, `None` without a budget
+    fn budget_remaining(&mut self, max_cycles: Option<u32>, now: Instant) -> Option<u32> {
+        let max_cycles = max_cycles?;
+        while let Some(&cycle) = self.cycles.front() {
+            if now.duration_since(cycle) < CYCLE_WINDOW {
+                break;
+            }
+            self.cycles.pop_front();
+        }
+        Some(max_cycles.saturating_sub(self.cycles.len() as u32))
+    }
+
+    /// Send the remaining cycle budget if it changed since it was last sent
+    fn report_budget(
+        &mut self,
+        disk: &str,
+        max_cycles: Option<u32>,
+        now: Instant,
+        tx: &Sender<MetricMessage>,
+    ) -> Result<()> {
+        let remaining = self.budget_remaining(max_cycles, now);
+        if remaining != self.reported_budget {
+            if let Some(remaining) = remaining {
+                tx.send(MetricMessage::CycleBudget {
+                    disk: disk.to_string(),
+                    remaining,
+                })?;
+            }
+            self.reported_budget = remaining;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks how long each disk has been idle and spins it down once it
+/// exceeds the idle timeout
+pub struct Spindown<C> {
+    control: C,
+    policy: SpindownPolicy,
+    disks: HashMap<String, IdleState>,
+    /// Source of the temperatures rules check and the filesystems to sync
+    disk_list: Option<Box<dyn DiskList + Send>>,
+    /// Backoff for disks that failed to spin down, e.g. because their USB
+    /// bridge rejects standby
+    retries: DiskRetries,
+}
+
+impl<C: DiskControl + DiskStatus> Spindown<C> {
+    pub fn new(control: C, policy: SpindownPolicy) -> Self {
+        Spindown {
+            control,
+            policy,
+            disks: HashMap::new(),
+            disk_list: None,
+            retries: DiskRetries::new(RetryPolicy::default()),
+        }
+    }
+
+    /// Back off like this after a spin-down failed
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retries = DiskRetries::new(policy);
+        self
+    }
+
+    pub fn with_disk_list(mut self, disk_list: impl DiskList + Send + 'static) -> Self {
+        self.disk_list = Some(Box::new(disk_list));
+        self
+    }
+
+    /// Replace the policy, e.g. after the config was reloaded. How long disks
+    /// have been idle is kept.
+    pub fn set_policy(&mut self, policy: SpindownPolicy) {
+        self.policy = policy;
+    }
+
+    /// Update the idle time of the disks in `events` and spin down the ones
+    /// that have been idle for long enough, unless `minute_of_day` (local
+    /// time) is inside one of their keep-awake windows. A disk that is seen
+    /// for the first time counts as active, disks without an event are
+    /// forgotten. With standby enforcement, spun down disks are queried and
+    /// put back into standby if they woke up without any I/O. Matching rules
+    /// take precedence over the idle timeout and keep-awake windows. Disks
+    /// without I/O for longer than the exercise interval are spun up.
+    pub fn handle_activity(
+        &mut self,
+        events: &[ActivityEvent],
+        now: Instant,
+        minute_of_day: u32,
+        tx: &Sender<MetricMessage>,
+    ) -> Result<()> {
+        let retries = &self.retries;
+        self.disks.retain(|disk, _| {
+            let present = events.iter().any(|event| &event.disk == disk);
+            if !present {
+                retries.forget(disk);
+            }
+            present
+        });
+        for event in events {
+            let disk = &event.disk;
+            let policy = self.policy.for_disk(disk);
+            let window = policy.keep_awake.contains(minute_of_day);
+            let Some(state) = self.disks.get_mut(disk) else {
+                self.disks.insert(
+                    disk.clone(),
+                    IdleState {
+                        active_at: now,
+                        spun_up_at: now,
+                        spun_down: false,
+                        spun_down_at: None,
+                        keep_awake: window,
+                        woke_at: None,
+                        cycles: VecDeque::new(),
+                        reported_budget: None,
+                        notified: HashSet::new(),
+                        exercised_at: None,
+                        skip_reported: false,
+                    },
+                );
+                continue;
+            };
+            state.report_budget(disk, policy.max_cycles, now, tx)?;
+            let budget_exhausted = state.budget_remaining(policy.max_cycles, now) == Some(0);
+            let window_started = window && !state.keep_awake;
+            state.keep_awake = window;
+            if event.is_active() {
+                if state.spun_down {
+                    state.spun_up_at = now;
+                }
+                let early = state
+                    .spun_down_at
+                    .take()
+                    .is_some_and(|at| now.duration_since(at) < self.policy.early_wakeup);
+                if early {
+                    debug!("{} woke up right after it was spun down", disk);
+                    tx.send(MetricMessage::EarlyWakeup { disk: disk.clone() })?;
+                }
+                state.active_at = now;
+                state.spun_down = false;
+                state.woke_at = None;
+                state.skip_reported = false;
+            }
+            let idle = now.duration_since(state.active_at);
+            let temperature = match &self.disk_list {
+                // Don't wake up disks just for their temperature
+                Some(disk_list) if self.policy.rules.uses_temperature() && !state.spun_down => {
+                    disk_list.get_temperature(disk).unwrap_or_else(|err| {
+                        debug!("Failed to read temperature of {}: {:?}", disk, err);
+                        None
+                    })
+                }
+                _ => None,
+            };
+            let situation = Situation {
+                idle,
+                minute_of_day,
+                temperature,
+            };
+            let decision = check_rules(
+                &self.policy.rules,
+                disk,
+                &situation,
+                &mut state.notified,
+                tx,
+            )?;
+            if event.is_active() {
+                continue;
+            }
+            let keep_awake = match decision {
+                Some((_, action)) => action == Action::KeepAwake,
+                None => window,
+            };
+            if keep_awake && window_started && policy.keep_awake_spinup {
+                match self.control.spinup(disk) {
+                    Ok(()) => info!(disk; "Spun up {} for keep-awake window", disk),
+                    Err(err) => error!(disk; "Failed to spin up {}: {:?}", disk, err),
+                }
+                // The read shows up as activity on the next poll
+                continue;
+            }
+            if let Some(interval) = policy.exercise {
+                // Don't retry right away if it failed
+                let since = state
+                    .exercised_at
+                    .map_or(state.active_at, |at| at.max(state.active_at));
+                if now.duration_since(since) >= interval {
+                    state.exercised_at = Some(now);
+                    match self.control.spinup(disk) {
+                        Ok(()) => {
+                            info!(disk; "Exercised {} after being idle for {:?}", disk, idle);
+                            tx.send(MetricMessage::Exercised { disk: disk.clone() })?;
+                        }
+                        Err(err) => error!(disk; "Failed to exercise {}: {:?}", disk, err),
+                    }
+                    // The read shows up as activity on the next poll
+                    continue;
+                }
+            }
+            if state.spun_down {
+                if let (Some(grace), false) = (self.policy.enforce_standby, keep_awake) {
+                    let woke_at = match self.control.get_disk_status(disk) {
+                        Ok(PowerState::Active | PowerState::Idle) => {
+                            *state.woke_at.get_or_insert(now)
+                        }
+                        Ok(_) => {
+                            state.woke_at = None;
+                            continue;
+                        }
+                        Err(err) => {
+                            debug!("Failed to check if {} is in standby: {:?}", disk, err);
+                            continue;
+                        }
+                    };
+                    // Give the I/O that woke the disk time to show up
+                    if now.duration_since(woke_at) < grace {
+                        continue;
+                    }
+                    if budget_exhausted {
+                        debug!("Not spinning down {} again, cycle budget exhausted", disk);
+                        continue;
+                    }
+                    if self.policy.sync_filesystems {
+                        sync_filesystems(self.disk_list.as_deref(), disk);
+                    }
+                    let success = match self.control.spindown(disk) {
+                        Ok(()) => {
+                            info!(disk; "Spun down {} again after it woke up without I/O", disk);
+                            state.cycles.push_back(now);
+                            state.spun_down_at = Some(now);
+                            true
+                        }
+                        Err(err) => {
+                            error!(disk; "Failed to spin down {} again: {:?}", disk, err);
+                            false
+                        }
+                    };
+                    state.woke_at = None;
+                    tx.send(MetricMessage::StandbyEnforced {
+                        disk: disk.clone(),
+                        success,
+                    })?;
+                    state.report_budget(disk, policy.max_cycles, now, tx)?;
+                }
+                continue;
+            }
+            let timed_out = policy.idle_timeout.is_some_and(|timeout| idle >= timeout);
+            let spin_down = match decision {
+                Some((_, action)) => action == Action::Spindown && policy.may_spindown,
+                None => !keep_awake && timed_out,
+            };
+            let skipped = if !spin_down || now.duration_since(state.spun_up_at) < policy.min_spinup
+            {
+                true
+            } else if budget_exhausted {
+                debug!("Not spinning down {}, cycle budget exhausted", disk);
+                true
+            } else {
+                false
+            };
+            if skipped {
+                let due =
+                    timed_out || decision.is_some_and(|(_, action)| action == Action::Spindown);
+                // Once per idle period, not on every poll
+                if due && !state.skip_reported {
+                    state.skip_reported = true;
+                    tx.send(MetricMessage::SpindownAttempt {
+                        disk: disk.clone(),
+                        result: SpindownResult::SkippedPolicy,
+                    })?;
+                }
+                continue;
+            }
+            if !self.retries.should_query(disk, now) {
+                continue;
+            }
+            if self.policy.sync_filesystems {
+                sync_filesystems(self.disk_list.as_deref(), disk);
+            }
+
+            let result = match self.control.spindown(disk) {
+                Ok(()) => {
+                    match decision {
+                        Some((index, _)) => info!(
+                            disk;
+                            "Spun down {} after being idle for {:?} (rule {})",
+                            disk,
+                            idle,
+                            self.policy.rules.name(index)
+                        ),
+                        None => info!(disk; "Spun down {} after being idle for {:?}", disk, idle),
+                    }
+                    state.cycles.push_back(now);
+                    state.spun_down_at = Some(now);
+                    // Only try again after the disk was active
+                    state.spun_down = true;
+                    self.retries.record_success(disk);
+                    SpindownResult::Success
+                }
+                Err(err) => {
+                    match self.retries.record_failure(disk, now) {
+                        Some(backoff) => error!(
+                            disk;
+                            "Failed to spin down {}, trying again in {:?}: {:?}",
+                            disk,
+                            backoff,
+                            err
+                        ),
+                        None => error!(
+                            disk;
+                            "Failed to spin down {}, giving up: {:?}",
+                            disk,
+                            err
+                        ),
+                    }
+                    SpindownResult::Failure
+                }
+            };
+            tx.send(MetricMessage::SpindownAttempt {
+                disk: disk.clone(),
+                result,
+            })?;
+            state.report_budget(disk, policy.max_cycles, now, tx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write back dirty pages of the filesystems on `disk`, which would otherwise
+/// wake it up again shortly after it was spun down
+fn sync_filesystems(disk_list: Option<&(dyn DiskList + Send)>, disk: &str) {
+    let Some(disk_list) = disk_list else {
+        return;
+    };
+    let mounts = match disk_list.get_mounts(disk) {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            warn!(disk; "Failed to find the filesystems on {}: {:?}", disk, err);
+            return;
+        }
+    };
+    for mount in mounts {
+        match syncfs(&mount) {
+            Ok(()) => debug!(
+                "Synced {} before spinning down {}",
+                mount.to_string_lossy(),
+                disk
+            ),
+            Err(err) => warn!("{:?}", err),
+        }
+    }
+}
+
+/// Notify about the notify rules that started matching `disk` and return the
+/// first matching rule that decides whether it's spun down
+fn check_rules(
+    rules: &Rules,
+    disk: &str,
+    situation: &Situation,
+    notified: &mut HashSet<usize>,
+    tx: &Sender<MetricMessage>,
+) -> Result<Option<(usize, Action)>> {
+    let matching = rules.matching(disk, situation);
+    for &(index, action) in &matching {
+        if action == Action::Notify && notified.insert(index) {
+            let rule = rules.name(index);
+            warn!(disk; "Rule {} matches {}", rule, disk);
+            tx.send(MetricMessage::PolicyNotification {
+                disk: disk.to_string(),
+                rule,
+            })?;
+        }
+    }
+    notified.retain(|index| matching.iter().any(|(matched, _)| matched == index));
+    Ok(matching
+        .into_iter()
+        .find(|(_, action)| *action != Action::Notify))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::bail;
+
+    use crate::diskstats::DiskStats;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct FakeControl {
+        pub spun_down: Mutex<Vec<String>>,
+        pub spun_up: Mutex<Vec<String>>,
+        /// Reported by `get_disk_status`, standby unless set
+        pub state: Mutex<Option<PowerState>>,
+        /// Let `spindown` fail after recording the attempt
+        pub fail_spindown: Mutex<bool>,
+    }
+
+    impl DiskStatus for &FakeControl {
+        fn get_disk_status(&self, _disk: &str) -> Result<PowerState> {
+            Ok(self.state.lock().unwrap().unwrap_or(PowerState::Standby))
+        }
+    }
+
+    impl DiskControl for &FakeControl {
+        fn spindown(&self, disk: &str) -> Result<()> {
+            self.spun_down.lock().unwrap().push(disk.to_string());
+            if *self.fail_spindown.lock().unwrap() {
+                bail!("hdparm failed");
+            }
+            Ok(())
+        }
+
+        fn spinup(&self, disk: &str) -> Result<()> {
+            self.spun_up.lock().unwrap().push(disk.to_string());
+            Ok(())
+        }
+    }
+
+    pub fn activity(disk: &str, sectors_written: u64) -> ActivityEvent {
+        ActivityEvent {
+            disk: disk.to_string(),
+            reads: 0,
+            writes: sectors_written.div_ceil(8),
+            sectors_read: 0,
+            sectors_written,
+            stats: DiskStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_spindown_after_idle() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(600)),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        // activity resets the idle time
+        check(300, 8);
+        check(600, 0);
+        assert!(control.spun_down.lock().unwrap().is_empty());
+
+        check(900, 0);
+        // only once until the disk was active again
+        check(1200, 0);
+        assert_eq!(*control.spun_down.lock().unwrap(), vec!["/dev/sda"]);
+
+        drop(tx);
+        let messages: Vec<_> = rx.iter().collect();
+        assert!(matches!(
+            &messages[..],
+            [MetricMessage::SpindownAttempt { disk, result: SpindownResult::Success }]
+                if disk == "/dev/sda"
+        ));
+    }
+
+    #[test]
+    fn test_per_disk_policy() {
+        let config = Config::parse(
+            r#"
+[disks."/dev/sda"]
+spindown_after = 60
+min_spinup = 1800
+
+[disks."/dev/sdb"]
+never_spindown = true
+keep_awake = "02:00-05:00"
+keep_awake_spinup = true
+"#,
+        )
+        .unwrap();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(600)),
+            ..Default::default()
+        }
+        .with_config(&config);
+        assert!(policy.is_enabled());
+        let sda = policy.for_disk("/dev/sda");
+        assert_eq!(sda.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(sda.min_spinup, Duration::from_secs(1800));
+        let sdb = policy.for_disk("/dev/sdb");
+        assert_eq!(sdb.idle_timeout, None);
+        assert_eq!(sdb.min_spinup, Duration::ZERO);
+        assert!(sdb.keep_awake.contains(3 * 60));
+        assert!(sdb.keep_awake_spinup);
+        let sdc = policy.for_disk("/dev/sdc");
+        assert_eq!(sdc.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(sdc.min_spinup, Duration::ZERO);
+        assert!(sdc.keep_awake.is_empty());
+        assert!(!sdc.keep_awake_spinup);
+
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(600)),
+            protected: vec![String::from("/dev/sda"), String::from("/dev/sdc")],
+            ..Default::default()
+        }
+        .with_config(&config);
+        assert_eq!(policy.for_disk("/dev/sda").idle_timeout, None);
+        assert_eq!(policy.for_disk("/dev/sdc").idle_timeout, None);
+
+        let policy = SpindownPolicy::default().with_config(&config);
+        assert!(policy.is_enabled());
+        assert_eq!(policy.for_disk("/dev/sdc").idle_timeout, None);
+        assert!(!SpindownPolicy::default().is_enabled());
+    }
+
+    #[test]
+    fn test_min_spinup() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            min_spinup: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let events = [activity("/dev/sda", 0)];
+        let start = Instant::now();
+
+        spindown.handle_activity(&events, start, 0, &tx).unwrap();
+        // idle for long enough, but hasn't been spinning for long enough
+        spindown
+            .handle_activity(&events, start + Duration::from_secs(120), 0, &tx)
+            .unwrap();
+        assert!(control.spun_down.lock().unwrap().is_empty());
+
+        spindown
+            .handle_activity(&events, start + Duration::from_secs(600), 0, &tx)
+            .unwrap();
+        assert_eq!(*control.spun_down.lock().unwrap(), vec!["/dev/sda"]);
+    }
+
+    #[test]
+    fn test_keep_awake() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            keep_awake: "02:00-05:00".parse().unwrap(),
+            keep_awake_spinup: true,
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let events = [activity("/dev/sda", 0)];
+        let start = Instant::now();
+        let mut check = |secs: u64, minute_of_day: u32| {
+            spindown
+                .handle_activity(
+                    &events,
+                    start + Duration::from_secs(secs),
+                    minute_of_day,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 100);
+        check(60, 101);
+        assert_eq!(*control.spun_down.lock().unwrap(), vec!["/dev/sda"]);
+
+        // window starts: spin up once, then never spin down inside it
+        check(120, 120);
+        check(180, 121);
+        check(600, 200);
+        assert_eq!(*control.spun_up.lock().unwrap(), vec!["/dev/sda"]);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_skipped_by_policy() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            keep_awake: "00:00-23:59".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    12 * 60,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        check(30, 0);
+        // reported once per idle period
+        check(60, 0);
+        check(120, 0);
+        check(180, 8);
+        check(300, 0);
+        assert!(control.spun_down.lock().unwrap().is_empty());
+
+        drop(tx);
+        let skipped = rx
+            .iter()
+            .filter(|message| {
+                matches!(
+                    message,
+                    MetricMessage::SpindownAttempt {
+                        result: SpindownResult::SkippedPolicy,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn test_early_wakeup() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            early_wakeup: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        check(60, 0);
+        // woke up right away
+        check(120, 8);
+        check(180, 0);
+        check(240, 0);
+        // but not this time
+        check(600, 8);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 2);
+
+        drop(tx);
+        let early = rx
+            .iter()
+            .filter(|message| matches!(message, MetricMessage::EarlyWakeup { .. }))
+            .count();
+        assert_eq!(early, 1);
+    }
+
+    #[test]
+    fn test_exercise() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            exercise: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        check(60, 0);
+        check(3000, 0);
+        assert!(control.spun_up.lock().unwrap().is_empty());
+        check(3600, 0);
+        // the read of the exercise resets the interval
+        check(3660, 8);
+        check(7200, 0);
+        assert_eq!(*control.spun_up.lock().unwrap(), vec!["/dev/sda"]);
+        check(7260, 0);
+        assert_eq!(control.spun_up.lock().unwrap().len(), 2);
+
+        drop(tx);
+        let exercised = rx
+            .iter()
+            .filter(|message| matches!(message, MetricMessage::Exercised { .. }))
+            .count();
+        assert_eq!(exercised, 2);
+    }
+
+    struct FakeTemperature(Arc<Mutex<Option<f64>>>);
+
+    impl DiskList for FakeTemperature {
+        fn get_all_disks(&self) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+
+        fn get_temperature(&self, _disk: &str) -> Result<Option<f64>> {
+            Ok(*self.0.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = Config::parse(
+            r#"
+[[rules]]
+name = "hot"
+min_temperature = 50.0
+action = "notify"
+
+[[rules]]
+disks = ["/dev/sda"]
+during = "22:00-07:00"
+idle_for = 60
+action = "spindown"
+
+[[rules]]
+disks = ["/dev/sdb"]
+action = "keep-awake"
+"#,
+        )
+        .unwrap();
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(600)),
+            ..Default::default()
+        }
+        .with_config(&config);
+        let temperature = Arc::new(Mutex::new(Some(40.0)));
+        let mut spindown =
+            Spindown::new(&control, policy).with_disk_list(FakeTemperature(temperature.clone()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let events = [activity("/dev/sda", 0), activity("/dev/sdb", 0)];
+        let start = Instant::now();
+        let mut check = |secs: u64, minute_of_day: u32| {
+            spindown
+                .handle_activity(
+                    &events,
+                    start + Duration::from_secs(secs),
+                    minute_of_day,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 12 * 60);
+        check(120, 12 * 60);
+        assert!(control.spun_down.lock().unwrap().is_empty());
+
+        // spun down by the rule long before the idle timeout
+        check(180, 23 * 60);
+        // the keep-awake rule overrides the idle timeout
+        check(700, 12 * 60);
+        assert_eq!(*control.spun_down.lock().unwrap(), vec!["/dev/sda"]);
+
+        // notifies once, and not about the spun down disk
+        *temperature.lock().unwrap() = Some(55.0);
+        check(760, 12 * 60);
+        check(820, 12 * 60);
+
+        drop(tx);
+        let notifications: Vec<_> = rx
+            .iter()
+            .filter_map(|message| match message {
+                MetricMessage::PolicyNotification { disk, rule } => Some((disk, rule)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notifications,
+            vec![(String::from("/dev/sdb"), String::from("hot"))]
+        );
+    }
+
+    #[test]
+    fn test_enforce_standby() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            enforce_standby: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        check(60, 0);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 1);
+
+        // woke up without I/O, gets a grace period before being spun down
+        *control.state.lock().unwrap() = Some(PowerState::Active);
+        check(70, 0);
+        check(90, 0);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 1);
+        check(100, 0);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 2);
+
+        // woke up because of I/O, left alone until idle again
+        check(110, 8);
+        check(150, 0);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 2);
+
+        drop(tx);
+        let enforced = rx
+            .iter()
+            .filter(|msg| matches!(msg, MetricMessage::StandbyEnforced { success: true, .. }))
+            .count();
+        assert_eq!(enforced, 1);
+    }
+
+    #[test]
+    fn test_spindown_failure() {
+        let control = FakeControl {
+            fail_spindown: Mutex::new(true),
+            // Still spinning after the failed attempts
+            state: Mutex::new(Some(PowerState::Active)),
+            ..Default::default()
+        };
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            enforce_standby: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy).with_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", 0)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0);
+        check(60);
+        // neither enforcing a standby that never happened nor trying again
+        // on every poll
+        check(70);
+        check(80);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 1);
+        // after backing off
+        *control.fail_spindown.lock().unwrap() = false;
+        check(120);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 2);
+
+        drop(tx);
+        let results: Vec<_> = rx
+            .iter()
+            .filter_map(|msg| match msg {
+                MetricMessage::SpindownAttempt { result, .. } => Some(result),
+                MetricMessage::StandbyEnforced { .. } => panic!("standby enforced"),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results, [SpindownResult::Failure, SpindownResult::Success]);
+    }
+
+    #[test]
+    fn test_cycle_budget() {
+        let control = FakeControl::default();
+        let policy = SpindownPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            max_cycles: Some(2),
+            ..Default::default()
+        };
+        let mut spindown = Spindown::new(&control, policy);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let mut check = |secs: u64, sectors_written: u64| {
+            spindown
+                .handle_activity(
+                    &[activity("/dev/sda", sectors_written)],
+                    start + Duration::from_secs(secs),
+                    0,
+                    &tx,
+                )
+                .unwrap();
+        };
+
+        check(0, 0);
+        for cycle in 0..3 {
+            check(cycle * 120 + 60, 0);
+            check(cycle * 120 + 120, 8);
+        }
+        assert_eq!(control.spun_down.lock().unwrap().len(), 2);
+
+        // the first cycle leaves the window after a day
+        check(24 * 60 * 60 + 60, 0);
+        check(24 * 60 * 60 + 120, 0);
+        assert_eq!(control.spun_down.lock().unwrap().len(), 3);
+
+        drop(tx);
+        let budgets: Vec<_> = rx
+            .iter()
+            .filter_map(|msg| match msg {
+                MetricMessage::CycleBudget { remaining, .. } => Some(remaining),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(budgets, vec![2, 1, 0, 1, 0]);
+    }
+}
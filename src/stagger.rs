@@ -0,0 +1,135 @@
+use std::{
+    sync::{Condvar, Mutex},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Spreads disk accesses over time so that many disks don't spin up at
+/// once and overload the power supply
+#[derive(Debug, Default)]
+pub struct Stagger {
+    /// Minimum time between two accesses
+    delay: Duration,
+    /// Up to this much random extra delay before each access
+    jitter: Duration,
+    /// Earliest time the next access may start
+    next: Mutex<Option<Instant>>,
+    /// Number of spin-ups allowed at the same time, `None` for no limit
+    max_spinups: Option<usize>,
+    spinups: Mutex<usize>,
+    spinup_done: Condvar,
+}
+
+impl Stagger {
+    pub fn new(delay: Duration, jitter: Duration) -> Self {
+        Stagger {
+            delay,
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_spinups(mut self, max_spinups: Option<usize>) -> Self {
+        self.max_spinups = max_spinups.map(|max| max.max(1));
+        self
+    }
+
+    /// Block until it's this access' turn
+    pub fn wait(&self) {
+        if self.delay.is_zero() && self.jitter.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = next.map_or(now, |next| next.max(now)) + self.random_jitter();
+            *next = Some(start + self.delay);
+            start
+        };
+        sleep(start.saturating_duration_since(now));
+    }
+
+    /// Run `spinup` once it's its turn and fewer than the maximum number of
+    /// spin-ups are in progress
+    pub fn spinup<T>(&self, spinup: impl FnOnce() -> T) -> T {
+        let _slot = self.max_spinups.map(|max| {
+            let mut running = self
+                .spinup_done
+                .wait_while(self.spinups.lock().unwrap(), |running| *running >= max)
+                .unwrap();
+            *running += 1;
+            SpinupSlot(self)
+        });
+        self.wait();
+        spinup()
+    }
+
+    fn random_jitter(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        // Doesn't need to be good randomness, just not the same every time
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as u128;
+        Duration::from_nanos((nanos * 7919 % (self.jitter.as_nanos() + 1)) as u64)
+    }
+}
+
+/// Frees a spin-up slot when dropped, even if the spin-up panicked
+struct SpinupSlot<'a>(&'a Stagger);
+
+impl Drop for SpinupSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.spinups.lock().unwrap() -= 1;
+        self.0.spinup_done.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_wait() {
+        let stagger = Stagger::new(Duration::from_millis(50), Duration::ZERO);
+        let start = Instant::now();
+        for _ in 0..3 {
+            stagger.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let stagger = Stagger::default();
+        let start = Instant::now();
+        for _ in 0..3 {
+            stagger.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_max_spinups() {
+        let stagger = Stagger::default().with_max_spinups(Some(2));
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    stagger.spinup(|| {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                });
+            }
+        });
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::disks::DiskSelection;
+
+/// How the `status` and `list-disks` subcommands print the disks
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for humans
+    #[default]
+    Table,
+    Json,
+    Yaml,
+}
+
+/// Something printed as a line of a table or an object of JSON or YAML
+pub trait Row {
+    /// Column headers of the table
+    const HEADER: &'static [&'static str];
+
+    /// Cells of the table, in the order of `HEADER`
+    fn cells(&self) -> Vec<String>;
+
+    /// Fields of JSON and YAML, in order
+    fn fields(&self) -> Vec<(&'static str, Value)>;
+}
+
+/// A line of the `status` output
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskRow {
+    /// Kernel name like `/dev/sda`
+    pub device: String,
+    /// Friendly name from the config file
+    pub name: Option<String>,
+    pub state: String,
+    /// Only known when asking the daemon
+    pub since_change_seconds: Option<u64>,
+    pub temperature_celsius: Option<f64>,
+}
+
+/// The parts of the daemon's JSON status document `status` shows
+#[derive(Deserialize)]
+struct Document {
+    disks: BTreeMap<String, DocumentDisk>,
+}
+
+#[derive(Deserialize)]
+struct DocumentDisk {
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    state: Option<String>,
+    last_change: Option<f64>,
+    temperature_celsius: Option<f64>,
+}
+
+/// Rows from the JSON status document the daemon writes with
+/// `--json-status`, at unix time `now`
+pub fn read_json_status(path: &Path, now: f64) -> Result<Vec<DiskRow>> {
+    let document = fs::read(path)
+        .with_context(|| format!("Failed to read JSON status {}", path.to_string_lossy()))?;
+    let document: Document = serde_json::from_slice(&document)
+        .with_context(|| format!("Invalid JSON status {}", path.to_string_lossy()))?;
+    Ok(document
+        .disks
+        .into_iter()
+        .map(|(device, disk)| DiskRow {
+            device,
+            name: disk.labels.get("name").cloned(),
+            state: disk.state.unwrap_or_else(|| String::from("unknown")),
+            since_change_seconds: disk
+                .last_change
+                .map(|changed| (now - changed).max(0.0) as u64),
+            temperature_celsius: disk.temperature_celsius,
+        })
+        .collect())
+}
+
+impl Row for DiskRow {
+    const HEADER: &'static [&'static str] =
+        &["DEVICE", "NAME", "STATE", "SINCE CHANGE", "TEMPERATURE"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.device.clone(),
+            self.name.clone().unwrap_or_else(|| String::from("-")),
+            self.state.clone(),
+            self.since_change_seconds
+                .map_or_else(|| String::from("-"), format_duration),
+            self.temperature_celsius
+                .map_or_else(|| String::from("-"), |celsius| format!("{}°C", celsius)),
+        ]
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("device", json!(self.device)),
+            ("name", json!(self.name)),
+            ("state", json!(self.state)),
+            ("since_change_seconds", json!(self.since_change_seconds)),
+            ("temperature_celsius", json!(self.temperature_celsius)),
+        ]
+    }
+}
+
+impl Row for DiskSelection {
+    const HEADER: &'static [&'static str] = &["DISK", "MONITORED", "REASON"];
+
+    fn cells(&self) -> Vec<String> {
+        let (monitored, reason) = match self.excluded {
+            Some(exclusion) => ("no", exclusion.description()),
+            None => ("yes", "-"),
+        };
+        vec![self.disk.clone(), monitored.to_string(), reason.to_string()]
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("disk", json!(self.disk)),
+            ("monitored", json!(self.excluded.is_none())),
+            ("excluded", json!(self.excluded)),
+        ]
+    }
+}
+
+pub fn render<R: Row>(rows: &[R], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Table => render_table(rows),
+        OutputFormat::Json => render_json(rows),
+        OutputFormat::Yaml => render_yaml(rows),
+    }
+}
+
+fn render_table<R: Row>(rows: &[R]) -> String {
+    let header: Vec<String> = R::HEADER.iter().map(|name| name.to_string()).collect();
+    let cells: Vec<Vec<String>> = rows.iter().map(Row::cells).collect();
+    let mut widths = vec![0; header.len()];
+    for line in std::iter::once(&header).chain(&cells) {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for line in std::iter::once(&header).chain(&cells) {
+        let mut text = String::new();
+        for (cell, width) in line.iter().zip(&widths) {
+            let padding = width - cell.chars().count();
+            write!(text, "{}{}  ", cell, " ".repeat(padding)).unwrap();
+        }
+        table.push_str(text.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Written by hand to keep the fields in order
+fn render_json<R: Row>(rows: &[R]) -> String {
+    let objects: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let fields: Vec<String> = row
+                .fields()
+                .iter()
+                .map(|(key, value)| format!("    {}: {}", json!(key), value))
+                .collect();
+            format!("  {{\n{}\n  }}", fields.join(",\n"))
+        })
+        .collect();
+    if objects.is_empty() {
+        return String::from("[]\n");
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+/// A list of flat mappings. Strings are written the way JSON quotes them,
+/// which YAML reads the same.
+fn render_yaml<R: Row>(rows: &[R]) -> String {
+    if rows.is_empty() {
+        return String::from("[]\n");
+    }
+    let mut yaml = String::new();
+    for row in rows {
+        for (index, (key, value)) in row.fields().iter().enumerate() {
+            let indent = if index == 0 { "- " } else { "  " };
+            writeln!(yaml, "{}{}: {}", indent, key, value).unwrap();
+        }
+    }
+    yaml
+}
+
+/// Like `3d 4h`, `2h 5m` or `40s`, the two largest units are enough to read
+/// at a glance
+fn format_duration(seconds: u64) -> String {
+    let units = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let parts: Vec<String> = units
+        .iter()
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect();
+    if parts.is_empty() {
+        String::from("0s")
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::disks::Exclusion;
+
+    fn rows() -> Vec<DiskRow> {
+        vec![
+            DiskRow {
+                device: String::from("/dev/sda"),
+                name: Some(String::from("media-1")),
+                state: String::from("standby"),
+                since_change_seconds: Some(11100),
+                temperature_celsius: Some(31.0),
+            },
+            DiskRow {
+                device: String::from("/dev/sdb"),
+                state: String::from("active"),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(&rows(), OutputFormat::Table),
+            "DEVICE    NAME     STATE    SINCE CHANGE  TEMPERATURE
+/dev/sda  media-1  standby  3h 5m         31°C
+/dev/sdb  -        active   -             -
+"
+        );
+        assert_eq!(
+            render(&rows(), OutputFormat::Yaml),
+            "- device: \"/dev/sda\"
+  name: \"media-1\"
+  state: \"standby\"
+  since_change_seconds: 11100
+  temperature_celsius: 31.0
+- device: \"/dev/sdb\"
+  name: null
+  state: \"active\"
+  since_change_seconds: null
+  temperature_celsius: null
+"
+        );
+        assert_eq!(render::<DiskRow>(&[], OutputFormat::Yaml), "[]\n");
+        let json = render(&rows(), OutputFormat::Json);
+        assert!(
+            json.starts_with("[\n  {\n    \"device\": \"/dev/sda\",\n    \"name\": \"media-1\",")
+        );
+        let json: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json[1]["since_change_seconds"], Value::Null);
+        assert_eq!(render::<DiskRow>(&[], OutputFormat::Json), "[]\n");
+
+        let selection = [
+            DiskSelection {
+                disk: String::from("/dev/sda"),
+                excluded: None,
+            },
+            DiskSelection {
+                disk: String::from("/dev/sdb"),
+                excluded: Some(Exclusion::NonRotational),
+            },
+        ];
+        assert_eq!(
+            render(&selection, OutputFormat::Table),
+            "DISK      MONITORED  REASON
+/dev/sda  yes        -
+/dev/sdb  no         not rotational, see --monitor-all-disks
+"
+        );
+        let json: Value = serde_json::from_str(&render(&selection, OutputFormat::Json)).unwrap();
+        assert_eq!(
+            json[1],
+            json!({"disk": "/dev/sdb", "monitored": false, "excluded": "non_rotational"})
+        );
+
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(3 * 86400 + 60), "3d");
+        assert_eq!(format_duration(125), "2m 5s");
+    }
+
+    #[test]
+    fn test_read_json_status() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("status.json");
+        fs::write(
+            &path,
+            r#"{"updated": 1700011100.0, "disks": {
+                "/dev/sda": {"labels": {"disk": "/dev/sda", "name": "media-1"},
+                    "state": "standby", "spinning": false, "last_update": 1700011100.0,
+                    "last_change": 1700000000.0, "temperature_celsius": 31.0, "errors": {}},
+                "/dev/sdb": {"labels": {"disk": "/dev/sdb"}, "state": null,
+                    "spinning": null, "last_update": null, "last_change": null,
+                    "temperature_celsius": null, "errors": {}}
+            }}"#,
+        )
+        .unwrap();
+
+        let rows = read_json_status(&path, 1700011100.0).unwrap();
+        assert_eq!(rows[0], self::rows()[0]);
+        assert_eq!(rows[1].state, "unknown");
+        assert!(read_json_status(&dir.path().join("missing.json"), 0.0).is_err());
+    }
+}
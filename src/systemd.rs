@@ -0,0 +1,115 @@
+use std::{
+    env,
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+/// Tells systemd how the daemon is doing, for units with `Type=notify`. See
+/// sd_notify(3) for the protocol.
+pub struct SystemdNotifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// `WatchdogSec` of the unit, if systemd restarts the daemon when it
+    /// doesn't ping within that time
+    watchdog: Option<Duration>,
+}
+
+impl SystemdNotifier {
+    /// The notifier for `$NOTIFY_SOCKET`, none if systemd didn't set it. The
+    /// watchdog is enabled with `$WATCHDOG_USEC`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let notifier = Self::new(&path)?;
+        // Set for another process if we inherited them
+        let for_us =
+            env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| for_us)
+            .map(|usec| {
+                usec.parse()
+                    .with_context(|| format!("Invalid WATCHDOG_USEC {}", usec))
+            })
+            .transpose()?
+            .filter(|usec| *usec > 0)
+            .map(Duration::from_micros);
+        Ok(Some(match watchdog {
+            Some(timeout) => notifier.with_watchdog(timeout),
+            None => notifier,
+        }))
+    }
+
+    /// Notify the socket at `path`, which is in the abstract namespace if it
+    /// starts with `@`
+    pub fn new(path: &OsStr) -> Result<Self> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(path),
+        }
+        .with_context(|| format!("Invalid NOTIFY_SOCKET {}", path.to_string_lossy()))?;
+        let socket = UnixDatagram::unbound().context("Failed to create socket for systemd")?;
+        Ok(SystemdNotifier {
+            socket,
+            addr,
+            watchdog: None,
+        })
+    }
+
+    /// Systemd expects a ping within `timeout`
+    pub fn with_watchdog(mut self, timeout: Duration) -> Self {
+        self.watchdog = Some(timeout);
+        self
+    }
+
+    /// How often to send `WATCHDOG=1`, half the timeout as recommended by
+    /// sd_watchdog_enabled(3). None without a watchdog.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    /// Send `state`, assignments like `READY=1` separated by newlines
+    pub fn notify(&self, state: &str) -> Result<()> {
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.addr)
+            .context("Failed to notify systemd")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = SystemdNotifier::new(path.as_os_str()).unwrap();
+        notifier.notify("READY=1\nSTATUS=Monitoring").unwrap();
+        let mut buffer = [0; 64];
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1\nSTATUS=Monitoring");
+
+        let name = format!("disk_spin_manager-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+        let notifier = SystemdNotifier::new(OsStr::new(&format!("@{}", name))).unwrap();
+        notifier.notify("STOPPING=1").unwrap();
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"STOPPING=1");
+    }
+}
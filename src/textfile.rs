@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use prometheus::{Gauge, IntCounter, Registry};
+
+use crate::{exposition::Format, own_io::OwnIo, permissions::FilePermissions, sink::ExportSink};
+
+/// Writes the metrics to a file for node_exporter's textfile collector,
+/// keeping metrics about how that goes
+pub struct TextfileSink {
+    path: PathBuf,
+    format: Format,
+    permissions: FilePermissions,
+    own_io: Option<OwnIo>,
+    write_failures: IntCounter,
+    last_write: Gauge,
+    write_duration: Gauge,
+}
+
+impl TextfileSink {
+    /// Write to `path`, with the metrics about writing in `registry`. A
+    /// temporary file left behind by an earlier run is removed.
+    pub fn new(path: PathBuf, registry: &Registry) -> Result<Self> {
+        let write_failures = IntCounter::new(
+            "textfile_write_failures_total",
+            "Number of times writing the textfile failed",
+        )?;
+        registry
+            .register(Box::new(write_failures.clone()))
+            .context("Failed to register textfile_write_failures")?;
+
+        let last_write = Gauge::new(
+            "textfile_last_write_timestamp_seconds",
+            "Unix timestamp of the last successful write of the textfile",
+        )?;
+        registry
+            .register(Box::new(last_write.clone()))
+            .context("Failed to register textfile_last_write")?;
+
+        let write_duration = Gauge::new(
+            "textfile_write_duration_seconds",
+            "Time the previous successful write of the textfile took",
+        )?;
+        registry
+            .register(Box::new(write_duration.clone()))
+            .context("Failed to register textfile_write_duration")?;
+
+        let sink = TextfileSink {
+            path,
+            format: Format::default(),
+            permissions: FilePermissions::default(),
+            own_io: None,
+            write_failures,
+            last_write,
+            write_duration,
+        };
+        sink.remove_stale_tempfile();
+        Ok(sink)
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Mode and ownership of the textfile instead of what the umask and the
+    /// daemon's user give
+    pub fn with_permissions(mut self, permissions: FilePermissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Record the writes, so they aren't mistaken for disk activity
+    pub fn with_own_io(mut self, own_io: OwnIo) -> Self {
+        self.own_io = Some(own_io);
+        self
+    }
+
+    /// Write to a temporary file that replaces the textfile, so a scrape
+    /// never sees it half written
+    fn write(&self, registry: &Registry) -> Result<()> {
+        let tempfile = tempfile_path(&self.path);
+        let mut textfile = fs::File::create(&tempfile).with_context(|| {
+            format!("Failed to create textfile: {}", tempfile.to_string_lossy())
+        })?;
+        let buffer = self
+            .format
+            .encode(&registry.gather())
+            .context("Failed to encode metrics into textfile")?;
+        textfile
+            .write_all(&buffer)
+            .context("Failed to write textfile")?;
+        self.permissions.apply(&tempfile)?;
+        fs::rename(&tempfile, &self.path).with_context(|| {
+            format!(
+                "Failed to replace textfile: {}",
+                self.path.to_string_lossy()
+            )
+        })?;
+        if let Some(own_io) = &self.own_io {
+            if let Err(err) = own_io.record_write(&self.path, buffer.len() as u64) {
+                debug!("Failed to record textfile write: {:?}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Left behind if writing the textfile was interrupted
+    fn remove_stale_tempfile(&self) {
+        let tempfile = tempfile_path(&self.path);
+        match fs::remove_file(&tempfile) {
+            Ok(()) => info!("Removed stale {}", tempfile.to_string_lossy()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => error!("Failed to remove {}: {:?}", tempfile.to_string_lossy(), err),
+        }
+    }
+}
+
+impl ExportSink for TextfileSink {
+    fn name(&self) -> &str {
+        "textfile"
+    }
+
+    /// Write the textfile, counting failures. The file can't include how
+    /// long writing itself took, that's only in the next one.
+    fn export(&self, registry: &Registry) -> Result<()> {
+        let previous = self.last_write.get();
+        self.last_write.set(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+        let started = Instant::now();
+        let result = self.write(registry);
+        match &result {
+            Ok(()) => self.write_duration.set(started.elapsed().as_secs_f64()),
+            Err(_) => {
+                self.last_write.set(previous);
+                self.write_failures.inc();
+            }
+        }
+        result
+    }
+}
+
+/// `disk_status.prom.tmp` next to `disk_status.prom`. node_exporter only
+/// reads files ending in `.prom`.
+fn tempfile_path(textfile: &Path) -> PathBuf {
+    let mut name = textfile.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    textfile.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_textfile_replaced() {
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("disk_status.prom");
+        let tempfile = textfile_dir.path().join("disk_status.prom.tmp");
+        fs::write(&tempfile, "half written").unwrap();
+        let registry = Registry::new();
+        let permissions = FilePermissions {
+            mode: Some(0o604),
+            ..Default::default()
+        };
+        let sink = TextfileSink::new(textfile.clone(), &registry)
+            .unwrap()
+            .with_permissions(permissions);
+        assert!(!tempfile.exists());
+
+        sink.export(&registry).unwrap();
+        assert!(!tempfile.exists());
+        assert!(fs::read_to_string(&textfile)
+            .unwrap()
+            .contains("\ntextfile_write_failures_total 0\n"));
+        let mode = fs::metadata(&textfile).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o604);
+    }
+
+    #[test]
+    fn test_textfile_write_failure() {
+        let textfile_dir = TempDir::new().unwrap();
+        let textfile = textfile_dir.path().join("missing").join("disk_status.prom");
+        let registry = Registry::new();
+        let sink = TextfileSink::new(textfile.clone(), &registry).unwrap();
+
+        assert!(sink.export(&registry).is_err());
+        assert!(sink.export(&registry).is_err());
+        assert_eq!(sink.write_failures.get(), 2);
+        assert_eq!(sink.last_write.get(), 0.0);
+
+        fs::create_dir(textfile.parent().unwrap()).unwrap();
+        sink.export(&registry).unwrap();
+        assert!(sink.last_write.get() > 0.0);
+    }
+}
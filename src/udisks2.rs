@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    command::CommandRunner,
+    disk_status::{DiskControl, DiskStatus, PowerState},
+};
+
+const UDISKS2_SERVICE: &str = "org.freedesktop.UDisks2";
+
+/// Queries the power state through udisks2 on the system bus, which lets the
+/// daemon run unprivileged as long as polkit allows the `ata-check-power`
+/// action (the default for local sessions).
+pub struct Udisks2 {
+    pub busctl: String,
+    pub runner: Arc<dyn CommandRunner>,
+}
+
+#[derive(Deserialize)]
+struct BusctlReply<T> {
+    data: T,
+}
+
+impl Udisks2 {
+    fn run_busctl(&self, args: &[&str]) -> Result<String> {
+        let mut busctl_args = vec!["--system", "--json=short"];
+        busctl_args.extend(args);
+        let output = self.runner.run(&self.busctl, &busctl_args)?;
+        if !output.status.success() {
+            bail!("busctl execution error: {:?}", output);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        debug!("busctl {:?} returned '{}'", args, stdout);
+        Ok(stdout)
+    }
+
+    fn busctl<T: DeserializeOwned>(&self, args: &[&str]) -> Result<T> {
+        let stdout = self.run_busctl(args)?;
+        let reply: BusctlReply<T> =
+            serde_json::from_str(&stdout).context("Failed to parse busctl output")?;
+        Ok(reply.data)
+    }
+
+    fn drive_object(&self, disk: &str) -> Result<String> {
+        let block_object = block_object_path(disk)?;
+        let drive: String = self
+            .busctl(&[
+                "get-property",
+                UDISKS2_SERVICE,
+                &block_object,
+                "org.freedesktop.UDisks2.Block",
+                "Drive",
+            ])
+            .with_context(|| format!("Failed to find udisks2 drive for {}", disk))?;
+        if drive == "/" {
+            bail!("udisks2 has no drive associated with {}", disk);
+        }
+        Ok(drive)
+    }
+}
+
+impl DiskStatus for Udisks2 {
+    fn get_disk_status(&self, disk: &str) -> Result<PowerState> {
+        let drive = self.drive_object(disk)?;
+        let state: Vec<u8> = self.busctl(&[
+            "call",
+            UDISKS2_SERVICE,
+            &drive,
+            "org.freedesktop.UDisks2.Drive.Ata",
+            "PmGetState",
+            "a{sv}",
+            "0",
+        ])?;
+        let state = state
+            .first()
+            .context("PmGetState returned no power state")?;
+        parse_pm_state(*state)
+    }
+}
+
+impl DiskControl for Udisks2 {
+    /// Requires the `ata-standby` polkit action
+    fn spindown(&self, disk: &str) -> Result<()> {
+        let drive = self.drive_object(disk)?;
+        // No return value, so there's nothing to parse
+        self.run_busctl(&[
+            "call",
+            UDISKS2_SERVICE,
+            &drive,
+            "org.freedesktop.UDisks2.Drive.Ata",
+            "PmStandby",
+            "a{sv}",
+            "0",
+        ])?;
+        Ok(())
+    }
+}
+
+/// Map the ATA CHECK POWER MODE count register as returned by `PmGetState`
+pub fn parse_pm_state(state: u8) -> Result<PowerState> {
+    match state {
+        // standby, standby_y/standby_z and NVcache spun down
+        0x00 | 0x01 | 0x40 => Ok(PowerState::Standby),
+        // idle including idle_a/b/c
+        0x80..=0x83 => Ok(PowerState::Idle),
+        // NVcache spun up and active/idle
+        0x41 | 0xff => Ok(PowerState::Active),
+        _ => bail!("Unrecognized power state: {:#04x}", state),
+    }
+}
+
+/// Object path of the udisks2 block device for e.g. `/dev/sda`, escaped the
+/// same way udisks2 does it: any character other than `[A-Za-z0-9]` becomes
+/// `_` followed by its hex value.
+fn block_object_path(disk: &str) -> Result<String> {
+    let name = disk
+        .strip_prefix("/dev/")
+        .with_context(|| format!("Not a device path: {}", disk))?;
+    let escaped: String = name
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() {
+                (b as char).to_string()
+            } else {
+                format!("_{:02x}", b)
+            }
+        })
+        .collect();
+    Ok(format!(
+        "/org/freedesktop/UDisks2/block_devices/{}",
+        escaped
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pm_state() {
+        assert_eq!(parse_pm_state(0x00).unwrap(), PowerState::Standby);
+        assert_eq!(parse_pm_state(0x40).unwrap(), PowerState::Standby);
+        assert_eq!(parse_pm_state(0x80).unwrap(), PowerState::Idle);
+        assert_eq!(parse_pm_state(0xff).unwrap(), PowerState::Active);
+        assert!(parse_pm_state(0x20).is_err());
+    }
+
+    #[test]
+    fn test_block_object_path() {
+        assert_eq!(
+            block_object_path("/dev/sda").unwrap(),
+            "/org/freedesktop/UDisks2/block_devices/sda"
+        );
+        assert_eq!(
+            block_object_path("/dev/dm-0").unwrap(),
+            "/org/freedesktop/UDisks2/block_devices/dm_2d0"
+        );
+        assert!(block_object_path("sda").is_err());
+    }
+
+    #[test]
+    fn test_busctl_reply() {
+        let reply: BusctlReply<String> = serde_json::from_str(
+            r#"{"type":"o","data":"/org/freedesktop/UDisks2/drives/WDC_WD40EFRX_68N32N0_WD_2dWCC7K0000000"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            reply.data,
+            "/org/freedesktop/UDisks2/drives/WDC_WD40EFRX_68N32N0_WD_2dWCC7K0000000"
+        );
+
+        let reply: BusctlReply<Vec<u8>> =
+            serde_json::from_str(r#"{"type":"y","data":[255]}"#).unwrap();
+        assert_eq!(reply.data, vec![255]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::disk_status::PowerState;
+
+/// What probably woke a disk up
+#[derive(Clone, Debug, PartialEq)]
+pub enum WakeCause {
+    /// Event in a watched directory on the disk
+    Path(String),
+    /// I/O that didn't come from a watched directory
+    Io,
+    Unknown,
+}
+
+impl fmt::Display for WakeCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WakeCause::Path(path) => f.write_str(path),
+            WakeCause::Io => f.write_str("diskstats"),
+            WakeCause::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// A disk that woke up and what probably caused it
+#[derive(Debug, PartialEq)]
+pub struct Wakeup {
+    pub disk: String,
+    pub cause: WakeCause,
+    /// Process that accessed the disk first, if fanotify is used
+    pub process: Option<String>,
+}
+
+struct PendingWakeup {
+    /// Last time the disk was seen in standby, anything before can't have
+    /// woken it up
+    standby_at: Instant,
+    seen_at: Instant,
+}
+
+/// Correlates disks waking up from standby with notify events and I/O
+/// around the same time to guess what woke them up
+pub struct WakeCauses {
+    /// How long after a wakeup was seen to wait for events that explain it,
+    /// they can be reported late
+    window: Duration,
+    /// Disks each watched directory is on
+    watches: Vec<(String, Vec<String>)>,
+    last_notify: HashMap<String, Instant>,
+    last_io: HashMap<String, Instant>,
+    /// Last access of each process per disk
+    last_process: HashMap<String, HashMap<String, Instant>>,
+    /// Last time each disk was seen in standby, `None` while it's spinning
+    standby: HashMap<String, Option<Instant>>,
+    pending: HashMap<String, PendingWakeup>,
+}
+
+impl WakeCauses {
+    pub fn new(window: Duration) -> Self {
+        WakeCauses {
+            window,
+            watches: Vec::new(),
+            last_notify: HashMap::new(),
+            last_io: HashMap::new(),
+            last_process: HashMap::new(),
+            standby: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Watched directories with the disks they are on
+    pub fn with_watches(mut self, watches: Vec<(String, Vec<String>)>) -> Self {
+        self.watches = watches;
+        self
+    }
+
+    pub fn record_notify(&mut self, path: &str, now: Instant) {
+        self.last_notify.insert(path.to_string(), now);
+    }
+
+    pub fn record_io(&mut self, disk: &str, now: Instant) {
+        self.last_io.insert(disk.to_string(), now);
+    }
+
+    pub fn record_process(&mut self, disk: &str, comm: &str, now: Instant) {
+        self.last_process
+            .entry(disk.to_string())
+            .or_default()
+            .insert(comm.to_string(), now);
+    }
+
+    pub fn record_state(&mut self, disk: &str, state: PowerState, now: Instant) {
+        match state {
+            PowerState::Standby | PowerState::Sleeping => {
+                self.pending.remove(disk);
+                self.standby.insert(disk.to_string(), Some(now));
+            }
+            PowerState::Active | PowerState::Idle => {
+                if let Some(Some(standby_at)) = self.standby.insert(disk.to_string(), None) {
+                    let wakeup = PendingWakeup {
+                        standby_at,
+                        seen_at: now,
+                    };
+                    self.pending.insert(disk.to_string(), wakeup);
+                }
+            }
+            PowerState::Unknown => {}
+        }
+    }
+
+    pub fn forget(&mut self, disk: &str) {
+        self.last_io.remove(disk);
+        self.last_process.remove(disk);
+        self.standby.remove(disk);
+        self.pending.remove(disk);
+    }
+
+    /// All values the `cause` label can have
+    pub fn cause_labels(&self) -> Vec<String> {
+        self.watches
+            .iter()
+            .map(|(path, _)| WakeCause::Path(path.clone()))
+            .chain([WakeCause::Io, WakeCause::Unknown])
+            .map(|cause| cause.to_string())
+            .collect()
+    }
+
+    /// Causes of the wakeups whose window has passed
+    pub fn resolve(&mut self, now: Instant) -> Vec<Wakeup> {
+        let done: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, wakeup)| now.duration_since(wakeup.seen_at) >= self.window)
+            .map(|(disk, _)| disk.clone())
+            .collect();
+        done.into_iter()
+            .map(|disk| {
+                let pending = self.pending.remove(&disk).unwrap();
+                let in_window = |at: &Instant| {
+                    *at > pending.standby_at && at.duration_since(pending.seen_at) <= self.window
+                };
+                let cause = self.cause(&disk, in_window);
+                // The earliest access is the most likely to have caused it
+                let process = self
+                    .last_process
+                    .get(&disk)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, at)| in_window(at))
+                    .min_by_key(|(_, at)| **at)
+                    .map(|(comm, _)| comm.clone());
+                Wakeup {
+                    disk,
+                    cause,
+                    process,
+                }
+            })
+            .collect()
+    }
+
+    fn cause(&self, disk: &str, in_window: impl Fn(&Instant) -> bool) -> WakeCause {
+        let path = self
+            .watches
+            .iter()
+            .filter(|(_, disks)| disks.iter().any(|d| d == disk))
+            .filter_map(|(path, _)| Some((path, self.last_notify.get(path)?)))
+            .filter(|(_, at)| in_window(at))
+            // The earliest event is the most likely to have caused it
+            .min_by_key(|(_, at)| **at);
+        if let Some((path, _)) = path {
+            return WakeCause::Path(path.clone());
+        }
+        match self.last_io.get(disk) {
+            Some(at) if in_window(at) => WakeCause::Io,
+            _ => WakeCause::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wake_causes() {
+        let mut causes = WakeCauses::new(Duration::from_secs(30)).with_watches(vec![
+            (String::from("/srv/media"), vec![String::from("/dev/sda")]),
+            (String::from("/srv/backup"), vec![String::from("/dev/sdb")]),
+        ]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        for disk in ["/dev/sda", "/dev/sdb", "/dev/sdc"] {
+            causes.record_state(disk, PowerState::Standby, at(0));
+        }
+        // before the disk was last seen in standby, so not the cause
+        causes.record_notify("/srv/media", at(0));
+        causes.record_io("/dev/sdc", at(0));
+        for disk in ["/dev/sda", "/dev/sdb", "/dev/sdc"] {
+            causes.record_state(disk, PowerState::Standby, at(60));
+        }
+        causes.record_notify("/srv/backup", at(70));
+        causes.record_process("/dev/sdb", "updatedb", at(30));
+        causes.record_process("/dev/sdb", "rsync", at(69));
+        causes.record_process("/dev/sdb", "smbd", at(100));
+        for disk in ["/dev/sda", "/dev/sdb", "/dev/sdc"] {
+            causes.record_state(disk, PowerState::Active, at(120));
+        }
+        // reported late, but still within the window
+        causes.record_io("/dev/sda", at(125));
+
+        assert!(causes.resolve(at(130)).is_empty());
+        let mut resolved = causes.resolve(at(150));
+        resolved.sort_by(|a, b| a.disk.cmp(&b.disk));
+        let causes_of: Vec<_> = resolved
+            .iter()
+            .map(|wakeup| (wakeup.disk.as_str(), wakeup.cause.clone()))
+            .collect();
+        assert_eq!(
+            causes_of,
+            vec![
+                ("/dev/sda", WakeCause::Io),
+                ("/dev/sdb", WakeCause::Path(String::from("/srv/backup"))),
+                ("/dev/sdc", WakeCause::Unknown),
+            ]
+        );
+        assert_eq!(resolved[1].process.as_deref(), Some("rsync"));
+        assert_eq!(resolved[0].process, None);
+
+        // still spinning, not another wakeup
+        causes.record_state("/dev/sda", PowerState::Active, at(180));
+        assert!(causes.resolve(at(300)).is_empty());
+    }
+}
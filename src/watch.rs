@@ -1,22 +1,198 @@
 use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    sync::mpsc::Sender,
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail, Result};
-use log::error;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use anyhow::{anyhow, bail, Context, Result};
+use glob::{MatchOptions, Pattern};
+use log::{debug, error, info, warn};
+use notify::{
+    event::{DataChange, MetadataKind, ModifyKind, RenameMode},
+    EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use serde::Deserialize;
 
-use crate::metrics::MetricMessage;
+use crate::{
+    fanotify::FanotifyWatcher,
+    metrics::MetricMessage,
+    mounts::{find_mount, read_mounts, Mount},
+};
 
-fn match_base_path(base_paths: &[PathBuf], paths: &[PathBuf]) -> Result<String> {
-    for base in base_paths {
-        for event_path in paths.iter() {
-            if event_path.starts_with(base) {
-                return Ok(base.to_string_lossy().to_string());
+/// An event below one of the watched directories
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchEvent {
+    /// The watched directory, see [`match_base_path`]
+    pub path: String,
+    pub kind: EventKind,
+    /// The files and directories the event is about
+    pub paths: Vec<PathBuf>,
+    /// The process behind the event, only known in fanotify mode
+    pub pid: Option<u32>,
+    /// Its name as in `/proc/<pid>/comm`
+    pub comm: Option<String>,
+}
+
+impl WatchEvent {
+    /// All values of [`WatchEvent::kind_label`]
+    pub const KIND_LABELS: [&'static str; 5] = ["create", "modify", "remove", "access", "other"];
+
+    /// The `kind` label of `notify_events`: create, modify, remove, access or
+    /// other
+    pub fn kind_label(&self) -> &'static str {
+        match self.kind {
+            EventKind::Create(_) => "create",
+            EventKind::Modify(_) => "modify",
+            EventKind::Remove(_) => "remove",
+            EventKind::Access(_) => "access",
+            EventKind::Any | EventKind::Other => "other",
+        }
+    }
+}
+
+/// A kind of event that can be counted or not, see `--watch-events`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchEventKind {
+    Create,
+    /// Changes of the content or the name
+    Modify,
+    /// Changes of only permissions, owner or timestamps
+    Metadata,
+    Remove,
+    Access,
+    /// Events whose kind isn't known
+    Other,
+}
+
+impl WatchEventKind {
+    fn matches(self, kind: &EventKind) -> bool {
+        match (self, kind) {
+            (WatchEventKind::Create, EventKind::Create(_)) => true,
+            (WatchEventKind::Modify, EventKind::Modify(ModifyKind::Metadata(_))) => false,
+            (WatchEventKind::Modify, EventKind::Modify(_)) => true,
+            (WatchEventKind::Metadata, EventKind::Modify(ModifyKind::Metadata(_))) => true,
+            (WatchEventKind::Remove, EventKind::Remove(_)) => true,
+            (WatchEventKind::Access, EventKind::Access(_)) => true,
+            (WatchEventKind::Other, EventKind::Any | EventKind::Other) => true,
+            _ => false,
+        }
+    }
+}
+
+/// How a watched directory notices changes
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// inotify, or polling on network and FUSE filesystems
+    #[default]
+    Auto,
+    Inotify,
+    /// Scan the whole directory for changes every poll interval. This reads
+    /// the metadata of every file, so it keeps local disks from spinning
+    /// down.
+    Poll,
+    /// One fanotify mark for the whole mount instead of an inotify watch per
+    /// directory. Sees reads, writes and opens but not files being created
+    /// or removed. Needs CAP_SYS_ADMIN
+    Fanotify,
+}
+
+/// Filesystems where inotify misses changes made by other hosts or below
+/// the FUSE layer, e.g. on a branch of mergerfs
+fn misses_changes(fstype: &str) -> bool {
+    matches!(
+        fstype,
+        "nfs" | "nfs4" | "cifs" | "smb3" | "smbfs" | "9p" | "ceph" | "afs"
+    ) || (fstype.starts_with("fuse") && fstype != "fuseblk")
+}
+
+/// Which events of the watched directories count
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WatchOptions {
+    /// Globs of paths whose events are dropped, e.g. `*.tmp` or
+    /// `**/.recycle/**`. Without a `/` they match the file name, otherwise
+    /// the path below the watched directory.
+    pub ignore: Vec<Pattern>,
+    /// Events of the same paths and kind within this window after the first
+    /// one are dropped, zero to keep all of them
+    pub debounce: Duration,
+    /// Kinds of events that count, all of them if empty
+    pub events: Vec<WatchEventKind>,
+    /// Kinds of events that count below the watched directories matching a
+    /// glob, instead of `events`. The first match wins.
+    pub directory_events: Vec<(Pattern, Vec<WatchEventKind>)>,
+    /// How directories without a mode of their own notice changes
+    pub mode: WatchMode,
+    /// How the watched directories matching a glob notice changes, instead
+    /// of `mode`. The first match wins.
+    pub directory_modes: Vec<(Pattern, WatchMode)>,
+    /// How often polled directories are scanned, notify's default of 30
+    /// seconds if zero
+    pub poll_interval: Duration,
+}
+
+impl WatchOptions {
+    fn is_ignored(&self, base: &Path, path: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let relative = path.strip_prefix(base).unwrap_or(path);
+        self.ignore.iter().any(|pattern| {
+            if pattern.as_str().contains('/') {
+                pattern.matches_path_with(relative, options)
+            } else {
+                path.file_name()
+                    .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), options))
             }
+        })
+    }
+
+    /// How `directory` is watched, never [`WatchMode::Auto`]
+    fn mode(&self, directory: &Path, mounts: &[Mount]) -> WatchMode {
+        let mode = self
+            .directory_modes
+            .iter()
+            .find(|(pattern, _)| pattern.matches_path(directory))
+            .map_or(self.mode, |(_, mode)| *mode);
+        match mode {
+            WatchMode::Auto
+                if find_mount(mounts, directory)
+                    .is_some_and(|mount| misses_changes(&mount.fstype)) =>
+            {
+                WatchMode::Poll
+            }
+            WatchMode::Auto => WatchMode::Inotify,
+            mode => mode,
         }
     }
+
+    /// Whether events of `kind` count below the watched directory `base`
+    fn counts(&self, base: &Path, kind: &EventKind) -> bool {
+        let events = self
+            .directory_events
+            .iter()
+            .find(|(directory, _)| directory.matches_path(base))
+            .map_or(&self.events, |(_, events)| events);
+        events.is_empty() || events.iter().any(|event| event.matches(kind))
+    }
+}
+
+/// The watched directory an event belongs to, used as the `path` label of
+/// `notify_events`. With nested watches the innermost one wins, so a busy
+/// share isn't hidden behind its parent.
+fn match_base_path(base_paths: &[PathBuf], paths: &[PathBuf]) -> Result<String> {
+    let base = base_paths
+        .iter()
+        .filter(|base| paths.iter().any(|path| path.starts_with(base)))
+        .max_by_key(|base| base.components().count());
+    if let Some(base) = base {
+        return Ok(base.to_string_lossy().to_string());
+    }
     bail!(
         "No match for event in any of the paths. paths: {:?}, base_paths: {:?}",
         paths,
@@ -24,34 +200,358 @@ fn match_base_path(base_paths: &[PathBuf], paths: &[PathBuf]) -> Result<String>
     )
 }
 
-fn handle_notify_event(
-    watches: &[PathBuf],
-    tx: &Sender<MetricMessage>,
-    res: notify::Result<notify::Event>,
-) {
-    let message = match res {
-        Ok(event) => match_base_path(watches, &event.paths),
-        Err(e) => Err(anyhow!(e)),
-    };
-    if let Err(err) = tx.send(MetricMessage::NotifyEvent(message)) {
+/// Whether a watch directory is a glob pattern like `/srv/media/*`
+pub fn is_pattern(directory: &str) -> bool {
+    directory.contains(['*', '?', '['])
+}
+
+/// The directories to watch, with glob patterns expanded to the directories
+/// matching them right now. Other paths are kept as they are.
+pub fn expand_directories(directories: &[String]) -> Result<Vec<PathBuf>> {
+    let mut expanded = Vec::new();
+    for directory in directories {
+        if !is_pattern(directory) {
+            expanded.push(PathBuf::from(directory));
+            continue;
+        }
+        let matches: Vec<PathBuf> = glob::glob(directory)
+            .with_context(|| format!("Invalid watch directory pattern {}", directory))?
+            .flatten()
+            .filter(|path| path.is_dir())
+            .collect();
+        if matches.is_empty() {
+            debug!("{} doesn't match any directories yet", directory);
+        }
+        expanded.extend(matches);
+    }
+    let mut seen = HashSet::new();
+    expanded.retain(|path| seen.insert(path.clone()));
+    Ok(expanded)
+}
+
+/// Turns the events of the watcher into messages, dropping the ones that
+/// don't count
+struct NotifyHandler {
+    watches: Vec<PathBuf>,
+    options: WatchOptions,
+    /// When an event was last sent, by its paths and kind label
+    sent: HashMap<(Vec<PathBuf>, &'static str), Instant>,
+    /// Watched directories that were deleted or moved away, which ends their
+    /// watch
+    removed: HashSet<PathBuf>,
+}
+
+impl NotifyHandler {
+    fn new(watches: Vec<PathBuf>, options: WatchOptions) -> Self {
+        NotifyHandler {
+            watches,
+            options,
+            sent: HashMap::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    /// The message for an event at `now`, if any
+    fn handle(
+        &mut self,
+        res: notify::Result<notify::Event>,
+        now: Instant,
+    ) -> Option<Result<WatchEvent>> {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                // The watch may not work anymore, have the directories the
+                // error is about (all if it doesn't say) watched again
+                let lost: Vec<PathBuf> = self
+                    .watches
+                    .iter()
+                    .filter(|watch| {
+                        e.paths.is_empty() || e.paths.iter().any(|path| path.starts_with(watch))
+                    })
+                    .cloned()
+                    .collect();
+                self.removed.extend(lost);
+                return Some(Err(anyhow!(e)));
+            }
+        };
+        if matches!(
+            event.kind,
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From))
+        ) {
+            for path in &event.paths {
+                if self.watches.contains(path) {
+                    self.removed.insert(path.clone());
+                }
+            }
+        }
+        let path = match match_base_path(&self.watches, &event.paths) {
+            Ok(path) => path,
+            Err(err) => return Some(Err(err)),
+        };
+        let base = Path::new(&path);
+        // Paths can't be empty once they matched a watch
+        if event.paths.iter().all(|p| self.options.is_ignored(base, p))
+            || !self.options.counts(base, &event.kind)
+        {
+            return None;
+        }
+        let event = WatchEvent {
+            path,
+            pid: event.attrs.process_id(),
+            comm: event.attrs.info().map(String::from),
+            kind: event.kind,
+            paths: event.paths,
+        };
+        self.debounce(&event, now).then_some(Ok(event))
+    }
+
+    /// Whether `event` is the first of its paths and kind within the
+    /// debounce window
+    fn debounce(&mut self, event: &WatchEvent, now: Instant) -> bool {
+        let window = self.options.debounce;
+        if window.is_zero() {
+            return true;
+        }
+        self.sent
+            .retain(|_, sent| now.saturating_duration_since(*sent) < window);
+        let key = (event.paths.clone(), event.kind_label());
+        if self.sent.contains_key(&key) {
+            return false;
+        }
+        self.sent.insert(key, now);
+        true
+    }
+}
+
+/// Wait this long before watching a lost directory again, doubled after
+/// each attempt that fails
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Device and inode of a directory. A mount over it changes them.
+fn identity(directory: &Path) -> Option<(u64, u64)> {
+    fs::metadata(directory)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// The watchers of the directories, which stop once dropped
+pub struct Watchers {
+    _inotify: RecommendedWatcher,
+    _poll: Option<PollWatcher>,
+    _fanotify: Option<FanotifyWatcher>,
+    handler: Arc<Mutex<NotifyHandler>>,
+    /// What the directories that could be watched were when they were
+    identities: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl Watchers {
+    /// Directories that aren't watched: they couldn't be, or were deleted,
+    /// moved or mounted over since, which ends inotify watches silently
+    pub fn lost(&self) -> Vec<PathBuf> {
+        let handler = self.handler.lock().unwrap();
+        handler
+            .watches
+            .iter()
+            .filter(|watch| {
+                handler.removed.contains(*watch)
+                    || self.identities.get(*watch).copied() != identity(watch)
+                    || !self.identities.contains_key(*watch)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// When to try watching lost directories again
+#[derive(Debug, Default)]
+pub struct WatchRetries {
+    /// Failed attempts so far and when to try again, by directory
+    retries: HashMap<PathBuf, (u32, Instant)>,
+}
+
+impl WatchRetries {
+    /// Start retrying the directories of `lost` that weren't lost before and
+    /// forget the ones that aren't anymore. Returns the newly lost ones.
+    pub fn update(&mut self, lost: &[PathBuf], now: Instant) -> Vec<PathBuf> {
+        self.retries.retain(|directory, _| lost.contains(directory));
+        let new: Vec<PathBuf> = lost
+            .iter()
+            .filter(|directory| !self.retries.contains_key(*directory))
+            .cloned()
+            .collect();
+        for directory in &new {
+            self.retries
+                .insert(directory.clone(), (0, now + RETRY_INITIAL_BACKOFF));
+        }
+        new
+    }
+
+    /// The directories it's time to try again
+    pub fn due(&self, now: Instant) -> Vec<PathBuf> {
+        self.retries
+            .iter()
+            .filter(|(_, (_, retry_at))| *retry_at <= now)
+            .map(|(directory, _)| directory.clone())
+            .collect()
+    }
+
+    /// Back off further after watching `directory` failed again. Returns
+    /// how long until the next attempt.
+    pub fn failed(&mut self, directory: &Path, now: Instant) -> Duration {
+        let (attempts, retry_at) = self
+            .retries
+            .entry(directory.to_path_buf())
+            .or_insert((0, now));
+        *attempts += 1;
+        let backoff = RETRY_INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(*attempts))
+            .min(RETRY_MAX_BACKOFF);
+        *retry_at = now + backoff;
+        backoff
+    }
+
+    /// When the next directory is due, if any is lost
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.retries.values().map(|(_, retry_at)| *retry_at).min()
+    }
+}
+
+/// Sends the messages for the events of a watcher. Polling only sees the
+/// modification time change when a file is written, which is reported as a
+/// change of its content instead.
+fn send_events(
+    handler: Arc<Mutex<NotifyHandler>>,
+    tx: Sender<MetricMessage>,
+    polled: bool,
+) -> impl FnMut(notify::Result<notify::Event>) + Send + 'static {
+    move |mut res| {
+        if let Ok(event) = &mut res {
+            if polled
+                && event.kind == EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+            {
+                event.kind = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+            }
+        }
+        let Some(message) = handler.lock().unwrap().handle(res, Instant::now()) else {
+            return;
+        };
+        if let Err(err) = tx.send(MetricMessage::NotifyEvent(message)) {
+            error!("Error sending message: {:?}", err);
+        }
+    }
+}
+
+/// Logs why `directory` couldn't be watched, or only partly, and counts it
+fn watch_failed(directory: &Path, err: &anyhow::Error, tx: &Sender<MetricMessage>) {
+    let watch_limit = err
+        .downcast_ref::<notify::Error>()
+        .is_some_and(|err| matches!(err.kind, notify::ErrorKind::MaxFilesWatch));
+    if watch_limit {
+        let limit = fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+            .map(|limit| limit.trim().to_string())
+            .unwrap_or_else(|_| String::from("unknown"));
+        error!(
+            "Ran out of inotify watches for {}, only some of its subdirectories are watched. \
+             Raise the sysctl fs.inotify.max_user_watches (now {}) or poll the directory",
+            directory.to_string_lossy(),
+            limit
+        );
+    } else {
+        error!("Failed to watch {}: {:#}", directory.to_string_lossy(), err);
+    }
+    if let Err(err) = tx.send(MetricMessage::WatchSetupFailed { watch_limit }) {
         error!("Error sending message: {:?}", err);
     }
 }
 
-pub fn watch(watches: Vec<&Path>, tx: Sender<MetricMessage>) -> Result<RecommendedWatcher> {
-    let watches_matcher: Result<Vec<PathBuf>> = watches
+/// Watches `watches` recursively. Directories that can't be watched are
+/// logged and counted, the others are watched anyway.
+pub fn watch(
+    watches: Vec<&Path>,
+    options: WatchOptions,
+    tx: Sender<MetricMessage>,
+) -> Result<Watchers> {
+    let watches: Vec<PathBuf> = watches
         .iter()
         .map(|p| Ok(std::path::absolute(p)?))
-        .collect();
-    let watches_matcher = watches_matcher?;
-    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
-        handle_notify_event(&watches_matcher, &tx, res)
-    })?;
-    for watch in watches {
-        watcher.watch(watch, RecursiveMode::Recursive)?;
+        .collect::<Result<_>>()?;
+    let mounts = read_mounts(Path::new("/proc/mounts")).unwrap_or_else(|err| {
+        warn!("Watching everything with inotify: {:?}", err);
+        Vec::new()
+    });
+    let (mut watched, mut polled, mut marked) = (Vec::new(), Vec::new(), Vec::new());
+    for watch in &watches {
+        match options.mode(watch, &mounts) {
+            WatchMode::Poll => polled.push(watch),
+            WatchMode::Fanotify => marked.push(watch),
+            _ => watched.push(watch),
+        }
+    }
+    let mut config = notify::Config::default();
+    if !options.poll_interval.is_zero() {
+        config = config.with_poll_interval(options.poll_interval);
     }
+    let handler = Arc::new(Mutex::new(NotifyHandler::new(watches.clone(), options)));
+    let mut identities = HashMap::new();
+    let mut established = |watch: &Path, result: Result<()>| match result {
+        Ok(()) => {
+            if let Some(id) = identity(watch) {
+                identities.insert(watch.to_path_buf(), id);
+            }
+        }
+        Err(err) => watch_failed(watch, &err, &tx),
+    };
 
-    Ok(watcher)
+    let mut inotify = notify::recommended_watcher(send_events(handler.clone(), tx.clone(), false))?;
+    for watch in watched {
+        established(
+            watch,
+            inotify
+                .watch(watch, RecursiveMode::Recursive)
+                .map_err(Into::into),
+        );
+    }
+    let poll = if polled.is_empty() {
+        None
+    } else {
+        info!("Polling {:?} for changes", polled);
+        let mut poll = PollWatcher::new(send_events(handler.clone(), tx.clone(), true), config)?;
+        for watch in polled {
+            established(
+                watch,
+                poll.watch(watch, RecursiveMode::Recursive)
+                    .map_err(Into::into),
+            );
+        }
+        Some(poll)
+    };
+    let fanotify = if marked.is_empty() {
+        None
+    } else {
+        match FanotifyWatcher::new(send_events(handler.clone(), tx.clone(), false)) {
+            Ok(mut fanotify) => {
+                for watch in marked {
+                    established(watch, fanotify.watch(watch));
+                }
+                Some(fanotify)
+            }
+            Err(err) => {
+                for watch in marked {
+                    watch_failed(watch, &err, &tx);
+                }
+                None
+            }
+        }
+    };
+
+    Ok(Watchers {
+        _inotify: inotify,
+        _poll: poll,
+        _fanotify: fanotify,
+        handler,
+        identities,
+    })
 }
 
 #[cfg(test)]
@@ -63,6 +563,290 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_match_base_path() {
+        let base_paths = [PathBuf::from("/srv"), PathBuf::from("/srv/media")];
+        let matched = |path: &str| match_base_path(&base_paths, &[PathBuf::from(path)]);
+        assert_eq!(matched("/srv/media/movie.mkv").unwrap(), "/srv/media");
+        assert_eq!(matched("/srv/backup/full.tar").unwrap(), "/srv");
+        // not a path prefix, only a string prefix
+        assert_eq!(matched("/srv/mediacenter/x").unwrap(), "/srv");
+        assert!(matched("/home/user").is_err());
+    }
+
+    #[test]
+    fn test_ignore() {
+        let options = WatchOptions {
+            ignore: ["*.tmp", ".DS_Store", "**/.recycle/**", "incoming/*.part"]
+                .map(|pattern| Pattern::new(pattern).unwrap())
+                .to_vec(),
+            ..Default::default()
+        };
+        let base = Path::new("/srv/media");
+        let ignored = |path: &str| options.is_ignored(base, Path::new(path));
+        assert!(ignored("/srv/media/movies/upload.tmp"));
+        assert!(ignored("/srv/media/.DS_Store"));
+        assert!(ignored("/srv/media/.recycle/movie.mkv"));
+        assert!(ignored("/srv/media/movies/.recycle/old/movie.mkv"));
+        assert!(ignored("/srv/media/incoming/movie.part"));
+        assert!(!ignored("/srv/media/movies/incoming/movie.part"));
+        assert!(!ignored("/srv/media/movies/movie.mkv"));
+        assert!(!ignored("/srv/media/recycle.txt"));
+
+        let mut handler = NotifyHandler::new(vec![base.to_path_buf()], options);
+        let now = Instant::now();
+        assert!(handler
+            .handle(event(EventKind::Any, "/srv/media/a.tmp"), now)
+            .is_none());
+        let message = handler.handle(event(EventKind::Any, "/srv/media/a.mkv"), now);
+        assert!(message.is_some_and(|event| event.unwrap().path == "/srv/media"));
+    }
+
+    fn event(kind: EventKind, path: &str) -> notify::Result<notify::Event> {
+        Ok(notify::Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn test_event_kinds() {
+        use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind};
+
+        let options = WatchOptions {
+            events: vec![
+                WatchEventKind::Create,
+                WatchEventKind::Modify,
+                WatchEventKind::Remove,
+            ],
+            directory_events: vec![
+                (
+                    Pattern::new("/srv/backup").unwrap(),
+                    vec![WatchEventKind::Access],
+                ),
+                (Pattern::new("/srv/shares/*").unwrap(), Vec::new()),
+            ],
+            ..Default::default()
+        };
+        let watches = ["/srv/media", "/srv/backup", "/srv/shares/alice"].map(PathBuf::from);
+        let mut handler = NotifyHandler::new(watches.to_vec(), options);
+        let mut counts = |kind, path| handler.handle(event(kind, path), Instant::now()).is_some();
+        let create = EventKind::Create(CreateKind::File);
+        let write = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        let chmod = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions));
+        let read = EventKind::Access(AccessKind::Read);
+        assert!(counts(create, "/srv/media/a.mkv"));
+        assert!(counts(write, "/srv/media/a.mkv"));
+        assert!(!counts(chmod, "/srv/media/a.mkv"));
+        assert!(!counts(read, "/srv/media/a.mkv"));
+        assert!(!counts(EventKind::Any, "/srv/media/a.mkv"));
+        assert!(counts(read, "/srv/backup/a.tar"));
+        assert!(!counts(write, "/srv/backup/a.tar"));
+        // an empty list counts everything
+        assert!(counts(chmod, "/srv/shares/alice/a.txt"));
+        assert!(counts(read, "/srv/shares/alice/a.txt"));
+    }
+
+    #[test]
+    fn test_poll() {
+        let mounts = crate::mounts::parse_mounts(
+            "/dev/sda2 / ext4 rw 0 0
+nas:/media /srv/nas nfs4 rw 0 0
+pool /srv/pool fuse.mergerfs rw 0 0
+/dev/sdb1 /srv/usb fuseblk rw 0 0
+",
+        );
+        let options = WatchOptions {
+            directory_modes: vec![
+                (Pattern::new("/srv/nas/live").unwrap(), WatchMode::Inotify),
+                (Pattern::new("/srv/media/*").unwrap(), WatchMode::Poll),
+            ],
+            ..Default::default()
+        };
+        let polls =
+            |directory: &str| options.mode(Path::new(directory), &mounts) == WatchMode::Poll;
+        assert!(polls("/srv/nas/movies"));
+        assert!(polls("/srv/pool"));
+        assert!(!polls("/srv/usb"));
+        assert!(!polls("/srv/nas/live"));
+        assert!(!polls("/srv/media"));
+        assert!(polls("/srv/media/movies"));
+        let options = WatchOptions {
+            mode: WatchMode::Fanotify,
+            ..options
+        };
+        assert_eq!(
+            options.mode(Path::new("/srv/nas"), &mounts),
+            WatchMode::Fanotify
+        );
+        assert_eq!(
+            options.mode(Path::new("/srv/media/movies"), &mounts),
+            WatchMode::Poll
+        );
+
+        let dir = TempDir::new().unwrap();
+        let options = WatchOptions {
+            directory_modes: vec![(
+                Pattern::new(&dir.path().to_string_lossy()).unwrap(),
+                WatchMode::Poll,
+            )],
+            poll_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _watchers = watch(vec![dir.path()], options, tx).unwrap();
+        fs::write(dir.path().join("movie.mkv"), "").unwrap();
+        let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(
+            message,
+            MetricMessage::NotifyEvent(Ok(event)) if event.kind_label() == "create"
+        ));
+    }
+
+    #[test]
+    fn test_watch_failed() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _watchers = watch(
+            vec![missing.as_path(), dir.path()],
+            WatchOptions::default(),
+            tx,
+        )
+        .unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(MetricMessage::WatchSetupFailed { watch_limit: false })
+        ));
+        // the other directory is still watched
+        fs::write(dir.path().join("movie.mkv"), "").unwrap();
+        let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(message, MetricMessage::NotifyEvent(Ok(_))));
+    }
+
+    #[test]
+    fn test_lost() {
+        let dir = TempDir::new().unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir(&share).unwrap();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let watchers = watch(
+            vec![share.as_path(), dir.path()],
+            WatchOptions::default(),
+            tx,
+        )
+        .unwrap();
+        assert!(watchers.lost().is_empty());
+
+        // the inode number may be reused, but the removal was seen
+        fs::remove_dir(&share).unwrap();
+        fs::create_dir(&share).unwrap();
+        let start = Instant::now();
+        while watchers.lost().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(watchers.lost(), [share]);
+    }
+
+    #[test]
+    fn test_retries() {
+        let (share, media) = (PathBuf::from("/srv/share"), PathBuf::from("/srv/media"));
+        let mut retries = WatchRetries::default();
+        let start = Instant::now();
+        assert_eq!(retries.next_retry(), None);
+        let lost = [share.clone()];
+        assert_eq!(retries.update(&lost, start), lost);
+        assert!(retries.due(start).is_empty());
+        let due = start + RETRY_INITIAL_BACKOFF;
+        assert_eq!(retries.due(due), lost);
+        assert_eq!(retries.failed(&share, due), Duration::from_secs(10));
+        assert_eq!(retries.failed(&share, due), Duration::from_secs(20));
+        assert_eq!(retries.next_retry(), Some(due + Duration::from_secs(20)));
+        for _ in 0..10 {
+            retries.failed(&share, due);
+        }
+        assert_eq!(retries.failed(&share, due), RETRY_MAX_BACKOFF);
+        // lost before, so not new
+        let lost = [media];
+        assert_eq!(retries.update(&[share, lost[0].clone()], due), lost);
+        retries.update(&lost, due);
+        assert_eq!(retries.due(due + RETRY_MAX_BACKOFF), lost);
+        retries.update(&[], due);
+        assert_eq!(retries.next_retry(), None);
+    }
+
+    #[test]
+    fn test_debounce() {
+        let options = WatchOptions {
+            debounce: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut handler = NotifyHandler::new(vec![PathBuf::from("/srv/media")], options);
+        let modify = EventKind::Modify(notify::event::ModifyKind::Any);
+        let start = Instant::now();
+        let mut sent = |kind, path, millis| {
+            let now = start + Duration::from_millis(millis);
+            handler.handle(event(kind, path), now).is_some()
+        };
+        assert!(sent(modify, "/srv/media/a.mkv", 0));
+        assert!(!sent(modify, "/srv/media/a.mkv", 10));
+        assert!(!sent(modify, "/srv/media/a.mkv", 999));
+        // other paths and kinds aren't held back
+        assert!(sent(modify, "/srv/media/b.mkv", 10));
+        assert!(sent(EventKind::Any, "/srv/media/a.mkv", 10));
+        assert!(sent(modify, "/srv/media/a.mkv", 1000));
+        // errors always get through
+        let mut handler = NotifyHandler::new(Vec::new(), WatchOptions::default());
+        assert!(handler
+            .handle(event(modify, "/home"), start)
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_error_watches_again() {
+        let (media, nas) = (PathBuf::from("/srv/media"), PathBuf::from("/srv/nas"));
+        let mut handler =
+            NotifyHandler::new(vec![media.clone(), nas.clone()], WatchOptions::default());
+        let now = Instant::now();
+        let error = notify::Error::generic("read failed").add_path(media.join("a.mkv"));
+        assert!(handler.handle(Err(error), now).unwrap().is_err());
+        assert_eq!(handler.removed, HashSet::from([media.clone()]));
+
+        let error = notify::Error::generic("queue overflow");
+        assert!(handler.handle(Err(error), now).unwrap().is_err());
+        assert_eq!(handler.removed, HashSet::from([media, nas]));
+    }
+
+    #[test]
+    fn test_expand_directories() {
+        let root = TempDir::new().unwrap();
+        for share in ["movies", "music", "photos"] {
+            fs::create_dir(root.path().join(share)).unwrap();
+        }
+        fs::write(root.path().join("mfile"), "").unwrap();
+        let root_path = root.path().to_string_lossy();
+        let expanded = expand_directories(&[
+            format!("{}/m*", root_path),
+            format!("{}/photos", root_path),
+            format!("{}/music", root_path),
+            format!("{}/missing/*", root_path),
+        ])
+        .unwrap();
+        assert_eq!(
+            expanded,
+            ["movies", "music", "photos"].map(|share| root.path().join(share))
+        );
+
+        // new matches show up when expanding again
+        fs::create_dir(root.path().join("mixes")).unwrap();
+        let expanded = expand_directories(&[format!("{}/m*", root_path)]).unwrap();
+        assert_eq!(
+            expanded,
+            ["mixes", "movies", "music"].map(|share| root.path().join(share))
+        );
+
+        assert!(!is_pattern("/srv/media"));
+        assert!(expand_directories(&[String::from("/srv/[media")]).is_err());
+    }
+
     #[test]
     fn it_works() {
         crate::metrics::test::init();
@@ -71,12 +855,12 @@ mod test {
         let event_file = monitored_dir.path().join("text.txt");
         let watches = vec![monitored_dir.path()];
         let (tx, rx) = std::sync::mpsc::channel();
-        let watcher = watch(watches, tx).unwrap();
+        let watcher = watch(watches, WatchOptions::default(), tx).unwrap();
 
         // emit some events by changing a file
         std::fs::write(event_file, b"Lorem ipsum").unwrap();
 
-        let mut counter = 0;
+        let mut kinds = Vec::new();
         // need to know exactly how many events to expect
         // making a blocking call isn't possible as it's not clear when all events have been
         // received.
@@ -85,8 +869,11 @@ mod test {
             match res {
                 MetricMessage::NotifyEvent(Ok(event)) => {
                     info!("event: {:?}", event);
-                    assert_eq!(event, monitored_dir.path().to_string_lossy().to_string());
-                    counter += 1
+                    assert_eq!(
+                        event.path,
+                        monitored_dir.path().to_string_lossy().to_string()
+                    );
+                    kinds.push(event.kind_label());
                 }
                 MetricMessage::NotifyEvent(Err(e)) => {
                     panic!("watch error: {:?}", e);
@@ -98,7 +885,8 @@ mod test {
         // Ensure transmitting side is closed
         drop(watcher);
 
-        assert_eq!(counter, 3);
+        // file create, write & close
+        assert_eq!(kinds, vec!["create", "modify", "access"]);
     }
 
     #[test]
@@ -113,7 +901,7 @@ mod test {
         let event_file = subdir1.join("text.txt");
         let watches = vec![subdir1.as_path()];
         let (tx, rx) = std::sync::mpsc::channel();
-        let watcher = watch(watches, tx).unwrap();
+        let watcher = watch(watches, WatchOptions::default(), tx).unwrap();
 
         // emit some events by changing a file
         std::fs::write(event_file, b"Lorem ipsum").unwrap();
@@ -127,7 +915,7 @@ mod test {
             match res {
                 MetricMessage::NotifyEvent(Ok(event)) => {
                     info!("event: {:?}", event);
-                    assert_eq!(event, subdir1.to_string_lossy().to_string());
+                    assert_eq!(event.path, subdir1.to_string_lossy().to_string());
                     counter += 1
                 }
                 MetricMessage::NotifyEvent(Err(e)) => {
@@ -0,0 +1,284 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use prometheus::{proto::MetricFamily, Registry};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exposition::{samples, Sample},
+    net,
+    own_io::OwnIo,
+    sink::ExportSink,
+};
+
+/// Where values for trapper items go
+enum Target {
+    /// Zabbix server or proxy like `zabbix:10051`, spoken to like
+    /// `zabbix_sender` does
+    Server(String),
+    /// Input file for `zabbix_sender -T -i`, replaced on every export
+    File(PathBuf),
+}
+
+/// Sends metrics as values of Zabbix trapper items. Item keys are the metric
+/// names with the label values as parameters, like
+/// `disk_status[/dev/sda]`.
+pub struct ZabbixSender {
+    target: Target,
+    /// Host the items belong to in Zabbix
+    host: String,
+    own_io: Option<OwnIo>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    request: &'static str,
+    data: Vec<Item<'a>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Item<'a> {
+    host: &'a str,
+    key: String,
+    value: String,
+    clock: u64,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    response: String,
+    #[serde(default)]
+    info: String,
+}
+
+const HEADER: &[u8] = b"ZBXD\x01";
+
+impl ZabbixSender {
+    pub fn server(addr: &str, host: &str) -> Self {
+        ZabbixSender {
+            target: Target::Server(addr.to_string()),
+            host: host.to_string(),
+            own_io: None,
+        }
+    }
+
+    pub fn file(path: PathBuf, host: &str) -> Self {
+        ZabbixSender {
+            target: Target::File(path),
+            host: host.to_string(),
+            own_io: None,
+        }
+    }
+
+    /// Record the writes to the file, so they aren't mistaken for disk
+    /// activity
+    pub fn with_own_io(mut self, own_io: OwnIo) -> Self {
+        self.own_io = Some(own_io);
+        self
+    }
+
+    pub fn send(&self, families: &[MetricFamily]) -> Result<()> {
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let items = items(families, &self.host, clock);
+        match &self.target {
+            Target::Server(addr) => send_items(addr, items),
+            Target::File(path) => {
+                let input: String = items
+                    .iter()
+                    .map(|item| {
+                        format!(
+                            "{} {} {} {}\n",
+                            quote(item.host),
+                            quote(&item.key),
+                            item.clock,
+                            quote(&item.value)
+                        )
+                    })
+                    .collect();
+                fs::write(path, &input).with_context(|| {
+                    format!(
+                        "Failed to write zabbix_sender input to {}",
+                        path.to_string_lossy()
+                    )
+                })?;
+                if let Some(own_io) = &self.own_io {
+                    if let Err(err) = own_io.record_write(path, input.len() as u64) {
+                        debug!("Failed to record zabbix_sender input write: {:?}", err);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ExportSink for ZabbixSender {
+    fn name(&self) -> &str {
+        "zabbix"
+    }
+
+    fn export(&self, registry: &Registry) -> Result<()> {
+        self.send(&registry.gather())
+    }
+}
+
+fn send_items(addr: &str, items: Vec<Item>) -> Result<()> {
+    let request = serde_json::to_vec(&Request {
+        request: "sender data",
+        data: items,
+    })?;
+    let mut stream = net::connect(addr, Duration::from_secs(10)).context("Zabbix unavailable")?;
+    let mut packet = HEADER.to_vec();
+    packet.extend_from_slice(&(request.len() as u64).to_le_bytes());
+    packet.extend_from_slice(&request);
+    stream
+        .write_all(&packet)
+        .with_context(|| format!("Failed to send values to Zabbix at {}", addr))?;
+
+    let mut header = [0; 13];
+    stream
+        .read_exact(&mut header)
+        .with_context(|| format!("No answer from Zabbix at {}", addr))?;
+    if &header[..5] != HEADER {
+        bail!("Unexpected answer from Zabbix at {}", addr);
+    }
+    let length = u64::from_le_bytes(header[5..].try_into()?);
+    let mut body = Vec::new();
+    stream.take(length).read_to_end(&mut body)?;
+    let response: Response = serde_json::from_slice(&body)
+        .with_context(|| format!("Unexpected answer from Zabbix at {}", addr))?;
+    if response.response != "success" {
+        bail!("Zabbix at {} refused the values: {}", addr, response.info);
+    }
+    // Values for items that don't exist are silently dropped, only the info
+    // says so
+    debug!("Sent values to Zabbix: {}", response.info);
+    Ok(())
+}
+
+/// A value for every finite sample
+fn items<'a>(families: &[MetricFamily], host: &'a str, clock: u64) -> Vec<Item<'a>> {
+    families
+        .iter()
+        .flat_map(samples)
+        .filter(|sample| sample.value.is_finite())
+        .map(|sample| Item {
+            host,
+            key: key(&sample),
+            value: sample.value.to_string(),
+            clock,
+        })
+        .collect()
+}
+
+/// Item key with the label values as parameters, quoted where needed
+fn key(sample: &Sample) -> String {
+    if sample.labels.is_empty() {
+        return sample.name.clone();
+    }
+    let parameters: Vec<String> = sample
+        .labels
+        .iter()
+        .map(|(_, value)| {
+            if value.contains([',', ']', '"', ' ', '[']) {
+                format!("\"{}\"", value.replace('"', "\\\""))
+            } else {
+                value.clone()
+            }
+        })
+        .collect();
+    format!("{}[{}]", sample.name, parameters.join(","))
+}
+
+/// Quote a field of zabbix_sender input if it needs to be
+fn quote(field: &str) -> String {
+    if field.contains([' ', '"', '\\', '\t']) {
+        format!("\"{}\"", field.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::TcpListener, thread};
+
+    use prometheus::{GaugeVec, IntCounter, Opts};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        let spinups = IntCounter::new("disk_spinups_total", "Spin-ups").unwrap();
+        let status = GaugeVec::new(Opts::new("disk_status", "Status"), &["disk", "name"]).unwrap();
+        registry.register(Box::new(spinups.clone())).unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+        spinups.inc_by(3);
+        status.with_label_values(&["/dev/sda", "media 1"]).set(1.0);
+        status.with_label_values(&["/dev/sdb", ""]).set(f64::NAN);
+        registry
+    }
+
+    #[test]
+    fn test_items() {
+        let items = items(&registry().gather(), "nas", 1700000000);
+        let keys: Vec<&str> = items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["disk_spinups_total", "disk_status[/dev/sda,\"media 1\"]"]
+        );
+        assert_eq!(items[0].value, "3");
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("zabbix_sender.txt");
+        ZabbixSender::file(path.clone(), "nas")
+            .send(&registry().gather())
+            .unwrap();
+        let input = fs::read_to_string(&path).unwrap();
+        let mut lines = input.lines().map(|line| line.rsplit_once(' ').unwrap());
+        let (start, value) = lines.nth(1).unwrap();
+        assert!(start.starts_with("nas \"disk_status[/dev/sda,\\\"media 1\\\"]\" "));
+        assert_eq!(value, "1");
+    }
+
+    #[test]
+    fn test_send_to_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 13];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(&header[..5], HEADER);
+            let length = u64::from_le_bytes(header[5..].try_into().unwrap()) as usize;
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            let response = br#"{"response":"success","info":"processed: 2; failed: 0"}"#;
+            stream.write_all(HEADER).unwrap();
+            stream
+                .write_all(&(response.len() as u64).to_le_bytes())
+                .unwrap();
+            stream.write_all(response).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        });
+
+        ZabbixSender::server(&addr.to_string(), "nas")
+            .send(&registry().gather())
+            .unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request["request"], "sender data");
+        assert_eq!(request["data"][0]["host"], "nas");
+        assert_eq!(request["data"][0]["key"], "disk_spinups_total");
+        assert_eq!(request["data"][0]["value"], "3");
+    }
+}